@@ -0,0 +1,607 @@
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::docker::build_queue::{BuildQueue, DEFAULT_PRIORITY};
+use crate::docker::cancellation::CancellationHandle;
+use crate::docker::deploy::{self, RedeployOptions};
+use crate::docker::desired_state::Reconciler;
+use crate::docker::docker_container::ContainerQuery;
+use crate::docker::docker_image::ImageQuery;
+use crate::docker::endpoints::EndpointPublisher;
+use crate::docker::history::DeploymentHistory;
+use crate::docker::source_archive::{ArchiveLimits, SourceArchive};
+use crate::docker::{DockerBroker, DockerImageBuildResult};
+
+pub mod signature;
+pub mod webhook;
+
+use signature::constant_time_eq;
+
+/// The body of `POST /containers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartRequest {
+    /// The image to run
+    pub image: String,
+
+    /// The port the container listens on, published on the same host port
+    pub port: i64,
+
+    /// The container name, defaults to the image
+    pub name: Option<String>,
+}
+
+/// A failed request, sent to the client as `{"error": message}`
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: &str) -> ApiError {
+        ApiError {
+            status,
+            message: String::from(message),
+        }
+    }
+}
+
+/// Errors from the broker are failures of docker rather than of the request
+impl From<String> for ApiError {
+    fn from(message: String) -> ApiError {
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message,
+        }
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+/// What the API's routes are served from
+pub struct Services {
+    /// The docker daemon, `None` under the process runtime
+    pub docker: Option<Arc<DockerBroker>>,
+
+    /// The queue uploaded projects are built with, whose runtime serves everything else
+    pub builds: BuildQueue,
+
+    /// The reconcile loop's reconciler, if a desired state is being converged on
+    pub reconciler: Option<Arc<Reconciler>>,
+
+    /// The deployments which can be rolled back to
+    pub history: Arc<DeploymentHistory>,
+
+    /// Where rolled back apps' endpoints are published
+    pub endpoints: Arc<dyn EndpointPublisher>,
+
+    /// How a rolled back version is given a port and probed
+    pub rollback_options: RedeployOptions,
+}
+
+/// Serves the runtime's operations as a JSON API until `shutdown` is triggered
+///
+/// | Route | |
+/// | --- | --- |
+/// | `GET /containers?all=true&app=<app>` | Lists containers |
+/// | `POST /containers` | Starts a container from a `StartRequest` |
+/// | `POST /containers/<id>/stop` | Stops a container |
+/// | `GET /containers/<id>/logs?tail=<n>` | Fetches the output of a container |
+/// | `GET /containers/<id>/stats` | Fetches the resource usage of a running container |
+/// | `GET /images?app=<app>` | Lists images |
+/// | `POST /builds` | Builds the project in the uploaded tarball (optionally gzipped, see `SourceArchive`) |
+/// | `POST /prune` | Removes stopped containers and unused images |
+/// | `GET /system` | Fetches the daemon's info and disk usage |
+/// | `GET /reconcile` | Fetches the outcome of the latest pass of the reconcile loop, `null` before the first |
+/// | `POST /reconcile` | Runs a pass of the reconcile loop now, answering with its outcome |
+/// | `GET /apps/<app>/endpoint` | Fetches the host and port an app's endpoint points at, `404` if it has none |
+/// | `POST /apps/<app>/rollback?to=<id>` | Rolls an app back to a deployment, by default the one before its current deployment |
+///
+/// Failures are answered with `{"error": "..."}`.
+/// `GET /system` is only served with a docker daemon, and answered `501 Not Implemented` under the process runtime.
+/// The `/reconcile` routes answer `404 Not Found` without a `reconciler`.
+///
+/// With a `token`, every request must carry it as `Authorization: Bearer <token>` or is answered `401 Unauthorized`.
+/// Without one the API can only listen on a loopback address, as anyone who can reach it could run containers.
+///
+/// # Arguments
+///
+/// * `services` - What the routes are served from
+/// * `addr` - The address to listen on
+/// * `token` - The bearer token requests must carry, e.g. from `KRAKEN_API_TOKEN`
+/// * `shutdown` - Stops the server once in flight requests have been answered
+///
+/// # Examples
+///
+/// ```
+/// let docker = Arc::new(DockerBroker::new().await.unwrap());
+/// let services = Services {
+///     docker: Some(docker.clone()),
+///     builds: BuildQueue::new(docker, 2, QueueOrdering::Fifo),
+///     reconciler: None,
+///     history: Arc::new(DeploymentHistory::new()),
+///     endpoints: Arc::new(EndpointTable::new()),
+///     rollback_options: RedeployOptions::default(),
+/// };
+/// let shutdown = CancellationHandle::new();
+/// let token = env::var("KRAKEN_API_TOKEN").ok();
+/// api::serve(services, "0.0.0.0:8000".parse().unwrap(), token, &shutdown).await?;
+/// ```
+pub async fn serve(
+    services: Services,
+    addr: SocketAddr,
+    token: Option<String>,
+    shutdown: &CancellationHandle,
+) -> Result<(), String> {
+    let token = token.filter(|t| !t.is_empty());
+    check_exposure(&addr, token.is_some())?;
+    let token = Arc::new(token);
+    let services = Arc::new(services);
+    let make_service = make_service_fn(move |_| {
+        let services = services.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let services = services.clone();
+                let token = token.clone();
+                async move { Ok::<_, Infallible>(handle(&services, token.as_deref(), req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?
+        .serve(make_service);
+    info!("Serving the API on {}", addr);
+    server
+        .with_graceful_shutdown(shutdown.cancelled())
+        .await
+        .map_err(|e| format!("API server failed: {}", e))?;
+    info!("API server on {} stopped", addr);
+    Ok(())
+}
+
+/// Refuses to serve the API beyond this host without a token
+fn check_exposure(addr: &SocketAddr, has_token: bool) -> Result<(), String> {
+    if has_token || addr.ip().is_loopback() {
+        return Ok(());
+    }
+    Err(format!(
+        "Refusing to serve the API on {} without a token, set KRAKEN_API_TOKEN or listen on a loopback address",
+        addr
+    ))
+}
+
+/// Checks a request's `Authorization: Bearer <token>` header against the API token
+fn authorized(req: &Request<Body>, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(t) => t,
+        None => return true,
+    };
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("");
+    constant_time_eq(given.trim().as_bytes(), token.as_bytes())
+}
+
+async fn handle(services: &Services, token: Option<&str>, req: Request<Body>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = String::from(req.uri().path());
+    if !authorized(&req, token) {
+        warn!("{} {} -> 401: missing or wrong token", method, path);
+        let mut response = json_response(
+            StatusCode::UNAUTHORIZED,
+            &json!({ "error": "Missing or wrong bearer token" }),
+        );
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    match route(services, req).await {
+        Ok(response) => {
+            info!("{} {} -> {}", method, path, response.status());
+            response
+        }
+        Err(e) => {
+            if e.status.is_server_error() {
+                error!("{} {} -> {}: {}", method, path, e.status, e.message);
+            } else {
+                warn!("{} {} -> {}: {}", method, path, e.status, e.message);
+            }
+            json_response(e.status, &json!({ "error": e.message }))
+        }
+    }
+}
+
+async fn route(services: &Services, req: Request<Body>) -> ApiResult {
+    let runtime = services.builds.runtime();
+    let method = req.method().clone();
+    let query = parse_query(req.uri().query());
+    let segments: Vec<String> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["containers"]) => {
+            let mut q = ContainerQuery::new();
+            if query.get("all").map(|a| a == "true").unwrap_or(false) {
+                q = q.all();
+            }
+            if let Some(app) = query.get("app") {
+                q = q.app(app);
+            }
+            ok(&runtime.list_containers(&q).await?)
+        }
+        (&Method::POST, ["containers"]) => {
+            let start: StartRequest = serde_json::from_slice(&read_body(req).await?)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, &e.to_string()))?;
+            let name = start.name.clone().unwrap_or_else(|| start.image.clone());
+            let id = runtime
+                .start_named_container(&start.image, &name, start.port)
+                .await?;
+            Ok(json_response(
+                StatusCode::CREATED,
+                &json!({ "id": id, "name": name, "port": start.port }),
+            ))
+        }
+        (&Method::POST, ["containers", id, "stop"]) => {
+            runtime.stop_container(id).await?;
+            ok(&json!({ "stopped": id }))
+        }
+        (&Method::GET, ["containers", id, "logs"]) => {
+            let tail = match query.get("tail") {
+                Some(t) => Some(t.parse::<usize>().map_err(|_| {
+                    ApiError::new(StatusCode::BAD_REQUEST, &format!("Invalid tail {}", t))
+                })?),
+                None => None,
+            };
+            ok(&runtime.container_logs(id, tail).await?)
+        }
+        (&Method::GET, ["containers", id, "stats"]) => ok(&runtime.container_stats(id).await?),
+        (&Method::GET, ["images"]) => {
+            let mut q = ImageQuery::new();
+            if let Some(app) = query.get("app") {
+                q = q.app(app);
+            }
+            ok(&runtime.list_images(&q).await?)
+        }
+        (&Method::POST, ["builds"]) => {
+            let build = build_upload(&services.builds, req).await?;
+            Ok(json_response(StatusCode::CREATED, &build))
+        }
+        (&Method::POST, ["prune"]) => {
+            runtime.prune().await?;
+            ok(&json!({ "pruned": true }))
+        }
+        (&Method::GET, ["system"]) => {
+            let docker = services.docker.as_ref().ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "The process runtime has no docker daemon to describe",
+                )
+            })?;
+            let daemon = docker.daemon_info().await?;
+            let disk = docker.disk_usage().await?;
+            ok(&json!({ "daemon": daemon, "disk": disk }))
+        }
+        (&Method::GET, ["apps", app, "endpoint"]) => {
+            let port = services.endpoints.published_port(app).ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    &format!("{} has no published endpoint", app),
+                )
+            })?;
+            ok(&json!({ "app": app, "host": runtime.published_host(), "port": port }))
+        }
+        (&Method::POST, ["apps", app, "rollback"]) => {
+            let to = match query.get("to") {
+                Some(id) => Some(id.parse::<u64>().map_err(|_| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid deployment {}", id),
+                    )
+                })?),
+                None => None,
+            };
+            let rollback = deploy::rollback(
+                runtime.as_ref(),
+                &*services.endpoints,
+                &services.history,
+                app,
+                to,
+                &services.rollback_options,
+            )
+            .await?;
+            ok(&rollback)
+        }
+        (&Method::GET, ["reconcile"]) => ok(&reconciler(services)?.last_run()),
+        (&Method::POST, ["reconcile"]) => ok(&reconciler(services)?.reconcile_once().await),
+        (_, ["containers"])
+        | (_, ["containers", _, "stop"])
+        | (_, ["containers", _, "logs"])
+        | (_, ["containers", _, "stats"])
+        | (_, ["images"])
+        | (_, ["builds"])
+        | (_, ["prune"])
+        | (_, ["system"])
+        | (_, ["reconcile"])
+        | (_, ["apps", _, "endpoint"])
+        | (_, ["apps", _, "rollback"]) => Err(ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        )),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+/// The reconciler behind the `/reconcile` routes, or a `404` if there isn't one
+fn reconciler(services: &Services) -> Result<&Reconciler, ApiError> {
+    services.reconciler.as_deref().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "No desired state is being reconciled, start serve with --desired",
+        )
+    })
+}
+
+/// Unpacks an uploaded project archive into a scratch folder and builds it
+///
+/// Malformed, unsafe or oversized archives are refused before anything is built.
+async fn build_upload(
+    builds: &BuildQueue,
+    req: Request<Body>,
+) -> Result<DockerImageBuildResult, ApiError> {
+    let limits = ArchiveLimits::default();
+    let declared_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok());
+    if let Some(length) = declared_length {
+        if length > limits.max_archive_bytes {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!(
+                    "Archive is {} bytes, over the limit of {}",
+                    length, limits.max_archive_bytes
+                ),
+            ));
+        }
+    }
+    let source = SourceArchive::unpack_stream(req.into_body(), &limits)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, &e))?;
+    Ok(builds
+        .build_image(&source.path(), &HashMap::new(), DEFAULT_PRIORITY)
+        .await?)
+}
+
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, ApiError> {
+    hyper::body::to_bytes(req.into_body())
+        .await
+        .map(|b| b.to_vec())
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                &format!("Failed to read body: {}", e),
+            )
+        })
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn ok<T: Serialize>(value: &T) -> ApiResult {
+    Ok(json_response(StatusCode::OK, value))
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_else(|e| {
+        error!("Failed to serialize response: {}", e);
+        b"{\"error\":\"Failed to serialize response\"}".to_vec()
+    });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .expect("static response parts are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::build_queue::QueueOrdering;
+    use crate::docker::endpoints::EndpointTable;
+    use crate::docker::fake_runtime::FakeRuntime;
+    use crate::docker::history::{DeploymentOutcome, DeploymentRecord};
+    use crate::docker::runtime::ContainerRuntime;
+    use std::time::Duration;
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::get("/containers");
+        if let Some(a) = authorization {
+            builder = builder.header(AUTHORIZATION, a);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn requests_need_the_token_when_there_is_one() {
+        assert!(authorized(&request(Some("Bearer s3cret")), Some("s3cret")));
+        assert!(!authorized(&request(Some("Bearer s3cre")), Some("s3cret")));
+        assert!(!authorized(&request(Some("s3cret")), Some("s3cret")));
+        assert!(!authorized(&request(None), Some("s3cret")));
+        assert!(authorized(&request(None), None));
+    }
+
+    #[test]
+    fn only_loopback_addresses_are_served_without_a_token() {
+        let local: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let public: SocketAddr = "0.0.0.0:8000".parse().unwrap();
+        assert!(check_exposure(&local, false).is_ok());
+        assert!(check_exposure(&"[::1]:8000".parse().unwrap(), false).is_ok());
+        assert!(check_exposure(&public, false).is_err());
+        assert!(check_exposure(&public, true).is_ok());
+    }
+
+    fn services(runtime: &Arc<FakeRuntime>, reconciler: Option<Arc<Reconciler>>) -> Services {
+        Services {
+            docker: None,
+            builds: BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo),
+            reconciler,
+            history: Arc::new(DeploymentHistory::new()),
+            endpoints: Arc::new(EndpointTable::new()),
+            rollback_options: RedeployOptions {
+                ready_timeout: Duration::from_millis(500),
+                probe_interval: Duration::from_millis(20),
+                ports: 22700..22800,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_are_served_by_the_queue_runtime() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let image = runtime.add_image("scapegoat", "1.0.0");
+        let services = services(&runtime, None);
+
+        let start = Request::post("/containers")
+            .body(Body::from(
+                json!({ "image": image, "port": 22600 }).to_string(),
+            ))
+            .unwrap();
+        let response = route(&services, start).await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let containers = runtime
+            .list_containers(&ContainerQuery::new())
+            .await
+            .unwrap();
+        assert_eq!(containers.len(), 1);
+
+        let system = Request::get("/system").body(Body::empty()).unwrap();
+        let error = route(&services, system).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn reconcile_passes_are_run_and_reported() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let desired =
+            std::env::temp_dir().join(format!("kraken-api-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&desired, "").unwrap();
+        let builds = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo);
+        let reconciler = Arc::new(Reconciler::new(builds, &desired));
+        let served = services(&runtime, Some(reconciler.clone()));
+        let get = || Request::get("/reconcile").body(Body::empty()).unwrap();
+
+        let before = route(&served, get()).await.ok().unwrap();
+        let body = hyper::body::to_bytes(before.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"null");
+        let post = Request::post("/reconcile").body(Body::empty()).unwrap();
+        assert_eq!(
+            route(&served, post).await.ok().unwrap().status(),
+            StatusCode::OK
+        );
+        assert!(reconciler.last_run().unwrap().converged());
+
+        let without = services(&runtime, None);
+        let error = route(&without, get()).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn apps_are_rolled_back_to_their_previous_deployment() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let served = services(&runtime, None);
+        for version in &["1.0.0", "2.0.0"] {
+            let image = runtime.add_image("scapegoat", version);
+            served.history.record(DeploymentRecord {
+                image_id: Some(image),
+                ..DeploymentRecord::new(
+                    "scapegoat",
+                    version,
+                    &HashMap::new(),
+                    DeploymentOutcome::Succeeded,
+                )
+            });
+        }
+        let endpoint = || {
+            Request::get("/apps/scapegoat/endpoint")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let error = route(&served, endpoint()).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        let rollback = |query: &str| {
+            Request::post(format!("/apps/scapegoat/rollback{}", query))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let error = route(&served, rollback("?to=first")).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        let response = route(&served, rollback("")).await.ok().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let current = served.history.current("scapegoat").unwrap();
+        assert_eq!(current.version, "1.0.0");
+        assert_eq!(current.rollback_of, Some(1));
+        let port = served.endpoints.published_port("scapegoat").unwrap();
+        let response = route(&served, endpoint()).await.ok().unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let endpoint: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(endpoint["host"], "127.0.0.1");
+        assert_eq!(endpoint["port"], port);
+    }
+}
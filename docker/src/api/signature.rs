@@ -0,0 +1,133 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Hashes `data` with SHA-256
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Computes the HMAC-SHA256 of `data` under `key`
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Formats bytes as lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses hex (in either case) into bytes, `None` if it isn't valid hex
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Checks a webhook signature against the payload it was sent with
+///
+/// Accepts both GitHub's `sha256=<hex>` (`X-Hub-Signature-256`) and Gitea's bare `<hex>` (`X-Gitea-Signature`).
+/// The comparison takes the same time wherever the signatures differ, so it can't be used to guess a signature byte by byte.
+///
+/// # Arguments
+///
+/// * `secret` - The secret shared with the git host
+/// * `payload` - The raw request body
+/// * `signature` - The signature header's value
+///
+/// # Examples
+///
+/// ```
+/// let signature = format!("sha256={}", to_hex(&hmac_sha256(b"secret", b"{}")));
+/// assert!(verify_signature(b"secret", b"{}", &signature));
+/// ```
+pub fn verify_signature(secret: &[u8], payload: &[u8], signature: &str) -> bool {
+    let signature = signature.trim();
+    let signature = match from_hex(signature.strip_prefix("sha256=").unwrap_or(signature)) {
+        Some(s) => s,
+        None => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(payload);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Compares two secrets in a time which only depends on their length, so a mismatch can't be found byte by byte
+///
+/// # Examples
+///
+/// ```
+/// assert!(constant_time_eq(b"token", b"token"));
+/// assert!(!constant_time_eq(b"token", b"tokem"));
+/// ```
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_fips_180_4() {
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        // Test case 1
+        assert_eq!(
+            to_hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        // Test case 2, a key shorter than the output
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Test case 6, a key longer than the block size
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn signatures_are_checked_in_both_formats() {
+        let hex = to_hex(&hmac_sha256(b"secret", b"{}"));
+        assert!(verify_signature(
+            b"secret",
+            b"{}",
+            &format!("sha256={}", hex)
+        ));
+        assert!(verify_signature(b"secret", b"{}", &hex.to_uppercase()));
+        assert!(!verify_signature(b"other", b"{}", &hex));
+        assert!(!verify_signature(b"secret", b"{ }", &hex));
+        assert!(!verify_signature(b"secret", b"{}", &hex[..62]));
+        assert!(!verify_signature(b"secret", b"{}", "sha256=not hex"));
+    }
+}
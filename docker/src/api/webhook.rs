@@ -0,0 +1,793 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::signature::verify_signature;
+use super::{json_response, ApiError, ApiResult};
+use crate::docker::build_queue::BuildQueue;
+use crate::docker::cancellation::CancellationHandle;
+use crate::docker::deploy::{self, RedeployOptions};
+use crate::docker::endpoints::EndpointPublisher;
+use crate::docker::git_source::{self, GitCheckout};
+use crate::docker::history::DeploymentHistory;
+use crate::docker::manifest::{ShipwreckManifest, MANIFEST_FILE_NAME};
+use crate::docker::source_archive;
+use crate::docker::COMMIT_LABEL;
+
+/// How many deliveries are kept for `GET /deliveries`
+const KEPT_DELIVERIES: usize = 50;
+
+/// How many delivery IDs and pushes are remembered to recognise a payload being sent again
+const REMEMBERED_DELIVERIES: usize = 1000;
+
+/// The largest payload accepted, which is where GitHub caps its payloads
+const MAX_PAYLOAD: u64 = 25 * 1024 * 1024;
+
+/// The `after` of a push which deleted its ref, as Gitea sends no `deleted` field
+const NULL_COMMIT: &str = "0000000000000000000000000000000000000000";
+
+/// An application which is redeployed when its repository is pushed to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchedApp {
+    /// The application name from its `shipwreck.toml`
+    pub name: String,
+
+    /// The `app.endpoint` from its `shipwreck.toml`, which pushed repositories are matched against
+    pub endpoint: String,
+
+    /// The local repository (or mirror) the application is built from
+    pub repo: String,
+
+    /// The branch which is deployed, or the repository's default branch if `None`
+    pub branch: Option<String>,
+}
+
+impl WatchedApp {
+    /// Watches the application in a local repository, reading its `shipwreck.toml` from git
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The local repository, as a path or a `file://` URL
+    /// * `branch` - The branch to deploy, or `None` for the repository's default branch
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let app = WatchedApp::from_repo("../scapegoat", Some("main"))?;
+    /// println!("{} deploys pushes to {}", app.name, app.endpoint);
+    /// ```
+    pub fn from_repo(repo: &str, branch: Option<&str>) -> Result<WatchedApp, String> {
+        let manifest = ShipwreckManifest::parse(&git_source::read_file_at(
+            repo,
+            branch.unwrap_or("HEAD"),
+            MANIFEST_FILE_NAME,
+        )?)?;
+        if manifest.app.endpoint.is_empty() {
+            return Err(format!(
+                "The {} of {} has no app.endpoint to match pushes against",
+                MANIFEST_FILE_NAME, repo
+            ));
+        }
+        Ok(WatchedApp {
+            name: manifest.app.name,
+            endpoint: manifest.app.endpoint,
+            repo: String::from(repo),
+            branch: branch.map(String::from),
+        })
+    }
+}
+
+/// What became of a push
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// The push didn't call for a deployment (e.g. it was to another branch)
+    Ignored(String),
+
+    /// The pushed commit is being built and deployed
+    Deploying,
+
+    /// The pushed commit was deployed
+    Deployed { deployment_id: u64, port: i64 },
+
+    /// The pushed commit could not be deployed
+    Failed(String),
+}
+
+/// A push received from a git host, and the deployment it triggered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    /// The delivery ID sent by the git host, or a generated one if it sent none
+    pub id: String,
+
+    /// The app being deployed, if the push matched one
+    pub app: Option<String>,
+
+    /// The full name of the pushed repository (e.g. `ethanshry/scapegoat`)
+    pub repository: String,
+
+    /// The pushed ref (e.g. `refs/heads/main`)
+    pub reference: String,
+
+    /// The SHA of the pushed commit
+    pub commit: String,
+
+    /// When the push was received, as seconds since the epoch
+    pub received_at: u64,
+
+    /// Whether the push was deployed
+    pub status: DeliveryStatus,
+}
+
+/// The parts of a GitHub or Gitea push payload which are needed to deploy it
+#[derive(Debug, Deserialize)]
+struct PushPayload {
+    #[serde(rename = "ref")]
+    reference: String,
+
+    /// The SHA of the branch's head before the push
+    #[serde(default)]
+    before: String,
+
+    /// The SHA of the branch's new head
+    after: String,
+
+    /// Whether the push deleted the ref
+    #[serde(default)]
+    deleted: bool,
+
+    repository: PushRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRepository {
+    #[serde(default)]
+    full_name: String,
+
+    #[serde(default)]
+    clone_url: String,
+
+    #[serde(default)]
+    html_url: String,
+
+    #[serde(default)]
+    ssh_url: String,
+
+    #[serde(default)]
+    default_branch: String,
+}
+
+/// Receives push webhooks from a git host and redeploys the applications whose repositories were pushed to
+///
+/// Pushes are matched to watched applications by their manifest `endpoint`, ignoring the URL scheme, user and `.git` suffix,
+/// so `https://github.com/ethanshry/scapegoat` matches pushes to `git@github.com:ethanshry/scapegoat.git`.
+/// Matching pushes to the deployed branch are checked out of the app's local repository at the pushed commit and redeployed with `deploy::redeploy`, one at a time.
+/// The new image is labelled with the pushed commit (`COMMIT_LABEL`).
+/// A delivery whose ID has been seen before is a replay (or a redelivery from the git host's settings), and is ignored.
+/// As the delivery ID header isn't signed, a push of the same repository, ref, `before` and `after` as a recent one is also a replay, whatever its ID.
+/// (`before` tells a replay apart from a branch being pushed back to a commit it was at earlier.)
+pub struct WebhookReceiver {
+    /// The secret shared with the git host, which payloads are signed with
+    secret: Vec<u8>,
+
+    apps: Vec<WatchedApp>,
+    builds: BuildQueue,
+    endpoints: Arc<dyn EndpointPublisher>,
+    history: Arc<DeploymentHistory>,
+    options: RedeployOptions,
+
+    /// The most recent deliveries, newest last
+    deliveries: Mutex<VecDeque<WebhookDelivery>>,
+
+    /// The IDs of the most recent deliveries the git host sent, newest last
+    delivery_ids: Mutex<VecDeque<String>>,
+
+    /// The repository, ref, `before` and `after` of the most recent pushes, newest last
+    pushes: Mutex<VecDeque<String>>,
+
+    /// Held while deploying, so two quick pushes don't redeploy over each other
+    deploy_lock: tokio::sync::Mutex<()>,
+}
+
+impl WebhookReceiver {
+    /// Creates a receiver for a set of applications
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret configured for the webhook on the git host
+    /// * `apps` - The applications to redeploy
+    /// * `builds` - The queue to build with, whose runtime the applications are deployed to
+    /// * `endpoints` - Where the applications' endpoints are published
+    /// * `history` - Where deployments are recorded
+    /// * `options` - How to pick a port and probe each new version
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let receiver = WebhookReceiver::new(
+    ///     "hunter2",
+    ///     vec![WatchedApp::from_repo("../scapegoat", None)?],
+    ///     BuildQueue::new(Arc::new(docker), 2, QueueOrdering::Fifo),
+    ///     Arc::new(EndpointTable::new()),
+    ///     Arc::new(DeploymentHistory::new()),
+    ///     RedeployOptions::default(),
+    /// );
+    /// ```
+    pub fn new(
+        secret: &str,
+        apps: Vec<WatchedApp>,
+        builds: BuildQueue,
+        endpoints: Arc<dyn EndpointPublisher>,
+        history: Arc<DeploymentHistory>,
+        options: RedeployOptions,
+    ) -> WebhookReceiver {
+        WebhookReceiver {
+            secret: secret.as_bytes().to_vec(),
+            apps,
+            builds,
+            endpoints,
+            history,
+            options,
+            deliveries: Mutex::new(VecDeque::new()),
+            delivery_ids: Mutex::new(VecDeque::new()),
+            pushes: Mutex::new(VecDeque::new()),
+            deploy_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Checks a payload's signature, from its `X-Hub-Signature-256` or `X-Gitea-Signature` header
+    pub fn verify(&self, payload: &[u8], signature: &str) -> bool {
+        verify_signature(&self.secret, payload, signature)
+    }
+
+    /// Gets the most recent deliveries, newest first
+    pub fn deliveries(&self) -> Vec<WebhookDelivery> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Handles a verified push payload, starting a redeploy of each application it matches
+    ///
+    /// Returns straight away with one delivery per matched application (or a single ignored one), since git hosts give up on slow webhooks.
+    /// The deployments carry on in the background, and their outcomes show up in `deliveries`.
+    /// A delivery ID or push which was already received is answered with a single ignored delivery, which isn't kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `delivery_id` - The delivery ID sent by the git host, if any
+    /// * `payload` - The JSON body of the push event
+    pub fn receive_push(
+        self: &Arc<Self>,
+        delivery_id: Option<&str>,
+        payload: &[u8],
+    ) -> Result<Vec<WebhookDelivery>, String> {
+        let push: PushPayload =
+            serde_json::from_slice(payload).map_err(|e| format!("Invalid push payload: {}", e))?;
+        let branch = push.reference.trim_start_matches("refs/heads/");
+        let id = delivery_id
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string());
+        let push_key = format!(
+            "{} {} {} {}",
+            push.repository.full_name, push.reference, push.before, push.after
+        );
+        let replayed_id = delivery_id.is_some() && !remember(&self.delivery_ids, &id);
+        let delivery = |app: Option<&str>, status| WebhookDelivery {
+            id: id.clone(),
+            app: app.map(String::from),
+            repository: push.repository.full_name.clone(),
+            reference: push.reference.clone(),
+            commit: push.after.clone(),
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            status,
+        };
+
+        let pushed_urls: Vec<String> = [
+            &push.repository.clone_url,
+            &push.repository.html_url,
+            &push.repository.ssh_url,
+        ]
+        .iter()
+        .filter(|u| !u.is_empty())
+        .map(|u| normalize_repo_url(u))
+        .collect();
+        let matched: Vec<(&WatchedApp, Option<String>)> = self
+            .apps
+            .iter()
+            .filter(|a| pushed_urls.contains(&normalize_repo_url(&a.endpoint)))
+            .map(|app| {
+                let deployed_branch = app
+                    .branch
+                    .as_deref()
+                    .unwrap_or(&push.repository.default_branch);
+                let ignored = if push.deleted || push.after == NULL_COMMIT {
+                    Some(String::from("The push deleted the branch"))
+                } else if !push.reference.starts_with("refs/heads/") || branch != deployed_branch {
+                    Some(format!("{} deploys {}", app.name, deployed_branch))
+                } else {
+                    None
+                };
+                (app, ignored)
+            })
+            .collect();
+        // Only pushes which deploy are remembered, a replay of an ignored one is harmless
+        let deploys = matched.iter().any(|(_, ignored)| ignored.is_none());
+
+        if replayed_id {
+            warn!("Ignoring delivery {}, which was already received", id);
+            return Ok(vec![delivery(
+                None,
+                DeliveryStatus::Ignored(format!("Delivery {} was already received", id)),
+            )]);
+        }
+        if deploys && !remember(&self.pushes, &push_key) {
+            warn!(
+                "Ignoring delivery {}, a push of {} to {} which was already received",
+                id, push.after, push.reference
+            );
+            return Ok(vec![delivery(
+                None,
+                DeliveryStatus::Ignored(format!(
+                    "A push of {} to {} was already received",
+                    push.after, push.reference
+                )),
+            )]);
+        }
+        let mut deliveries = vec![];
+        if matched.is_empty() {
+            deliveries.push(delivery(
+                None,
+                DeliveryStatus::Ignored(String::from("No app is watching this repository")),
+            ));
+        }
+        for (app, ignored) in matched {
+            match ignored {
+                Some(reason) => {
+                    deliveries.push(delivery(Some(&app.name), DeliveryStatus::Ignored(reason)))
+                }
+                None => {
+                    let d = delivery(Some(&app.name), DeliveryStatus::Deploying);
+                    info!(
+                        "Push {} to {} of {}, deploying {}",
+                        d.id, branch, d.repository, d.commit
+                    );
+                    tokio::spawn(self.clone().deploy(d.clone(), app.clone()));
+                    deliveries.push(d);
+                }
+            }
+        }
+
+        let mut kept = self.deliveries.lock().unwrap();
+        for d in &deliveries {
+            kept.push_back(d.clone());
+            if kept.len() > KEPT_DELIVERIES {
+                kept.pop_front();
+            }
+        }
+        Ok(deliveries)
+    }
+
+    async fn deploy(self: Arc<Self>, delivery: WebhookDelivery, app: WatchedApp) {
+        let _deploying = self.deploy_lock.lock().await;
+        let result = async {
+            let repo = app.repo.clone();
+            let commit = delivery.commit.clone();
+            let checkout = tokio::task::spawn_blocking(move || {
+                // The local repository may be a mirror which hasn't seen the push yet
+                if let Err(e) = git_source::fetch(&repo) {
+                    warn!("Failed to fetch {}: {}", repo, e);
+                }
+                GitCheckout::checkout(&repo, &commit)
+            })
+            .await
+            .map_err(|e| format!("Checkout of {} failed: {}", app.repo, e))??;
+            let mut options = self.options.clone();
+            options
+                .labels
+                .insert(String::from(COMMIT_LABEL), checkout.commit.clone());
+            deploy::redeploy(
+                &self.builds,
+                &*self.endpoints,
+                &self.history,
+                &checkout.path(),
+                &options,
+            )
+            .await
+        }
+        .await;
+
+        let status = match result {
+            Ok(redeployment) => {
+                info!(
+                    "Deployed {} of {} on port {}",
+                    delivery.commit, app.name, redeployment.port
+                );
+                DeliveryStatus::Deployed {
+                    deployment_id: redeployment.deployment.deployment_id,
+                    port: redeployment.port,
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Failed to deploy {} of {}: {}",
+                    delivery.commit, app.name, e
+                );
+                DeliveryStatus::Failed(e)
+            }
+        };
+        let mut kept = self.deliveries.lock().unwrap();
+        if let Some(d) = kept
+            .iter_mut()
+            .find(|d| d.id == delivery.id && d.app == delivery.app)
+        {
+            d.status = status;
+        }
+    }
+}
+
+/// Records a delivery ID or push, returning `false` if it had already been received
+fn remember(seen: &Mutex<VecDeque<String>>, key: &str) -> bool {
+    let mut seen = seen.lock().unwrap();
+    if seen.iter().any(|k| k == key) {
+        return false;
+    }
+    seen.push_back(String::from(key));
+    if seen.len() > REMEMBERED_DELIVERIES {
+        seen.pop_front();
+    }
+    true
+}
+
+/// Reduces a git remote URL to `host/owner/repo`, so the same repository matches however it is addressed
+fn normalize_repo_url(url: &str) -> String {
+    let url = url.trim().to_lowercase();
+    let (rest, scp_like) = match url.find("://") {
+        Some(i) => (&url[i + 3..], false),
+        None => (url.as_str(), true),
+    };
+    // Drop the user of `git@host` and `https://user@host`
+    let host_end = rest.find('/').unwrap_or(rest.len());
+    let rest = match rest[..host_end].rfind('@') {
+        Some(i) => &rest[i + 1..],
+        None => rest,
+    };
+    // `git@host:owner/repo` addresses the path after a colon
+    let rest = if scp_like {
+        rest.replacen(':', "/", 1)
+    } else {
+        String::from(rest)
+    };
+    let rest = rest.trim_end_matches('/');
+    String::from(rest.strip_suffix(".git").unwrap_or(rest))
+}
+
+/// Listens for push webhooks until `shutdown` is triggered
+///
+/// | Route | |
+/// | --- | --- |
+/// | `POST /webhook` | Receives a push event from GitHub or Gitea |
+/// | `GET /deliveries` | Lists recent pushes and the deployments they triggered |
+///
+/// Payloads without a valid `X-Hub-Signature-256` or `X-Gitea-Signature` are refused with 401, and payloads over 25 MB with 413 before they are checked.
+/// Events other than pushes (such as GitHub's `ping`) are acknowledged and ignored.
+///
+/// # Arguments
+///
+/// * `receiver` - The receiver to hand pushes to
+/// * `addr` - The address to listen on
+/// * `shutdown` - Stops the listener once in flight requests have been answered
+///
+/// # Examples
+///
+/// ```
+/// let shutdown = CancellationHandle::new();
+/// webhook::serve(Arc::new(receiver), "0.0.0.0:8001".parse().unwrap(), &shutdown).await?;
+/// ```
+pub async fn serve(
+    receiver: Arc<WebhookReceiver>,
+    addr: SocketAddr,
+    shutdown: &CancellationHandle,
+) -> Result<(), String> {
+    let make_service = make_service_fn(move |_| {
+        let receiver = receiver.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let receiver = receiver.clone();
+                async move { Ok::<_, Infallible>(handle(&receiver, req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?
+        .serve(make_service);
+    info!("Listening for webhooks on {}", addr);
+    server
+        .with_graceful_shutdown(shutdown.cancelled())
+        .await
+        .map_err(|e| format!("Webhook listener failed: {}", e))?;
+    info!("Webhook listener on {} stopped", addr);
+    Ok(())
+}
+
+async fn handle(receiver: &Arc<WebhookReceiver>, req: Request<Body>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = String::from(req.uri().path());
+    match route(receiver, req).await {
+        Ok(response) => {
+            info!("{} {} -> {}", method, path, response.status());
+            response
+        }
+        Err(e) => {
+            warn!("{} {} -> {}: {}", method, path, e.status, e.message);
+            json_response(e.status, &json!({ "error": e.message }))
+        }
+    }
+}
+
+async fn route(receiver: &Arc<WebhookReceiver>, req: Request<Body>) -> ApiResult {
+    let method = req.method().clone();
+    match (&method, req.uri().path().trim_end_matches('/')) {
+        (&Method::POST, "/webhook") => {
+            let headers = req.headers().clone();
+            let payload = source_archive::read_stream(req.into_body(), MAX_PAYLOAD)
+                .await
+                .map_err(|e| ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, &e))?;
+            let signature = header(&headers, "X-Hub-Signature-256")
+                .or_else(|| header(&headers, "X-Gitea-Signature"))
+                .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Missing signature"))?;
+            if !receiver.verify(&payload, signature) {
+                return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid signature"));
+            }
+
+            let event =
+                header(&headers, "X-GitHub-Event").or_else(|| header(&headers, "X-Gitea-Event"));
+            if event != Some("push") {
+                return Ok(json_response(
+                    StatusCode::OK,
+                    &json!({ "ignored": event.unwrap_or("unknown event") }),
+                ));
+            }
+            let delivery_id = header(&headers, "X-GitHub-Delivery")
+                .or_else(|| header(&headers, "X-Gitea-Delivery"));
+            let deliveries = receiver
+                .receive_push(delivery_id, &payload)
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, &e))?;
+            Ok(json_response(StatusCode::ACCEPTED, &deliveries))
+        }
+        (&Method::GET, "/deliveries") => Ok(json_response(StatusCode::OK, &receiver.deliveries())),
+        (_, "/webhook") | (_, "/deliveries") => Err(ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        )),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::signature::{hmac_sha256, to_hex};
+    use crate::docker::build_queue::QueueOrdering;
+    use crate::docker::endpoints::EndpointTable;
+    use crate::docker::fake_runtime::FakeRuntime;
+    use std::fs;
+    use std::process::Command;
+    use std::time::Duration;
+
+    const GITHUB_PUSH: &[u8] = include_bytes!("../../tests/fixtures/github-push.json");
+    const GITEA_PUSH: &[u8] = include_bytes!("../../tests/fixtures/gitea-push.json");
+
+    fn receiver(runtime: Arc<FakeRuntime>, apps: Vec<WatchedApp>) -> Arc<WebhookReceiver> {
+        Arc::new(WebhookReceiver::new(
+            "secret",
+            apps,
+            BuildQueue::new(runtime, 1, QueueOrdering::Fifo),
+            Arc::new(EndpointTable::new()),
+            Arc::new(DeploymentHistory::new()),
+            RedeployOptions {
+                ready_timeout: Duration::from_millis(500),
+                probe_interval: Duration::from_millis(20),
+                ports: 22100..22200,
+                ..Default::default()
+            },
+        ))
+    }
+
+    fn scapegoat(endpoint: &str, repo: &str) -> WatchedApp {
+        WatchedApp {
+            name: String::from("scapegoat"),
+            endpoint: String::from(endpoint),
+            repo: String::from(repo),
+            branch: None,
+        }
+    }
+
+    /// A fixture with some of its fields replaced
+    fn edited(fixture: &[u8], edit: impl FnOnce(&mut serde_json::Value)) -> Vec<u8> {
+        let mut payload: serde_json::Value = serde_json::from_slice(fixture).unwrap();
+        edit(&mut payload);
+        serde_json::to_vec(&payload).unwrap()
+    }
+
+    fn statuses(deliveries: &[WebhookDelivery]) -> Vec<DeliveryStatus> {
+        deliveries.iter().map(|d| d.status.clone()).collect()
+    }
+
+    /// Creates a repository holding one commit of a project, returning its path and the commit's SHA
+    fn repository() -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("kraken-webhook-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(MANIFEST_FILE_NAME),
+            "[app]\nname = \"scapegoat\"\nversion = \"1.0.0\"\n\n[config]\nport = 9000\n",
+        )
+        .unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args([
+                    "-c",
+                    "user.name=Kraken",
+                    "-c",
+                    "user.email=kraken@localhost",
+                ])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "git {:?} failed", args);
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "Initial commit"]);
+        let commit = git(&["rev-parse", "HEAD"]);
+        (dir.to_string_lossy().into_owned(), commit)
+    }
+
+    #[tokio::test]
+    async fn fixtures_are_verified_in_each_hosts_format() {
+        let receiver = receiver(Arc::new(FakeRuntime::new()), vec![]);
+        // GitHub sends `X-Hub-Signature-256: sha256=<hex>`, Gitea sends `X-Gitea-Signature: <hex>`
+        let github = format!("sha256={}", to_hex(&hmac_sha256(b"secret", GITHUB_PUSH)));
+        let gitea = to_hex(&hmac_sha256(b"secret", GITEA_PUSH));
+
+        assert!(receiver.verify(GITHUB_PUSH, &github));
+        assert!(receiver.verify(GITEA_PUSH, &gitea));
+        assert!(!receiver.verify(GITEA_PUSH, &github));
+        assert!(!receiver.verify(&GITHUB_PUSH[1..], &github));
+    }
+
+    #[tokio::test]
+    async fn oversized_payloads_are_refused_before_their_signature_is_checked() {
+        let receiver = receiver(Arc::new(FakeRuntime::new()), vec![]);
+        let request = Request::post("/webhook")
+            .header("X-Hub-Signature-256", "sha256=00")
+            .body(Body::from(vec![b' '; MAX_PAYLOAD as usize + 1]))
+            .unwrap();
+
+        let error = route(&receiver, request).await.err().unwrap();
+
+        assert_eq!(error.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn pushes_to_other_branches_and_tags_are_ignored() {
+        let app = scapegoat("https://github.com/ethanshry/scapegoat", "/nonexistent");
+        let receiver = receiver(Arc::new(FakeRuntime::new()), vec![app]);
+
+        for reference in &["refs/heads/feature", "refs/tags/main"] {
+            let payload = edited(GITHUB_PUSH, |p| p["ref"] = json!(reference));
+            let deliveries = receiver.receive_push(None, &payload).unwrap();
+            assert_eq!(
+                statuses(&deliveries),
+                vec![DeliveryStatus::Ignored(String::from(
+                    "scapegoat deploys main"
+                ))]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_of_other_repositories_are_ignored() {
+        let app = scapegoat("git@github.com:ethanshry/kraken.git", "/nonexistent");
+        let receiver = receiver(Arc::new(FakeRuntime::new()), vec![app]);
+
+        let deliveries = receiver.receive_push(None, GITHUB_PUSH).unwrap();
+
+        assert_eq!(deliveries[0].app, None);
+        assert_eq!(
+            statuses(&deliveries),
+            vec![DeliveryStatus::Ignored(String::from(
+                "No app is watching this repository"
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn deleted_branches_are_ignored() {
+        let apps = vec![
+            scapegoat("https://github.com/ethanshry/scapegoat", "/nonexistent"),
+            scapegoat("git@git.example.com:ethanshry/scapegoat", "/nonexistent"),
+        ];
+        let receiver = receiver(Arc::new(FakeRuntime::new()), apps);
+        let deleted = DeliveryStatus::Ignored(String::from("The push deleted the branch"));
+
+        // GitHub says so, Gitea only pushes the null commit
+        let github = edited(GITHUB_PUSH, |p| {
+            p["deleted"] = json!(true);
+            p["after"] = json!(NULL_COMMIT);
+        });
+        let gitea = edited(GITEA_PUSH, |p| p["after"] = json!(NULL_COMMIT));
+
+        let deliveries = receiver.receive_push(None, &github).unwrap();
+        assert_eq!(statuses(&deliveries), vec![deleted.clone()]);
+        let deliveries = receiver.receive_push(None, &gitea).unwrap();
+        assert_eq!(statuses(&deliveries), vec![deleted]);
+    }
+
+    #[tokio::test]
+    async fn pushes_deploy_with_their_commit_once() {
+        let (repo, commit) = repository();
+        let runtime = Arc::new(FakeRuntime::new());
+        let app = scapegoat("https://git.example.com/ethanshry/scapegoat", &repo);
+        let receiver = receiver(runtime.clone(), vec![app]);
+        let payload = edited(GITEA_PUSH, |p| p["after"] = json!(commit));
+
+        let deliveries = receiver.receive_push(Some("delivery-1"), &payload).unwrap();
+        assert_eq!(statuses(&deliveries), vec![DeliveryStatus::Deploying]);
+        let replayed = receiver.receive_push(Some("delivery-1"), &payload).unwrap();
+        assert_eq!(
+            statuses(&replayed),
+            vec![DeliveryStatus::Ignored(String::from(
+                "Delivery delivery-1 was already received"
+            ))]
+        );
+        // The delivery ID isn't signed, so a replay may come with another or none
+        let resent = receiver.receive_push(Some("delivery-2"), &payload).unwrap();
+        let without_id = receiver.receive_push(None, &payload).unwrap();
+        let already_pushed = DeliveryStatus::Ignored(format!(
+            "A push of {} to refs/heads/main was already received",
+            commit
+        ));
+        assert_eq!(statuses(&resent), vec![already_pushed.clone()]);
+        assert_eq!(statuses(&without_id), vec![already_pushed]);
+
+        let mut status = DeliveryStatus::Deploying;
+        for _ in 0..100 {
+            status = receiver.deliveries()[0].status.clone();
+            if status != DeliveryStatus::Deploying {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert!(
+            matches!(status, DeliveryStatus::Deployed { .. }),
+            "{:?}",
+            status
+        );
+        assert_eq!(receiver.deliveries().len(), 1);
+        let images = runtime.images();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].labels[COMMIT_LABEL], commit);
+    }
+}
@@ -0,0 +1,66 @@
+/// The arguments of a subcommand, consumed as they are read so leftovers can be reported
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    pub fn new(args: Vec<String>) -> Args {
+        Args { args }
+    }
+
+    /// Takes a boolean flag (e.g. `--all`), given under any of `names`
+    pub fn flag(&mut self, names: &[&str]) -> bool {
+        let before = self.args.len();
+        self.args.retain(|a| !names.contains(&a.as_str()));
+        self.args.len() != before
+    }
+
+    /// Takes the value of an option given as `--name value` or `--name=value`
+    pub fn value(&mut self, name: &str) -> Result<Option<String>, String> {
+        let prefix = format!("{}=", name);
+        if let Some(i) = self.args.iter().position(|a| a.starts_with(&prefix)) {
+            return Ok(Some(self.args.remove(i)[prefix.len()..].to_string()));
+        }
+        match self.args.iter().position(|a| a == name) {
+            Some(i) if i + 1 < self.args.len() => {
+                self.args.remove(i);
+                Ok(Some(self.args.remove(i)))
+            }
+            Some(_) => Err(format!("{} needs a value", name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes the value of an option and parses it
+    pub fn parsed<T: std::str::FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name)? {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid value for {}: {}", name, v)),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes the next positional argument
+    ///
+    /// Anything not starting with `-` counts, so the values of options must be taken (with `value` or `parsed`) before the positional arguments they may sit in front of.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - A description of the argument for the error when it is missing (e.g. `<dir>`)
+    pub fn positional(&mut self, what: &str) -> Result<String, String> {
+        match self.args.iter().position(|a| !a.starts_with('-')) {
+            Some(i) => Ok(self.args.remove(i)),
+            None => Err(format!("Missing {}", what)),
+        }
+    }
+
+    /// Fails if any arguments were not consumed
+    pub fn finish(self) -> Result<(), String> {
+        match self.args.first() {
+            Some(a) => Err(format!("Unexpected argument {}", a)),
+            None => Ok(()),
+        }
+    }
+}
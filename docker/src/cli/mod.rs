@@ -0,0 +1,1104 @@
+use futures_util::stream;
+use log::{info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api;
+use crate::api::webhook::{self, WatchedApp, WebhookReceiver};
+use crate::docker::build_queue::{BuildQueue, QueueOrdering, DEFAULT_PRIORITY};
+use crate::docker::cancellation::CancellationHandle;
+use crate::docker::connection::ConnectionConfig;
+use crate::docker::deploy::{self, RedeployOptions};
+use crate::docker::desired_state::Reconciler;
+use crate::docker::disk_guard::DiskGuard;
+use crate::docker::docker_container::ContainerQuery;
+use crate::docker::history::DeploymentHistory;
+use crate::docker::image_transfer::TransferProgress;
+use crate::docker::process_runtime::ProcessRuntime;
+use crate::docker::registry::{ImageReference, RegistryCredentials, RegistryProgress};
+use crate::docker::retention::RetentionPolicy;
+use crate::docker::runtime::ContainerRuntime;
+use crate::docker::stack;
+use crate::docker::state_store::StateStore;
+use crate::docker::{DockerBroker, APP_LABEL};
+
+pub mod args;
+pub mod output;
+
+use args::Args;
+use output::{human_bytes, print_json, print_table, short_id, OutputFormat};
+
+/// The command succeeded
+pub const EXIT_OK: i32 = 0;
+
+/// The command was understood but failed (e.g. a build error)
+pub const EXIT_FAILURE: i32 = 1;
+
+/// The command line could not be parsed
+pub const EXIT_USAGE: i32 = 2;
+
+/// The docker daemon could not be reached
+pub const EXIT_UNAVAILABLE: i32 = 3;
+
+/// Where deployments are recorded when `KRAKEN_STATE` is not set
+const DEFAULT_STATE_PATH: &str = "./tmp/state.jsonl";
+
+/// Where `serve` listens when `--listen` is not given
+const DEFAULT_LISTEN: &str = "127.0.0.1:8000";
+
+/// Where `webhook` listens when `--listen` is not given
+const DEFAULT_WEBHOOK_LISTEN: &str = "127.0.0.1:8001";
+
+/// How often `serve --desired` converges on its desired-state file
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many images are built at once when `KRAKEN_BUILD_PARALLELISM` is not set
+const DEFAULT_BUILD_PARALLELISM: usize = 2;
+
+const USAGE: &str = "Usage: kraken [--json] <command> [args]
+
+Commands:
+  build <dir> [--ref <ref>]        Build an image from a project folder, or from a branch,
+                                   tag or commit when <dir> is a git repository
+  run <image> --port <port>        Start a container, publishing <port> on the host
+      [--name <name>]
+  ps [--all] [--app <app>]         List containers
+  logs <container> [--tail <n>]    Print the output of a container
+  stats <container>                Show the resource usage of a running container
+  stop <container>                 Stop a running container
+  prune                            Remove stopped containers and unused images
+  retention [--keep <n>]           Remove all but the newest <n> images of each app (default 3),
+      [--dry-run]                  keeping images a container uses
+  info                             Show the docker daemon and the disk it uses
+  save <image>... --output <file>  Save images to a tar archive, to load on another node
+  load <file>                      Load the images in a tar archive made by save
+  tag <image> <target>             Name an image for a registry, e.g. localhost:5000/app:1.0
+  push <image>                     Push an image to the registry in its name
+  pull <image>                     Pull an image by tag or by digest (<image>@sha256:...)
+  deploy <dir> --port <port>       Build, test and start a project, recording the deployment
+  rollback <app> [--to <id>]       Switch an app back to an earlier deployment, by default the
+                                   one before its current deployment
+  stack up <dir>                   Start the services a project's shipwreck.toml declares
+  stack down <app>                 Stop and remove the services and networks of a stack
+  serve [--listen <addr>]          Serve these commands as a JSON API (default 127.0.0.1:8000),
+      [--desired <file>]           requiring KRAKEN_API_TOKEN as a bearer token if it is set,
+                                   and converge on a desired-state file every 30 seconds
+  webhook --repo <repo>...         Redeploy the apps in local git repositories when a git host
+      [--branch <branch>]          sends a push webhook, signed with KRAKEN_WEBHOOK_SECRET
+      [--listen <addr>]            (default 127.0.0.1:8001)
+
+Options:
+  --json                           Print results as JSON
+  -h, --help                       Show this message
+
+Docker is reached through the DOCKER_* environment variables, deployments are recorded in KRAKEN_STATE.
+With KRAKEN_RUNTIME=process, serve and webhook run apps as child processes instead of docker containers.
+Builds need KRAKEN_MIN_FREE_DISK free on the docker data root (default 2G, 0 to turn off),
+and at most KRAKEN_BUILD_PARALLELISM run at once (default 2).";
+
+/// A parsed subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Build {
+        dir: String,
+        reference: Option<String>,
+    },
+    Run {
+        image: String,
+        port: i64,
+        name: Option<String>,
+    },
+    Ps {
+        all: bool,
+        app: Option<String>,
+    },
+    Logs {
+        container: String,
+        tail: Option<usize>,
+    },
+    Stats {
+        container: String,
+    },
+    Stop {
+        container: String,
+    },
+    Prune,
+    Retention {
+        keep: Option<usize>,
+        dry_run: bool,
+    },
+    Info,
+    Save {
+        images: Vec<String>,
+        output: String,
+    },
+    Load {
+        file: String,
+    },
+    Tag {
+        image: String,
+        target: String,
+    },
+    Push {
+        image: String,
+    },
+    Pull {
+        image: String,
+    },
+    Deploy {
+        dir: String,
+        port: i64,
+    },
+    Rollback {
+        app: String,
+        to: Option<u64>,
+    },
+    StackUp {
+        dir: String,
+    },
+    StackDown {
+        app: String,
+    },
+    Serve {
+        listen: SocketAddr,
+        desired: Option<String>,
+    },
+    Webhook {
+        listen: SocketAddr,
+        repos: Vec<String>,
+        branch: Option<String>,
+    },
+    Help,
+}
+
+/// Parses the command line, without the program name
+///
+/// Returns the output format and the subcommand.
+pub fn parse(args: Vec<String>) -> Result<(OutputFormat, Command), String> {
+    let mut args = Args::new(args);
+    let format = if args.flag(&["--json"]) {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Table
+    };
+    if args.flag(&["-h", "--help"]) {
+        return Ok((format, Command::Help));
+    }
+    let listen = |args: &mut Args, default: &str| -> Result<SocketAddr, String> {
+        Ok(match args.parsed("--listen")? {
+            Some(listen) => listen,
+            None => default.parse().expect("the default address is valid"),
+        })
+    };
+    let port = |args: &mut Args| -> Result<i64, String> {
+        args.parsed("--port")?
+            .ok_or_else(|| String::from("Missing --port"))
+    };
+
+    // Options are taken before positional arguments, so an option's value is never mistaken for one
+    let command = match args.positional("<command>")?.as_str() {
+        "build" => {
+            let reference = args.value("--ref")?;
+            Command::Build {
+                dir: args.positional("<dir>")?,
+                reference,
+            }
+        }
+        "run" => {
+            let port = port(&mut args)?;
+            let name = args.value("--name")?;
+            Command::Run {
+                image: args.positional("<image>")?,
+                port,
+                name,
+            }
+        }
+        "ps" => Command::Ps {
+            all: args.flag(&["-a", "--all"]),
+            app: args.value("--app")?,
+        },
+        "logs" => {
+            let tail = args.parsed("--tail")?;
+            Command::Logs {
+                container: args.positional("<container>")?,
+                tail,
+            }
+        }
+        "stats" => Command::Stats {
+            container: args.positional("<container>")?,
+        },
+        "stop" => Command::Stop {
+            container: args.positional("<container>")?,
+        },
+        "prune" => Command::Prune,
+        "retention" => Command::Retention {
+            keep: args.parsed("--keep")?,
+            dry_run: args.flag(&["--dry-run"]),
+        },
+        "info" => Command::Info,
+        "save" => {
+            let output = args
+                .value("--output")?
+                .ok_or_else(|| String::from("Missing --output"))?;
+            let mut images = vec![args.positional("<image>")?];
+            while let Ok(image) = args.positional("<image>") {
+                images.push(image);
+            }
+            Command::Save { images, output }
+        }
+        "load" => Command::Load {
+            file: args.positional("<file>")?,
+        },
+        "tag" => Command::Tag {
+            image: args.positional("<image>")?,
+            target: args.positional("<target>")?,
+        },
+        "push" => Command::Push {
+            image: args.positional("<image>")?,
+        },
+        "pull" => Command::Pull {
+            image: args.positional("<image>")?,
+        },
+        "deploy" => {
+            let port = port(&mut args)?;
+            Command::Deploy {
+                dir: args.positional("<dir>")?,
+                port,
+            }
+        }
+        "rollback" => {
+            let to = args.parsed("--to")?;
+            Command::Rollback {
+                app: args.positional("<app>")?,
+                to,
+            }
+        }
+        "stack" => match args.positional("up or down after stack")?.as_str() {
+            "up" => Command::StackUp {
+                dir: args.positional("<dir>")?,
+            },
+            "down" => Command::StackDown {
+                app: args.positional("<app>")?,
+            },
+            other => return Err(format!("Unknown stack command {}", other)),
+        },
+        "serve" => Command::Serve {
+            listen: listen(&mut args, DEFAULT_LISTEN)?,
+            desired: args.value("--desired")?,
+        },
+        "webhook" => {
+            let mut repos = vec![];
+            while let Some(repo) = args.value("--repo")? {
+                repos.push(repo);
+            }
+            if repos.is_empty() {
+                return Err(String::from("Missing --repo"));
+            }
+            Command::Webhook {
+                listen: listen(&mut args, DEFAULT_WEBHOOK_LISTEN)?,
+                repos,
+                branch: args.value("--branch")?,
+            }
+        }
+        "help" => Command::Help,
+        other => return Err(format!("Unknown command {}", other)),
+    };
+    args.finish()?;
+    Ok((format, command))
+}
+
+/// Runs the command line, returning the process exit code
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments, without the program name
+pub async fn run(args: Vec<String>) -> i32 {
+    let (format, command) = match parse(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    if command == Command::Help {
+        println!("{}", USAGE);
+        return EXIT_OK;
+    }
+
+    let listening = matches!(command, Command::Serve { .. } | Command::Webhook { .. });
+    let use_processes = match process_runtime_selected() {
+        Ok(selected) => selected,
+        Err(e) => {
+            report_error(format, &e);
+            return EXIT_USAGE;
+        }
+    };
+    if use_processes && !listening {
+        report_error(
+            format,
+            "Only serve and webhook can use KRAKEN_RUNTIME=process, as its processes stop when kraken exits",
+        );
+        return EXIT_USAGE;
+    }
+    let parallelism = match build_parallelism() {
+        Ok(parallelism) => parallelism,
+        Err(e) => {
+            report_error(format, &e);
+            return EXIT_USAGE;
+        }
+    };
+    if use_processes {
+        let builds = BuildQueue::new(
+            Arc::new(ProcessRuntime::new()),
+            parallelism,
+            QueueOrdering::Fifo,
+        );
+        // Runs until the process is killed
+        return match listen(None, builds, command).await {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                report_error(format, &e);
+                EXIT_FAILURE
+            }
+        };
+    }
+
+    let disk_guard = match DiskGuard::from_env() {
+        Ok(guard) => guard,
+        Err(e) => {
+            report_error(format, &e);
+            return EXIT_USAGE;
+        }
+    };
+    let connection = match ConnectionConfig::from_env() {
+        Ok(config) => DockerBroker::connect(&config).await,
+        Err(e) => Err(e),
+    };
+    let mut docker = match connection {
+        Ok(docker) => docker,
+        Err(e) => {
+            report_error(format, &e);
+            return EXIT_UNAVAILABLE;
+        }
+    };
+    if let Some(guard) = disk_guard {
+        docker = docker.with_disk_guard(guard);
+    }
+    // Every build in the process goes through the one queue, so the limit holds across them
+    let docker = Arc::new(docker);
+    let builds = BuildQueue::new(docker.clone(), parallelism, QueueOrdering::Fifo);
+
+    if listening {
+        // Runs until the process is killed
+        return match listen(Some(docker), builds, command).await {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                report_error(format, &e);
+                EXIT_FAILURE
+            }
+        };
+    }
+
+    match execute(&docker, &builds, format, command).await {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            report_error(format, &e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Runs one of the commands which listen for requests
+async fn listen(
+    docker: Option<Arc<DockerBroker>>,
+    builds: BuildQueue,
+    command: Command,
+) -> Result<(), String> {
+    let shutdown = CancellationHandle::new();
+    match command {
+        Command::Serve { listen, desired } => {
+            let token = env::var("KRAKEN_API_TOKEN").ok();
+            let reconciler = desired.map(|path| Arc::new(Reconciler::new(builds.clone(), path)));
+            if let Some(reconciler) = reconciler.clone() {
+                info!(
+                    "Converging on {} every {:?}",
+                    reconciler.desired_path().display(),
+                    RECONCILE_INTERVAL
+                );
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move { reconciler.run(RECONCILE_INTERVAL, &shutdown).await });
+            }
+            let store = open_store(builds.runtime().as_ref()).await?;
+            let services = api::Services {
+                docker,
+                builds,
+                reconciler,
+                history: Arc::new(DeploymentHistory::persisted(store.clone())),
+                endpoints: store,
+                rollback_options: RedeployOptions::default(),
+            };
+            api::serve(services, listen, token, &shutdown).await
+        }
+        Command::Webhook {
+            listen,
+            repos,
+            branch,
+        } => {
+            let secret = env::var("KRAKEN_WEBHOOK_SECRET")
+                .map_err(|_| String::from("KRAKEN_WEBHOOK_SECRET must be set"))?;
+            let apps = repos
+                .iter()
+                .map(|r| WatchedApp::from_repo(r, branch.as_deref()))
+                .collect::<Result<Vec<_>, _>>()?;
+            for app in &apps {
+                info!("Watching {} for pushes to {}", app.repo, app.endpoint);
+            }
+            let store = open_store(builds.runtime().as_ref()).await?;
+            let receiver = WebhookReceiver::new(
+                &secret,
+                apps,
+                builds,
+                store.clone(),
+                Arc::new(DeploymentHistory::persisted(store)),
+                RedeployOptions::default(),
+            );
+            webhook::serve(Arc::new(receiver), listen, &shutdown).await
+        }
+        _ => unreachable!("only listening commands are passed to listen"),
+    }
+}
+
+/// Reads how many images may be built at once from `KRAKEN_BUILD_PARALLELISM`
+fn build_parallelism() -> Result<usize, String> {
+    match env::var("KRAKEN_BUILD_PARALLELISM") {
+        Ok(value) if !value.is_empty() => value
+            .parse::<usize>()
+            .ok()
+            .filter(|p| *p > 0)
+            .ok_or_else(|| {
+                format!(
+                    "KRAKEN_BUILD_PARALLELISM must be a positive number, got {}",
+                    value
+                )
+            }),
+        _ => Ok(DEFAULT_BUILD_PARALLELISM),
+    }
+}
+
+/// Reads whether `KRAKEN_RUNTIME` asks for the process runtime rather than docker
+fn process_runtime_selected() -> Result<bool, String> {
+    match env::var("KRAKEN_RUNTIME").as_deref() {
+        Ok("process") => Ok(true),
+        Ok("docker") | Ok("") | Err(_) => Ok(false),
+        Ok(other) => Err(format!(
+            "KRAKEN_RUNTIME must be docker or process, got {}",
+            other
+        )),
+    }
+}
+
+/// Opens the state recorded in `KRAKEN_STATE`, which holds the deployment history and where each app's endpoint points
+async fn open_store(runtime: &dyn ContainerRuntime) -> Result<Arc<StateStore>, String> {
+    let state_path = env::var("KRAKEN_STATE").unwrap_or_else(|_| String::from(DEFAULT_STATE_PATH));
+    let store = Arc::new(StateStore::open(&state_path)?);
+    // Another agent run may have changed what is running since the store was written
+    let reconciled = match runtime.list_containers(&ContainerQuery::new()).await {
+        Ok(running) => store.reconcile(&running).map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = reconciled {
+        warn!("Failed to reconcile {}: {}", state_path, e);
+    }
+    Ok(store)
+}
+
+/// Shows how far a save or load has got on stderr, overwriting the previous update
+fn report_progress(format: OutputFormat, progress: &TransferProgress) {
+    if format == OutputFormat::Json {
+        return;
+    }
+    match progress {
+        TransferProgress::Exporting { image, bytes } => {
+            eprint!("\rSaving {}: {}        ", image, human_bytes(*bytes))
+        }
+        TransferProgress::Sending { bytes } => eprint!("\rSent {}        ", human_bytes(*bytes)),
+        TransferProgress::Loaded { .. } => {}
+    }
+}
+
+/// Finds the login for the registry an image belongs to
+fn registry_credentials(image: &str) -> Result<Option<RegistryCredentials>, String> {
+    RegistryCredentials::lookup(ImageReference::parse(image)?.registry())
+}
+
+/// Shows each step of a push or pull on stderr, skipping the byte counts in between
+fn report_registry_progress(format: OutputFormat, progress: &RegistryProgress) {
+    if format == OutputFormat::Json {
+        return;
+    }
+    match progress {
+        RegistryProgress::Layer {
+            id,
+            status,
+            current: None,
+            ..
+        } => eprintln!("{}: {}", id, status),
+        RegistryProgress::Layer { .. } => {}
+        RegistryProgress::Status { message } => eprintln!("{}", message),
+        RegistryProgress::Pushing { image } => eprintln!("Pushing {}", image),
+    }
+}
+
+fn report_error(format: OutputFormat, error: &str) {
+    match format {
+        OutputFormat::Json => print_json(&json!({ "error": error })),
+        OutputFormat::Table => eprintln!("error: {}", error),
+    }
+}
+
+async fn execute(
+    docker: &DockerBroker,
+    builds: &BuildQueue,
+    format: OutputFormat,
+    command: Command,
+) -> Result<(), String> {
+    match command {
+        Command::Build {
+            dir,
+            reference: Some(reference),
+        } => {
+            let build = builds
+                .build_image_from_git(&dir, &reference, DEFAULT_PRIORITY)
+                .await?;
+            match format {
+                OutputFormat::Json => print_json(&build),
+                OutputFormat::Table => {
+                    for line in &build.build.log {
+                        println!("{}", line);
+                    }
+                    println!("Built {} from {}", build.build.image_id, build.commit);
+                }
+            }
+        }
+        Command::Build {
+            dir,
+            reference: None,
+        } => {
+            let build = builds
+                .build_image(&dir, &HashMap::new(), DEFAULT_PRIORITY)
+                .await?;
+            match format {
+                OutputFormat::Json => print_json(&build),
+                OutputFormat::Table => {
+                    for line in &build.log {
+                        println!("{}", line);
+                    }
+                    println!("Built {}", build.image_id);
+                }
+            }
+        }
+        Command::Run { image, port, name } => {
+            let name = name.unwrap_or_else(|| image.clone());
+            let id = docker.start_named_container(&image, &name, port).await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "id": id, "name": name, "port": port })),
+                OutputFormat::Table => println!("{}", id),
+            }
+        }
+        Command::Ps { all, app } => {
+            let mut query = ContainerQuery::new();
+            if all {
+                query = query.all();
+            }
+            if let Some(app) = &app {
+                query = query.app(app);
+            }
+            let containers = docker.list_containers(&query).await?;
+            match format {
+                OutputFormat::Json => print_json(&containers),
+                OutputFormat::Table => {
+                    let rows: Vec<Vec<String>> = containers
+                        .iter()
+                        .map(|c| {
+                            let ports: Vec<String> =
+                                c.public_ports().iter().map(|p| p.to_string()).collect();
+                            vec![
+                                short_id(&c.id),
+                                c.name.clone(),
+                                c.image.as_deref().map(short_id).unwrap_or_default(),
+                                c.labels.get(APP_LABEL).cloned().unwrap_or_default(),
+                                c.status.clone().unwrap_or_default(),
+                                ports.join(","),
+                            ]
+                        })
+                        .collect();
+                    print_table(
+                        &["CONTAINER ID", "NAME", "IMAGE", "APP", "STATUS", "PORTS"],
+                        &rows,
+                    );
+                }
+            }
+        }
+        Command::Logs { container, tail } => {
+            let lines = docker.get_container_logs(&container, tail).await?;
+            match format {
+                OutputFormat::Json => print_json(&lines),
+                OutputFormat::Table => {
+                    for line in lines {
+                        println!("{}", line);
+                    }
+                }
+            }
+        }
+        Command::Stats { container } => {
+            let stats = docker.get_container_stats(&container).await?;
+            match format {
+                OutputFormat::Json => print_json(&stats),
+                OutputFormat::Table => print_table(
+                    &["NAME", "CPU %", "MEM USAGE / LIMIT", "NET I/O", "PIDS"],
+                    &[vec![
+                        stats.name.clone(),
+                        format!("{:.2}%", stats.cpu_percent),
+                        format!(
+                            "{} / {}",
+                            human_bytes(stats.memory_usage),
+                            human_bytes(stats.memory_limit)
+                        ),
+                        format!(
+                            "{} / {}",
+                            human_bytes(stats.network_rx_bytes),
+                            human_bytes(stats.network_tx_bytes)
+                        ),
+                        stats.pids.to_string(),
+                    ]],
+                ),
+            }
+        }
+        Command::Stop { container } => {
+            docker.stop_container(&container).await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "stopped": container })),
+                OutputFormat::Table => println!("{}", container),
+            }
+        }
+        Command::Info => {
+            let daemon = docker.daemon_info().await?;
+            let disk = docker.disk_usage().await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "daemon": daemon, "disk": disk })),
+                OutputFormat::Table => {
+                    let unknown = || String::from("-");
+                    println!(
+                        "Docker {} (API {}) on {}/{}, kernel {}",
+                        daemon.version,
+                        daemon.api_version,
+                        daemon.os,
+                        daemon.arch,
+                        daemon.kernel_version
+                    );
+                    println!(
+                        "Storage driver: {}",
+                        daemon.storage_driver.unwrap_or_else(unknown)
+                    );
+                    println!(
+                        "CPUs: {}",
+                        daemon.cpus.map(|c| c.to_string()).unwrap_or_else(unknown)
+                    );
+                    println!(
+                        "Memory: {}",
+                        daemon.memory_bytes.map(human_bytes).unwrap_or_else(unknown)
+                    );
+                    println!("Data root: {}", daemon.data_root.unwrap_or_else(unknown));
+                    println!();
+                    let mut rows: Vec<Vec<String>> = disk
+                        .categories()
+                        .iter()
+                        .map(|(name, usage)| {
+                            vec![
+                                name.to_string(),
+                                usage.count.to_string(),
+                                usage.active.to_string(),
+                                human_bytes(usage.size_bytes),
+                                human_bytes(usage.reclaimable_bytes),
+                            ]
+                        })
+                        .collect();
+                    rows.push(vec![
+                        String::from("Total"),
+                        String::new(),
+                        String::new(),
+                        human_bytes(disk.total_bytes()),
+                        human_bytes(disk.reclaimable_bytes()),
+                    ]);
+                    print_table(&["TYPE", "TOTAL", "ACTIVE", "SIZE", "RECLAIMABLE"], &rows);
+                }
+            }
+        }
+        Command::Save { images, output } => {
+            let file =
+                File::create(&output).map_err(|e| format!("Failed to create {}: {}", output, e))?;
+            let images: Vec<&str> = images.iter().map(|i| i.as_str()).collect();
+            let bytes = docker
+                .export_images(&images, file, &|p| report_progress(format, &p))
+                .await?;
+            match format {
+                OutputFormat::Json => {
+                    print_json(&json!({ "images": images, "output": output, "bytes": bytes }))
+                }
+                OutputFormat::Table => {
+                    eprintln!();
+                    println!(
+                        "Saved {} ({}) to {}",
+                        images.join(", "),
+                        human_bytes(bytes),
+                        output
+                    );
+                }
+            }
+        }
+        Command::Load { file } => {
+            let archive =
+                File::open(&file).map_err(|e| format!("Failed to open {}: {}", file, e))?;
+            let chunks = stream::unfold(archive, |mut archive| async move {
+                let mut chunk = vec![0; 64 * 1024];
+                match archive.read(&mut chunk) {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Some((Ok(chunk), archive))
+                    }
+                    Err(e) => Some((Err(e), archive)),
+                }
+            });
+            let loaded = docker
+                .import_images(Box::pin(chunks), &|p| report_progress(format, &p))
+                .await?;
+            match format {
+                OutputFormat::Json => print_json(&loaded),
+                OutputFormat::Table => {
+                    eprintln!();
+                    for image in loaded {
+                        println!("Loaded {}", image);
+                    }
+                }
+            }
+        }
+        Command::Tag { image, target } => {
+            docker.tag_image(&image, &target).await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "image": image, "target": target })),
+                OutputFormat::Table => println!("Tagged {} as {}", image, target),
+            }
+        }
+        Command::Push { image } => {
+            let credentials = registry_credentials(&image)?;
+            let pushed = docker
+                .push_image(&image, credentials.as_ref(), &|p| {
+                    report_registry_progress(format, &p)
+                })
+                .await?;
+            match format {
+                OutputFormat::Json => print_json(&pushed),
+                OutputFormat::Table => println!("Pushed {}", pushed.digest),
+            }
+        }
+        Command::Pull { image } => {
+            let credentials = registry_credentials(&image)?;
+            docker
+                .pull_image(&image, credentials.as_ref(), &|p| {
+                    report_registry_progress(format, &p)
+                })
+                .await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "image": image })),
+                OutputFormat::Table => println!("Pulled {}", image),
+            }
+        }
+        Command::Prune => {
+            docker.prune().await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "pruned": true })),
+                OutputFormat::Table => println!("Pruned stopped containers and unused images"),
+            }
+        }
+        Command::Retention { keep, dry_run } => {
+            let mut policy = match keep {
+                Some(keep) => RetentionPolicy::new(keep),
+                None => RetentionPolicy::default(),
+            };
+            if dry_run {
+                policy = policy.dry_run();
+            }
+            let report = docker.apply_retention_policy(&policy).await?;
+            match format {
+                OutputFormat::Json => print_json(&report),
+                OutputFormat::Table => {
+                    let removed = if report.dry_run {
+                        "would remove"
+                    } else {
+                        "removed"
+                    };
+                    let mut rows = vec![];
+                    let mut add = |ids: &[String], action: &str| {
+                        for id in ids {
+                            rows.push(vec![short_id(id), String::from(action)]);
+                        }
+                    };
+                    add(&report.kept, "kept");
+                    add(&report.in_use, "in use");
+                    add(&report.removed, removed);
+                    for (id, e) in &report.failed {
+                        rows.push(vec![short_id(id), format!("failed: {}", e)]);
+                    }
+                    print_table(&["IMAGE", "ACTION"], &rows);
+                }
+            }
+            if !report.failed.is_empty() {
+                return Err(format!(
+                    "Failed to remove {} of the images retention selected",
+                    report.failed.len()
+                ));
+            }
+        }
+        Command::Deploy { dir, port } => {
+            let history = DeploymentHistory::persisted(open_store(docker).await?);
+            let deployment = deploy::deploy(builds, &history, &dir, port).await?;
+            match format {
+                OutputFormat::Json => print_json(&deployment),
+                OutputFormat::Table => {
+                    let tests = match &deployment.build.test {
+                        Some(t) if t.passed() => String::from("passed"),
+                        Some(t) => format!("exited {}", t.exit_code),
+                        None => String::from("none"),
+                    };
+                    print_table(
+                        &["DEPLOYMENT", "IMAGE", "CONTAINER", "PORT", "TESTS"],
+                        &[vec![
+                            deployment.deployment_id.to_string(),
+                            deployment.build.image_id.clone(),
+                            short_id(&deployment.container_id),
+                            port.to_string(),
+                            tests,
+                        ]],
+                    );
+                }
+            }
+        }
+        Command::Rollback { app, to } => {
+            let store = open_store(docker).await?;
+            let history = DeploymentHistory::persisted(store.clone());
+            let rollback = deploy::rollback(
+                docker,
+                &*store,
+                &history,
+                &app,
+                to,
+                &RedeployOptions::default(),
+            )
+            .await?;
+            match format {
+                OutputFormat::Json => print_json(&rollback),
+                OutputFormat::Table => print_table(
+                    &["DEPLOYMENT", "IMAGE", "CONTAINER", "PORT", "REPLACED"],
+                    &[vec![
+                        rollback.deployment.deployment_id.to_string(),
+                        rollback.deployment.build.image_id.clone(),
+                        short_id(&rollback.deployment.container_id),
+                        rollback.port.to_string(),
+                        rollback
+                            .replaced
+                            .iter()
+                            .map(|id| short_id(id))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ]],
+                ),
+            }
+        }
+        Command::StackUp { dir } => {
+            let stack = stack::stack_up(docker, &dir).await?;
+            match format {
+                OutputFormat::Json => print_json(&stack),
+                OutputFormat::Table => {
+                    let rows: Vec<Vec<String>> = stack
+                        .services
+                        .iter()
+                        .map(|s| {
+                            let ports: Vec<String> =
+                                s.ports.iter().map(|p| p.to_string()).collect();
+                            vec![
+                                s.name.clone(),
+                                short_id(&s.container_id),
+                                short_id(&s.image),
+                                ports.join(","),
+                            ]
+                        })
+                        .collect();
+                    print_table(&["SERVICE", "CONTAINER", "IMAGE", "PORTS"], &rows);
+                }
+            }
+        }
+        Command::StackDown { app } => {
+            stack::stack_down(docker, &app).await?;
+            match format {
+                OutputFormat::Json => print_json(&json!({ "app": app, "down": true })),
+                OutputFormat::Table => println!("Brought down stack {}", app),
+            }
+        }
+        Command::Serve { .. } | Command::Webhook { .. } | Command::Help => {
+            println!("{}", USAGE)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_command(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(String::from).collect()).map(|(_, c)| c)
+    }
+
+    #[test]
+    fn options_may_come_before_positional_arguments() {
+        assert_eq!(
+            parse_command("build --ref main ./repo"),
+            Ok(Command::Build {
+                dir: String::from("./repo"),
+                reference: Some(String::from("main")),
+            })
+        );
+        assert_eq!(
+            parse_command("run --port 9000 --name web scapegoat"),
+            Ok(Command::Run {
+                image: String::from("scapegoat"),
+                port: 9000,
+                name: Some(String::from("web")),
+            })
+        );
+        assert_eq!(
+            parse_command("deploy --port 9000 ./scapegoat"),
+            Ok(Command::Deploy {
+                dir: String::from("./scapegoat"),
+                port: 9000,
+            })
+        );
+        assert_eq!(
+            parse_command("logs --tail 5 web"),
+            Ok(Command::Logs {
+                container: String::from("web"),
+                tail: Some(5),
+            })
+        );
+    }
+
+    #[test]
+    fn options_may_come_after_positional_arguments() {
+        assert_eq!(
+            parse_command("--json run scapegoat --port=9000"),
+            Ok(Command::Run {
+                image: String::from("scapegoat"),
+                port: 9000,
+                name: None,
+            })
+        );
+        assert_eq!(
+            parse_command("save a --output out.tar b"),
+            Ok(Command::Save {
+                images: vec![String::from("a"), String::from("b")],
+                output: String::from("out.tar"),
+            })
+        );
+    }
+
+    #[test]
+    fn rollback_takes_an_optional_deployment() {
+        assert_eq!(
+            parse_command("rollback scapegoat"),
+            Ok(Command::Rollback {
+                app: String::from("scapegoat"),
+                to: None,
+            })
+        );
+        assert_eq!(
+            parse_command("rollback --to 3 scapegoat"),
+            Ok(Command::Rollback {
+                app: String::from("scapegoat"),
+                to: Some(3),
+            })
+        );
+    }
+
+    #[test]
+    fn retention_takes_a_count_and_a_dry_run() {
+        assert_eq!(
+            parse_command("retention"),
+            Ok(Command::Retention {
+                keep: None,
+                dry_run: false,
+            })
+        );
+        assert_eq!(
+            parse_command("retention --dry-run --keep 5"),
+            Ok(Command::Retention {
+                keep: Some(5),
+                dry_run: true,
+            })
+        );
+        assert!(parse_command("retention --keep all").is_err());
+    }
+
+    #[test]
+    fn stack_takes_up_or_down() {
+        assert_eq!(
+            parse_command("stack up ./scapegoat"),
+            Ok(Command::StackUp {
+                dir: String::from("./scapegoat"),
+            })
+        );
+        assert_eq!(
+            parse_command("stack down scapegoat"),
+            Ok(Command::StackDown {
+                app: String::from("scapegoat"),
+            })
+        );
+        assert_eq!(
+            parse_command("stack restart scapegoat"),
+            Err(String::from("Unknown stack command restart"))
+        );
+    }
+
+    #[test]
+    fn serve_takes_a_desired_state() {
+        assert_eq!(
+            parse_command("serve --desired desired.toml"),
+            Ok(Command::Serve {
+                listen: DEFAULT_LISTEN.parse().unwrap(),
+                desired: Some(String::from("desired.toml")),
+            })
+        );
+    }
+
+    #[test]
+    fn leftover_and_missing_arguments_are_errors() {
+        assert_eq!(
+            parse_command("run --port 9000"),
+            Err(String::from("Missing <image>"))
+        );
+        assert_eq!(
+            parse_command("stop web extra"),
+            Err(String::from("Unexpected argument extra"))
+        );
+        assert_eq!(
+            parse_command("deploy ./scapegoat --port"),
+            Err(String::from("--port needs a value"))
+        );
+    }
+}
@@ -0,0 +1,503 @@
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use super::runtime::ContainerRuntime;
+use super::DockerImageBuildResult;
+
+/// The priority of builds nobody asked to hurry
+pub const DEFAULT_PRIORITY: i32 = 0;
+
+/// The order queued builds are started in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOrdering {
+    /// Builds start in the order they were submitted
+    Fifo,
+
+    /// Builds with a higher priority start first, ties start in the order they were submitted
+    Priority,
+}
+
+/// Where a build is in the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+    /// Waiting for a free slot, `position` builds will start before this one
+    Queued { position: usize },
+
+    /// Being built
+    Running,
+
+    /// Built, or failed to build
+    Finished,
+}
+
+type SharedBuild = Shared<BoxFuture<'static, Result<DockerImageBuildResult, String>>>;
+
+/// A queue in front of a `ContainerRuntime` which limits how many images are built at once
+///
+/// Requests to build a project folder whose contents and labels are identical to a build which is already queued or running
+/// are not built again, every requester receives the result of the same build.
+/// A process should share one queue between everything that builds, so the limit holds across them.
+///
+/// # Examples
+///
+/// ```
+/// let queue = BuildQueue::new(Arc::new(docker), 2, QueueOrdering::Priority);
+/// let ticket = queue.submit("scapegoat", &HashMap::new(), 10).await?;
+/// println!("{:?}", ticket.status());
+/// let result = ticket.wait().await?;
+/// ```
+#[derive(Clone)]
+pub struct BuildQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    runtime: Arc<dyn ContainerRuntime>,
+    parallelism: usize,
+    ordering: QueueOrdering,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: Vec<QueuedBuild>,
+    running: Vec<RunningBuild>,
+    next_seq: u64,
+}
+
+struct QueuedBuild {
+    seq: u64,
+    priority: i32,
+    source_hash: String,
+    source_path: String,
+    labels: HashMap<String, String>,
+    result: SharedBuild,
+    sender: oneshot::Sender<Result<DockerImageBuildResult, String>>,
+}
+
+struct RunningBuild {
+    seq: u64,
+    source_hash: String,
+    result: SharedBuild,
+}
+
+/// A caller's handle on a queued build
+#[derive(Clone)]
+pub struct BuildTicket {
+    seq: u64,
+    source_hash: String,
+    queue: Arc<QueueInner>,
+    result: SharedBuild,
+}
+
+impl BuildQueue {
+    /// Creates a queue
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - The runtime the images are built with
+    /// * `parallelism` - The most builds to run at once, at least 1
+    /// * `ordering` - The order queued builds are started in
+    pub fn new(
+        runtime: Arc<dyn ContainerRuntime>,
+        parallelism: usize,
+        ordering: QueueOrdering,
+    ) -> BuildQueue {
+        BuildQueue {
+            inner: Arc::new(QueueInner {
+                runtime,
+                parallelism: parallelism.max(1),
+                ordering,
+                state: Mutex::new(QueueState::default()),
+            }),
+        }
+    }
+
+    /// The runtime the images are built with
+    pub fn runtime(&self) -> &Arc<dyn ContainerRuntime> {
+        &self.inner.runtime
+    }
+
+    /// Queues a build of a project folder, or joins an identical build which is already queued or running
+    ///
+    /// Must be called from within a tokio runtime, builds are run on spawned tasks.
+    /// The folder is hashed on the blocking pool, as large projects take a while to read.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` - The project folder, containing a `Dockerfile`
+    /// * `labels` - Extra labels for the image, e.g. `COMMIT_LABEL` for a build of a git commit
+    /// * `priority` - Higher priorities start first when the queue uses `QueueOrdering::Priority`
+    pub async fn submit(
+        &self,
+        source_path: &str,
+        labels: &HashMap<String, String>,
+        priority: i32,
+    ) -> Result<BuildTicket, String> {
+        let (path, hashed_labels) = (String::from(source_path), labels.clone());
+        let source_hash = tokio::task::spawn_blocking(move || hash_source(&path, &hashed_labels))
+            .await
+            .map_err(|e| format!("Failed to hash {}: {}", source_path, e))??;
+        let mut state = self.inner.state.lock().unwrap();
+
+        let existing = state
+            .running
+            .iter()
+            .map(|r| (r.seq, &r.source_hash, &r.result))
+            .chain(
+                state
+                    .pending
+                    .iter()
+                    .map(|p| (p.seq, &p.source_hash, &p.result)),
+            )
+            .find(|(_, hash, _)| **hash == source_hash)
+            .map(|(seq, _, result)| (seq, result.clone()));
+        if let Some((seq, result)) = existing {
+            info!(
+                "Joining queued build of {} with identical source {}",
+                source_path, source_hash
+            );
+            if let Some(p) = state.pending.iter_mut().find(|p| p.seq == seq) {
+                p.priority = p.priority.max(priority);
+            }
+            return Ok(BuildTicket {
+                seq,
+                source_hash,
+                queue: self.inner.clone(),
+                result,
+            });
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let result: SharedBuild = receiver
+            .map(|r| r.unwrap_or_else(|_| Err(String::from("Build was dropped by the queue"))))
+            .boxed()
+            .shared();
+        state.next_seq += 1;
+        let seq = state.next_seq;
+        state.pending.push(QueuedBuild {
+            seq,
+            priority,
+            source_hash: source_hash.clone(),
+            source_path: String::from(source_path),
+            labels: labels.clone(),
+            result: result.clone(),
+            sender,
+        });
+        info!("Queued build of {} ({})", source_path, source_hash);
+        QueueInner::dispatch(&self.inner, &mut state);
+
+        Ok(BuildTicket {
+            seq,
+            source_hash,
+            queue: self.inner.clone(),
+            result,
+        })
+    }
+
+    /// Queues a build and waits for its result
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` - The project folder, containing a `Dockerfile`
+    /// * `labels` - Extra labels for the image, e.g. `COMMIT_LABEL` for a build of a git commit
+    /// * `priority` - Higher priorities start first when the queue uses `QueueOrdering::Priority`
+    pub async fn build_image(
+        &self,
+        source_path: &str,
+        labels: &HashMap<String, String>,
+        priority: i32,
+    ) -> Result<DockerImageBuildResult, String> {
+        self.submit(source_path, labels, priority)
+            .await?
+            .wait()
+            .await
+    }
+
+    /// The number of builds waiting for a free slot
+    pub fn queued(&self) -> usize {
+        self.inner.state.lock().unwrap().pending.len()
+    }
+
+    /// The number of builds currently running
+    pub fn running(&self) -> usize {
+        self.inner.state.lock().unwrap().running.len()
+    }
+}
+
+impl QueueInner {
+    /// Starts queued builds until every slot is taken
+    fn dispatch(inner: &Arc<QueueInner>, state: &mut QueueState) {
+        while state.running.len() < inner.parallelism {
+            let next = match inner.next_index(&state.pending) {
+                Some(i) => state.pending.remove(i),
+                None => return,
+            };
+            state.running.push(RunningBuild {
+                seq: next.seq,
+                source_hash: next.source_hash.clone(),
+                result: next.result.clone(),
+            });
+
+            let queue = inner.clone();
+            tokio::spawn(async move {
+                info!(
+                    "Starting build of {} ({})",
+                    next.source_path, next.source_hash
+                );
+                let result = queue
+                    .runtime
+                    .build_labelled_image(&next.source_path, &next.labels)
+                    .await;
+                if let Err(e) = &result {
+                    error!("Queued build of {} failed: {}", next.source_path, e);
+                }
+
+                {
+                    let mut state = queue.state.lock().unwrap();
+                    state.running.retain(|r| r.seq != next.seq);
+                    QueueInner::dispatch(&queue, &mut state);
+                }
+                // Nobody may be waiting on the result any more, which is fine
+                let _ = next.sender.send(result);
+            });
+        }
+    }
+
+    /// Finds the queued build which should start next
+    fn next_index(&self, pending: &[QueuedBuild]) -> Option<usize> {
+        let ordering = self.ordering;
+        pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, p)| match ordering {
+                QueueOrdering::Fifo => (0, p.seq),
+                QueueOrdering::Priority => (-(p.priority as i64), p.seq),
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Gets the number of queued builds which will start before a build
+    fn position(&self, state: &QueueState, seq: u64) -> Option<usize> {
+        let build = state.pending.iter().find(|p| p.seq == seq)?;
+        let ahead = state
+            .pending
+            .iter()
+            .filter(|p| match self.ordering {
+                QueueOrdering::Fifo => p.seq < build.seq,
+                QueueOrdering::Priority => (p.priority, build.seq) > (build.priority, p.seq),
+            })
+            .count();
+        Some(ahead)
+    }
+}
+
+impl BuildTicket {
+    /// The hash of the project folder and labels being built
+    pub fn source_hash(&self) -> &str {
+        &self.source_hash
+    }
+
+    /// Where the build is in the queue
+    pub fn status(&self) -> BuildStatus {
+        let state = self.queue.state.lock().unwrap();
+        if let Some(position) = self.queue.position(&state, self.seq) {
+            BuildStatus::Queued { position }
+        } else if state.running.iter().any(|r| r.seq == self.seq) {
+            BuildStatus::Running
+        } else {
+            BuildStatus::Finished
+        }
+    }
+
+    /// Waits for the build to finish
+    pub async fn wait(self) -> Result<DockerImageBuildResult, String> {
+        self.result.await
+    }
+}
+
+/// Hashes the contents of a project folder and the labels it is built with, so identical builds can be shared
+///
+/// The hash is a SHA-256, as builds of different sources must never be mistaken for each other.
+///
+/// # Arguments
+///
+/// * `source_path` - The project folder
+/// * `labels` - The extra labels of the build
+pub fn hash_source(source_path: &str, labels: &HashMap<String, String>) -> Result<String, String> {
+    let root = Path::new(source_path);
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", source_path));
+    }
+    let mut hasher = Sha256::new();
+    let mut labels: Vec<_> = labels.iter().collect();
+    labels.sort();
+    for (key, value) in labels {
+        hash_field(&mut hasher, key.as_bytes());
+        hash_field(&mut hasher, value.as_bytes());
+    }
+    hash_dir(root, root, &mut hasher)
+        .map_err(|e| format!("Failed to read {}: {}", source_path, e))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Hashes a length then the bytes, so moving bytes between neighbouring fields changes the hash
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Hashes every entry under `dir`, in name order
+///
+/// Symlinks are hashed by their target rather than followed, so a link out of the project (or back into it) can neither leak into nor loop the hash.
+/// Sockets and pipes are hashed by name only, as reading them could block.
+fn hash_dir(root: &Path, dir: &Path, hasher: &mut Sha256) -> std::io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        hash_field(hasher, relative.to_string_lossy().as_bytes());
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            hasher.update(b"l");
+            hash_field(hasher, fs::read_link(&path)?.as_os_str().as_bytes());
+        } else if file_type.is_dir() {
+            // Tells an empty folder from an empty file
+            hasher.update(b"d");
+            hash_dir(root, &path, hasher)?;
+        } else if file_type.is_file() {
+            hasher.update(b"f");
+            hash_field(hasher, &fs::read(&path)?);
+        } else {
+            hasher.update(b"o");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::{FakeRuntime, TempProject};
+    use crate::docker::COMMIT_LABEL;
+
+    fn commit(sha: &str) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        labels.insert(String::from(COMMIT_LABEL), String::from(sha));
+        labels
+    }
+
+    #[test]
+    fn hash_is_the_sha256_of_sources_and_labels() {
+        let a = TempProject::with_files(&[("Dockerfile", "FROM scratch"), ("src/app.py", "")]);
+        let b = TempProject::with_files(&[("Dockerfile", "FROM scratch"), ("src/app.py", "")]);
+        let moved = TempProject::with_files(&[("Dockerfile", "FROM scratch"), ("src", "")]);
+        let none = HashMap::new();
+
+        let hash = hash_source(&a.path(), &none).unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_source(&b.path(), &none).unwrap());
+        assert_ne!(hash, hash_source(&moved.path(), &none).unwrap());
+        assert_ne!(hash, hash_source(&a.path(), &commit("abc")).unwrap());
+        assert_ne!(
+            hash_source(&a.path(), &commit("abc")).unwrap(),
+            hash_source(&a.path(), &commit("abd")).unwrap()
+        );
+    }
+
+    #[test]
+    fn symlinks_are_hashed_by_their_target_without_being_followed() {
+        let outside = TempProject::with_files(&[("secret", "1")]);
+        let project = TempProject::with_files(&[("Dockerfile", "FROM scratch")]);
+        let root = Path::new(&project.path()).to_path_buf();
+        std::os::unix::fs::symlink(Path::new(&outside.path()).join("secret"), root.join("link"))
+            .unwrap();
+        // Following this would recurse forever
+        std::os::unix::fs::symlink(".", root.join("loop")).unwrap();
+        let none = HashMap::new();
+
+        let hash = hash_source(&project.path(), &none).unwrap();
+        fs::write(Path::new(&outside.path()).join("secret"), "2").unwrap();
+        assert_eq!(hash, hash_source(&project.path(), &none).unwrap());
+
+        fs::remove_file(root.join("link")).unwrap();
+        std::os::unix::fs::symlink("Dockerfile", root.join("link")).unwrap();
+        assert_ne!(hash, hash_source(&project.path(), &none).unwrap());
+    }
+
+    #[tokio::test]
+    async fn identical_builds_are_shared() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let queue = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo);
+        let source = TempProject::with_files(&[("Dockerfile", "FROM scratch")]);
+        let copy = TempProject::with_files(&[("Dockerfile", "FROM scratch")]);
+        runtime.hold_builds();
+
+        let first = queue
+            .submit(&source.path(), &commit("abc"), 0)
+            .await
+            .unwrap();
+        let joined = queue.submit(&copy.path(), &commit("abc"), 0).await.unwrap();
+        let relabelled = queue
+            .submit(&source.path(), &commit("abd"), 0)
+            .await
+            .unwrap();
+        assert_eq!(first.source_hash(), joined.source_hash());
+        assert_ne!(first.source_hash(), relabelled.source_hash());
+
+        runtime.release_builds();
+        let first = first.wait().await.unwrap();
+        assert_eq!(joined.wait().await.unwrap().image_id, first.image_id);
+        assert_ne!(relabelled.wait().await.unwrap().image_id, first.image_id);
+        let images = runtime.images();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].labels[COMMIT_LABEL], "abc");
+        assert_eq!(images[1].labels[COMMIT_LABEL], "abd");
+    }
+
+    #[tokio::test]
+    async fn builds_wait_for_a_free_slot() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let queue = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Priority);
+        let sources: Vec<TempProject> = (0..3)
+            .map(|i| TempProject::with_files(&[("Dockerfile", &format!("FROM scratch\n# {}", i))]))
+            .collect();
+        runtime.hold_builds();
+
+        let running = queue
+            .submit(&sources[0].path(), &HashMap::new(), 0)
+            .await
+            .unwrap();
+        let low = queue
+            .submit(&sources[1].path(), &HashMap::new(), 0)
+            .await
+            .unwrap();
+        let high = queue
+            .submit(&sources[2].path(), &HashMap::new(), 10)
+            .await
+            .unwrap();
+
+        assert_eq!(running.status(), BuildStatus::Running);
+        assert_eq!(high.status(), BuildStatus::Queued { position: 0 });
+        assert_eq!(low.status(), BuildStatus::Queued { position: 1 });
+        assert_eq!((queue.running(), queue.queued()), (1, 2));
+        runtime.release_builds();
+        for ticket in [running, low, high] {
+            ticket.wait().await.unwrap();
+        }
+        assert_eq!((queue.running(), queue.queued()), (0, 0));
+    }
+}
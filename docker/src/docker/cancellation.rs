@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// A handle used to cancel a long-running operation, such as a build, from another task
+///
+/// Cloned handles all cancel the same operation.
+///
+/// # Examples
+///
+/// ```
+/// let cancel = CancellationHandle::new();
+/// let build = docker.build_image_cancellable("scapegoat", &cancel);
+/// // ...from elsewhere, when the deploy is abandoned
+/// cancel.cancel();
+/// ```
+#[derive(Clone, Default)]
+pub struct CancellationHandle {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationHandle {
+    pub fn new() -> CancellationHandle {
+        CancellationHandle::default()
+    }
+
+    /// Requests that the operation stops as soon as possible
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify();
+    }
+
+    /// Whether `cancel` has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once `cancel` has been called
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            self.inner.notify.notified().await;
+        }
+        // Each notification only wakes one waiter, so pass it on to the next
+        self.inner.notify.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::{StalledDaemon, TempProject};
+    use crate::docker::timeouts::OperationTimeouts;
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn cancelling_wakes_every_waiter() {
+        let cancel = CancellationHandle::new();
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let cancel = cancel.clone();
+                tokio::spawn(async move { cancel.cancelled().await })
+            })
+            .collect();
+        // Let both start waiting before cancelling
+        time::delay_for(Duration::from_millis(20)).await;
+        assert!(!cancel.is_cancelled());
+
+        cancel.cancel();
+
+        for waiter in waiters {
+            time::timeout(Duration::from_secs(1), waiter)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(cancel.is_cancelled());
+        // Waiting after the fact completes straight away
+        time::timeout(Duration::from_secs(1), cancel.cancelled())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_build_removes_its_partial_image_and_tarball() {
+        let daemon = StalledDaemon::start();
+        let docker = daemon.broker(OperationTimeouts::default());
+        let project = TempProject::new("scapegoat", "1.0.0", "");
+        let path = project.path();
+        let cancel = CancellationHandle::new();
+
+        let build = docker.build_image_cancellable(&path, &cancel);
+        let cancel_once_streaming = async {
+            while !daemon.requests().iter().any(|r| r.contains("/build")) {
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+            cancel.cancel();
+        };
+        let (result, _) = futures_util::future::join(build, cancel_once_streaming).await;
+
+        assert_eq!(
+            result.unwrap_err(),
+            "Failed to build image: Build cancelled"
+        );
+        let removal = daemon
+            .requests()
+            .into_iter()
+            .find(|r| r.starts_with("DELETE ") && r.contains("/images/"))
+            .expect("the partial image was not removed");
+        let tag = removal
+            .rsplit("/images/")
+            .next()
+            .unwrap()
+            .split('?')
+            .next()
+            .unwrap()
+            .to_string();
+        assert!(!Path::new(&format!("./tmp/containers/{}.tar.gz", tag)).exists());
+    }
+}
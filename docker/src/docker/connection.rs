@@ -0,0 +1,379 @@
+use bollard::{ClientVersion, Docker, API_DEFAULT_VERSION};
+use std::env;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The socket docker listens on when `DOCKER_HOST` is not set
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/docker.sock";
+
+/// How long to wait for the docker daemon to answer a request, in seconds
+pub const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+/// Where the docker daemon is listening
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DockerHost {
+    /// A unix socket at a path (e.g. `/var/run/docker.sock`)
+    Unix(PathBuf),
+
+    /// Plain, unauthenticated TCP (e.g. `localhost:2375`)
+    Tcp(String),
+
+    /// TCP secured with TLS client certificates (e.g. `node-1:2376`)
+    Tls {
+        /// The `host:port` of the daemon
+        addr: String,
+
+        /// The folder holding `cert.pem` and `key.pem` (the client certificate and key) and `ca.pem`, as laid out for the docker CLI
+        cert_path: PathBuf,
+    },
+}
+
+impl DockerHost {
+    /// The host the daemon publishes container ports on, which is this machine for a unix socket
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// assert_eq!(DockerHost::Tcp(String::from("node-1:2375")).published_host(), "node-1");
+    /// ```
+    pub fn published_host(&self) -> String {
+        let addr = match self {
+            DockerHost::Unix(_) => return String::from("127.0.0.1"),
+            DockerHost::Tcp(addr) | DockerHost::Tls { addr, .. } => addr,
+        };
+        // Keeps the brackets of an IPv6 address such as `[::1]:2376`, so a port can be added back
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                String::from(host)
+            }
+            _ => addr.clone(),
+        }
+    }
+}
+
+/// Settings used by `DockerBroker` to connect to a docker daemon
+///
+/// # Examples
+///
+/// ```
+/// // Read DOCKER_HOST, DOCKER_TLS_VERIFY, DOCKER_CERT_PATH etc.
+/// let config = ConnectionConfig::from_env()?;
+///
+/// // Or describe the daemon explicitly
+/// let config = ConnectionConfig {
+///     host: DockerHost::Tcp(String::from("node-1:2375")),
+///     timeout_secs: 30,
+///     ..Default::default()
+/// };
+/// let docker = DockerBroker::connect(&config).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// Where the docker daemon is listening
+    pub host: DockerHost,
+
+    /// How long to wait for the daemon to answer a request, in seconds
+    pub timeout_secs: u64,
+
+    /// The API version to speak (e.g. `1.40`), bollard's default if `None`
+    pub api_version: Option<String>,
+
+    /// Whether to downgrade `api_version` to the daemon's version if the daemon is older
+    pub negotiate_version: bool,
+}
+
+impl Default for ConnectionConfig {
+    /// Connects to the default unix socket, negotiating the API version
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            host: DockerHost::Unix(PathBuf::from(DEFAULT_SOCKET_PATH)),
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            api_version: None,
+            negotiate_version: true,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Builds a config from the environment variables used by the docker CLI
+    ///
+    /// * `DOCKER_HOST` - `unix:///path/to/docker.sock` or `tcp://host:port`, defaults to the default unix socket
+    /// * `DOCKER_TLS_VERIFY` - Any non-empty value other than `0` secures a `tcp://` host with TLS
+    /// * `DOCKER_CERT_PATH` - The folder holding `cert.pem`, `key.pem` and `ca.pem`, defaults to `~/.docker`
+    /// * `DOCKER_TIMEOUT` - The request timeout in seconds
+    /// * `DOCKER_API_VERSION` - The API version to speak, which also disables negotiation
+    pub fn from_env() -> Result<ConnectionConfig, String> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let tls_verify = var("DOCKER_TLS_VERIFY").is_some_and(|v| v != "0");
+        let host = match var("DOCKER_HOST") {
+            Some(h) => parse_host(&h, tls_verify, var("DOCKER_CERT_PATH").map(PathBuf::from))?,
+            None => DockerHost::Unix(PathBuf::from(DEFAULT_SOCKET_PATH)),
+        };
+        let timeout_secs = match var("DOCKER_TIMEOUT") {
+            Some(t) => t
+                .parse()
+                .map_err(|_| format!("DOCKER_TIMEOUT must be a number of seconds, got {}", t))?,
+            None => DEFAULT_TIMEOUT_SECS,
+        };
+        let api_version = var("DOCKER_API_VERSION");
+        Ok(ConnectionConfig {
+            host,
+            timeout_secs,
+            negotiate_version: api_version.is_none(),
+            api_version,
+        })
+    }
+
+    /// Creates a docker client for this config
+    ///
+    /// This does not talk to the daemon, so it only fails for malformed settings or unreadable certificates.
+    pub fn client(&self) -> Result<Docker, String> {
+        let version = match &self.api_version {
+            Some(v) => parse_api_version(v)?,
+            None => ClientVersion {
+                major_version: API_DEFAULT_VERSION.major_version,
+                minor_version: API_DEFAULT_VERSION.minor_version,
+            },
+        };
+        let client = match &self.host {
+            DockerHost::Unix(path) => {
+                Docker::connect_with_unix(&path.to_string_lossy(), self.timeout_secs, &version)
+            }
+            DockerHost::Tcp(addr) => Docker::connect_with_http(addr, self.timeout_secs, &version),
+            #[cfg(feature = "tls")]
+            DockerHost::Tls { addr, cert_path } => {
+                return connect_with_pem(addr, cert_path, self.timeout_secs, &version)
+            }
+            #[cfg(not(feature = "tls"))]
+            DockerHost::Tls { addr, .. } => {
+                return Err(format!(
+                    "Cannot connect to {} over TLS, this build does not have the `tls` feature",
+                    addr
+                ))
+            }
+        };
+        client.map_err(|e| {
+            format!(
+                "Invalid docker connection settings {:?}: {:?}",
+                self.host, e
+            )
+        })
+    }
+}
+
+/// Parses a `DOCKER_HOST` value
+fn parse_host(
+    host: &str,
+    tls_verify: bool,
+    cert_path: Option<PathBuf>,
+) -> Result<DockerHost, String> {
+    if let Some(path) = host.strip_prefix("unix://") {
+        return Ok(DockerHost::Unix(PathBuf::from(path)));
+    }
+    let addr = match host.strip_prefix("tcp://") {
+        Some(addr) => addr,
+        None => return Err(format!("Unsupported DOCKER_HOST {}", host)),
+    };
+    if !tls_verify {
+        return Ok(DockerHost::Tcp(String::from(addr)));
+    }
+    let cert_path = match cert_path {
+        Some(p) => p,
+        None => env::var("HOME")
+            .map(|h| PathBuf::from(h).join(".docker"))
+            .map_err(|_| String::from("DOCKER_TLS_VERIFY is set but DOCKER_CERT_PATH is not"))?,
+    };
+    Ok(DockerHost::Tls {
+        addr: String::from(addr),
+        cert_path,
+    })
+}
+
+/// Connects over TLS with the `cert.pem`, `key.pem` and `ca.pem` in `cert_path`
+///
+/// bollard only takes the client certificate and key as a PKCS #12 archive on disk, so one is made from the PEM files.
+/// It is written to a file only the current user can read, which is removed as soon as bollard has loaded it.
+#[cfg(feature = "tls")]
+fn connect_with_pem(
+    addr: &str,
+    cert_path: &Path,
+    timeout_secs: u64,
+    version: &ClientVersion,
+) -> Result<Docker, String> {
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::stack::Stack;
+    use openssl::x509::X509;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let read = |name: &str| {
+        let path = cert_path.join(name);
+        fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+    };
+    let invalid = |name: &str, e: openssl::error::ErrorStack| {
+        format!("Invalid {} in {}: {}", name, cert_path.display(), e)
+    };
+
+    // cert.pem may carry intermediate certificates after the client's own
+    let mut chain = X509::stack_from_pem(&read("cert.pem")?)
+        .map_err(|e| invalid("cert.pem", e))?
+        .into_iter();
+    let cert = chain
+        .next()
+        .ok_or_else(|| format!("No certificate in {}", cert_path.join("cert.pem").display()))?;
+    let mut intermediates = Stack::new().map_err(|e| invalid("cert.pem", e))?;
+    for c in chain {
+        intermediates.push(c).map_err(|e| invalid("cert.pem", e))?;
+    }
+    let key = PKey::private_key_from_pem(&read("key.pem")?).map_err(|e| invalid("key.pem", e))?;
+    let identity = Pkcs12::builder()
+        .name("kraken")
+        .pkey(&key)
+        .cert(&cert)
+        .ca(intermediates)
+        .build2("")
+        .and_then(|p| p.to_der())
+        .map_err(|e| invalid("cert.pem and key.pem", e))?;
+
+    let identity_path =
+        env::temp_dir().join(format!("kraken-identity-{}.pfx", uuid::Uuid::new_v4()));
+    let write = || -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&identity_path)?;
+        file.write_all(&identity)
+    };
+    let written = write().map_err(|e| {
+        format!(
+            "Failed to write the TLS identity to {}: {}",
+            identity_path.display(),
+            e
+        )
+    });
+    let client = written.and_then(|_| {
+        Docker::connect_with_tls(
+            addr,
+            &identity_path,
+            &cert_path.join("ca.pem"),
+            "",
+            timeout_secs,
+            version,
+        )
+        .map_err(|e| format!("Failed to connect to {} over TLS: {:?}", addr, e))
+    });
+    let _ = fs::remove_file(&identity_path);
+    client
+}
+
+/// Parses an API version such as `1.40`
+fn parse_api_version(version: &str) -> Result<ClientVersion, String> {
+    let invalid = || format!("Invalid docker API version {}", version);
+    let mut parts = version.trim_start_matches('v').splitn(2, '.');
+    let major_version = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    let minor_version = parts
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok(ClientVersion {
+        major_version,
+        minor_version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_parsed_like_the_docker_cli() {
+        assert_eq!(
+            parse_host("unix:///tmp/docker.sock", true, None).unwrap(),
+            DockerHost::Unix(PathBuf::from("/tmp/docker.sock"))
+        );
+        assert_eq!(
+            parse_host("tcp://node-1:2375", false, None).unwrap(),
+            DockerHost::Tcp(String::from("node-1:2375"))
+        );
+        assert_eq!(
+            parse_host("tcp://node-1:2376", true, Some(PathBuf::from("/certs"))).unwrap(),
+            DockerHost::Tls {
+                addr: String::from("node-1:2376"),
+                cert_path: PathBuf::from("/certs"),
+            }
+        );
+        assert!(parse_host("ssh://node-1", false, None).is_err());
+    }
+
+    #[test]
+    fn ports_are_published_on_the_daemons_host() {
+        let unix = DockerHost::Unix(PathBuf::from(DEFAULT_SOCKET_PATH));
+        assert_eq!(unix.published_host(), "127.0.0.1");
+        let tcp = DockerHost::Tcp(String::from("node-1:2375"));
+        assert_eq!(tcp.published_host(), "node-1");
+        let tls = DockerHost::Tls {
+            addr: String::from("[::1]:2376"),
+            cert_path: PathBuf::from("/certs"),
+        };
+        assert_eq!(tls.published_host(), "[::1]");
+        assert_eq!(
+            DockerHost::Tcp(String::from("node-1")).published_host(),
+            "node-1"
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    fn write_certs(dir: &Path) {
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "kraken").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build().to_pem().unwrap();
+
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+        std::fs::write(dir.join("ca.pem"), &cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_clients_are_made_from_the_docker_cli_cert_layout() {
+        let dir = env::temp_dir().join(format!("kraken-certs-{}", uuid::Uuid::new_v4()));
+        let config = ConnectionConfig {
+            host: DockerHost::Tls {
+                addr: String::from("node-1:2376"),
+                cert_path: dir.clone(),
+            },
+            ..Default::default()
+        };
+        let error = config.client().err().unwrap();
+        assert!(error.contains("cert.pem"), "{}", error);
+
+        write_certs(&dir);
+        config.client().unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use bollard::container::Stats;
+use serde::{Deserialize, Serialize};
+
+/// A point-in-time snapshot of the resources used by a container
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContainerStats {
+    /// The ID of the container
+    pub id: String,
+
+    /// The name of the container
+    pub name: String,
+
+    /// CPU usage as a percentage of a single core (e.g. `150.0` is one and a half cores)
+    pub cpu_percent: f64,
+
+    /// Memory currently used, in bytes
+    pub memory_usage: u64,
+
+    /// The most memory the container may use, in bytes
+    pub memory_limit: u64,
+
+    /// Bytes received over all networks
+    pub network_rx_bytes: u64,
+
+    /// Bytes sent over all networks
+    pub network_tx_bytes: u64,
+
+    /// The number of processes running in the container
+    pub pids: u64,
+}
+
+impl From<Stats> for ContainerStats {
+    fn from(s: Stats) -> ContainerStats {
+        // Same calculation as `docker stats`: the container's share of the system's CPU time since the previous sample
+        let cpu_delta =
+            s.cpu_stats.cpu_usage.total_usage as f64 - s.precpu_stats.cpu_usage.total_usage as f64;
+        let system_delta = s.cpu_stats.system_cpu_usage.unwrap_or(0) as f64
+            - s.precpu_stats.system_cpu_usage.unwrap_or(0) as f64;
+        let cpus = s.cpu_stats.online_cpus.unwrap_or_else(|| {
+            s.cpu_stats
+                .cpu_usage
+                .percpu_usage
+                .as_ref()
+                .map(|p| p.len() as u64)
+                .unwrap_or(1)
+        }) as f64;
+        let cpu_percent = if cpu_delta > 0.0 && system_delta > 0.0 {
+            cpu_delta / system_delta * cpus * 100.0
+        } else {
+            0.0
+        };
+        let (rx, tx) = s
+            .networks
+            .unwrap_or_default()
+            .values()
+            .fold((0, 0), |(rx, tx), n| (rx + n.rx_bytes, tx + n.tx_bytes));
+        ContainerStats {
+            id: s.id,
+            name: String::from(s.name.trim_start_matches('/')),
+            cpu_percent,
+            memory_usage: s.memory_stats.usage.unwrap_or(0),
+            memory_limit: s.memory_stats.limit.unwrap_or(0),
+            network_rx_bytes: rx,
+            network_tx_bytes: tx,
+            pids: s.pids_stats.current.unwrap_or(0),
+        }
+    }
+}
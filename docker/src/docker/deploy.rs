@@ -0,0 +1,787 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use super::build_queue::{BuildQueue, DEFAULT_PRIORITY};
+use super::docker_container::ContainerQuery;
+use super::endpoints::EndpointPublisher;
+use super::history::{DeploymentHistory, DeploymentOutcome, DeploymentRecord};
+use super::manifest::ShipwreckManifest;
+use super::readiness::ReadinessProbe;
+use super::runtime::ContainerRuntime;
+use super::DockerImageBuildResult;
+
+/// The number of lines of failing test output included in a refused deploy's error
+const FAILED_TEST_OUTPUT_LINES: usize = 20;
+
+/// An application which has been built, tested and started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    /// The build the container was started from, including its test result
+    pub build: DockerImageBuildResult,
+
+    /// The ID of the started container
+    pub container_id: String,
+
+    /// The id of this deployment in the `DeploymentHistory`
+    pub deployment_id: u64,
+}
+
+/// Builds an image and runs its manifest's `config.test` command against it
+///
+/// The test result is attached to the returned build, `test` is `None` if the project has no test command.
+/// A failing test is not an error here, check `TestResult::passed` (or use `deploy`, which refuses failing builds).
+///
+/// # Arguments
+///
+/// * `builds` - The queue to build with, whose runtime the tests run on
+/// * `source_path` - The project folder, containing a `Dockerfile` and `shipwreck.toml`
+/// * `labels` - Extra labels for the image, e.g. `COMMIT_LABEL` for a build of a git commit
+pub async fn build_and_test(
+    builds: &BuildQueue,
+    source_path: &str,
+    labels: &HashMap<String, String>,
+) -> Result<DockerImageBuildResult, String> {
+    let mut build = builds
+        .build_image(source_path, labels, DEFAULT_PRIORITY)
+        .await?;
+    let manifest = match ShipwreckManifest::from_dir(source_path) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Not testing {}: {}", source_path, e);
+            return Ok(build);
+        }
+    };
+    let command = manifest.config.test.trim();
+    if command.is_empty() {
+        info!("{} has no test command", manifest.app.name);
+        return Ok(build);
+    }
+    build.test = Some(
+        builds
+            .runtime()
+            .run_tests(&build.image_id, command, &manifest.env_vars)
+            .await?,
+    );
+    Ok(build)
+}
+
+/// Builds, tests and starts an application, refusing to start it if its tests fail
+///
+/// The deployment is recorded in `history` however it ends.
+///
+/// # Arguments
+///
+/// * `builds` - The queue to build with, whose runtime the application is deployed to
+/// * `history` - Where the deployment is recorded
+/// * `source_path` - The project folder, containing a `Dockerfile` and `shipwreck.toml`
+/// * `port` - The host port to publish the application's port on
+///
+/// # Examples
+///
+/// ```
+/// let docker = DockerBroker::new().await.unwrap();
+/// let builds = BuildQueue::new(Arc::new(docker), 2, QueueOrdering::Fifo);
+/// let history = DeploymentHistory::new();
+/// let deployment = deploy(&builds, &history, "./tmp/scapegoat", 9000).await?;
+/// println!("Started {}", deployment.container_id);
+/// ```
+pub async fn deploy(
+    builds: &BuildQueue,
+    history: &DeploymentHistory,
+    source_path: &str,
+    port: i64,
+) -> Result<Deployment, String> {
+    // Projects without a manifest can still be deployed, they are recorded under their folder
+    let (app, version, env) = match ShipwreckManifest::from_dir(source_path) {
+        Ok(m) => (m.app.name, m.app.version, m.env_vars),
+        Err(_) => (String::from(source_path), String::new(), Default::default()),
+    };
+    let record = |outcome| DeploymentRecord::new(&app, &version, &env, outcome);
+
+    let build =
+        build_and_test_recorded(builds, history, source_path, &HashMap::new(), &record).await?;
+    let runtime = builds.runtime().as_ref();
+    let started = match container_port(runtime, &build.image_id, port).await {
+        Ok(container_port) => {
+            runtime
+                .start_mapped_container(&build.image_id, &build.image_id, container_port, port)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let container_id = match started {
+        Ok(id) => id,
+        Err(e) => {
+            history.record(DeploymentRecord {
+                image_id: Some(build.image_id.clone()),
+                ..record(DeploymentOutcome::Failed(e.clone()))
+            });
+            return Err(e);
+        }
+    };
+    info!("Deployed {} as {}", source_path, container_id);
+    let deployment_id = history.record(DeploymentRecord {
+        image_id: Some(build.image_id.clone()),
+        container_id: Some(container_id.clone()),
+        ports: vec![port],
+        ..record(DeploymentOutcome::Succeeded)
+    });
+    Ok(Deployment {
+        build,
+        container_id,
+        deployment_id,
+    })
+}
+
+/// Builds and tests a project, recording the deployment as failed if either fails
+async fn build_and_test_recorded(
+    builds: &BuildQueue,
+    history: &DeploymentHistory,
+    source_path: &str,
+    labels: &HashMap<String, String>,
+    record: &(dyn Fn(DeploymentOutcome) -> DeploymentRecord + Sync),
+) -> Result<DockerImageBuildResult, String> {
+    let build = match build_and_test(builds, source_path, labels).await {
+        Ok(b) => b,
+        Err(e) => {
+            history.record(record(DeploymentOutcome::BuildFailed(e.clone())));
+            return Err(e);
+        }
+    };
+    if let Err(e) = refuse_failed_tests(&build, source_path) {
+        let exit_code = build.test.as_ref().map(|t| t.exit_code).unwrap_or(0);
+        history.record(DeploymentRecord {
+            image_id: Some(build.image_id.clone()),
+            ..record(DeploymentOutcome::TestsFailed { exit_code })
+        });
+        return Err(e);
+    }
+    Ok(build)
+}
+
+/// Gets the port the application in an image listens on, which is `host_port` if the application listens wherever it is told to
+async fn container_port(
+    runtime: &dyn ContainerRuntime,
+    image_id: &str,
+    host_port: i64,
+) -> Result<i64, String> {
+    Ok(runtime.container_port(image_id).await?.unwrap_or(host_port))
+}
+
+/// Fails if the tests of a build were run and did not pass
+fn refuse_failed_tests(build: &DockerImageBuildResult, source_path: &str) -> Result<(), String> {
+    let test = match build.test.as_ref().filter(|t| !t.passed()) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    error!(
+        "Refusing to deploy {}, `{}` exited with {}",
+        source_path, test.command, test.exit_code
+    );
+    let skip = test.output.len().saturating_sub(FAILED_TEST_OUTPUT_LINES);
+    Err(format!(
+        "Refusing to deploy {}, `{}` exited with {}:\n{}",
+        source_path,
+        test.command,
+        test.exit_code,
+        test.output[skip..].join("\n")
+    ))
+}
+
+/// Settings for a blue/green redeploy
+#[derive(Debug, Clone)]
+pub struct RedeployOptions {
+    /// How to tell when the new version is ready, a `GET /` through its published port by default
+    ///
+    /// The port is probed on the runtime's `published_host`, i.e. the docker daemon's host.
+    pub probe: ReadinessProbe,
+
+    /// How long the new version has to become ready before it is rolled back
+    pub ready_timeout: Duration,
+
+    /// How long to wait between probes
+    pub probe_interval: Duration,
+
+    /// The host ports the new version may be started on
+    pub ports: Range<i64>,
+
+    /// Extra labels for the new version's image, e.g. `COMMIT_LABEL` for a build of a git commit
+    pub labels: HashMap<String, String>,
+}
+
+impl Default for RedeployOptions {
+    fn default() -> RedeployOptions {
+        RedeployOptions {
+            probe: ReadinessProbe::Http {
+                path: String::from("/"),
+            },
+            ready_timeout: Duration::from_secs(60),
+            probe_interval: Duration::from_secs(1),
+            ports: 20000..30000,
+            labels: HashMap::new(),
+        }
+    }
+}
+
+/// A completed blue/green redeploy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Redeployment {
+    /// The new version, which the app's endpoint now points at
+    pub deployment: Deployment,
+
+    /// The host port the new version was published on, and the app's endpoint now points at
+    pub port: i64,
+
+    /// The containers of the previous version which were stopped
+    pub replaced: Vec<String>,
+}
+
+/// Redeploys an application, keeping the old version serving until the new one is ready
+///
+/// The new version is built, tested and started on a spare port while the old version keeps serving.
+/// Once the new version passes its readiness probe the app's endpoint is switched to it and the old containers are stopped.
+/// If the new version never becomes ready (or the endpoint can't be switched) it is stopped and the old version is left untouched.
+/// The deployment is recorded in `history` however it ends.
+///
+/// The new version keeps its spare port, so this only avoids downtime for clients which follow the app's endpoint (e.g. a proxy configured from it).
+/// Connections to the old version's port are refused once it has stopped.
+///
+/// # Arguments
+///
+/// * `builds` - The queue to build with, whose runtime the application is deployed to
+/// * `endpoints` - Where the app's endpoint is published
+/// * `history` - Where the deployment is recorded
+/// * `source_path` - The project folder, containing a `Dockerfile` and `shipwreck.toml`
+/// * `options` - How to pick a port for, label and probe the new version
+///
+/// # Examples
+///
+/// ```
+/// let endpoints = EndpointTable::new();
+/// let options = RedeployOptions {
+///     probe: ReadinessProbe::Http { path: String::from("/health") },
+///     ..Default::default()
+/// };
+/// let redeployment = redeploy(&builds, &endpoints, &history, "./tmp/scapegoat", &options).await?;
+/// println!("scapegoat now on port {}", redeployment.port);
+/// ```
+pub async fn redeploy(
+    builds: &BuildQueue,
+    endpoints: &dyn EndpointPublisher,
+    history: &DeploymentHistory,
+    source_path: &str,
+    options: &RedeployOptions,
+) -> Result<Redeployment, String> {
+    let manifest = ShipwreckManifest::from_dir(source_path)?;
+    let app = &manifest.app.name;
+    let record =
+        |outcome| DeploymentRecord::new(app, &manifest.app.version, &manifest.env_vars, outcome);
+
+    let build =
+        build_and_test_recorded(builds, history, source_path, &options.labels, &record).await?;
+    let switched = switch_to(
+        builds.runtime().as_ref(),
+        endpoints,
+        app,
+        &build.image_id,
+        options,
+    )
+    .await;
+    finish_switch(
+        history,
+        build,
+        switched,
+        record(DeploymentOutcome::Succeeded),
+    )
+}
+
+/// Rolls an application back to a previous deployment, using the same blue/green switch as `redeploy`
+///
+/// The rollback is recorded in `history` as a new deployment of the old image.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime the application is deployed to
+/// * `endpoints` - Where the app's endpoint is published
+/// * `history` - The app's deployments
+/// * `app` - The application name from its `shipwreck.toml`
+/// * `deployment_id` - The successful deployment to go back to, or `None` for the one before the current deployment
+/// * `options` - How to pick a port and probe the old version
+///
+/// # Examples
+///
+/// ```
+/// let rollback = rollback(&docker, &endpoints, &history, "scapegoat", None, &options).await?;
+/// println!("scapegoat is back on {}", rollback.deployment.build.image_id);
+/// ```
+pub async fn rollback(
+    runtime: &dyn ContainerRuntime,
+    endpoints: &dyn EndpointPublisher,
+    history: &DeploymentHistory,
+    app: &str,
+    deployment_id: Option<u64>,
+    options: &RedeployOptions,
+) -> Result<Redeployment, String> {
+    let target = match deployment_id {
+        Some(id) => history
+            .get(id)
+            .filter(|r| r.app == app)
+            .ok_or_else(|| format!("{} has no deployment {}", app, id))?,
+        None => history
+            .previous(app)
+            .ok_or_else(|| format!("{} has no previous deployment to roll back to", app))?,
+    };
+    let image_id = match (&target.image_id, target.succeeded()) {
+        (Some(image), true) => image.clone(),
+        _ => {
+            return Err(format!(
+                "Deployment {} of {} never served, so it can't be rolled back to",
+                target.id, app
+            ))
+        }
+    };
+    if history.current(app).and_then(|c| c.image_id) == Some(image_id.clone()) {
+        return Err(format!(
+            "{} is already running deployment {}'s image",
+            app, target.id
+        ));
+    }
+    if !runtime.image_exists(&image_id).await? {
+        return Err(format!(
+            "Image {} of deployment {} no longer exists",
+            image_id, target.id
+        ));
+    }
+    // Containers are named after their image, so the old stopped container has to go first
+    if let Err(e) = runtime.remove_container(&image_id).await {
+        info!("Not removing previous container {}: {}", image_id, e);
+    }
+
+    info!(
+        "Rolling {} back to deployment {} ({})",
+        app, target.id, target.version
+    );
+    let build = DockerImageBuildResult {
+        log: vec![],
+        image_id: image_id.clone(),
+        test: None,
+    };
+    let switched = switch_to(runtime, endpoints, app, &image_id, options).await;
+    let record = DeploymentRecord {
+        env_hash: target.env_hash.clone(),
+        rollback_of: Some(target.id),
+        ..DeploymentRecord::new(
+            app,
+            &target.version,
+            &Default::default(),
+            DeploymentOutcome::Succeeded,
+        )
+    };
+    finish_switch(history, build, switched, record)
+}
+
+/// The result of `switch_to`: the new container, its port and the containers it replaced
+type Switch = Result<(String, i64, Vec<String>), (Option<String>, DeploymentOutcome, String)>;
+
+/// Starts an image published on a spare host port, waits for it to be ready, then points the app's endpoint at it and stops the previous containers
+///
+/// On failure this returns the container which was started (and since stopped), the outcome to record and the error.
+async fn switch_to(
+    runtime: &dyn ContainerRuntime,
+    endpoints: &dyn EndpointPublisher,
+    app: &str,
+    image_id: &str,
+    options: &RedeployOptions,
+) -> Switch {
+    let failed = |e: String| (None, DeploymentOutcome::Failed(e.clone()), e);
+    let previous = runtime
+        .list_containers(&ContainerQuery::new().app(app))
+        .await
+        .map_err(failed)?;
+    let port = find_spare_port(runtime, &options.ports)
+        .await
+        .map_err(failed)?;
+    let container_port = container_port(runtime, image_id, port)
+        .await
+        .map_err(failed)?;
+    info!(
+        "Starting {} ({}) with port {} published on spare port {}",
+        app, image_id, container_port, port
+    );
+    let container_id = runtime
+        .start_mapped_container(image_id, image_id, container_port, port)
+        .await
+        .map_err(failed)?;
+
+    let ready = options
+        .probe
+        .wait_until_ready(runtime, port, options.ready_timeout, options.probe_interval)
+        .await;
+    if let Err(e) = ready.and_then(|_| endpoints.publish(app, port)) {
+        error!("Rolling back {} to the previous version: {}", app, e);
+        if let Err(stop) = runtime.stop_container(&container_id).await {
+            error!("Failed to stop new container {}: {}", container_id, stop);
+        }
+        return Err((
+            Some(container_id),
+            DeploymentOutcome::RolledBack(e.clone()),
+            format!("Redeploy of {} rolled back: {}", app, e),
+        ));
+    }
+
+    let mut replaced = vec![];
+    for old in previous.into_iter().filter(|c| c.id != container_id) {
+        match runtime.stop_container(&old.id).await {
+            Ok(()) => replaced.push(old.id),
+            // The new version is already serving, so a straggler is not worth failing over
+            Err(e) => warn!("Failed to stop previous container {}: {}", old.id, e),
+        }
+    }
+    info!(
+        "Switched {} to port {}, replacing {:?}",
+        app, port, replaced
+    );
+    Ok((container_id, port, replaced))
+}
+
+/// Records the outcome of `switch_to` in the history
+fn finish_switch(
+    history: &DeploymentHistory,
+    build: DockerImageBuildResult,
+    switched: Switch,
+    record: DeploymentRecord,
+) -> Result<Redeployment, String> {
+    let record = DeploymentRecord {
+        image_id: Some(build.image_id.clone()),
+        ..record
+    };
+    match switched {
+        Ok((container_id, port, replaced)) => {
+            let deployment_id = history.record(DeploymentRecord {
+                container_id: Some(container_id.clone()),
+                ports: vec![port],
+                ..record
+            });
+            Ok(Redeployment {
+                deployment: Deployment {
+                    build,
+                    container_id,
+                    deployment_id,
+                },
+                port,
+                replaced,
+            })
+        }
+        Err((container_id, outcome, e)) => {
+            history.record(DeploymentRecord {
+                container_id,
+                outcome,
+                ..record
+            });
+            Err(e)
+        }
+    }
+}
+
+/// Finds a port in `ports` which no container publishes and nothing on the runtime's host is listening on
+async fn find_spare_port(
+    runtime: &dyn ContainerRuntime,
+    ports: &Range<i64>,
+) -> Result<i64, String> {
+    let taken: Vec<i64> = runtime
+        .list_containers(&ContainerQuery::new().all())
+        .await?
+        .iter()
+        .flat_map(|c| c.public_ports())
+        .collect();
+    for port in ports.clone().filter(|p| !taken.contains(p)) {
+        if runtime.host_port_free(port).await {
+            return Ok(port);
+        }
+    }
+    Err(format!(
+        "No spare port in {:?} on {}",
+        ports,
+        runtime.published_host()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::build_queue::QueueOrdering;
+    use crate::docker::endpoints::EndpointTable;
+    use crate::docker::fake_runtime::{FakeRuntime, TempProject};
+    use std::sync::Arc;
+
+    /// Options which give up quickly, on a range of ports no other test uses
+    fn options(ports: Range<i64>) -> RedeployOptions {
+        RedeployOptions {
+            ready_timeout: Duration::from_millis(500),
+            probe_interval: Duration::from_millis(20),
+            ports,
+            ..Default::default()
+        }
+    }
+
+    fn queue(runtime: &Arc<FakeRuntime>) -> BuildQueue {
+        BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo)
+    }
+
+    async fn is_running(runtime: &FakeRuntime, container_id: &str) -> bool {
+        let containers = runtime
+            .list_containers(&ContainerQuery::new().all())
+            .await
+            .unwrap();
+        containers
+            .iter()
+            .any(|c| c.id == container_id && c.state.as_deref() == Some("running"))
+    }
+
+    #[tokio::test]
+    async fn deploy_starts_the_build() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let history = DeploymentHistory::new();
+        let source = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+
+        let deployment = deploy(&builds, &history, &source.path(), 21200)
+            .await
+            .unwrap();
+
+        assert!(is_running(&runtime, &deployment.container_id).await);
+        let current = history.current("scapegoat").unwrap();
+        assert_eq!(current.id, deployment.deployment_id);
+        assert_eq!(current.ports, vec![21200]);
+        assert_eq!(current.image_id, Some(deployment.build.image_id));
+    }
+
+    #[tokio::test]
+    async fn deploy_refuses_failing_tests() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let history = DeploymentHistory::new();
+        let source = TempProject::new("scapegoat", "1.0.0", "test = \"pytest\"");
+        runtime.fail_tests_of(&source.path());
+
+        assert!(deploy(&builds, &history, &source.path(), 21300)
+            .await
+            .is_err());
+
+        let record = &history.for_app("scapegoat")[0];
+        assert_eq!(
+            record.outcome,
+            DeploymentOutcome::TestsFailed { exit_code: 1 }
+        );
+        assert!(runtime
+            .list_containers(&ContainerQuery::new().all())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn deploy_records_a_failed_start() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let history = DeploymentHistory::new();
+        let source = TempProject::new("scapegoat", "1.0.0", "");
+        runtime.fail_starts_of(&source.path());
+
+        assert!(deploy(&builds, &history, &source.path(), 21400)
+            .await
+            .is_err());
+
+        let record = &history.for_app("scapegoat")[0];
+        assert!(matches!(record.outcome, DeploymentOutcome::Failed(_)));
+        assert!(record.image_id.is_some());
+        assert_eq!(record.container_id, None);
+    }
+
+    #[tokio::test]
+    async fn redeploy_publishes_the_port_the_app_listens_on() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let source = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+
+        let redeployment = redeploy(
+            &builds,
+            &endpoints,
+            &history,
+            &source.path(),
+            &options(21000..21100),
+        )
+        .await
+        .unwrap();
+
+        let containers = runtime
+            .list_containers(&ContainerQuery::new().app("scapegoat"))
+            .await
+            .unwrap();
+        assert_eq!(containers.len(), 1);
+        assert_eq!(containers[0].ports[0].private_port, 9000);
+        assert_eq!(containers[0].ports[0].public_port, Some(redeployment.port));
+        assert!((21000..21100).contains(&redeployment.port));
+    }
+
+    #[tokio::test]
+    async fn http_probe_fails_when_the_app_is_not_published() {
+        let runtime = FakeRuntime::new();
+        let image = runtime.add_image_listening_on("scapegoat", "1.0.0", 9000);
+        // Publishing the host port as the container port leaves the app unreachable, but the port still accepts connections
+        runtime
+            .start_mapped_container(&image, &image, 21100, 21100)
+            .await
+            .unwrap();
+
+        let timeout = Duration::from_millis(200);
+        assert!(runtime
+            .check_ready(&ReadinessProbe::Tcp, 21100, timeout)
+            .await
+            .is_ok());
+        assert!(runtime
+            .check_ready(&options(0..0).probe, 21100, timeout)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn redeploy_replaces_the_previous_version() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let options = options(21500..21600);
+        let v1 = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "port = 9000");
+
+        let first = redeploy(&builds, &endpoints, &history, &v1.path(), &options)
+            .await
+            .unwrap();
+        let second = redeploy(&builds, &endpoints, &history, &v2.path(), &options)
+            .await
+            .unwrap();
+
+        assert_eq!(second.replaced, vec![first.deployment.container_id.clone()]);
+        assert_ne!(first.port, second.port);
+        assert_eq!(endpoints.published_port("scapegoat"), Some(second.port));
+        assert!(!is_running(&runtime, &first.deployment.container_id).await);
+        assert!(is_running(&runtime, &second.deployment.container_id).await);
+        assert_eq!(history.current("scapegoat").unwrap().version, "2.0.0");
+    }
+
+    #[tokio::test]
+    async fn redeploy_keeps_the_previous_version_when_the_probe_fails() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let options = options(21600..21700);
+        let v1 = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "port = 9000");
+        runtime.fail_probes_of(&v2.path());
+
+        let first = redeploy(&builds, &endpoints, &history, &v1.path(), &options)
+            .await
+            .unwrap();
+        assert!(
+            redeploy(&builds, &endpoints, &history, &v2.path(), &options)
+                .await
+                .is_err()
+        );
+
+        let failed = &history.for_app("scapegoat")[0];
+        assert!(matches!(failed.outcome, DeploymentOutcome::RolledBack(_)));
+        assert!(!is_running(&runtime, failed.container_id.as_ref().unwrap()).await);
+        assert!(is_running(&runtime, &first.deployment.container_id).await);
+        assert_eq!(endpoints.published_port("scapegoat"), Some(first.port));
+        assert_eq!(history.current("scapegoat").unwrap().version, "1.0.0");
+    }
+
+    #[tokio::test]
+    async fn redeploy_keeps_the_previous_version_when_the_start_fails() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let options = options(21700..21800);
+        let v1 = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "port = 9000");
+        runtime.fail_starts_of(&v2.path());
+
+        let first = redeploy(&builds, &endpoints, &history, &v1.path(), &options)
+            .await
+            .unwrap();
+        assert!(
+            redeploy(&builds, &endpoints, &history, &v2.path(), &options)
+                .await
+                .is_err()
+        );
+
+        let failed = &history.for_app("scapegoat")[0];
+        assert!(matches!(failed.outcome, DeploymentOutcome::Failed(_)));
+        assert_eq!(failed.container_id, None);
+        assert!(is_running(&runtime, &first.deployment.container_id).await);
+        assert_eq!(endpoints.published_port("scapegoat"), Some(first.port));
+    }
+
+    #[tokio::test]
+    async fn rollback_returns_to_the_previous_image() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let options = options(21800..21900);
+        let v1 = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "port = 9000");
+
+        let first = redeploy(&builds, &endpoints, &history, &v1.path(), &options)
+            .await
+            .unwrap();
+        let second = redeploy(&builds, &endpoints, &history, &v2.path(), &options)
+            .await
+            .unwrap();
+        let rollback = rollback(&*runtime, &endpoints, &history, "scapegoat", None, &options)
+            .await
+            .unwrap();
+
+        let current = history.current("scapegoat").unwrap();
+        assert_eq!(current.image_id, Some(first.deployment.build.image_id));
+        assert_eq!(current.version, "1.0.0");
+        assert_eq!(current.rollback_of, Some(first.deployment.deployment_id));
+        assert_eq!(rollback.replaced, vec![second.deployment.container_id]);
+        assert_eq!(endpoints.published_port("scapegoat"), Some(rollback.port));
+    }
+
+    #[tokio::test]
+    async fn rollback_refuses_removed_images() {
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = queue(&runtime);
+        let endpoints = EndpointTable::new();
+        let history = DeploymentHistory::new();
+        let options = options(21900..22000);
+        let v1 = TempProject::new("scapegoat", "1.0.0", "");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "");
+
+        let first = redeploy(&builds, &endpoints, &history, &v1.path(), &options)
+            .await
+            .unwrap();
+        redeploy(&builds, &endpoints, &history, &v2.path(), &options)
+            .await
+            .unwrap();
+        let image = first.deployment.build.image_id;
+        runtime.remove_container(&image).await.unwrap();
+        runtime.remove_image(&image).await.unwrap();
+
+        let error = rollback(&*runtime, &endpoints, &history, "scapegoat", None, &options)
+            .await
+            .unwrap_err();
+        assert!(error.contains("no longer exists"));
+        assert_eq!(history.current("scapegoat").unwrap().version, "2.0.0");
+    }
+}
@@ -0,0 +1,630 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+use super::build_queue::BuildQueue;
+use super::cancellation::CancellationHandle;
+use super::deploy::build_and_test;
+use super::docker_container::{ContainerQuery, DockerContainer};
+use super::docker_image::ImageQuery;
+use super::runtime::ContainerRuntime;
+use super::{APP_LABEL, DESIRED_LABEL, VERSION_LABEL};
+
+/// The apps an agent should be running, as read from a desired-state file
+///
+/// # Examples
+///
+/// ```toml
+/// [[app]]
+/// name = "scapegoat"
+/// version = "1.0.0"
+/// source = "./tmp/scapegoat"
+/// replicas = 2
+/// ports = [9000, 9001]
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DesiredState {
+    /// Every app which should be running, apps the reconciler started which are no longer listed here are stopped
+    #[serde(rename = "app", default)]
+    pub apps: Vec<DesiredApp>,
+}
+
+/// An app which should be running
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesiredApp {
+    /// The application name from its `shipwreck.toml`
+    pub name: String,
+
+    /// The version which should be running, matching `app.version` in its `shipwreck.toml`
+    pub version: String,
+
+    /// The project folder to build the image from if it doesn't exist yet
+    pub source: String,
+
+    /// How many containers of this version should be running
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+
+    /// The host port of each replica, at least one per replica
+    pub ports: Vec<i64>,
+}
+
+fn default_replicas() -> usize {
+    1
+}
+
+impl DesiredState {
+    /// Parses a desired state from the contents of a desired-state file
+    pub fn parse(contents: &str) -> Result<DesiredState, String> {
+        let state: DesiredState =
+            toml::from_str(contents).map_err(|e| format!("Invalid desired state: {}", e))?;
+        for app in &state.apps {
+            if app.ports.len() < app.replicas {
+                return Err(format!(
+                    "{} wants {} replicas but only has {} ports",
+                    app.name,
+                    app.replicas,
+                    app.ports.len()
+                ));
+            }
+            if state.apps.iter().filter(|a| a.name == app.name).count() > 1 {
+                return Err(format!("{} is listed more than once", app.name));
+            }
+        }
+        Ok(state)
+    }
+
+    /// Reads a desired-state file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<DesiredState, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        DesiredState::parse(&contents)
+    }
+}
+
+/// A single step towards the desired state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReconcileAction {
+    /// Build the image for a version which has none
+    Build {
+        app: String,
+        version: String,
+        source: String,
+    },
+
+    /// Start a replica of a version on a port
+    Start {
+        app: String,
+        version: String,
+        port: i64,
+    },
+
+    /// Stop a container which isn't wanted
+    Stop {
+        app: String,
+        container_id: String,
+        reason: String,
+    },
+}
+
+/// The result of applying one action
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedAction {
+    /// The action which was applied
+    pub action: ReconcileAction,
+
+    /// Why the action failed, `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// The outcome of a single pass of the reconcile loop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconcileRun {
+    /// When the pass finished, as seconds since the epoch
+    pub finished_at: i64,
+
+    /// Every action the pass took, in order
+    pub actions: Vec<AppliedAction>,
+
+    /// Why the pass could not plan at all (e.g. an invalid desired-state file)
+    pub error: Option<String>,
+}
+
+impl ReconcileRun {
+    /// Whether the actual state matched the desired state when the pass started
+    pub fn converged(&self) -> bool {
+        self.error.is_none() && self.actions.is_empty()
+    }
+
+    /// Whether every planned action was applied
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none() && self.actions.iter().all(|a| a.error.is_none())
+    }
+}
+
+/// Converges the containers of a runtime on a desired-state file
+///
+/// Each pass re-reads the file, compares it with the containers the runtime reports, then
+/// builds missing images, stops unwanted containers and starts missing replicas, in that order.
+/// The reconciler only touches its own containers: it labels the images it builds with `DESIRED_LABEL`, which their containers inherit,
+/// so apps started by `deploy`, the webhook or a stack keep running whatever the desired-state file says.
+/// Stopping first frees the ports of old versions for the replicas which replace them.
+/// Passes never overlap, so a pass asked for (e.g. by `POST /reconcile`) waits for the loop's pass to finish.
+///
+/// # Examples
+///
+/// ```
+/// let builds = BuildQueue::new(Arc::new(docker), 2, QueueOrdering::Fifo);
+/// let reconciler = Reconciler::new(builds, "./desired.toml");
+/// println!("{:?}", reconciler.plan().await?);
+/// let cancel = CancellationHandle::new();
+/// reconciler.run(Duration::from_secs(30), &cancel).await;
+/// ```
+pub struct Reconciler {
+    runtime: Arc<dyn ContainerRuntime>,
+    builds: BuildQueue,
+    desired_path: PathBuf,
+    last_run: Mutex<Option<ReconcileRun>>,
+    /// Held for the whole of a pass
+    pass: tokio::sync::Mutex<()>,
+}
+
+impl Reconciler {
+    /// Creates a reconciler
+    ///
+    /// # Arguments
+    ///
+    /// * `builds` - The queue missing images are built with, whose runtime's containers are converged
+    /// * `desired_path` - The desired-state file, which is re-read on every pass
+    pub fn new<P: AsRef<Path>>(builds: BuildQueue, desired_path: P) -> Reconciler {
+        Reconciler {
+            runtime: builds.runtime().clone(),
+            builds,
+            desired_path: desired_path.as_ref().to_path_buf(),
+            last_run: Mutex::new(None),
+            pass: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Gets the outcome of the most recent pass, `None` before the first one
+    pub fn last_run(&self) -> Option<ReconcileRun> {
+        self.last_run.lock().unwrap().clone()
+    }
+
+    /// Works out what a pass would do, without doing it
+    pub async fn plan(&self) -> Result<Vec<ReconcileAction>, String> {
+        let desired = DesiredState::from_file(&self.desired_path)?;
+        let running = self
+            .runtime
+            .list_containers(
+                &ContainerQuery::new()
+                    .managed()
+                    .label(&format!("{}=true", DESIRED_LABEL)),
+            )
+            .await?;
+        let mut actions = vec![];
+
+        for app in &desired.apps {
+            let images = self
+                .runtime
+                .list_images(&desired_images(&app.name, &app.version))
+                .await?;
+            if images.is_empty() {
+                actions.push(ReconcileAction::Build {
+                    app: app.name.clone(),
+                    version: app.version.clone(),
+                    source: app.source.clone(),
+                });
+            }
+
+            let (current, outdated): (Vec<&DockerContainer>, Vec<&DockerContainer>) = running
+                .iter()
+                .filter(|c| c.labels.get(APP_LABEL) == Some(&app.name))
+                .partition(|c| c.labels.get(VERSION_LABEL) == Some(&app.version));
+            for c in outdated {
+                actions.push(stop(c, &app.name, format!("not version {}", app.version)));
+            }
+
+            let wanted = &app.ports[..app.replicas];
+            let mut kept = vec![];
+            for c in current {
+                match c.public_ports().into_iter().find(|p| wanted.contains(p)) {
+                    Some(port) if !kept.contains(&port) => kept.push(port),
+                    _ => actions.push(stop(c, &app.name, String::from("extra replica"))),
+                }
+            }
+            for port in wanted.iter().filter(|p| !kept.contains(p)) {
+                actions.push(ReconcileAction::Start {
+                    app: app.name.clone(),
+                    version: app.version.clone(),
+                    port: *port,
+                });
+            }
+        }
+
+        for c in &running {
+            if let Some(app) = c.labels.get(APP_LABEL) {
+                if !desired.apps.iter().any(|a| &a.name == app) {
+                    actions.push(stop(c, app, String::from("not in the desired state")));
+                }
+            }
+        }
+
+        // Builds first, then stops to free ports, then starts
+        actions.sort_by_key(|a| match a {
+            ReconcileAction::Build { .. } => 0,
+            ReconcileAction::Stop { .. } => 1,
+            ReconcileAction::Start { .. } => 2,
+        });
+        Ok(actions)
+    }
+
+    /// The desired-state file being converged on
+    pub fn desired_path(&self) -> &Path {
+        &self.desired_path
+    }
+
+    /// Runs a single pass, converging the runtime on the desired state
+    pub async fn reconcile_once(&self) -> ReconcileRun {
+        let _pass = self.pass.lock().await;
+        let run = match self.plan().await {
+            Ok(actions) => ReconcileRun {
+                finished_at: 0,
+                actions: self.apply(actions).await,
+                error: None,
+            },
+            Err(e) => {
+                error!("Failed to plan reconcile: {}", e);
+                ReconcileRun {
+                    finished_at: 0,
+                    actions: vec![],
+                    error: Some(e),
+                }
+            }
+        };
+        let run = ReconcileRun {
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0),
+            ..run
+        };
+        if !run.converged() {
+            info!(
+                "Reconcile applied {} actions, succeeded: {}",
+                run.actions.len(),
+                run.succeeded()
+            );
+        }
+        *self.last_run.lock().unwrap() = Some(run.clone());
+        run
+    }
+
+    /// Runs passes every `interval` until `cancel` is triggered
+    pub async fn run(&self, interval: Duration, cancel: &CancellationHandle) {
+        while !cancel.is_cancelled() {
+            self.reconcile_once().await;
+            tokio::select! {
+                _ = time::delay_for(interval) => {}
+                _ = cancel.cancelled() => {}
+            }
+        }
+        info!("Reconcile loop for {} stopped", self.desired_path.display());
+    }
+
+    async fn apply(&self, actions: Vec<ReconcileAction>) -> Vec<AppliedAction> {
+        let mut built: HashMap<(String, String), String> = HashMap::new();
+        let mut failed_builds = vec![];
+        let mut applied = vec![];
+        for action in actions {
+            let result = match &action {
+                ReconcileAction::Build {
+                    app,
+                    version,
+                    source,
+                } => match self.build(app, version, source).await {
+                    Ok(image) => {
+                        built.insert((app.clone(), version.clone()), image);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        failed_builds.push(app.clone());
+                        Err(e)
+                    }
+                },
+                // The old version keeps serving if the new one couldn't be built
+                ReconcileAction::Stop { app, .. } if failed_builds.contains(app) => Err(format!(
+                    "Not stopping {} as its new version failed to build",
+                    app
+                )),
+                ReconcileAction::Stop { container_id, .. } => {
+                    self.runtime.stop_container(container_id).await
+                }
+                ReconcileAction::Start { app, .. } if failed_builds.contains(app) => {
+                    Err(format!("No image to start for {}", app))
+                }
+                ReconcileAction::Start { app, version, port } => {
+                    let image = match built.get(&(app.clone(), version.clone())) {
+                        Some(image) => Ok(image.clone()),
+                        None => self.find_image(app, version).await,
+                    };
+                    match image {
+                        Ok(image) => self.start_replica(&image, app, version, *port).await,
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            if let Err(e) = &result {
+                warn!("Reconcile action {:?} failed: {}", action, e);
+            }
+            applied.push(AppliedAction {
+                action,
+                error: result.err(),
+            });
+        }
+        applied
+    }
+
+    /// Builds and tests an image, checking its manifest declares the wanted version
+    async fn build(&self, app: &str, version: &str, source: &str) -> Result<String, String> {
+        let mut labels = HashMap::new();
+        labels.insert(String::from(DESIRED_LABEL), String::from("true"));
+        let build = build_and_test(&self.builds, source, &labels).await?;
+        if let Some(test) = build.test.as_ref().filter(|t| !t.passed()) {
+            return Err(format!(
+                "Tests of {} {} exited with {}",
+                app, version, test.exit_code
+            ));
+        }
+        let image = self
+            .runtime
+            .list_images(&desired_images(app, version))
+            .await?;
+        if image.is_empty() {
+            return Err(format!(
+                "{} did not build version {} of {}, check its shipwreck.toml",
+                source, version, app
+            ));
+        }
+        Ok(build.image_id)
+    }
+
+    /// Finds the image the reconciler built of a version, preferring its tag over its id
+    async fn find_image(&self, app: &str, version: &str) -> Result<String, String> {
+        let images = self
+            .runtime
+            .list_images(&desired_images(app, version))
+            .await?;
+        images
+            .into_iter()
+            .max_by_key(|i| i.created)
+            .map(|i| i.repo_tags.into_iter().next().unwrap_or(i.id))
+            .ok_or_else(|| format!("No image for {} {}", app, version))
+    }
+
+    /// Starts a replica named after its app, version and port, publishing the port its app listens on as `port`
+    async fn start_replica(
+        &self,
+        image: &str,
+        app: &str,
+        version: &str,
+        port: i64,
+    ) -> Result<(), String> {
+        let name = format!("{}-{}-{}", app, version, port);
+        // A stopped replica from an earlier pass would hold the name
+        if self.runtime.remove_container(&name).await.is_ok() {
+            info!("Removed stopped replica {}", name);
+        }
+        let container_port = self.runtime.container_port(image).await?.unwrap_or(port);
+        self.runtime
+            .start_mapped_container(image, &name, container_port, port)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// The images the reconciler built of a version
+fn desired_images(app: &str, version: &str) -> ImageQuery {
+    ImageQuery::new()
+        .app(app)
+        .version(version)
+        .label(&format!("{}=true", DESIRED_LABEL))
+}
+
+fn stop(container: &DockerContainer, app: &str, reason: String) -> ReconcileAction {
+    ReconcileAction::Stop {
+        app: String::from(app),
+        container_id: container.id.clone(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::build_queue::QueueOrdering;
+    use crate::docker::deploy::deploy;
+    use crate::docker::fake_runtime::{FakeRuntime, TempProject};
+    use crate::docker::history::DeploymentHistory;
+
+    /// A desired state of scapegoat at a `(version, source)`, or of no apps at all
+    fn desired(app: Option<(&str, &str)>, replicas: usize, ports: &[i64]) -> String {
+        match app {
+            Some((version, source)) => format!(
+                "[[app]]\nname = \"scapegoat\"\nversion = \"{}\"\nsource = {:?}\nreplicas = {}\nports = {:?}\n",
+                version, source, replicas, ports
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// A reconciler of a fresh fake runtime, and the folder holding the `desired.toml` it reads
+    fn reconciler(state: &str) -> (Arc<FakeRuntime>, Reconciler, TempProject) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let folder = TempProject::with_files(&[("desired.toml", state)]);
+        let builds = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo);
+        let reconciler = Reconciler::new(builds, Path::new(&folder.path()).join("desired.toml"));
+        (runtime, reconciler, folder)
+    }
+
+    /// Replaces the desired state a reconciler reads
+    fn rewrite(folder: &TempProject, state: &str) {
+        fs::write(Path::new(&folder.path()).join("desired.toml"), state).unwrap();
+    }
+
+    async fn running(runtime: &FakeRuntime) -> Vec<DockerContainer> {
+        runtime
+            .list_containers(&ContainerQuery::new().managed())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_pass_builds_and_starts_the_desired_replicas() {
+        let v1 = TempProject::new("scapegoat", "1.0.0", "port = 9000");
+        let (runtime, reconciler, _desired_file) =
+            reconciler(&desired(Some(("1.0.0", &v1.path())), 2, &[22500, 22501]));
+
+        let run = reconciler.reconcile_once().await;
+
+        assert!(run.succeeded(), "{:?}", run);
+        let actions: Vec<_> = run.actions.into_iter().map(|a| a.action).collect();
+        assert!(matches!(actions[0], ReconcileAction::Build { .. }));
+        assert!(matches!(
+            actions[1],
+            ReconcileAction::Start { port: 22500, .. }
+        ));
+        assert!(matches!(
+            actions[2],
+            ReconcileAction::Start { port: 22501, .. }
+        ));
+        let mut ports: Vec<(i64, Option<i64>)> = running(&runtime)
+            .await
+            .iter()
+            .map(|c| (c.ports[0].private_port, c.ports[0].public_port))
+            .collect();
+        ports.sort();
+        assert_eq!(ports, vec![(9000, Some(22500)), (9000, Some(22501))]);
+        assert!(reconciler.reconcile_once().await.converged());
+        assert!(reconciler.last_run().unwrap().converged());
+    }
+
+    #[tokio::test]
+    async fn a_new_version_replaces_the_old_one() {
+        let v1 = TempProject::new("scapegoat", "1.0.0", "");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "");
+        let (runtime, reconciler, desired_file) =
+            reconciler(&desired(Some(("1.0.0", &v1.path())), 1, &[22510]));
+        assert!(reconciler.reconcile_once().await.succeeded());
+
+        rewrite(
+            &desired_file,
+            &desired(Some(("2.0.0", &v2.path())), 1, &[22510]),
+        );
+        let run = reconciler.reconcile_once().await;
+
+        assert!(run.succeeded(), "{:?}", run);
+        let containers = running(&runtime).await;
+        assert_eq!(containers.len(), 1);
+        assert_eq!(containers[0].labels[VERSION_LABEL], "2.0.0");
+        assert_eq!(containers[0].public_ports(), vec![22510]);
+    }
+
+    #[tokio::test]
+    async fn the_old_version_keeps_serving_when_the_new_one_fails_to_build() {
+        let v1 = TempProject::new("scapegoat", "1.0.0", "");
+        let v2 = TempProject::new("scapegoat", "2.0.0", "");
+        let (runtime, reconciler, desired_file) =
+            reconciler(&desired(Some(("1.0.0", &v1.path())), 1, &[22520]));
+        assert!(reconciler.reconcile_once().await.succeeded());
+        runtime.fail_builds_of(&v2.path());
+
+        rewrite(
+            &desired_file,
+            &desired(Some(("2.0.0", &v2.path())), 1, &[22520]),
+        );
+        let run = reconciler.reconcile_once().await;
+
+        assert!(!run.succeeded());
+        let containers = running(&runtime).await;
+        assert_eq!(containers.len(), 1);
+        assert_eq!(containers[0].labels[VERSION_LABEL], "1.0.0");
+    }
+
+    #[tokio::test]
+    async fn apps_and_replicas_which_are_not_desired_are_stopped() {
+        let v1 = TempProject::new("scapegoat", "1.0.0", "");
+        let (runtime, reconciler, desired_file) =
+            reconciler(&desired(Some(("1.0.0", &v1.path())), 2, &[22530, 22531]));
+        assert!(reconciler.reconcile_once().await.succeeded());
+
+        rewrite(
+            &desired_file,
+            &desired(Some(("1.0.0", &v1.path())), 1, &[22530, 22531]),
+        );
+        let run = reconciler.reconcile_once().await;
+        assert_eq!(run.actions.len(), 1);
+        assert_eq!(running(&runtime).await[0].public_ports(), vec![22530]);
+
+        rewrite(&desired_file, &desired(None, 0, &[]));
+        assert!(reconciler.reconcile_once().await.succeeded());
+        assert!(running(&runtime).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn containers_the_reconciler_did_not_start_are_left_running() {
+        let v1 = TempProject::new("scapegoat", "1.0.0", "");
+        let other = TempProject::new("kraken", "1.0.0", "");
+        let (runtime, reconciler, desired_file) =
+            reconciler(&desired(Some(("1.0.0", &v1.path())), 1, &[22550]));
+        let builds = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo);
+        let history = DeploymentHistory::new();
+        deploy(&builds, &history, &v1.path(), 22551).await.unwrap();
+        deploy(&builds, &history, &other.path(), 22552)
+            .await
+            .unwrap();
+
+        let run = reconciler.reconcile_once().await;
+
+        assert!(run.succeeded(), "{:?}", run);
+        let mut ports: Vec<i64> = running(&runtime)
+            .await
+            .iter()
+            .flat_map(|c| c.public_ports())
+            .collect();
+        ports.sort();
+        assert_eq!(ports, vec![22550, 22551, 22552]);
+
+        rewrite(&desired_file, &desired(None, 0, &[]));
+        assert!(reconciler.reconcile_once().await.succeeded());
+        let mut ports: Vec<i64> = running(&runtime)
+            .await
+            .iter()
+            .flat_map(|c| c.public_ports())
+            .collect();
+        ports.sort();
+        assert_eq!(ports, vec![22551, 22552]);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_desired_state_is_reported_without_acting() {
+        let (_, reconciler, _desired_file) =
+            reconciler(&desired(Some(("1.0.0", "./x")), 2, &[22540]));
+
+        let run = reconciler.reconcile_once().await;
+
+        assert!(run.actions.is_empty());
+        assert_eq!(
+            run.error,
+            Some(String::from(
+                "scapegoat wants 2 replicas but only has 1 ports"
+            ))
+        );
+    }
+}
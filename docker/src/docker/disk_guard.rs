@@ -0,0 +1,306 @@
+use bollard::container::PruneContainersOptions;
+use bollard::image::PruneImagesOptions;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use super::connection::DockerHost;
+use super::timeouts::with_timeout;
+use super::{DockerBroker, MANAGED_LABEL};
+
+/// Where docker keeps its data unless the daemon says otherwise
+pub const DEFAULT_DATA_ROOT: &str = "/var/lib/docker";
+
+/// How much free disk a build needs before it starts, and what to do about it when there isn't enough
+///
+/// # Examples
+///
+/// ```
+/// let docker = DockerBroker::new().with_disk_guard(DiskGuard {
+///     min_free_bytes: 5 * 1024 * 1024 * 1024,
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct DiskGuard {
+    /// The least free space on the docker data root a build may start with, in bytes
+    pub min_free_bytes: u64,
+
+    /// How old Kraken's stopped containers and unused images must be for the guard's prune to remove them, as a docker duration (e.g. `10m`)
+    ///
+    /// This keeps images which were just built for a deployment that hasn't started them yet.
+    pub prune_until: String,
+
+    /// The docker data root to check, if the daemon can't be asked for it (see `DaemonInfo::data_root`)
+    pub data_root: Option<PathBuf>,
+}
+
+impl Default for DiskGuard {
+    fn default() -> DiskGuard {
+        DiskGuard {
+            min_free_bytes: 2 * 1024 * 1024 * 1024,
+            prune_until: String::from("10m"),
+            data_root: None,
+        }
+    }
+}
+
+impl DiskGuard {
+    /// Builds a guard from the environment, returning `None` if it is turned off
+    ///
+    /// * `KRAKEN_MIN_FREE_DISK` - The least free space, in bytes or with a `K`, `M` or `G` suffix (e.g. `500M`), defaults to `2G`. `0` turns the guard off
+    /// * `KRAKEN_DOCKER_DATA_ROOT` - The docker data root, if the daemon can't be asked for it
+    pub fn from_env() -> Result<Option<DiskGuard>, String> {
+        DiskGuard::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Builds a guard like `from_env`, reading each variable through `lookup`
+    fn from_vars<F: Fn(&str) -> Option<String>>(lookup: F) -> Result<Option<DiskGuard>, String> {
+        let var = |name: &str| lookup(name).filter(|v| !v.is_empty());
+        let mut guard = DiskGuard::default();
+        if let Some(min_free) = var("KRAKEN_MIN_FREE_DISK") {
+            guard.min_free_bytes = parse_size(&min_free).ok_or_else(|| {
+                format!(
+                    "KRAKEN_MIN_FREE_DISK must be a size such as 500M or 2G, got {}",
+                    min_free
+                )
+            })?;
+        }
+        if guard.min_free_bytes == 0 {
+            return Ok(None);
+        }
+        guard.data_root = var("KRAKEN_DOCKER_DATA_ROOT").map(PathBuf::from);
+        Ok(Some(guard))
+    }
+}
+
+impl DockerBroker {
+    /// Makes sure there is enough free disk for a build, pruning Kraken's unused images and containers if there isn't
+    ///
+    /// Does nothing without a `DiskGuard` (see `DockerBroker::with_disk_guard`).
+    /// Free space can only be measured when the daemon runs on this host (a unix socket), so for remote daemons the check is skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new().with_disk_guard(DiskGuard::default());
+    /// docker.ensure_disk_space().await?; // Err if the disk is still too full after pruning
+    /// ```
+    pub async fn ensure_disk_space(&self) -> Result<(), String> {
+        let guard = match &self.disk_guard {
+            Some(guard) => guard,
+            None => return Ok(()),
+        };
+        if !matches!(self.host, DockerHost::Unix(_)) {
+            warn!("Can't check the free disk of a remote docker daemon, building anyway");
+            return Ok(());
+        }
+        let data_root = match self.daemon_info().await.ok().and_then(|i| i.data_root) {
+            Some(root) => PathBuf::from(root),
+            None => guard
+                .data_root
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_ROOT)),
+        };
+        let free = match free_bytes(&data_root) {
+            Ok(free) => free,
+            Err(e) => {
+                warn!("{}, building anyway", e);
+                return Ok(());
+            }
+        };
+        if free >= guard.min_free_bytes {
+            return Ok(());
+        }
+
+        warn!(
+            "Only {} bytes free on {}, below the {} needed to build, pruning",
+            free,
+            data_root.display(),
+            guard.min_free_bytes
+        );
+        self.prune_managed(&guard.prune_until).await?;
+        let free = free_bytes(&data_root)?;
+        if free < guard.min_free_bytes {
+            return Err(format!(
+                "Not enough disk to build: {} bytes free on {} after pruning, {} needed",
+                free,
+                data_root.display(),
+                guard.min_free_bytes
+            ));
+        }
+        info!("Pruning freed enough disk, {} bytes free", free);
+        Ok(())
+    }
+
+    /// Removes stopped containers and unused images which Kraken created, leaving everything else on the host alone
+    ///
+    /// Returns the bytes reclaimed.
+    ///
+    /// # Arguments
+    ///
+    /// * `until` - Only containers and images older than this are removed, as a docker duration (e.g. `10m`)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let reclaimed = docker.prune_managed("1h").await?;
+    /// ```
+    pub async fn prune_managed(&self, until: &str) -> Result<u64, String> {
+        let label = format!("{}=true", MANAGED_LABEL);
+        let mut filters = HashMap::new();
+        filters.insert("until", vec![until]);
+        filters.insert("label", vec![label.as_str()]);
+
+        let containers = with_timeout("prune containers", self.timeouts.prune, async {
+            self.conn
+                .prune_containers(Some(PruneContainersOptions {
+                    filters: filters.clone(),
+                }))
+                .await
+                .map_err(|e| format!("Failed to prune containers: {:?}", e))
+        })
+        .await?;
+
+        // Without `dangling=false` only untagged images would go
+        filters.insert("dangling", vec!["false"]);
+        let images = with_timeout("prune images", self.timeouts.prune, async {
+            self.conn
+                .prune_images(Some(PruneImagesOptions { filters }))
+                .await
+                .map_err(|e| format!("Failed to prune images: {:?}", e))
+        })
+        .await?;
+
+        let reclaimed =
+            containers.space_reclaimed.unwrap_or(0).max(0) as u64 + images.space_reclaimed;
+        info!(
+            "Pruned {} containers and {} images created by Kraken, reclaimed {} bytes",
+            containers.containers_deleted.unwrap_or_default().len(),
+            images.images_deleted.unwrap_or_default().len(),
+            reclaimed
+        );
+        Ok(reclaimed)
+    }
+}
+
+/// The bytes free to unprivileged users on the filesystem holding `path`
+fn free_bytes(path: &Path) -> Result<u64, String> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path {}", path.display()))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // Safe as `c_path` is a valid C string and `stats` is a valid buffer for the call to fill
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(format!(
+            "Failed to read the free disk of {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Parses a size such as `2G`, `500M`, `64K` or `1048576`, in powers of 1024
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1024),
+        'M' => (&size[..size.len() - 1], 1024 * 1024),
+        'G' => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::connection::ConnectionConfig;
+    use crate::docker::timeouts::OperationTimeouts;
+
+    /// A broker for a daemon which is never contacted
+    fn broker(host: DockerHost) -> DockerBroker {
+        let config = ConnectionConfig {
+            host: host.clone(),
+            ..Default::default()
+        };
+        DockerBroker {
+            conn: config.client().unwrap(),
+            timeouts: OperationTimeouts::default(),
+            host,
+            disk_guard: None,
+        }
+    }
+
+    fn vars<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| String::from(*v))
+        }
+    }
+
+    #[test]
+    fn sizes_take_an_optional_suffix_in_either_case() {
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_size("500m"), Some(500 * 1024 * 1024));
+        assert_eq!(parse_size(" 2G "), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("0"), Some(0));
+    }
+
+    #[test]
+    fn invalid_and_overflowing_sizes_are_refused() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("2T"), None);
+        assert_eq!(parse_size("-1G"), None);
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size("18446744073709551615G"), None);
+    }
+
+    #[test]
+    fn the_guard_is_read_from_the_environment() {
+        let guard = DiskGuard::from_vars(vars(&[])).unwrap().unwrap();
+        assert_eq!(guard.min_free_bytes, DiskGuard::default().min_free_bytes);
+        assert_eq!(guard.data_root, None);
+
+        let guard = DiskGuard::from_vars(vars(&[
+            ("KRAKEN_MIN_FREE_DISK", "500M"),
+            ("KRAKEN_DOCKER_DATA_ROOT", "/srv/docker"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(guard.min_free_bytes, 500 * 1024 * 1024);
+        assert_eq!(guard.data_root, Some(PathBuf::from("/srv/docker")));
+    }
+
+    #[test]
+    fn zero_turns_the_guard_off_and_invalid_sizes_are_errors() {
+        assert!(DiskGuard::from_vars(vars(&[("KRAKEN_MIN_FREE_DISK", "0")]))
+            .unwrap()
+            .is_none());
+        let error = DiskGuard::from_vars(vars(&[("KRAKEN_MIN_FREE_DISK", "lots")])).unwrap_err();
+        assert_eq!(
+            error,
+            "KRAKEN_MIN_FREE_DISK must be a size such as 500M or 2G, got lots"
+        );
+    }
+
+    #[tokio::test]
+    async fn builds_go_ahead_without_a_guard_or_on_a_remote_daemon() {
+        let full = DiskGuard {
+            min_free_bytes: u64::MAX,
+            ..Default::default()
+        };
+        let local = broker(DockerHost::Unix(PathBuf::from("/nonexistent/docker.sock")));
+        assert_eq!(local.ensure_disk_space().await, Ok(()));
+
+        let remote = broker(DockerHost::Tcp(String::from("127.0.0.1:1"))).with_disk_guard(full);
+        assert_eq!(remote.ensure_disk_space().await, Ok(()));
+    }
+}
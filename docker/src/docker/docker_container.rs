@@ -1,23 +1,43 @@
-use std::fmt;
+use bollard::models::{ContainerInspectResponse, ContainerSummaryInner, Mount, MountPoint, Port};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
 /// This is a reduction of bollard's ContainerSummaryInner
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DockerContainer {
     /// The ID of this container
     pub id: String,
 
-    /// The names that this container has been given
+    /// The primary name of this container, without docker's leading `/`
     pub name: String,
 
+    /// Every name this container has been given, without docker's leading `/`
+    pub names: Vec<String>,
+
     /// The name of the image used when creating this container
     pub image: Option<String>,
 
     /// The ID of the image that this container was created from
     pub image_id: Option<String>,
 
+    /// The command this container is running
+    pub command: Option<String>,
+
     /// When the container was created
     pub created: Option<i64>,
 
     /// The ports exposed by this container
-    pub ports: Option<Vec<i64>>,
+    pub ports: Vec<DockerPort>,
+
+    /// The labels applied to this container
+    pub labels: HashMap<String, String>,
+
+    /// The IP address of this container on each network it is attached to, keyed by network name
+    pub networks: HashMap<String, String>,
+
+    /// The volumes and bind mounts attached to this container
+    pub mounts: Vec<DockerMount>,
 
     /// The state of this container (e.g. `Exited`)
     pub state: Option<String>,
@@ -27,40 +47,780 @@ pub struct DockerContainer {
 }
 
 impl DockerContainer {
-    pub fn new(
-        id: String,
-        name: String,
-        image: Option<String>,
-        image_id: Option<String>,
-        created: Option<i64>,
-        ports: Option<Vec<i64>>,
-        state: Option<String>,
-        status: Option<String>,
-    ) -> DockerContainer {
+    /// Gets the host ports this container is published on
+    pub fn public_ports(&self) -> Vec<i64> {
+        self.ports.iter().filter_map(|p| p.public_port).collect()
+    }
+}
+
+impl From<ContainerSummaryInner> for DockerContainer {
+    fn from(c: ContainerSummaryInner) -> DockerContainer {
+        // Docker prefixes every name with a `/`
+        let names: Vec<String> = c
+            .names
+            .unwrap_or_default()
+            .iter()
+            .map(|n| String::from(n.trim_start_matches('/')))
+            .collect();
+        let networks = c
+            .network_settings
+            .and_then(|n| n.networks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, endpoint)| match endpoint.ip_address {
+                Some(ip) if !ip.is_empty() => Some((name, ip)),
+                _ => None,
+            })
+            .collect();
+        DockerContainer {
+            id: c.id.unwrap_or_default(),
+            name: names.first().cloned().unwrap_or_default(),
+            names,
+            image: c.image,
+            image_id: c.image_id,
+            command: c.command,
+            created: c.created,
+            ports: c
+                .ports
+                .unwrap_or_default()
+                .into_iter()
+                .map(DockerPort::from)
+                .collect(),
+            labels: c.labels.unwrap_or_default(),
+            networks,
+            mounts: c
+                .mounts
+                .unwrap_or_default()
+                .into_iter()
+                .map(DockerMount::from)
+                .collect(),
+            state: c.state,
+            status: c.status,
+        }
+    }
+}
+
+/// A port exposed by a container, and where it is published on the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DockerPort {
+    /// The port inside the container
+    pub private_port: i64,
+
+    /// The port on the host, if this port is published
+    pub public_port: Option<i64>,
+
+    /// The protocol of this port (e.g. `tcp`)
+    pub protocol: String,
+
+    /// The host address this port is published on (e.g. `0.0.0.0`)
+    pub ip: Option<String>,
+}
+
+impl From<Port> for DockerPort {
+    fn from(p: Port) -> DockerPort {
+        DockerPort {
+            private_port: p.private_port,
+            public_port: p.public_port,
+            protocol: p.typ.map(|t| t.to_string()).unwrap_or_default(),
+            ip: p.ip,
+        }
+    }
+}
+
+/// A volume or bind mount attached to a container
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerMount {
+    /// The kind of mount (e.g. `bind` or `volume`)
+    pub kind: String,
+
+    /// Where the mount comes from on the host, or the volume name
+    pub source: Option<String>,
+
+    /// Where the mount appears inside the container
+    pub target: Option<String>,
+
+    /// Whether the mount is read only
+    pub read_only: bool,
+}
+
+impl From<Mount> for DockerMount {
+    fn from(m: Mount) -> DockerMount {
+        DockerMount {
+            kind: m.typ.map(|t| t.to_string()).unwrap_or_default(),
+            source: m.source,
+            target: m.target,
+            read_only: m.read_only.unwrap_or(false),
+        }
+    }
+}
+
+impl From<MountPoint> for DockerMount {
+    fn from(m: MountPoint) -> DockerMount {
+        DockerMount {
+            kind: m.typ.unwrap_or_default(),
+            // Volumes are identified by name rather than by their path on the host
+            source: m.name.filter(|n| !n.is_empty()).or(m.source),
+            target: m.destination,
+            read_only: !m.rw.unwrap_or(true),
+        }
+    }
+}
+
+/// Words of environment variable names whose values should never be displayed
+const SECRET_ENV_WORDS: [&str; 9] = [
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "PASS",
+    "TOKEN",
+    "KEY",
+    "APIKEY",
+    "CREDENTIAL",
+    "AUTH",
+];
+
+/// Masks the value of a `KEY=value` environment variable if its name looks like a secret
+///
+/// The name is split into words on anything but letters and digits, and is a secret if any word (or its plural) is one of `SECRET_ENV_WORDS`.
+/// Matching whole words keeps names such as `AUTHOR` or `MONKEY` visible.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(mask_env("DB_PASSWORD=hunter2"), "DB_PASSWORD=******");
+/// assert_eq!(mask_env("PORT=9000"), "PORT=9000");
+/// ```
+pub fn mask_env(var: &str) -> String {
+    match var.find('=') {
+        Some(index) => {
+            let name = var[..index].to_uppercase();
+            let is_secret = |word: &str| {
+                SECRET_ENV_WORDS
+                    .iter()
+                    .any(|s| word == *s || word.strip_suffix('S') == Some(s))
+            };
+            if name
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .any(is_secret)
+            {
+                format!("{}=******", &var[..index])
+            } else {
+                String::from(var)
+            }
+        }
+        None => String::from(var),
+    }
+}
+
+/// The runtime state of a container, as reported by a container inspect
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DockerContainerState {
+    /// The status of this container (e.g. `running` or `exited`)
+    pub status: String,
+
+    /// Whether the container is running
+    pub running: bool,
+
+    /// Whether the container is paused
+    pub paused: bool,
+
+    /// Whether the container is restarting
+    pub restarting: bool,
+
+    /// Whether the container was killed for running out of memory
+    pub oom_killed: bool,
+
+    /// Whether the container is dead
+    pub dead: bool,
+
+    /// The process ID of the container, 0 if it is not running
+    pub pid: i64,
+
+    /// The exit code of the last run of the container
+    pub exit_code: i64,
+
+    /// The error which stopped the container, if any
+    pub error: Option<String>,
+
+    /// When the container was last started, as an RFC 3339 timestamp
+    pub started_at: Option<String>,
+
+    /// When the container last exited, as an RFC 3339 timestamp
+    pub finished_at: Option<String>,
+
+    /// The healthcheck status of the container, `None` if it has no healthcheck
+    pub health: Option<String>,
+
+    /// The number of consecutive failed healthchecks
+    pub failing_streak: i64,
+}
+
+/// This is a reduction of bollard's ContainerInspectResponse
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerContainerDetails {
+    /// The ID of this container
+    pub id: String,
+
+    /// The name of this container, without docker's leading `/`
+    pub name: String,
+
+    /// The ID of the image this container was created from
+    pub image_id: Option<String>,
+
+    /// The name of the image used when creating this container
+    pub image: Option<String>,
+
+    /// When the container was created, as an RFC 3339 timestamp
+    pub created: Option<String>,
+
+    /// The runtime state of the container
+    pub state: DockerContainerState,
+
+    /// The number of times docker has restarted the container
+    pub restart_count: i64,
+
+    /// The IP address of this container on each network it is attached to, keyed by network name
+    pub networks: HashMap<String, String>,
+
+    /// The volumes and bind mounts attached to this container
+    pub mounts: Vec<DockerMount>,
+
+    /// The environment of the container as `KEY=value` strings, with secret values masked
+    pub env: Vec<String>,
+
+    /// The resolved command the container runs, entrypoint and arguments included
+    pub command: Vec<String>,
+
+    /// The labels applied to this container
+    pub labels: HashMap<String, String>,
+}
+
+impl From<ContainerInspectResponse> for DockerContainerDetails {
+    fn from(c: ContainerInspectResponse) -> DockerContainerDetails {
+        let state = match c.state {
+            Some(s) => {
+                let health = s.health.unwrap_or_default();
+                DockerContainerState {
+                    status: s.status.map(|s| s.to_string()).unwrap_or_default(),
+                    running: s.running.unwrap_or(false),
+                    paused: s.paused.unwrap_or(false),
+                    restarting: s.restarting.unwrap_or(false),
+                    oom_killed: s.oom_killed.unwrap_or(false),
+                    dead: s.dead.unwrap_or(false),
+                    pid: s.pid.unwrap_or(0),
+                    exit_code: s.exit_code.unwrap_or(0),
+                    error: s.error.filter(|e| !e.is_empty()),
+                    started_at: s.started_at,
+                    finished_at: s.finished_at,
+                    health: health.status.map(|h| h.to_string()),
+                    failing_streak: health.failing_streak.unwrap_or(0),
+                }
+            }
+            None => DockerContainerState::default(),
+        };
+        let networks = c
+            .network_settings
+            .and_then(|n| n.networks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, endpoint)| match endpoint.ip_address {
+                Some(ip) if !ip.is_empty() => Some((name, ip)),
+                _ => None,
+            })
+            .collect();
+        let config = c.config.unwrap_or_default();
+        let mut command = vec![];
+        command.extend(c.path);
+        command.extend(c.args.unwrap_or_default());
+        DockerContainerDetails {
+            id: c.id.unwrap_or_default(),
+            name: String::from(c.name.unwrap_or_default().trim_start_matches('/')),
+            image_id: c.image,
+            image: config.image,
+            created: c.created,
+            state,
+            restart_count: c.restart_count.unwrap_or(0),
+            networks,
+            mounts: c
+                .mounts
+                .unwrap_or_default()
+                .into_iter()
+                .map(DockerMount::from)
+                .collect(),
+            env: config
+                .env
+                .unwrap_or_default()
+                .iter()
+                .map(|e| mask_env(e))
+                .collect(),
+            command,
+            labels: config.labels.unwrap_or_default(),
+        }
+    }
+}
+
+/// The order in which listed containers are returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreatedOrder {
+    /// Most recently created containers first
+    NewestFirst,
+
+    /// Least recently created containers first
+    OldestFirst,
+}
+
+/// Filters used when listing containers
+///
+/// By default only running containers are included, matching `docker ps`.
+///
+/// # Examples
+///
+/// ```
+/// // Which scapegoat containers have died, most recent first?
+/// let query = ContainerQuery::new()
+///     .app("scapegoat")
+///     .status("exited")
+///     .sort_by_created(CreatedOrder::NewestFirst);
+/// let containers = docker.list_containers(&query);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ContainerQuery {
+    /// Include stopped, exited and crashed containers, not just running ones
+    pub all: bool,
+
+    /// Only include containers with these labels, as `key` or `key=value`
+    pub labels: Vec<String>,
+
+    /// Only include containers in one of these states (e.g. `running`, `exited`, `dead`)
+    pub statuses: Vec<String>,
+
+    /// Only include containers created from this exact image name or id
+    pub image: Option<String>,
+
+    /// Only include containers created from this image or one of its descendants
+    pub ancestor: Option<String>,
+
+    /// Sort the results by creation time
+    pub order: Option<CreatedOrder>,
+}
+
+impl ContainerQuery {
+    pub fn new() -> ContainerQuery {
+        ContainerQuery::default()
+    }
+
+    /// Includes containers in every state, not just running ones
+    pub fn all(mut self) -> ContainerQuery {
+        self.all = true;
+        self
+    }
+
+    /// Only includes containers with a label, given as `key` or `key=value`
+    pub fn label(mut self, label: &str) -> ContainerQuery {
+        self.labels.push(String::from(label));
+        self
+    }
+
+    /// Only includes containers of an application, as named in its `shipwreck.toml`
+    pub fn app(self, app: &str) -> ContainerQuery {
+        self.label(&format!("{}={}", super::APP_LABEL, app))
+    }
+
+    /// Only includes containers created by this broker
+    pub fn managed(self) -> ContainerQuery {
+        self.label(super::MANAGED_LABEL)
+    }
+
+    /// Only includes containers in a state (e.g. `exited`)
+    ///
+    /// Filtering on any state other than `running` implies `all`.
+    pub fn status(mut self, status: &str) -> ContainerQuery {
+        if status != "running" {
+            self.all = true;
+        }
+        self.statuses.push(String::from(status));
+        self
+    }
+
+    /// Only includes containers created from this exact image name or id
+    pub fn image(mut self, image: &str) -> ContainerQuery {
+        self.image = Some(String::from(image));
+        self
+    }
+
+    /// Only includes containers created from an image or any image built on top of it
+    pub fn ancestor(mut self, image: &str) -> ContainerQuery {
+        self.ancestor = Some(String::from(image));
+        self
+    }
+
+    /// Sorts the results by creation time
+    pub fn sort_by_created(mut self, order: CreatedOrder) -> ContainerQuery {
+        self.order = Some(order);
+        self
+    }
+
+    /// Converts this query to the filters understood by the docker API
+    pub fn filters(&self) -> HashMap<String, Vec<String>> {
+        let mut filters = HashMap::new();
+        if !self.labels.is_empty() {
+            filters.insert(String::from("label"), self.labels.clone());
+        }
+        if !self.statuses.is_empty() {
+            filters.insert(String::from("status"), self.statuses.clone());
+        }
+        if let Some(a) = &self.ancestor {
+            filters.insert(String::from("ancestor"), vec![a.clone()]);
+        }
+        filters
+    }
+
+    /// Checks whether a container matches every filter of this query
+    ///
+    /// This is used by runtimes which have no docker daemon to filter for them.
+    /// `ancestor` is treated like `image`, as there is no image hierarchy to walk.
+    pub fn matches(&self, container: &DockerContainer) -> bool {
+        let state = container.state.clone().unwrap_or_default().to_lowercase();
+        if !self.all && state != "running" {
+            return false;
+        }
+        if !self.statuses.is_empty() && !self.statuses.contains(&state) {
+            return false;
+        }
+        let has_label = |label: &String| match label.find('=') {
+            Some(index) => {
+                container.labels.get(&label[..index]).map(|v| v.as_str())
+                    == Some(&label[index + 1..])
+            }
+            None => container.labels.contains_key(label),
+        };
+        if !self.labels.iter().all(has_label) {
+            return false;
+        }
+        let from_image = |image: &String| {
+            container.image.as_ref() == Some(image) || container.image_id.as_ref() == Some(image)
+        };
+        self.image.iter().all(from_image) && self.ancestor.iter().all(from_image)
+    }
+
+    /// Applies the parts of this query docker cannot, filtering and sorting containers in place
+    pub fn apply(&self, containers: &mut Vec<DockerContainer>) {
+        if let Some(image) = &self.image {
+            containers
+                .retain(|c| c.image.as_ref() == Some(image) || c.image_id.as_ref() == Some(image));
+        }
+        match self.order {
+            Some(CreatedOrder::NewestFirst) => containers.sort_by_key(|c| Reverse(c.created)),
+            Some(CreatedOrder::OldestFirst) => containers.sort_by_key(|c| c.created),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{
+        ContainerConfig, ContainerState, ContainerStateStatusEnum,
+        ContainerSummaryInnerNetworkSettings, EndpointSettings, Health, HealthStatusEnum,
+        MountTypeEnum, PortTypeEnum,
+    };
+
+    fn endpoint(ip: &str) -> EndpointSettings {
+        EndpointSettings {
+            ip_address: Some(String::from(ip)),
+            ..Default::default()
+        }
+    }
+
+    fn summary() -> ContainerSummaryInner {
+        let mut networks = HashMap::new();
+        networks.insert(String::from("bridge"), endpoint("172.17.0.2"));
+        networks.insert(String::from("none"), endpoint(""));
+        let mut labels = HashMap::new();
+        labels.insert(String::from("kraken.app"), String::from("scapegoat"));
+        ContainerSummaryInner {
+            id: Some(String::from("12345")),
+            names: Some(vec![String::from("/scapegoat"), String::from("/alias")]),
+            image: Some(String::from("scapegoat:1.0.0")),
+            image_id: Some(String::from("sha256:1")),
+            created: Some(1600000000),
+            ports: Some(vec![
+                Port {
+                    ip: Some(String::from("0.0.0.0")),
+                    private_port: 9000,
+                    public_port: Some(21000),
+                    typ: Some(PortTypeEnum::TCP),
+                },
+                Port {
+                    ip: None,
+                    private_port: 9001,
+                    public_port: None,
+                    typ: Some(PortTypeEnum::UDP),
+                },
+            ]),
+            labels: Some(labels),
+            state: Some(String::from("running")),
+            network_settings: Some(ContainerSummaryInnerNetworkSettings {
+                networks: Some(networks),
+            }),
+            mounts: Some(vec![Mount {
+                typ: Some(MountTypeEnum::BIND),
+                source: Some(String::from("/srv/data")),
+                target: Some(String::from("/data")),
+                read_only: Some(true),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    /// A container of an app in a state, created at `created`
+    fn container(app: &str, state: &str, created: i64) -> DockerContainer {
         DockerContainer {
-            id: id,
-            name: name,
-            image: image,
-            image_id: image_id,
-            created: created,
-            ports: ports,
-            state: state,
-            status: status,
-        }
-    }
-}
-
-impl fmt::Debug for DockerContainer {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("DockerContainer")
-            .field("id", &self.id)
-            .field("name", &self.name)
-            .field("image", &self.image)
-            .field("image_id", &self.image_id)
-            .field("created", &self.created)
-            .field("ports", &self.ports)
-            .field("state", &self.state)
-            .field("status", &self.status)
-            .finish()
+            labels: [
+                (String::from("kraken.managed"), String::from("true")),
+                (String::from("kraken.app"), String::from(app)),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            state: Some(String::from(state)),
+            created: Some(created),
+            image: Some(format!("{}:1.0.0", app)),
+            image_id: Some(format!("sha256:{}", app)),
+            ..DockerContainer::from(ContainerSummaryInner::default())
+        }
+    }
+
+    #[test]
+    fn listed_containers_keep_every_name_port_network_and_mount() {
+        let container = DockerContainer::from(summary());
+
+        assert_eq!(container.name, "scapegoat");
+        assert_eq!(container.names, vec!["scapegoat", "alias"]);
+        assert_eq!(
+            container.ports,
+            vec![
+                DockerPort {
+                    private_port: 9000,
+                    public_port: Some(21000),
+                    protocol: String::from("tcp"),
+                    ip: Some(String::from("0.0.0.0")),
+                },
+                DockerPort {
+                    private_port: 9001,
+                    public_port: None,
+                    protocol: String::from("udp"),
+                    ip: None,
+                },
+            ]
+        );
+        assert_eq!(container.public_ports(), vec![21000]);
+        assert_eq!(container.labels["kraken.app"], "scapegoat");
+        // Networks the container has no address on are left out
+        assert_eq!(container.networks.len(), 1);
+        assert_eq!(container.networks["bridge"], "172.17.0.2");
+        assert_eq!(container.mounts[0].kind, "bind");
+        assert_eq!(container.mounts[0].source.as_deref(), Some("/srv/data"));
+        assert!(container.mounts[0].read_only);
+    }
+
+    #[test]
+    fn containers_without_names_or_ports_convert() {
+        let container = DockerContainer::from(ContainerSummaryInner::default());
+
+        assert_eq!(container.name, "");
+        assert!(container.names.is_empty());
+        assert!(container.ports.is_empty());
+        assert!(container.networks.is_empty());
+    }
+
+    #[test]
+    fn containers_survive_a_json_round_trip() {
+        let container = DockerContainer::from(summary());
+
+        let json = serde_json::to_string(&container).unwrap();
+        let parsed: DockerContainer = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed.id, container.id);
+        assert_eq!(parsed.names, container.names);
+        assert_eq!(parsed.ports, container.ports);
+        assert_eq!(parsed.labels, container.labels);
+        assert_eq!(parsed.networks, container.networks);
+        assert_eq!(parsed.mounts[0].target, container.mounts[0].target);
+    }
+
+    #[test]
+    fn queries_only_match_running_containers_by_default() {
+        let running = container("scapegoat", "running", 1);
+        let exited = container("scapegoat", "exited", 2);
+
+        assert!(ContainerQuery::new().matches(&running));
+        assert!(!ContainerQuery::new().matches(&exited));
+        assert!(ContainerQuery::new().all().matches(&exited));
+        // Filtering on a stopped state includes stopped containers
+        assert!(ContainerQuery::new().status("exited").matches(&exited));
+        assert!(!ContainerQuery::new().status("exited").matches(&running));
+        assert!(!ContainerQuery::new().status("running").matches(&exited));
+    }
+
+    #[test]
+    fn queries_match_labels_apps_and_images() {
+        let scapegoat = container("scapegoat", "running", 1);
+
+        assert!(ContainerQuery::new()
+            .managed()
+            .app("scapegoat")
+            .matches(&scapegoat));
+        assert!(!ContainerQuery::new().app("kraken").matches(&scapegoat));
+        assert!(!ContainerQuery::new()
+            .label("kraken.desired")
+            .matches(&scapegoat));
+        assert!(ContainerQuery::new()
+            .image("scapegoat:1.0.0")
+            .matches(&scapegoat));
+        assert!(ContainerQuery::new()
+            .image("sha256:scapegoat")
+            .matches(&scapegoat));
+        assert!(!ContainerQuery::new()
+            .image("scapegoat:2.0.0")
+            .matches(&scapegoat));
+        assert!(ContainerQuery::new()
+            .ancestor("scapegoat:1.0.0")
+            .matches(&scapegoat));
+    }
+
+    #[test]
+    fn queries_become_docker_filters_and_sort_what_docker_cannot() {
+        let query = ContainerQuery::new()
+            .app("scapegoat")
+            .status("exited")
+            .image("scapegoat:1.0.0")
+            .sort_by_created(CreatedOrder::NewestFirst);
+        let filters = query.filters();
+        assert!(query.all);
+        assert_eq!(filters["label"], vec!["kraken.app=scapegoat"]);
+        assert_eq!(filters["status"], vec!["exited"]);
+        // docker has no exact image filter, so `apply` handles it
+        assert!(!filters.contains_key("image"));
+
+        let mut containers = vec![
+            container("scapegoat", "exited", 1),
+            container("kraken", "exited", 3),
+            container("scapegoat", "exited", 2),
+        ];
+        query.apply(&mut containers);
+        let created: Vec<_> = containers.iter().map(|c| c.created).collect();
+        assert_eq!(created, vec![Some(2), Some(1)]);
+
+        ContainerQuery::new()
+            .sort_by_created(CreatedOrder::OldestFirst)
+            .apply(&mut containers);
+        assert_eq!(containers[0].created, Some(1));
+    }
+
+    #[test]
+    fn secret_looking_env_vars_are_masked() {
+        for var in &[
+            "DB_PASSWORD=hunter2",
+            "db_password=hunter2",
+            "GITHUB_TOKEN=ghp_1",
+            "API_KEY=1",
+            "AWS_SECRET_ACCESS_KEY=1",
+            "STRIPE_APIKEY=1",
+            "SSH_KEYS=1",
+            "BASIC_AUTH=user:pass",
+            "SMTP_PASS=1",
+            "app.credentials=1",
+        ] {
+            let name = &var[..var.find('=').unwrap()];
+            assert_eq!(mask_env(var), format!("{}=******", name));
+        }
+    }
+
+    #[test]
+    fn other_env_vars_are_left_alone() {
+        for var in &[
+            "PORT=9000",
+            "AUTHOR=ethan",
+            "MONKEY=george",
+            "KEYBOARD=dvorak",
+            "PASSENGER_COUNT=3",
+            "TOKENIZER=bpe",
+            "NO_EQUALS_SIGN",
+        ] {
+            assert_eq!(mask_env(var), *var);
+        }
+        assert_eq!(mask_env("EMPTY_TOKEN="), "EMPTY_TOKEN=******");
+    }
+
+    #[test]
+    fn inspected_containers_keep_their_state_and_mask_their_env() {
+        let inspect = ContainerInspectResponse {
+            id: Some(String::from("12345")),
+            name: Some(String::from("/scapegoat")),
+            image: Some(String::from("sha256:1")),
+            path: Some(String::from("./scapegoat")),
+            args: Some(vec![String::from("--port"), String::from("9000")]),
+            restart_count: Some(2),
+            state: Some(ContainerState {
+                status: Some(ContainerStateStatusEnum::EXITED),
+                oom_killed: Some(true),
+                exit_code: Some(137),
+                error: Some(String::new()),
+                health: Some(Health {
+                    status: Some(HealthStatusEnum::UNHEALTHY),
+                    failing_streak: Some(3),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            config: Some(ContainerConfig {
+                image: Some(String::from("scapegoat:1.0.0")),
+                env: Some(vec![
+                    String::from("PORT=9000"),
+                    String::from("DB_PASSWORD=hunter2"),
+                ]),
+                ..Default::default()
+            }),
+            mounts: Some(vec![MountPoint {
+                typ: Some(String::from("volume")),
+                name: Some(String::from("scapegoat-data")),
+                source: Some(String::from("/var/lib/docker/volumes/scapegoat-data/_data")),
+                destination: Some(String::from("/data")),
+                rw: Some(false),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        let details = DockerContainerDetails::from(inspect);
+
+        assert_eq!(details.name, "scapegoat");
+        assert_eq!(details.image.as_deref(), Some("scapegoat:1.0.0"));
+        assert_eq!(details.command, vec!["./scapegoat", "--port", "9000"]);
+        assert_eq!(details.restart_count, 2);
+        assert_eq!(details.state.status, "exited");
+        assert!(!details.state.running);
+        assert!(details.state.oom_killed);
+        assert_eq!(details.state.exit_code, 137);
+        assert_eq!(details.state.error, None);
+        assert_eq!(details.state.health.as_deref(), Some("unhealthy"));
+        assert_eq!(details.state.failing_streak, 3);
+        assert_eq!(details.env, vec!["PORT=9000", "DB_PASSWORD=******"]);
+        // Volumes are named rather than given by their path on the host
+        assert_eq!(details.mounts[0].source.as_deref(), Some("scapegoat-data"));
+        assert!(details.mounts[0].read_only);
+    }
+
+    #[test]
+    fn containers_which_never_started_have_a_default_state() {
+        let details = DockerContainerDetails::from(ContainerInspectResponse::default());
+
+        assert_eq!(details.state.status, "");
+        assert!(!details.state.running);
+        assert_eq!(details.state.health, None);
+        assert!(details.command.is_empty());
     }
 }
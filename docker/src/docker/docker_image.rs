@@ -0,0 +1,445 @@
+use bollard::image::ImageHistory;
+use bollard::models::{Image, ImageSummary};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// This is a reduction of bollard's ImageSummary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerImage {
+    /// The ID of this image (e.g. `sha256:...`)
+    pub id: String,
+
+    /// The `repository:tag` references pointing at this image
+    pub repo_tags: Vec<String>,
+
+    /// The size of this image in bytes
+    pub size: i64,
+
+    /// When the image was created, as seconds since the epoch
+    pub created: i64,
+
+    /// The labels applied to this image
+    pub labels: HashMap<String, String>,
+
+    /// The number of containers using this image, or `-1` if docker did not compute it
+    pub containers: i64,
+}
+
+impl From<ImageSummary> for DockerImage {
+    fn from(i: ImageSummary) -> DockerImage {
+        DockerImage {
+            id: i.id,
+            // docker reports untagged images as `<none>:<none>`
+            repo_tags: i
+                .repo_tags
+                .into_iter()
+                .filter(|t| t != "<none>:<none>")
+                .collect(),
+            size: i.size,
+            created: i.created,
+            labels: i.labels,
+            containers: i.containers,
+        }
+    }
+}
+
+/// This is a reduction of bollard's Image, as returned by an image inspect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerImageDetails {
+    /// The ID of this image
+    pub id: String,
+
+    /// The `repository:tag` references pointing at this image
+    pub repo_tags: Vec<String>,
+
+    /// The `repository@digest` references pointing at this image
+    pub repo_digests: Vec<String>,
+
+    /// The ID of the parent image, empty if there is none
+    pub parent: String,
+
+    /// When the image was created, as an RFC 3339 timestamp
+    pub created: String,
+
+    /// The author of the image
+    pub author: String,
+
+    /// The architecture the image was built for (e.g. `amd64`)
+    pub architecture: String,
+
+    /// The operating system the image was built for (e.g. `linux`)
+    pub os: String,
+
+    /// The size of this image in bytes
+    pub size: i64,
+
+    /// The labels applied to this image
+    pub labels: HashMap<String, String>,
+
+    /// The environment baked into this image, as `KEY=value` strings
+    pub env: Vec<String>,
+
+    /// The default command run by containers of this image
+    pub cmd: Vec<String>,
+
+    /// The ports exposed by this image (e.g. `9000/tcp`)
+    pub exposed_ports: Vec<String>,
+
+    /// The storage driver holding this image's layers (e.g. `overlay2`)
+    pub storage_driver: String,
+
+    /// The number of filesystem layers in this image
+    pub layers: usize,
+}
+
+impl From<Image> for DockerImageDetails {
+    fn from(i: Image) -> DockerImageDetails {
+        let config = i.config.unwrap_or_default();
+        let mut exposed_ports: Vec<String> = config
+            .exposed_ports
+            .unwrap_or_default()
+            .into_keys()
+            .collect();
+        exposed_ports.sort();
+        DockerImageDetails {
+            id: i.id,
+            repo_tags: i.repo_tags.unwrap_or_default(),
+            repo_digests: i.repo_digests.unwrap_or_default(),
+            parent: i.parent,
+            created: i.created,
+            author: i.author,
+            architecture: i.architecture,
+            os: i.os,
+            size: i.size,
+            labels: config.labels.unwrap_or_default(),
+            env: config.env.unwrap_or_default(),
+            cmd: config.cmd.unwrap_or_default(),
+            exposed_ports,
+            storage_driver: i.graph_driver.name,
+            layers: i.root_fs.layers.map(|l| l.len()).unwrap_or(0),
+        }
+    }
+}
+
+/// A single layer in the history of an image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerImageLayer {
+    /// The ID of the image this layer produced, `<missing>` for layers pulled from elsewhere
+    pub id: String,
+
+    /// When the layer was created, as seconds since the epoch
+    pub created: i64,
+
+    /// The Dockerfile instruction which created this layer
+    pub created_by: String,
+
+    /// The tags pointing at this layer
+    pub tags: Vec<String>,
+
+    /// The size this layer adds to the image, in bytes
+    pub size: i64,
+
+    /// Any comment recorded with the layer
+    pub comment: String,
+}
+
+impl From<ImageHistory> for DockerImageLayer {
+    fn from(h: ImageHistory) -> DockerImageLayer {
+        DockerImageLayer {
+            id: h.id,
+            created: h.created.timestamp(),
+            created_by: h.created_by,
+            tags: h.tags.unwrap_or_default(),
+            size: h.size as i64,
+            comment: h.comment,
+        }
+    }
+}
+
+/// Filters used when listing images
+///
+/// # Examples
+///
+/// ```
+/// let query = ImageQuery::new().app("scapegoat").dangling(false);
+/// let images = docker.list_images(&query);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ImageQuery {
+    /// Include intermediate images, not just those from a final layer
+    pub all: bool,
+
+    /// Only include images with these labels, as `key` or `key=value`
+    pub labels: Vec<String>,
+
+    /// Only include images matching this `name[:tag]` reference
+    pub reference: Option<String>,
+
+    /// Only include (or exclude) untagged images
+    pub dangling: Option<bool>,
+
+    /// Only include images created before this image
+    pub before: Option<String>,
+
+    /// Only include images created since this image
+    pub since: Option<String>,
+}
+
+impl ImageQuery {
+    pub fn new() -> ImageQuery {
+        ImageQuery::default()
+    }
+
+    /// Includes intermediate images
+    pub fn all(mut self) -> ImageQuery {
+        self.all = true;
+        self
+    }
+
+    /// Only includes images with a label, given as `key` or `key=value`
+    pub fn label(mut self, label: &str) -> ImageQuery {
+        self.labels.push(String::from(label));
+        self
+    }
+
+    /// Only includes images built from the `shipwreck.toml` of an application
+    pub fn app(self, app: &str) -> ImageQuery {
+        self.label(&format!("{}={}", super::APP_LABEL, app))
+    }
+
+    /// Only includes images built from a version of an application, as given in its `shipwreck.toml`
+    pub fn version(self, version: &str) -> ImageQuery {
+        self.label(&format!("{}={}", super::VERSION_LABEL, version))
+    }
+
+    /// Only includes images built from a git commit, given as its full SHA
+    pub fn commit(self, commit: &str) -> ImageQuery {
+        self.label(&format!("{}={}", super::COMMIT_LABEL, commit))
+    }
+
+    /// Only includes images built by this broker
+    pub fn managed(self) -> ImageQuery {
+        self.label(super::MANAGED_LABEL)
+    }
+
+    /// Only includes images matching a `name[:tag]` reference
+    pub fn reference(mut self, reference: &str) -> ImageQuery {
+        self.reference = Some(String::from(reference));
+        self
+    }
+
+    /// Only includes (or excludes) untagged images
+    pub fn dangling(mut self, dangling: bool) -> ImageQuery {
+        self.dangling = Some(dangling);
+        self
+    }
+
+    /// Only includes images created before another image
+    pub fn before(mut self, image: &str) -> ImageQuery {
+        self.before = Some(String::from(image));
+        self
+    }
+
+    /// Only includes images created since another image
+    pub fn since(mut self, image: &str) -> ImageQuery {
+        self.since = Some(String::from(image));
+        self
+    }
+
+    /// Converts this query to the filters understood by the docker API
+    pub fn filters(&self) -> HashMap<String, Vec<String>> {
+        let mut filters = HashMap::new();
+        if !self.labels.is_empty() {
+            filters.insert(String::from("label"), self.labels.clone());
+        }
+        if let Some(r) = &self.reference {
+            filters.insert(String::from("reference"), vec![r.clone()]);
+        }
+        if let Some(d) = self.dangling {
+            filters.insert(String::from("dangling"), vec![d.to_string()]);
+        }
+        if let Some(b) = &self.before {
+            filters.insert(String::from("before"), vec![b.clone()]);
+        }
+        if let Some(s) = &self.since {
+            filters.insert(String::from("since"), vec![s.clone()]);
+        }
+        filters
+    }
+
+    /// Checks whether an image matches the filters of this query
+    ///
+    /// This is used by runtimes which have no docker daemon to filter for them.
+    /// `reference` must match a tag exactly (with or without `:latest`), and `before`/`since` are ignored as there is no image ordering to compare.
+    pub fn matches(&self, image: &DockerImage) -> bool {
+        let has_label = |label: &String| match label.find('=') {
+            Some(index) => {
+                image.labels.get(&label[..index]).map(|v| v.as_str()) == Some(&label[index + 1..])
+            }
+            None => image.labels.contains_key(label),
+        };
+        if !self.labels.iter().all(has_label) {
+            return false;
+        }
+        if let Some(d) = self.dangling {
+            if d != image.repo_tags.is_empty() {
+                return false;
+            }
+        }
+        self.reference.iter().all(|r| {
+            image
+                .repo_tags
+                .iter()
+                .any(|t| t == r || t.trim_end_matches(":latest") == r)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::{ContainerConfig, GraphDriverData, ImageRootFs};
+
+    fn labels(labels: &[(&str, &str)]) -> HashMap<String, String> {
+        labels
+            .iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    fn image(tags: &[&str], labels: HashMap<String, String>) -> DockerImage {
+        DockerImage {
+            id: String::from("sha256:1"),
+            repo_tags: tags.iter().map(|t| String::from(*t)).collect(),
+            size: 0,
+            created: 0,
+            labels,
+            containers: 0,
+        }
+    }
+
+    #[test]
+    fn untagged_images_have_no_repo_tags() {
+        let image = DockerImage::from(ImageSummary {
+            id: String::from("sha256:1"),
+            repo_tags: vec![String::from("<none>:<none>")],
+            size: 42,
+            created: 1600000000,
+            labels: labels(&[("kraken.app", "scapegoat")]),
+            containers: -1,
+            ..Default::default()
+        });
+
+        assert!(image.repo_tags.is_empty());
+        assert_eq!(
+            (image.size, image.created, image.containers),
+            (42, 1600000000, -1)
+        );
+        assert_eq!(image.labels["kraken.app"], "scapegoat");
+    }
+
+    #[test]
+    fn inspected_images_keep_their_config_and_layers() {
+        let mut exposed_ports = HashMap::new();
+        exposed_ports.insert(String::from("9001/tcp"), HashMap::new());
+        exposed_ports.insert(String::from("9000/tcp"), HashMap::new());
+        let details = DockerImageDetails::from(Image {
+            id: String::from("sha256:1"),
+            repo_tags: Some(vec![String::from("scapegoat:1.0.0")]),
+            os: String::from("linux"),
+            config: Some(ContainerConfig {
+                exposed_ports: Some(exposed_ports),
+                env: Some(vec![String::from("PORT=9000")]),
+                labels: Some(labels(&[("kraken.version", "1.0.0")])),
+                ..Default::default()
+            }),
+            graph_driver: GraphDriverData {
+                name: String::from("overlay2"),
+                ..Default::default()
+            },
+            root_fs: ImageRootFs {
+                layers: Some(vec![String::from("sha256:a"), String::from("sha256:b")]),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        assert_eq!(details.repo_tags, vec!["scapegoat:1.0.0"]);
+        assert_eq!(details.exposed_ports, vec!["9000/tcp", "9001/tcp"]);
+        assert_eq!(details.env, vec!["PORT=9000"]);
+        assert_eq!(details.labels["kraken.version"], "1.0.0");
+        assert_eq!(
+            (details.storage_driver.as_str(), details.layers),
+            ("overlay2", 2)
+        );
+        assert!(details.repo_digests.is_empty());
+    }
+
+    #[test]
+    fn history_layers_are_read_from_docker() {
+        let history: ImageHistory = serde_json::from_str(
+            r#"{"Id":"<missing>","Created":1600000000,"CreatedBy":"/bin/sh -c cargo build","Tags":null,"Size":4096,"Comment":""}"#,
+        )
+        .unwrap();
+
+        let layer = DockerImageLayer::from(history);
+
+        assert_eq!(layer.id, "<missing>");
+        assert_eq!(layer.created, 1600000000);
+        assert_eq!(layer.size, 4096);
+        assert!(layer.tags.is_empty());
+    }
+
+    #[test]
+    fn queries_match_labels_by_key_or_value() {
+        let scapegoat = image(
+            &["scapegoat:1.0.0"],
+            labels(&[("kraken.managed", "true"), ("kraken.app", "scapegoat")]),
+        );
+
+        assert!(ImageQuery::new().matches(&scapegoat));
+        assert!(ImageQuery::new()
+            .managed()
+            .app("scapegoat")
+            .matches(&scapegoat));
+        assert!(!ImageQuery::new().app("kraken").matches(&scapegoat));
+        assert!(!ImageQuery::new().version("1.0.0").matches(&scapegoat));
+        assert!(!ImageQuery::new()
+            .label("kraken.app=scape")
+            .matches(&scapegoat));
+    }
+
+    #[test]
+    fn queries_match_references_and_dangling_images() {
+        let latest = image(&["scapegoat:latest"], HashMap::new());
+        let untagged = image(&[], HashMap::new());
+
+        assert!(ImageQuery::new().reference("scapegoat").matches(&latest));
+        assert!(ImageQuery::new()
+            .reference("scapegoat:latest")
+            .matches(&latest));
+        assert!(!ImageQuery::new()
+            .reference("scapegoat:1.0.0")
+            .matches(&latest));
+        assert!(!ImageQuery::new().reference("scapegoat").matches(&untagged));
+        assert!(ImageQuery::new().dangling(true).matches(&untagged));
+        assert!(!ImageQuery::new().dangling(true).matches(&latest));
+        assert!(ImageQuery::new().dangling(false).matches(&latest));
+        // There is no ordering to compare, so these never exclude anything
+        assert!(ImageQuery::new().before("x").since("y").matches(&latest));
+    }
+
+    #[test]
+    fn queries_become_docker_filters() {
+        let filters = ImageQuery::new()
+            .app("scapegoat")
+            .dangling(false)
+            .reference("scapegoat")
+            .filters();
+
+        assert_eq!(filters["label"], vec!["kraken.app=scapegoat"]);
+        assert_eq!(filters["dangling"], vec!["false"]);
+        assert_eq!(filters["reference"], vec!["scapegoat"]);
+        assert!(!filters.contains_key("before"));
+    }
+}
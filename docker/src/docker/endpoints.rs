@@ -0,0 +1,59 @@
+use log::info;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Something which routes traffic for an application to the port it is running on, such as a reverse proxy
+///
+/// Redeploys only switch an application's endpoint once the new version is ready, so whatever implements this sees a single switch from the old port to the new one.
+pub trait EndpointPublisher: Send + Sync {
+    /// Points an application's endpoint at a port
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name from its `shipwreck.toml`
+    /// * `port` - The host port the application is listening on
+    fn publish(&self, app: &str, port: i64) -> Result<(), String>;
+
+    /// Gets the port an application's endpoint points at, if it has been published
+    fn published_port(&self, app: &str) -> Option<i64>;
+}
+
+/// An in-memory table of the port each application is published on
+///
+/// # Examples
+///
+/// ```
+/// let endpoints = EndpointTable::new();
+/// endpoints.publish("scapegoat", 9000)?;
+/// assert_eq!(endpoints.published_port("scapegoat"), Some(9000));
+/// ```
+#[derive(Default)]
+pub struct EndpointTable {
+    ports: Mutex<HashMap<String, i64>>,
+}
+
+impl EndpointTable {
+    pub fn new() -> EndpointTable {
+        EndpointTable::default()
+    }
+
+    /// Gets every published application and its port
+    pub fn all(&self) -> HashMap<String, i64> {
+        self.ports.lock().unwrap().clone()
+    }
+}
+
+impl EndpointPublisher for EndpointTable {
+    fn publish(&self, app: &str, port: i64) -> Result<(), String> {
+        let previous = self.ports.lock().unwrap().insert(String::from(app), port);
+        match previous {
+            Some(p) => info!("Switched {} from port {} to {}", app, p, port),
+            None => info!("Published {} on port {}", app, port),
+        }
+        Ok(())
+    }
+
+    fn published_port(&self, app: &str) -> Option<i64> {
+        self.ports.lock().unwrap().get(app).copied()
+    }
+}
@@ -0,0 +1,843 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use super::connection::{ConnectionConfig, DockerHost};
+use super::container_stats::ContainerStats;
+use super::docker_container::{ContainerQuery, DockerContainer, DockerPort};
+use super::docker_image::{DockerImage, ImageQuery};
+use super::manifest::ShipwreckManifest;
+use super::readiness::ReadinessProbe;
+use super::runtime::ContainerRuntime;
+use super::stack::ServiceContainer;
+use super::timeouts::OperationTimeouts;
+use super::{
+    DockerBroker, DockerImageBuildResult, TestResult, APP_LABEL, MANAGED_LABEL, PORT_LABEL,
+    VERSION_LABEL,
+};
+
+/// An in-memory `ContainerRuntime` which behaves like a docker daemon without running anything
+///
+/// Images and containers only exist in memory.
+/// Starting a container fails the same way docker would for unknown images, reused names and host ports which are already taken.
+/// Containers keep running until they are stopped or `exit_container` simulates them dying.
+///
+/// Nothing listens on the host ports either: `host_port_free` and `check_ready` answer from the running containers.
+/// Like docker's userland proxy, a published port passes a TCP probe, but only passes an HTTP probe when the container port is the one its image's application listens on (its `PORT_LABEL`, or any port if it has none).
+/// Builds of a folder passed to `fail_probes_of` never pass an HTTP probe.
+/// Stack networks are only names, which service containers are attached to.
+///
+/// # Examples
+///
+/// ```
+/// let runtime = FakeRuntime::new();
+/// let image = runtime.add_image("scapegoat", "1.0.0");
+/// let id = runtime.start_container(&image, 9000).await?;
+/// runtime.exit_container(&id, 137);
+/// ```
+#[derive(Default)]
+pub struct FakeRuntime {
+    state: Mutex<FakeState>,
+    /// Whether builds wait for `release_builds` before finishing
+    builds_held: AtomicBool,
+    builds_released: Notify,
+}
+
+#[derive(Default)]
+struct FakeState {
+    images: Vec<DockerImage>,
+    containers: Vec<DockerContainer>,
+    stats: HashMap<String, ContainerStats>,
+    logs: HashMap<String, Vec<String>>,
+    failing_builds: HashSet<String>,
+    failing_tests: HashSet<String>,
+    /// Images built from a folder in `failing_tests`
+    failing_test_images: HashSet<String>,
+    failing_starts: HashSet<String>,
+    /// Images built from a folder in `failing_starts`
+    failing_start_images: HashSet<String>,
+    unready_builds: HashSet<String>,
+    /// Images built from a folder in `unready_builds`
+    unready_images: HashSet<String>,
+    /// The containers whose application doesn't answer on their published port
+    unreachable: HashSet<String>,
+    /// The names of the stack networks of each app
+    networks: HashMap<String, Vec<String>>,
+    /// The names of containers which can't be stopped
+    failing_stops: HashSet<String>,
+    next_id: u64,
+    /// Fake clock, so creation order is stable even within the same second
+    now: i64,
+}
+
+impl FakeState {
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("{:064x}", self.next_id)
+    }
+
+    fn tick(&mut self) -> i64 {
+        if self.now == 0 {
+            self.now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+        }
+        self.now += 1;
+        self.now
+    }
+
+    fn find_image(&self, image: &str) -> Option<&DockerImage> {
+        self.images.iter().find(|i| {
+            i.id == image
+                || i.repo_tags
+                    .iter()
+                    .any(|t| t == image || t.trim_end_matches(":latest") == image)
+        })
+    }
+
+    /// Checks a container could be started with a name and host ports, as docker would
+    fn check_start(&self, name: &str, host_ports: &[i64]) -> Result<(), String> {
+        if self.containers.iter().any(|c| c.name == name) {
+            return Err(format!(
+                "Conflict. The container name \"/{}\" is already in use",
+                name
+            ));
+        }
+        let taken = self.containers.iter().find_map(|c| {
+            let running = c.state.as_deref() == Some("running");
+            c.public_ports()
+                .into_iter()
+                .find(|p| running && host_ports.contains(p))
+        });
+        match taken {
+            Some(port) => Err(format!(
+                "Bind for 0.0.0.0:{} failed: port is already allocated",
+                port
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether an image was built from one of a set of project folders
+    fn built_from(image: &DockerImage, folders: &HashSet<String>) -> bool {
+        image
+            .repo_tags
+            .iter()
+            .any(|t| folders.contains(t.trim_end_matches(":latest")))
+    }
+
+    fn find_container(&mut self, container: &str) -> Option<&mut DockerContainer> {
+        self.containers
+            .iter_mut()
+            .find(|c| c.id == container || c.names.iter().any(|n| n == container))
+    }
+}
+
+impl FakeRuntime {
+    pub fn new() -> FakeRuntime {
+        FakeRuntime::default()
+    }
+
+    /// Adds an image as if it had been built from a `shipwreck.toml`, returning its tag
+    ///
+    /// The image's application listens on whichever port its container is started with.
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name to label the image with
+    /// * `version` - The application version to label the image with
+    pub fn add_image(&self, app: &str, version: &str) -> String {
+        let mut labels = HashMap::new();
+        labels.insert(String::from(APP_LABEL), String::from(app));
+        labels.insert(String::from(VERSION_LABEL), String::from(version));
+        self.insert_image(labels)
+    }
+
+    /// Adds an image like `add_image` whose application only listens on `port` inside its container
+    pub fn add_image_listening_on(&self, app: &str, version: &str, port: i64) -> String {
+        let mut labels = HashMap::new();
+        labels.insert(String::from(APP_LABEL), String::from(app));
+        labels.insert(String::from(VERSION_LABEL), String::from(version));
+        labels.insert(String::from(PORT_LABEL), port.to_string());
+        self.insert_image(labels)
+    }
+
+    /// Makes builds wait until `release_builds` before they finish, so what is queued and running can be looked at
+    pub fn hold_builds(&self) {
+        self.builds_held.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets held builds, and every future build, finish
+    pub fn release_builds(&self) {
+        self.builds_held.store(false, Ordering::SeqCst);
+        self.builds_released.notify();
+    }
+
+    /// Makes every future build of a project folder fail
+    pub fn fail_builds_of(&self, source_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failing_builds.insert(String::from(source_path));
+    }
+
+    /// Makes the tests of every future build of a project folder exit with code 1
+    pub fn fail_tests_of(&self, source_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failing_tests.insert(String::from(source_path));
+    }
+
+    /// Makes starting a container from every future build of a project folder fail
+    pub fn fail_starts_of(&self, source_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failing_starts.insert(String::from(source_path));
+    }
+
+    /// Makes the application of every future build of a project folder never answer its readiness probe
+    pub fn fail_probes_of(&self, source_path: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.unready_builds.insert(String::from(source_path));
+    }
+
+    /// Makes stopping a container fail, e.g. because the daemon timed out
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the container
+    pub fn fail_stops_of(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.failing_stops.insert(String::from(name));
+    }
+
+    /// Simulates a running container exiting on its own (e.g. crashing)
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    /// * `exit_code` - The code the container exited with
+    pub fn exit_container(&self, container_id: &str, exit_code: i64) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let id = match state.find_container(container_id) {
+            Some(c) => {
+                c.state = Some(String::from("exited"));
+                c.status = Some(format!("Exited ({})", exit_code));
+                c.id.clone()
+            }
+            None => return Err(format!("No such container: {}", container_id)),
+        };
+        state.unreachable.remove(&id);
+        Ok(())
+    }
+
+    /// Sets the stats reported for a container
+    pub fn set_stats(&self, container_id: &str, stats: ContainerStats) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.insert(String::from(container_id), stats);
+    }
+
+    /// Appends a line to the output of a container
+    pub fn push_log(&self, container_id: &str, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .logs
+            .entry(String::from(container_id))
+            .or_default()
+            .push(String::from(line));
+    }
+
+    /// Gets every image currently known to the runtime
+    pub fn images(&self) -> Vec<DockerImage> {
+        self.state.lock().unwrap().images.clone()
+    }
+
+    fn insert_image(&self, mut labels: HashMap<String, String>) -> String {
+        let mut state = self.state.lock().unwrap();
+        let tag = Uuid::new_v4().to_hyphenated().to_string();
+        labels.insert(String::from(MANAGED_LABEL), String::from("true"));
+        let image = DockerImage {
+            id: format!("sha256:{}", state.next_id()),
+            repo_tags: vec![format!("{}:latest", tag)],
+            size: 0,
+            created: state.tick(),
+            labels,
+            containers: 0,
+        };
+        state.images.push(image);
+        tag
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    async fn build_labelled_image(
+        &self,
+        source_path: &str,
+        extra_labels: &HashMap<String, String>,
+    ) -> Result<DockerImageBuildResult, String> {
+        if self.builds_held.load(Ordering::SeqCst) {
+            while self.builds_held.load(Ordering::SeqCst) {
+                self.builds_released.notified().await;
+            }
+            // Each notification only wakes one build, so pass it on to the next
+            self.builds_released.notify();
+        }
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .failing_builds
+            .contains(source_path)
+        {
+            return Err(String::from("Failed to build image"));
+        }
+        let mut labels = extra_labels.clone();
+        if let Ok(m) = ShipwreckManifest::from_dir(source_path) {
+            labels.insert(String::from(APP_LABEL), m.app.name);
+            labels.insert(String::from(VERSION_LABEL), m.app.version);
+            if let Some(port) = m.config.port {
+                labels.insert(String::from(PORT_LABEL), port.to_string());
+            }
+        }
+        let image_id = self.insert_image(labels);
+        let mut state = self.state.lock().unwrap();
+        if state.failing_tests.contains(source_path) {
+            state.failing_test_images.insert(image_id.clone());
+        }
+        if state.failing_starts.contains(source_path) {
+            state.failing_start_images.insert(image_id.clone());
+        }
+        if state.unready_builds.contains(source_path) {
+            state.unready_images.insert(image_id.clone());
+        }
+        Ok(DockerImageBuildResult {
+            log: vec![format!("Successfully tagged {}:latest", image_id)],
+            image_id,
+            test: None,
+        })
+    }
+
+    async fn run_tests(
+        &self,
+        image_id: &str,
+        command: &str,
+        _env: &HashMap<String, String>,
+    ) -> Result<TestResult, String> {
+        let state = self.state.lock().unwrap();
+        if state.find_image(image_id).is_none() {
+            return Err(format!("No such image: {}", image_id));
+        }
+        let exit_code = if state.failing_test_images.contains(image_id) {
+            1
+        } else {
+            0
+        };
+        Ok(TestResult {
+            command: String::from(command),
+            exit_code,
+            output: vec![format!("{} exited with {}", command, exit_code)],
+        })
+    }
+
+    async fn start_mapped_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        container_port: i64,
+        host_port: i64,
+    ) -> Result<String, String> {
+        let mut state = self.state.lock().unwrap();
+        let image = match state.find_image(image_id) {
+            Some(i) => i.clone(),
+            None => return Err(format!("No such image: {}", image_id)),
+        };
+        state.check_start(name, &[host_port])?;
+        if FakeState::built_from(&image, &state.failing_start_images) {
+            return Err(format!(
+                "OCI runtime create failed: container {} failed to start",
+                name
+            ));
+        }
+        let unready = FakeState::built_from(&image, &state.unready_images);
+        let reachable = !unready
+            && match image.labels.get(PORT_LABEL) {
+                Some(port) => *port == container_port.to_string(),
+                None => true,
+            };
+        let id = state.next_id();
+        if !reachable {
+            state.unreachable.insert(id.clone());
+        }
+        let created = state.tick();
+        state.containers.push(DockerContainer {
+            id: id.clone(),
+            name: String::from(name),
+            names: vec![String::from(name)],
+            image: Some(String::from(image_id)),
+            image_id: Some(image.id.clone()),
+            command: None,
+            created: Some(created),
+            ports: vec![DockerPort {
+                private_port: container_port,
+                public_port: Some(host_port),
+                protocol: String::from("tcp"),
+                ip: Some(String::from("0.0.0.0")),
+            }],
+            labels: image.labels,
+            networks: HashMap::new(),
+            mounts: vec![],
+            state: Some(String::from("running")),
+            status: Some(String::from("Up")),
+        });
+        Ok(id)
+    }
+
+    async fn stop_container(&self, container_id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let failing_stops = state.failing_stops.clone();
+        let id = match state.find_container(container_id) {
+            Some(c) if failing_stops.contains(&c.name) => {
+                return Err(format!("Failed to stop container {}", c.name))
+            }
+            Some(c) => {
+                if c.state.as_deref() == Some("running") {
+                    c.state = Some(String::from("exited"));
+                    c.status = Some(String::from("Exited (0)"));
+                }
+                c.id.clone()
+            }
+            None => return Err(format!("No such container: {}", container_id)),
+        };
+        state.unreachable.remove(&id);
+        Ok(())
+    }
+
+    async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let container = match state.find_container(container_id) {
+            Some(c) => c,
+            None => return Err(format!("No such container: {}", container_id)),
+        };
+        if container.state.as_deref() == Some("running") {
+            return Err(format!(
+                "You cannot remove a running container {}. Stop the container before attempting removal",
+                container_id
+            ));
+        }
+        let id = container.id.clone();
+        state.containers.retain(|c| c.id != id);
+        Ok(())
+    }
+
+    async fn image_exists(&self, image_id: &str) -> Result<bool, String> {
+        Ok(self.state.lock().unwrap().find_image(image_id).is_some())
+    }
+
+    async fn remove_image(&self, image_id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let id = match state.find_image(image_id) {
+            Some(i) => i.id.clone(),
+            None => return Err(format!("No such image: {}", image_id)),
+        };
+        if state
+            .containers
+            .iter()
+            .any(|c| c.image_id.as_deref() == Some(id.as_str()))
+        {
+            return Err(format!(
+                "conflict: unable to remove image {} (must force) - image is being used by a container",
+                image_id
+            ));
+        }
+        state.images.retain(|i| i.id != id);
+        Ok(())
+    }
+
+    async fn container_port(&self, image_id: &str) -> Result<Option<i64>, String> {
+        let state = self.state.lock().unwrap();
+        let image = state
+            .find_image(image_id)
+            .ok_or_else(|| format!("No such image: {}", image_id))?;
+        Ok(image.labels.get(PORT_LABEL).and_then(|p| p.parse().ok()))
+    }
+
+    async fn list_images(&self, query: &ImageQuery) -> Result<Vec<DockerImage>, String> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .images
+            .iter()
+            .filter(|i| query.matches(i))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_containers(
+        &self,
+        query: &ContainerQuery,
+    ) -> Result<Vec<DockerContainer>, String> {
+        let state = self.state.lock().unwrap();
+        let mut containers = state
+            .containers
+            .iter()
+            .filter(|c| query.matches(c))
+            .cloned()
+            .collect();
+        query.apply(&mut containers);
+        Ok(containers)
+    }
+
+    async fn container_stats(&self, container_id: &str) -> Result<ContainerStats, String> {
+        let mut state = self.state.lock().unwrap();
+        let (id, name) = match state.find_container(container_id) {
+            Some(c) if c.state.as_deref() == Some("running") => (c.id.clone(), c.name.clone()),
+            Some(_) => return Err(format!("Container {} is not running", container_id)),
+            None => return Err(format!("No such container: {}", container_id)),
+        };
+        let stats = state
+            .stats
+            .get(&id)
+            .or_else(|| state.stats.get(&name))
+            .cloned();
+        Ok(stats.unwrap_or(ContainerStats {
+            id,
+            name,
+            ..Default::default()
+        }))
+    }
+
+    async fn container_logs(
+        &self,
+        container_id: &str,
+        tail: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        let mut state = self.state.lock().unwrap();
+        let (id, name) = match state.find_container(container_id) {
+            Some(c) => (c.id.clone(), c.name.clone()),
+            None => return Err(format!("No such container: {}", container_id)),
+        };
+        let logs = state
+            .logs
+            .get(&id)
+            .or_else(|| state.logs.get(&name))
+            .cloned()
+            .unwrap_or_default();
+        let skip = tail.map(|t| logs.len().saturating_sub(t)).unwrap_or(0);
+        Ok(logs.into_iter().skip(skip).collect())
+    }
+
+    async fn create_network(&self, app: &str, name: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.networks.values().flatten().any(|n| n == name) {
+            return Err(format!("network with name {} already exists", name));
+        }
+        state
+            .networks
+            .entry(String::from(app))
+            .or_default()
+            .push(String::from(name));
+        Ok(())
+    }
+
+    async fn list_networks(&self, app: &str) -> Result<Vec<String>, String> {
+        let state = self.state.lock().unwrap();
+        Ok(state.networks.get(app).cloned().unwrap_or_default())
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .containers
+            .iter()
+            .any(|c| c.networks.contains_key(name))
+        {
+            return Err(format!(
+                "error while removing network: network {} has active endpoints",
+                name
+            ));
+        }
+        let mut found = false;
+        for networks in state.networks.values_mut() {
+            let before = networks.len();
+            networks.retain(|n| n != name);
+            found |= networks.len() != before;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(format!("No such network: {}", name))
+        }
+    }
+
+    async fn start_service(&self, service: &ServiceContainer) -> Result<String, String> {
+        let mut state = self.state.lock().unwrap();
+        let image = match state.find_image(&service.image) {
+            Some(i) => i.clone(),
+            None => return Err(format!("No such image: {}", service.image)),
+        };
+        if let Some(missing) = service
+            .networks
+            .iter()
+            .find(|n| !state.networks.values().flatten().any(|m| m == *n))
+        {
+            return Err(format!("network {} not found", missing));
+        }
+        state.check_start(&service.name, &service.ports)?;
+        if FakeState::built_from(&image, &state.failing_start_images) {
+            return Err(format!(
+                "OCI runtime create failed: container {} failed to start",
+                service.name
+            ));
+        }
+        let id = state.next_id();
+        let created = state.tick();
+        let mut labels = image.labels.clone();
+        labels.extend(service.labels.clone());
+        state.containers.push(DockerContainer {
+            id: id.clone(),
+            name: service.name.clone(),
+            names: vec![service.name.clone()],
+            image: Some(service.image.clone()),
+            image_id: Some(image.id.clone()),
+            command: service.command.clone(),
+            created: Some(created),
+            ports: service
+                .ports
+                .iter()
+                .map(|p| DockerPort {
+                    private_port: *p,
+                    public_port: Some(*p),
+                    protocol: String::from("tcp"),
+                    ip: Some(String::from("0.0.0.0")),
+                })
+                .collect(),
+            labels,
+            networks: service
+                .networks
+                .iter()
+                .map(|n| (n.clone(), String::new()))
+                .collect(),
+            mounts: vec![],
+            state: Some(String::from("running")),
+            status: Some(String::from("Up")),
+        });
+        Ok(id)
+    }
+
+    async fn pull_image_if_missing(&self, image: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.find_image(image).is_some() {
+            return Ok(());
+        }
+        // Pulled images carry no kraken labels
+        let tag = if image.rsplit('/').next().unwrap_or(image).contains(':') {
+            String::from(image)
+        } else {
+            format!("{}:latest", image)
+        };
+        let pulled = DockerImage {
+            id: format!("sha256:{}", state.next_id()),
+            repo_tags: vec![tag],
+            size: 0,
+            created: state.tick(),
+            labels: HashMap::new(),
+            containers: 0,
+        };
+        state.images.push(pulled);
+        Ok(())
+    }
+
+    async fn host_port_free(&self, port: i64) -> bool {
+        let state = self.state.lock().unwrap();
+        !state
+            .containers
+            .iter()
+            .any(|c| c.state.as_deref() == Some("running") && c.public_ports().contains(&port))
+    }
+
+    async fn check_ready(
+        &self,
+        probe: &ReadinessProbe,
+        port: i64,
+        _timeout: Duration,
+    ) -> Result<(), String> {
+        let state = self.state.lock().unwrap();
+        let addr = format!("{}:{}", self.published_host(), port);
+        let container = state
+            .containers
+            .iter()
+            .find(|c| c.state.as_deref() == Some("running") && c.public_ports().contains(&port))
+            .ok_or_else(|| format!("Failed to connect to {}: Connection refused", addr))?;
+        match probe {
+            ReadinessProbe::Http { .. } if state.unreachable.contains(&container.id) => {
+                Err(format!("No answer to probe from {}", addr))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn prune(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .containers
+            .retain(|c| c.state.as_deref() == Some("running"));
+        let in_use: HashSet<String> = state
+            .containers
+            .iter()
+            .filter_map(|c| c.image_id.clone())
+            .collect();
+        state.images.retain(|i| in_use.contains(&i.id));
+        Ok(())
+    }
+}
+
+/// A project folder in the system's temporary directory, removed when it is dropped
+pub struct TempProject {
+    dir: PathBuf,
+}
+
+impl TempProject {
+    /// Writes a project folder with a `shipwreck.toml`, with `config` as its `[config]` table
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name
+    /// * `version` - The application version
+    /// * `config` - The contents of the `[config]` table, e.g. `port = 9000`
+    pub fn new(app: &str, version: &str, config: &str) -> TempProject {
+        TempProject::with_files(&[(
+            "shipwreck.toml",
+            &format!(
+                "[app]\nname = \"{}\"\nversion = \"{}\"\n\n[config]\n{}\n",
+                app, version, config
+            ),
+        )])
+    }
+
+    /// Writes a project folder holding `files`, as paths relative to the folder and their contents
+    pub fn with_files(files: &[(&str, &str)]) -> TempProject {
+        let dir = std::env::temp_dir().join(format!("kraken-project-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        TempProject { dir }
+    }
+
+    /// The project folder
+    pub fn path(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempProject {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A docker daemon on a local port which starts answering builds but never finishes them, and never answers other calls
+///
+/// Only image removals get a reply, so the cleanup after a timed out or cancelled build can be checked with `requests`.
+pub struct StalledDaemon {
+    addr: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl StalledDaemon {
+    pub fn start() -> StalledDaemon {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let seen = seen.clone();
+                thread::spawn(move || StalledDaemon::serve(stream.unwrap(), &seen));
+            }
+        });
+        StalledDaemon { addr, requests }
+    }
+
+    /// A broker talking to this daemon
+    pub fn broker(&self, timeouts: OperationTimeouts) -> DockerBroker {
+        let host = DockerHost::Tcp(self.addr.clone());
+        let config = ConnectionConfig {
+            host: host.clone(),
+            negotiate_version: false,
+            ..Default::default()
+        };
+        DockerBroker {
+            conn: config.client().unwrap(),
+            timeouts,
+            host,
+            disk_guard: None,
+        }
+    }
+
+    /// Every request received, as `METHOD /path`
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Answers the requests of one connection until the client hangs up
+    fn serve(stream: TcpStream, seen: &Mutex<Vec<String>>) {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        loop {
+            let mut head = vec![];
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                head.push(line.trim_end().to_string());
+                line.clear();
+            }
+            if head.is_empty() {
+                return;
+            }
+            let length = head
+                .iter()
+                .filter_map(|h| {
+                    h.to_lowercase()
+                        .strip_prefix("content-length:")
+                        .map(|l| l.trim().parse::<usize>())
+                })
+                .next()
+                .and_then(|l| l.ok())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            if reader.read_exact(&mut body).is_err() {
+                return;
+            }
+            let request: Vec<&str> = head[0].split(' ').collect();
+            seen.lock()
+                .unwrap()
+                .push(format!("{} {}", request[0], request[1]));
+            if request[0] == "DELETE" && request[1].contains("/images/") {
+                let _ = writer.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]",
+                );
+                continue;
+            }
+            if request[1].contains("/build") {
+                let chunk = "{\"stream\":\"Step 1/2 : FROM scratch\\n\"}";
+                let _ = write!(
+                    writer,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                    chunk.len(),
+                    chunk
+                );
+            }
+            // Hang until the client gives up and closes the connection
+            let _ = reader.read_to_end(&mut vec![]);
+            return;
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+use super::build_queue::BuildQueue;
+use super::cancellation::CancellationHandle;
+use super::{DockerBroker, DockerImageBuildResult, COMMIT_LABEL};
+
+/// Where repositories are checked out while they are built
+const CHECKOUT_DIR: &str = "./tmp/checkouts";
+
+/// A build of a git repository at a particular commit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitBuild {
+    /// The branch, tag or commit which was asked for
+    pub reference: String,
+
+    /// The full SHA of the commit which was built, also recorded in the image's `kraken.commit` label
+    pub commit: String,
+
+    /// The result of the build
+    pub build: DockerImageBuildResult,
+}
+
+/// A repository checked out at a single commit into a scratch folder
+///
+/// The scratch folder is removed when this is dropped.
+#[derive(Debug)]
+pub struct GitCheckout {
+    /// The folder holding the checked out files
+    dir: PathBuf,
+
+    /// The full SHA of the checked out commit
+    pub commit: String,
+}
+
+impl GitCheckout {
+    /// Checks out a branch, tag or commit of a local repository by shelling out to `git`
+    ///
+    /// Only local repositories are supported, either as a path or a `file://` URL.
+    /// The checkout's `.git` folder is removed so it isn't sent to docker as part of the build context.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The repository, e.g. `../scapegoat` or `file:///srv/git/scapegoat.git`
+    /// * `reference` - The branch, tag or commit SHA to check out
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let checkout = GitCheckout::checkout("file:///srv/git/scapegoat.git", "v1.0.0")?;
+    /// println!("Checked out {} into {}", checkout.commit, checkout.path());
+    /// ```
+    pub fn checkout(repo: &str, reference: &str) -> Result<GitCheckout, String> {
+        GitCheckout::checkout_in(Path::new(CHECKOUT_DIR), repo, reference)
+    }
+
+    /// Checks out a branch, tag or commit like `checkout`, on the blocking pool so it doesn't hold up the executor
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The repository, as a path or a `file://` URL
+    /// * `reference` - The branch, tag or commit SHA to check out
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let checkout = GitCheckout::checkout_blocking("../scapegoat", "main").await?;
+    /// ```
+    pub async fn checkout_blocking(repo: &str, reference: &str) -> Result<GitCheckout, String> {
+        let (repo, reference) = (String::from(repo), String::from(reference));
+        tokio::task::spawn_blocking(move || GitCheckout::checkout(&repo, &reference))
+            .await
+            .map_err(|e| format!("Failed to check out: {}", e))?
+    }
+
+    /// Checks out a branch, tag or commit into a new folder under `root`
+    fn checkout_in(root: &Path, repo: &str, reference: &str) -> Result<GitCheckout, String> {
+        // Also catches scp-like `host:path` remotes, which git would try to reach over ssh
+        if !repo.starts_with("file://") && !Path::new(repo).exists() {
+            return Err(format!(
+                "Can't check out {}, only local repositories and file:// URLs are supported",
+                repo
+            ));
+        }
+        // Anything starting with a dash would be read by git as an option
+        if reference.is_empty() || reference.starts_with('-') {
+            return Err(format!("Invalid git reference {:?}", reference));
+        }
+
+        let dir = root.join(Uuid::new_v4().to_hyphenated().to_string());
+        fs::create_dir_all(root)
+            .map_err(|e| format!("Failed to create {}: {}", root.display(), e))?;
+        // From here on the folder is cleaned up on error by dropping `checkout`
+        let mut checkout = GitCheckout {
+            dir,
+            commit: String::new(),
+        };
+        let dir = checkout.path();
+
+        git(
+            None,
+            &[
+                "clone",
+                "--quiet",
+                "--no-checkout",
+                "--",
+                repo,
+                dir.as_str(),
+            ],
+        )?;
+        // Branches only exist as `origin/<branch>` in a fresh clone
+        let commit = [
+            format!("{}^{{commit}}", reference),
+            format!("origin/{}^{{commit}}", reference),
+        ]
+        .iter()
+        .find_map(|r| git(Some(&dir), &["rev-parse", "--verify", "--quiet", r]).ok())
+        .ok_or_else(|| format!("{} has no branch, tag or commit {}", repo, reference))?;
+        git(
+            Some(&dir),
+            &["checkout", "--quiet", "--detach", commit.as_str()],
+        )?;
+
+        fs::remove_dir_all(checkout.dir.join(".git"))
+            .map_err(|e| format!("Failed to remove the .git folder of {}: {}", dir, e))?;
+        info!("Checked out {} of {} at {}", reference, repo, commit);
+        checkout.commit = commit;
+        Ok(checkout)
+    }
+
+    /// The folder holding the checked out files, which can be passed to `DockerBroker::build_image`
+    pub fn path(&self) -> String {
+        self.dir.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for GitCheckout {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Reads a file as of a branch, tag or commit, without checking the repository out
+///
+/// # Arguments
+///
+/// * `repo` - The repository, as a path or a `file://` URL
+/// * `reference` - The branch, tag or commit SHA to read the file at
+/// * `path` - The file, relative to the root of the repository
+///
+/// # Examples
+///
+/// ```
+/// let manifest = git_source::read_file_at("../scapegoat", "HEAD", "shipwreck.toml")?;
+/// ```
+pub fn read_file_at(repo: &str, reference: &str, path: &str) -> Result<String, String> {
+    if reference.starts_with('-') {
+        return Err(format!("Invalid git reference {:?}", reference));
+    }
+    git(
+        Some(local_path(repo)),
+        &["show", &format!("{}:{}", reference, path)],
+    )
+}
+
+/// Fetches every remote of a repository, so commits pushed to its upstream can be checked out
+///
+/// # Arguments
+///
+/// * `repo` - The repository, as a path or a `file://` URL
+pub fn fetch(repo: &str) -> Result<(), String> {
+    git(
+        Some(local_path(repo)),
+        &["fetch", "--all", "--tags", "--quiet"],
+    )
+    .map(|_| ())
+}
+
+/// The folder of a repository given as a path or a `file://` URL
+fn local_path(repo: &str) -> &str {
+    repo.strip_prefix("file://").unwrap_or(repo)
+}
+
+/// Runs a git command, returning its trimmed stdout
+fn git(dir: Option<&str>, args: &[&str]) -> Result<String, String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    let output = command
+        .args(args)
+        // Never wait on a credential prompt
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl BuildQueue {
+    /// Builds an image from a local git repository at a branch, tag or commit
+    ///
+    /// The commit is checked out into a scratch folder, which is removed once the build ends.
+    /// The image is labelled with the commit's SHA, so deployments can be traced back to it (see `ImageQuery::commit`).
+    /// Unlike `DockerBroker::build_image_from_git`, the build waits for a slot in the queue like any other.
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The repository, as a path or a `file://` URL
+    /// * `reference` - The branch, tag or commit SHA to build
+    /// * `priority` - Higher priorities start first when the queue uses `QueueOrdering::Priority`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let builds = BuildQueue::new(Arc::new(docker), 2, QueueOrdering::Fifo);
+    /// let build = builds.build_image_from_git("../scapegoat", "main", DEFAULT_PRIORITY).await?;
+    /// println!("Built {} from {}", build.build.image_id, build.commit);
+    /// ```
+    pub async fn build_image_from_git(
+        &self,
+        repo: &str,
+        reference: &str,
+        priority: i32,
+    ) -> Result<GitBuild, String> {
+        let checkout = GitCheckout::checkout_blocking(repo, reference).await?;
+        let mut labels = HashMap::new();
+        labels.insert(String::from(COMMIT_LABEL), checkout.commit.clone());
+        let build = self
+            .build_image(&checkout.path(), &labels, priority)
+            .await?;
+        Ok(GitBuild {
+            reference: String::from(reference),
+            commit: checkout.commit.clone(),
+            build,
+        })
+    }
+}
+
+impl DockerBroker {
+    /// Builds a docker image from a local git repository at a branch, tag or commit
+    ///
+    /// The commit is checked out into a scratch folder, which is removed once the build ends.
+    /// The image is labelled with the commit's SHA, so deployments can be traced back to it (see `ImageQuery::commit`).
+    ///
+    /// # Arguments
+    ///
+    /// * `repo` - The repository, as a path or a `file://` URL
+    /// * `reference` - The branch, tag or commit SHA to build
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let build = docker.build_image_from_git("file:///srv/git/scapegoat.git", "v1.0.0").await?;
+    /// println!("Built {} from {}", build.build.image_id, build.commit);
+    /// ```
+    pub async fn build_image_from_git(
+        &self,
+        repo: &str,
+        reference: &str,
+    ) -> Result<GitBuild, String> {
+        let checkout = GitCheckout::checkout_blocking(repo, reference).await?;
+        let mut labels = HashMap::new();
+        labels.insert(COMMIT_LABEL, checkout.commit.as_str());
+        let build = self
+            .build_image_with_labels(&checkout.path(), &labels, &CancellationHandle::new())
+            .await?;
+        Ok(GitBuild {
+            reference: String::from(reference),
+            commit: checkout.commit.clone(),
+            build,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::build_queue::{QueueOrdering, DEFAULT_PRIORITY};
+    use crate::docker::docker_image::ImageQuery;
+    use crate::docker::fake_runtime::{FakeRuntime, TempProject};
+    use crate::docker::runtime::ContainerRuntime;
+    use crate::docker::VERSION_LABEL;
+    use std::sync::Arc;
+
+    /// A repository whose `main` has a `1.0.0` commit tagged `v1.0.0` followed by a `2.0.0` one, and their SHAs
+    fn repo() -> (TempProject, String, String) {
+        let project = TempProject::new("scapegoat", "1.0.0", "");
+        let dir = project.path();
+        let commit = |message: &str| {
+            git(
+                Some(&dir),
+                &[
+                    "-c",
+                    "user.name=Kraken",
+                    "-c",
+                    "user.email=kraken@example.com",
+                    "-c",
+                    "commit.gpgsign=false",
+                    "commit",
+                    "--quiet",
+                    "-m",
+                    message,
+                ],
+            )
+            .unwrap();
+            git(Some(&dir), &["rev-parse", "HEAD"]).unwrap()
+        };
+        git(Some(&dir), &["init", "--quiet", "--initial-branch=main"]).unwrap();
+        git(Some(&dir), &["add", "."]).unwrap();
+        let v1 = commit("1.0.0");
+        git(Some(&dir), &["tag", "v1.0.0"]).unwrap();
+        fs::write(
+            Path::new(&dir).join("shipwreck.toml"),
+            "[app]\nname = \"scapegoat\"\nversion = \"2.0.0\"\n",
+        )
+        .unwrap();
+        git(Some(&dir), &["add", "."]).unwrap();
+        let v2 = commit("2.0.0");
+        (project, v1, v2)
+    }
+
+    fn version(checkout: &GitCheckout) -> String {
+        let manifest =
+            fs::read_to_string(Path::new(&checkout.path()).join("shipwreck.toml")).unwrap();
+        String::from(if manifest.contains("2.0.0") {
+            "2.0.0"
+        } else {
+            "1.0.0"
+        })
+    }
+
+    /// The folders left under a checkout root
+    fn leftovers(root: &Path) -> usize {
+        fs::read_dir(root).map(|d| d.count()).unwrap_or(0)
+    }
+
+    #[test]
+    fn branches_tags_and_shas_are_checked_out() {
+        let (repo, v1, v2) = repo();
+        let root = TempProject::with_files(&[]);
+        let root = Path::new(&root.path()).join("checkouts");
+
+        let branch = GitCheckout::checkout_in(&root, &repo.path(), "main").unwrap();
+        assert_eq!(
+            (branch.commit.as_str(), version(&branch).as_str()),
+            (v2.as_str(), "2.0.0")
+        );
+        assert!(!Path::new(&branch.path()).join(".git").exists());
+
+        let tag = GitCheckout::checkout_in(&root, &repo.path(), "v1.0.0").unwrap();
+        assert_eq!(
+            (tag.commit.as_str(), version(&tag).as_str()),
+            (v1.as_str(), "1.0.0")
+        );
+
+        let sha = GitCheckout::checkout_in(&root, &repo.path(), &v1).unwrap();
+        assert_eq!(
+            (sha.commit.as_str(), version(&sha).as_str()),
+            (v1.as_str(), "1.0.0")
+        );
+
+        let url = format!("file://{}", repo.path());
+        let from_url = GitCheckout::checkout_in(&root, &url, "main").unwrap();
+        assert_eq!(from_url.commit, v2);
+    }
+
+    #[test]
+    fn invalid_and_unknown_refs_are_refused_without_leaving_a_folder() {
+        let (repo, _, _) = repo();
+        let root = TempProject::with_files(&[]);
+        let root = Path::new(&root.path()).join("checkouts");
+
+        let error = GitCheckout::checkout_in(&root, &repo.path(), "--upload-pack=touch /tmp/x")
+            .unwrap_err();
+        assert!(error.starts_with("Invalid git reference"), "{}", error);
+        let error = GitCheckout::checkout_in(&root, &repo.path(), "nope").unwrap_err();
+        assert!(
+            error.contains("has no branch, tag or commit nope"),
+            "{}",
+            error
+        );
+        let error = GitCheckout::checkout_in(&root, "host:scapegoat.git", "main").unwrap_err();
+        assert!(error.contains("only local repositories"), "{}", error);
+        assert_eq!(leftovers(&root), 0);
+    }
+
+    #[test]
+    fn dropping_a_checkout_removes_its_folder() {
+        let (repo, _, _) = repo();
+        let root = TempProject::with_files(&[]);
+        let root = Path::new(&root.path()).join("checkouts");
+
+        let checkout = GitCheckout::checkout_in(&root, &repo.path(), "main").unwrap();
+        let path = checkout.path();
+        assert!(Path::new(&path).join("shipwreck.toml").exists());
+        drop(checkout);
+
+        assert!(!Path::new(&path).exists());
+        assert_eq!(leftovers(&root), 0);
+    }
+
+    #[test]
+    fn read_file_at_refuses_refs_starting_with_a_dash() {
+        let (repo, _, _) = repo();
+
+        let manifest = read_file_at(&repo.path(), "v1.0.0", "shipwreck.toml").unwrap();
+        assert!(manifest.contains("1.0.0"));
+        let error = read_file_at(&repo.path(), "--output=/tmp/x", "shipwreck.toml").unwrap_err();
+        assert!(error.starts_with("Invalid git reference"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn git_builds_are_labelled_with_their_commit() {
+        let (repo, v1, _) = repo();
+        let runtime = Arc::new(FakeRuntime::new());
+        let builds = BuildQueue::new(runtime.clone(), 1, QueueOrdering::Fifo);
+
+        let build = builds
+            .build_image_from_git(&repo.path(), "v1.0.0", DEFAULT_PRIORITY)
+            .await
+            .unwrap();
+
+        assert_eq!(build.commit, v1);
+        let images = runtime
+            .list_images(&ImageQuery::new().commit(&v1))
+            .await
+            .unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].labels[VERSION_LABEL], "1.0.0");
+    }
+}
@@ -0,0 +1,243 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::state_store::StateStore;
+use crate::api::signature::{sha256, to_hex};
+
+/// How a deployment ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeploymentOutcome {
+    /// The new version was started and is serving
+    Succeeded,
+
+    /// The image could not be built
+    BuildFailed(String),
+
+    /// The manifest's test command exited non-zero, so nothing was started
+    TestsFailed { exit_code: i64 },
+
+    /// The new version was started but never became ready, so the previous version was kept
+    RolledBack(String),
+
+    /// The new version could not be started
+    Failed(String),
+}
+
+/// A single attempt to deploy an application
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeploymentRecord {
+    /// Identifies this deployment within the history, assigned when it is recorded
+    pub id: u64,
+
+    /// The application name from its `shipwreck.toml`
+    pub app: String,
+
+    /// The application version from its `shipwreck.toml`
+    pub version: String,
+
+    /// The image which was deployed, `None` if the build failed
+    pub image_id: Option<String>,
+
+    /// The container which was started, `None` if nothing was started
+    pub container_id: Option<String>,
+
+    /// The host ports the container published
+    pub ports: Vec<i64>,
+
+    /// A hash of the environment variables the application was deployed with
+    pub env_hash: String,
+
+    /// When the deployment finished, as seconds since the epoch
+    pub timestamp: i64,
+
+    /// How the deployment ended
+    pub outcome: DeploymentOutcome,
+
+    /// The deployment this one rolled back to, if it was a rollback
+    pub rollback_of: Option<u64>,
+}
+
+impl DeploymentRecord {
+    /// Creates a record for a deployment which just finished, with its `id` unassigned
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name
+    /// * `version` - The application version
+    /// * `env` - The environment variables the application was deployed with
+    /// * `outcome` - How the deployment ended
+    pub fn new(
+        app: &str,
+        version: &str,
+        env: &HashMap<String, String>,
+        outcome: DeploymentOutcome,
+    ) -> DeploymentRecord {
+        DeploymentRecord {
+            id: 0,
+            app: String::from(app),
+            version: String::from(version),
+            image_id: None,
+            container_id: None,
+            ports: vec![],
+            env_hash: hash_env(env),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0),
+            outcome,
+            rollback_of: None,
+        }
+    }
+
+    /// Whether the deployment left its version serving
+    pub fn succeeded(&self) -> bool {
+        self.outcome == DeploymentOutcome::Succeeded
+    }
+}
+
+/// Hashes a set of environment variables, independent of their order
+///
+/// This is the SHA-256 of the variables as a JSON object sorted by name, so it stays the same across builds and restarts.
+pub fn hash_env(env: &HashMap<String, String>) -> String {
+    let sorted: BTreeMap<&String, &String> = env.iter().collect();
+    let json = serde_json::to_vec(&sorted).expect("a map of strings is valid JSON");
+    to_hex(&sha256(&json))
+}
+
+/// Every deployment made by the broker, oldest first
+///
+/// A history created with `persisted` also writes each deployment to a `StateStore`, so it survives restarts.
+///
+/// # Examples
+///
+/// ```
+/// let history = DeploymentHistory::new();
+/// deploy(&docker, &history, "./tmp/scapegoat", 9000).await?;
+/// for record in history.for_app("scapegoat") {
+///     println!("{} {} {:?}", record.id, record.version, record.outcome);
+/// }
+/// ```
+#[derive(Default)]
+pub struct DeploymentHistory {
+    records: Mutex<Vec<DeploymentRecord>>,
+    store: Option<Arc<StateStore>>,
+}
+
+impl DeploymentHistory {
+    pub fn new() -> DeploymentHistory {
+        DeploymentHistory::default()
+    }
+
+    /// Creates a history from previously recorded deployments
+    pub fn from_records(records: Vec<DeploymentRecord>) -> DeploymentHistory {
+        DeploymentHistory {
+            records: Mutex::new(records),
+            store: None,
+        }
+    }
+
+    /// Creates a history backed by a state store, starting from the deployments already in it
+    pub fn persisted(store: Arc<StateStore>) -> DeploymentHistory {
+        let mut records = store.deployments();
+        records.sort_by_key(|r| r.id);
+        DeploymentHistory {
+            records: Mutex::new(records),
+            store: Some(store),
+        }
+    }
+
+    /// Adds a deployment to the history, returning the id assigned to it
+    ///
+    /// The deployment has already happened, so failing to persist it is logged rather than returned.
+    /// The store is written without holding the history's lock, so readers aren't held up by the disk.
+    pub fn record(&self, mut record: DeploymentRecord) -> u64 {
+        {
+            let mut records = self.records.lock().unwrap();
+            // Concurrent deployments may reach the store out of order, so the newest isn't always last
+            record.id = records.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+            records.push(record.clone());
+        }
+        if let Some(store) = &self.store {
+            if let Err(e) = store.record_deployment(&record) {
+                error!("Failed to persist deployment {}: {}", record.id, e);
+            }
+        }
+        record.id
+    }
+
+    /// Gets every recorded deployment, oldest first
+    pub fn all(&self) -> Vec<DeploymentRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Gets a deployment by its id
+    pub fn get(&self, id: u64) -> Option<DeploymentRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|r| r.id == id)
+            .cloned()
+    }
+
+    /// Gets the deployments of an application, newest first
+    pub fn for_app(&self, app: &str) -> Vec<DeploymentRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|r| r.app == app)
+            .cloned()
+            .collect()
+    }
+
+    /// Gets the deployment currently serving an application
+    pub fn current(&self, app: &str) -> Option<DeploymentRecord> {
+        self.for_app(app).into_iter().find(|r| r.succeeded())
+    }
+
+    /// Gets the successful deployment of an application before the current one, which is where a rollback goes by default
+    pub fn previous(&self, app: &str) -> Option<DeploymentRecord> {
+        let current = self.current(app)?;
+        self.for_app(app)
+            .into_iter()
+            .filter(|r| r.succeeded() && r.id < current.id)
+            .find(|r| r.image_id != current.image_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(k, v)| (String::from(*k), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn hash_env_is_the_sha256_of_the_sorted_variables() {
+        assert_eq!(
+            hash_env(&HashMap::new()),
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(
+            hash_env(&env(&[("B", "2"), ("A", "1")])),
+            "3eda2f06741f1d082a60e34bf134dace8b60136ae69b552a2137bd898f27ea85"
+        );
+    }
+
+    #[test]
+    fn hash_env_tells_apart_variables_which_concatenate_alike() {
+        assert_ne!(
+            hash_env(&env(&[("A", "1B2")])),
+            hash_env(&env(&[("A", "1"), ("B", "2")]))
+        );
+        assert_ne!(hash_env(&env(&[("AB", "")])), hash_env(&env(&[("A", "B")])));
+    }
+}
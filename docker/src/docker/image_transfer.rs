@@ -0,0 +1,448 @@
+use bollard::image::{ImportImageOptions, ImportImageResults};
+use futures_util::future;
+use futures_util::stream::{Stream, StreamExt};
+use hyper::body::{Body, Bytes};
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use super::timeouts::with_timeout;
+use super::DockerBroker;
+
+/// Where the archives of single images are kept while several are merged into one
+const EXPORT_DIR: &str = "./tmp/exports";
+
+/// Files of a `docker save` archive which describe the images in it, and are merged rather than copied when archives are combined
+const INDEX_FILES: [&str; 3] = ["manifest.json", "repositories", "index.json"];
+
+/// How far an image export or import has got
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferProgress {
+    /// Some of an image's archive has been received from docker
+    Exporting {
+        /// The image being exported
+        image: String,
+
+        /// The bytes of the image's archive received so far
+        bytes: u64,
+    },
+
+    /// Some of an archive has been sent to docker to load
+    Sending {
+        /// The bytes of the archive sent so far
+        bytes: u64,
+    },
+
+    /// Docker has loaded an image from the archive
+    Loaded {
+        /// The image's `repository:tag`, or its ID if it was saved without one
+        image: String,
+    },
+}
+
+impl DockerBroker {
+    /// Saves images to a tar archive which `import_images` (or `docker load`) can load on another node
+    ///
+    /// Images which share layers only carry them once.
+    ///
+    /// # Arguments
+    ///
+    /// * `images` - The images to save, by ID or `repository:tag`
+    /// * `out` - Where the archive is written
+    /// * `progress` - Called as the archive of each image is received from docker
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let out = File::create("scapegoat.tar")?;
+    /// let bytes = docker
+    ///     .export_images(&["scapegoat:1.0.0"], out, &|p| println!("{:?}", p))
+    ///     .await?;
+    /// ```
+    pub async fn export_images<W: Write>(
+        &self,
+        images: &[&str],
+        mut out: W,
+        progress: &(dyn Fn(TransferProgress) + Sync),
+    ) -> Result<u64, String> {
+        match images {
+            [] => Err(String::from("No images to export")),
+            // A single image's archive is passed straight through
+            [image] => {
+                let bytes = self.export_image(image, &mut out, progress).await?;
+                out.flush()
+                    .map_err(|e| format!("Failed to write archive: {}", e))?;
+                Ok(bytes)
+            }
+            _ => {
+                let dir = Path::new(EXPORT_DIR).join(Uuid::new_v4().to_hyphenated().to_string());
+                fs::create_dir_all(&dir)
+                    .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+                let result = self.export_merged(images, &dir, &mut out, progress).await;
+                if let Err(e) = fs::remove_dir_all(&dir) {
+                    warn!("Failed to remove {}: {}", dir.display(), e);
+                }
+                result
+            }
+        }
+    }
+
+    /// Loads the images in a tar archive made by `export_images` or `docker save`
+    ///
+    /// Returns the images which were loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `archive` - The chunks of the archive, such as a file read in pieces or an HTTP body
+    /// * `progress` - Called as the archive is sent to docker, and as each image is loaded
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let archive = fs::read("scapegoat.tar")?;
+    /// let chunks = stream::iter(archive.chunks(64 * 1024).map(Ok::<_, String>));
+    /// let loaded = docker.import_images(chunks, &|p| println!("{:?}", p)).await?;
+    /// ```
+    pub async fn import_images<S, B, E>(
+        &self,
+        archive: S,
+        progress: &(dyn Fn(TransferProgress) + Sync),
+    ) -> Result<Vec<String>, String>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: AsRef<[u8]>,
+        E: Display,
+    {
+        let (mut sender, body) = Body::channel();
+        let send = async move {
+            let mut archive = archive;
+            let mut sent = 0;
+            while let Some(chunk) = archive.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => Bytes::copy_from_slice(chunk.as_ref()),
+                    Err(e) => {
+                        // Aborting makes docker see a broken body rather than a short archive
+                        sender.abort();
+                        return Err(format!("Failed to read archive: {}", e));
+                    }
+                };
+                sent += chunk.len() as u64;
+                if sender.send_data(chunk).await.is_err() {
+                    // Docker stopped reading, its response says why
+                    return Ok(());
+                }
+                progress(TransferProgress::Sending { bytes: sent });
+            }
+            Ok(())
+        };
+        let load = async {
+            let mut results =
+                self.conn
+                    .import_image(ImportImageOptions { quiet: true }, body, None);
+            let mut loaded = vec![];
+            while let Some(result) = results.next().await {
+                let ImportImageResults::ImportImageStream { stream } =
+                    result.map_err(|e| format!("Failed to load images: {:?}", e))?;
+                for line in stream.lines() {
+                    let image = line
+                        .strip_prefix("Loaded image: ")
+                        .or_else(|| line.strip_prefix("Loaded image ID: "));
+                    if let Some(image) = image {
+                        info!("Loaded image {}", image);
+                        loaded.push(String::from(image));
+                        progress(TransferProgress::Loaded {
+                            image: String::from(image),
+                        });
+                    }
+                }
+            }
+            Ok::<_, String>(loaded)
+        };
+
+        let (sent, loaded) = with_timeout("load images", self.timeouts.transfer, async {
+            Ok(future::join(send, load).await)
+        })
+        .await?;
+        sent?;
+        let loaded = loaded?;
+        if loaded.is_empty() {
+            return Err(String::from("The archive held no images"));
+        }
+        Ok(loaded)
+    }
+
+    /// Writes the archive of a single image, returning its size
+    async fn export_image(
+        &self,
+        image: &str,
+        out: &mut dyn Write,
+        progress: &(dyn Fn(TransferProgress) + Sync),
+    ) -> Result<u64, String> {
+        info!("Exporting image {}", image);
+        with_timeout("save image", self.timeouts.transfer, async {
+            let mut chunks = self.conn.export_image(image);
+            let mut bytes = 0;
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk.map_err(|e| format!("Failed to export {}: {:?}", image, e))?;
+                out.write_all(&chunk)
+                    .map_err(|e| format!("Failed to write archive of {}: {}", image, e))?;
+                bytes += chunk.len() as u64;
+                progress(TransferProgress::Exporting {
+                    image: String::from(image),
+                    bytes,
+                });
+            }
+            Ok(bytes)
+        })
+        .await
+    }
+
+    /// Exports each image into `dir`, then merges their archives into `out`
+    async fn export_merged(
+        &self,
+        images: &[&str],
+        dir: &Path,
+        out: &mut dyn Write,
+        progress: &(dyn Fn(TransferProgress) + Sync),
+    ) -> Result<u64, String> {
+        let mut parts = vec![];
+        for (i, image) in images.iter().enumerate() {
+            let part = dir.join(format!("{}.tar", i));
+            let mut file = File::create(&part)
+                .map_err(|e| format!("Failed to create {}: {}", part.display(), e))?;
+            self.export_image(image, &mut file, progress).await?;
+            parts.push(part);
+        }
+        let mut counter = CountingWriter {
+            inner: out,
+            bytes: 0,
+        };
+        merge_archives(&parts, &mut counter)
+            .map_err(|e| format!("Failed to merge image archives: {}", e))?;
+        Ok(counter.bytes)
+    }
+}
+
+/// Combines `docker save` archives into one, keeping one copy of shared layers and merging the files listing the images
+fn merge_archives(parts: &[PathBuf], out: &mut dyn Write) -> std::io::Result<()> {
+    let mut builder = tar::Builder::new(out);
+    let mut written = HashSet::new();
+    let mut manifest: Vec<Value> = vec![];
+    let mut repositories = serde_json::Map::new();
+    let mut index: Option<Value> = None;
+
+    for part in parts {
+        let mut archive = tar::Archive::new(File::open(part)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let name = path.to_string_lossy().into_owned();
+            if INDEX_FILES.contains(&name.as_str()) {
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents)?;
+                let value: Value = serde_json::from_slice(&contents)?;
+                match (name.as_str(), value) {
+                    ("manifest.json", Value::Array(images)) => manifest.extend(images),
+                    ("repositories", Value::Object(repos)) => {
+                        for (repo, tags) in repos {
+                            match (repositories.get_mut(&repo), tags) {
+                                (Some(Value::Object(existing)), Value::Object(tags)) => {
+                                    existing.extend(tags)
+                                }
+                                (_, tags) => {
+                                    repositories.insert(repo, tags);
+                                }
+                            }
+                        }
+                    }
+                    ("index.json", value) => index = Some(merge_index(index, value)),
+                    _ => {}
+                }
+                continue;
+            }
+            // Layers shared between images appear in each of their archives
+            if !written.insert(name) {
+                continue;
+            }
+            let mut header = entry.header().clone();
+            match entry.link_name()? {
+                Some(target) if header.entry_type().is_symlink() => {
+                    let target = target.into_owned();
+                    builder.append_link(&mut header, &path, target)?
+                }
+                _ => builder.append_data(&mut header, &path, &mut entry)?,
+            }
+        }
+    }
+
+    append_json(&mut builder, "manifest.json", &Value::Array(manifest))?;
+    if !repositories.is_empty() {
+        append_json(&mut builder, "repositories", &Value::Object(repositories))?;
+    }
+    if let Some(index) = index {
+        append_json(&mut builder, "index.json", &index)?;
+    }
+    builder.finish()
+}
+
+/// Merges the `manifests` of an OCI `index.json` into another's
+fn merge_index(existing: Option<Value>, next: Value) -> Value {
+    match existing {
+        None => next,
+        Some(mut existing) => {
+            let more = next
+                .get("manifests")
+                .and_then(|m| m.as_array())
+                .cloned()
+                .unwrap_or_default();
+            if let Some(Value::Array(manifests)) = existing.get_mut("manifests") {
+                manifests.extend(more);
+            }
+            existing
+        }
+    }
+}
+
+fn append_json(
+    builder: &mut tar::Builder<&mut dyn Write>,
+    path: &str,
+    value: &Value,
+) -> std::io::Result<()> {
+    let contents = serde_json::to_vec(value)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, contents.as_slice())
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    bytes: u64,
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    /// Writes a `docker save` archive of a `scapegoat` image with a layer of its own and one shared with every other
+    fn save_archive(path: &Path, version: &str) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        let mut append = |name: &str, contents: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, contents).unwrap();
+        };
+        let config = format!("{}.json", version);
+        let layer = format!("{}/layer.tar", version);
+        let manifest = json!([{
+            "Config": config,
+            "RepoTags": [format!("scapegoat:{}", version)],
+            "Layers": ["shared/layer.tar", layer],
+        }]);
+        let repositories = json!({ "scapegoat": { version: version } });
+        let index = json!({
+            "schemaVersion": 2,
+            "manifests": [{ "digest": format!("sha256:{}", version) }],
+        });
+
+        append("shared/layer.tar", b"base layer");
+        append(&layer, version.as_bytes());
+        append(&config, b"{}");
+        append("manifest.json", manifest.to_string().as_bytes());
+        append("repositories", repositories.to_string().as_bytes());
+        append("index.json", index.to_string().as_bytes());
+
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        builder
+            .append_link(&mut link, "blobs/base", "../shared/layer.tar")
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn merged_archives_list_every_image_and_keep_one_copy_of_each_layer() {
+        let dir = std::env::temp_dir().join(format!("kraken-merge-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let parts = vec![dir.join("0.tar"), dir.join("1.tar")];
+        save_archive(&parts[0], "1.0.0");
+        save_archive(&parts[1], "2.0.0");
+
+        let mut merged = vec![];
+        merge_archives(&parts, &mut merged).unwrap();
+
+        let mut entries = HashMap::new();
+        let mut names = vec![];
+        let mut archive = tar::Archive::new(merged.as_slice());
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            if let Some(target) = entry.link_name().unwrap() {
+                assert_eq!(target.to_string_lossy(), "../shared/layer.tar");
+            }
+            let mut contents = vec![];
+            entry.read_to_end(&mut contents).unwrap();
+            names.push(name.clone());
+            entries.insert(name, contents);
+        }
+        let unique: HashSet<&String> = names.iter().collect();
+        assert_eq!(
+            unique.len(),
+            names.len(),
+            "duplicated entries in {:?}",
+            names
+        );
+        assert_eq!(entries["shared/layer.tar"], b"base layer");
+        assert_eq!(entries["1.0.0/layer.tar"], b"1.0.0");
+        assert_eq!(entries["2.0.0/layer.tar"], b"2.0.0");
+        assert!(entries.contains_key("blobs/base"));
+
+        let manifest: Value = serde_json::from_slice(&entries["manifest.json"]).unwrap();
+        let tags: Vec<&Value> = manifest
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| &image["RepoTags"][0])
+            .collect();
+        assert_eq!(tags, vec!["scapegoat:1.0.0", "scapegoat:2.0.0"]);
+        assert_eq!(
+            manifest[1]["Layers"],
+            json!(["shared/layer.tar", "2.0.0/layer.tar"])
+        );
+
+        let repositories: Value = serde_json::from_slice(&entries["repositories"]).unwrap();
+        assert_eq!(
+            repositories,
+            json!({ "scapegoat": { "1.0.0": "1.0.0", "2.0.0": "2.0.0" } })
+        );
+        let index: Value = serde_json::from_slice(&entries["index.json"]).unwrap();
+        assert_eq!(index["manifests"].as_array().unwrap().len(), 2);
+    }
+}
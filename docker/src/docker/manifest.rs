@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// The name of the manifest file expected at the root of every deployable project
+pub const MANIFEST_FILE_NAME: &str = "shipwreck.toml";
+
+/// The parsed contents of a project's `shipwreck.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShipwreckManifest {
+    /// Identifying information about the application
+    pub app: AppSection,
+
+    /// How the application is built, tested and run
+    pub config: ConfigSection,
+
+    /// Environment variables to provide to the running application
+    #[serde(rename = "env-vars", default)]
+    pub env_vars: HashMap<String, String>,
+
+    /// The containers making up the application when it is more than one, keyed by service name
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceSection>,
+}
+
+/// The `[app]` table of a `shipwreck.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppSection {
+    /// The name of the application, used to group its images and containers
+    pub name: String,
+
+    /// The version of the application (e.g. `1.0.0`)
+    pub version: String,
+
+    /// The author of the application
+    #[serde(default)]
+    pub author: String,
+
+    /// Where the source for the application lives (e.g. a git remote)
+    #[serde(default)]
+    pub endpoint: String,
+}
+
+/// The `[config]` table of a `shipwreck.toml`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigSection {
+    /// The language the application is written in
+    #[serde(default)]
+    pub lang: String,
+
+    /// The command used to test the application, empty if there is none
+    #[serde(default)]
+    pub test: String,
+
+    /// The command used to run the application
+    #[serde(default)]
+    pub run: String,
+
+    /// The port the application listens on inside its container, the image's `EXPOSE` if this is not set
+    #[serde(default)]
+    pub port: Option<i64>,
+}
+
+/// A `[services.<name>]` table of a `shipwreck.toml`, one container of a multi-service stack
+///
+/// # Examples
+///
+/// ```toml
+/// [services.web]
+/// build = "."
+/// ports = [9000]
+/// depends-on = ["redis"]
+///
+/// [services.worker]
+/// build = "."
+/// command = "python worker.py"
+/// depends-on = ["redis"]
+///
+/// [services.redis]
+/// image = "redis:6"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceSection {
+    /// An existing image to run (e.g. `redis:6`), pulled if it isn't present
+    #[serde(default)]
+    pub image: Option<String>,
+
+    /// A folder to build the image from, relative to the `shipwreck.toml`
+    #[serde(default)]
+    pub build: Option<String>,
+
+    /// A command to run instead of the image's default, through `sh -c`
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// The ports to publish, each mapped to the same port on the host
+    #[serde(default)]
+    pub ports: Vec<i64>,
+
+    /// Environment variables for this service, on top of the manifest's `env-vars`
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// Services which must be started before this one
+    #[serde(rename = "depends-on", default)]
+    pub depends_on: Vec<String>,
+
+    /// The stack networks to attach to, all services share the `default` network if this is empty
+    #[serde(default)]
+    pub networks: Vec<String>,
+}
+
+impl ShipwreckManifest {
+    /// Parses a manifest from the contents of a `shipwreck.toml`
+    pub fn parse(contents: &str) -> Result<ShipwreckManifest, String> {
+        toml::from_str(contents).map_err(|e| format!("Invalid {}: {}", MANIFEST_FILE_NAME, e))
+    }
+
+    /// Orders the services so each one comes after everything it depends on
+    ///
+    /// Fails if a service has both or neither of `image` and `build`, depends on an unknown service, or is part of a dependency cycle.
+    pub fn service_order(&self) -> Result<Vec<String>, String> {
+        for (name, service) in &self.services {
+            if service.image.is_some() == service.build.is_some() {
+                return Err(format!(
+                    "Service {} needs exactly one of `image` or `build`",
+                    name
+                ));
+            }
+            if let Some(d) = service
+                .depends_on
+                .iter()
+                .find(|d| !self.services.contains_key(*d))
+            {
+                return Err(format!("Service {} depends on unknown service {}", name, d));
+            }
+        }
+
+        let mut order: Vec<String> = vec![];
+        while order.len() < self.services.len() {
+            // Services are visited by name, so the order is the same on every run
+            let ready = self.services.iter().find(|(name, service)| {
+                !order.contains(name) && service.depends_on.iter().all(|d| order.contains(d))
+            });
+            match ready {
+                Some((name, _)) => order.push(name.clone()),
+                None => {
+                    let stuck: Vec<&String> = self
+                        .services
+                        .keys()
+                        .filter(|n| !order.contains(n))
+                        .collect();
+                    return Err(format!("Services {:?} depend on each other", stuck));
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Reads the manifest from the root of a project directory
+    ///
+    /// # Arguments
+    ///
+    /// * `project_path` - The directory containing the `shipwreck.toml`
+    pub fn from_dir(project_path: &str) -> Result<ShipwreckManifest, String> {
+        let path = Path::new(project_path).join(MANIFEST_FILE_NAME);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        ShipwreckManifest::parse(&contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(services: &str) -> ShipwreckManifest {
+        ShipwreckManifest::parse(&format!(
+            "[app]\nname = \"scapegoat\"\nversion = \"1.0.0\"\n\n[config]\n\n{}",
+            services
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn services_come_after_their_dependencies() {
+        let manifest = stack(
+            "[services.web]\nbuild = \".\"\ndepends-on = [\"redis\", \"worker\"]\n\n\
+             [services.worker]\nbuild = \".\"\ndepends-on = [\"redis\"]\n\n\
+             [services.redis]\nimage = \"redis:6\"\n\n\
+             [services.adminer]\nimage = \"adminer\"\n",
+        );
+
+        assert_eq!(
+            manifest.service_order(),
+            Ok(vec![
+                String::from("adminer"),
+                String::from("redis"),
+                String::from("worker"),
+                String::from("web"),
+            ])
+        );
+    }
+
+    #[test]
+    fn cycles_and_unknown_dependencies_are_refused() {
+        let cycle = stack(
+            "[services.a]\nimage = \"a\"\ndepends-on = [\"b\"]\n\n\
+             [services.b]\nimage = \"b\"\ndepends-on = [\"a\"]\n\n\
+             [services.c]\nimage = \"c\"\n",
+        );
+        assert_eq!(
+            cycle.service_order(),
+            Err(String::from("Services [\"a\", \"b\"] depend on each other"))
+        );
+
+        let unknown = stack("[services.web]\nbuild = \".\"\ndepends-on = [\"db\"]\n");
+        assert_eq!(
+            unknown.service_order(),
+            Err(String::from("Service web depends on unknown service db"))
+        );
+    }
+
+    #[test]
+    fn services_need_exactly_one_image_source() {
+        let both = stack("[services.web]\nbuild = \".\"\nimage = \"web\"\n");
+        let neither = stack("[services.web]\nports = [9000]\n");
+        let error = Err(String::from(
+            "Service web needs exactly one of `image` or `build`",
+        ));
+
+        assert_eq!(both.service_order(), error);
+        assert_eq!(neither.service_order(), error);
+        assert_eq!(stack("").service_order(), Ok(vec![]));
+    }
+}
@@ -1,49 +1,170 @@
-use bollard::image::ListImagesOptions;
+use async_trait::async_trait;
+use bollard::errors::ErrorKind;
+use bollard::image::{ListImagesOptions, RemoveImageOptions};
 use bollard::{
     container::{
-        Config, CreateContainerOptions, ListContainersOptions, PruneContainersOptions,
-        StartContainerOptions, StopContainerOptions,
+        Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions,
+        LogsOptions, PruneContainersOptions, RemoveContainerOptions, StartContainerOptions,
+        StatsOptions, StopContainerOptions, WaitContainerOptions,
     },
-    image::{BuildImageOptions, PruneImagesOptions},
+    image::{BuildImageOptions, BuildImageResults, PruneImagesOptions},
     service::{HostConfig, PortBinding},
-    Docker,
 };
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use futures_util::stream::StreamExt;
-use log::{error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use uuid::Uuid;
 
+pub mod build_queue;
+pub mod cancellation;
+pub mod connection;
+pub mod container_stats;
+pub mod deploy;
+pub mod desired_state;
+pub mod disk_guard;
 pub mod docker_container;
+pub mod docker_image;
+pub mod endpoints;
+#[cfg(test)]
+pub mod fake_runtime;
+pub mod git_source;
+pub mod history;
+pub mod image_transfer;
+pub mod manifest;
+pub mod process_runtime;
+pub mod readiness;
+pub mod registry;
+pub mod retention;
+pub mod runtime;
+pub mod source_archive;
+pub mod stack;
+pub mod state_store;
+pub mod system_info;
+pub mod timeouts;
 
-use docker_container::DockerContainer;
+use cancellation::CancellationHandle;
+use connection::{ConnectionConfig, DockerHost};
+use container_stats::ContainerStats;
+use disk_guard::DiskGuard;
+use docker_container::{ContainerQuery, DockerContainer, DockerContainerDetails};
+use docker_image::{DockerImage, DockerImageDetails, DockerImageLayer, ImageQuery};
+use manifest::ShipwreckManifest;
+use retention::{RetentionPolicy, RetentionReport};
+use runtime::ContainerRuntime;
+use stack::ServiceContainer;
+use timeouts::{with_timeout, OperationTimeouts};
+
+/// Label marking an image or container as owned by this broker
+pub const MANAGED_LABEL: &str = "kraken.managed";
+
+/// Label holding the `app.name` from the `shipwreck.toml` an image was built from
+pub const APP_LABEL: &str = "kraken.app";
+
+/// Label holding the `app.version` from the `shipwreck.toml` an image was built from
+pub const VERSION_LABEL: &str = "kraken.version";
+
+/// Label holding the `config.port` from the `shipwreck.toml` an image was built from, the port its application listens on
+pub const PORT_LABEL: &str = "kraken.port";
+
+/// Label holding the name of the `shipwreck.toml` service a container runs
+pub const SERVICE_LABEL: &str = "kraken.service";
+
+/// Label holding the git commit SHA an image was built from
+pub const COMMIT_LABEL: &str = "kraken.commit";
+
+/// Label marking an image as built by the `Reconciler`, whose containers it alone starts and stops
+pub const DESIRED_LABEL: &str = "kraken.desired";
 
 /// The interface between Kraken and Docker
 pub struct DockerBroker {
     /// Connection to the Rabbit Instance (Should be one per device)
     pub conn: bollard::Docker,
+
+    /// How long to wait for each kind of operation before giving up
+    pub timeouts: OperationTimeouts,
+
+    /// Where the daemon is listening, for requests bollard can't make
+    pub host: DockerHost,
+
+    /// How much free disk builds need, or `None` to build regardless
+    pub disk_guard: Option<DiskGuard>,
 }
 
 impl DockerBroker {
+    /// Connects to the docker daemon described by the `DOCKER_*` environment variables
+    ///
+    /// See `ConnectionConfig::from_env` for the variables which are read. Without any of them set, this connects to the default unix socket.
     pub async fn new() -> Option<DockerBroker> {
-        let conn = Docker::connect_with_unix_defaults();
-        match conn {
-            Ok(c) => {
-                let version = c.version().await;
-                info!("Docker {} connection established", version.unwrap().version);
-                Some(DockerBroker { conn: c })
-            }
+        let connection = match ConnectionConfig::from_env() {
+            Ok(config) => DockerBroker::connect(&config).await,
+            Err(e) => Err(e),
+        };
+        match connection {
+            Ok(broker) => Some(broker),
             Err(e) => {
-                error!("Error establishing conn: {:?}", e);
+                error!("Error establishing conn: {}", e);
                 None
             }
         }
     }
 
+    /// Connects to a docker daemon
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Where the daemon is listening and how to talk to it
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let config = ConnectionConfig {
+    ///     host: DockerHost::Unix(PathBuf::from("/run/user/1000/docker.sock")),
+    ///     ..Default::default()
+    /// };
+    /// let docker = DockerBroker::connect(&config).await?;
+    /// ```
+    pub async fn connect(config: &ConnectionConfig) -> Result<DockerBroker, String> {
+        let mut conn = config.client()?;
+        if config.negotiate_version {
+            conn = conn
+                .negotiate_version()
+                .await
+                .map_err(|e| format!("Failed to reach docker at {:?}: {:?}", config.host, e))?;
+        }
+        let version = conn
+            .version()
+            .await
+            .map_err(|e| format!("Failed to reach docker at {:?}: {:?}", config.host, e))?;
+        info!(
+            "Docker {} connection established (API {})",
+            version.version, version.api_version
+        );
+        Ok(DockerBroker {
+            conn,
+            timeouts: OperationTimeouts::default(),
+            host: config.host.clone(),
+            disk_guard: None,
+        })
+    }
+
+    /// Replaces the timeouts used for each kind of operation
+    pub fn with_timeouts(mut self, timeouts: OperationTimeouts) -> DockerBroker {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Checks for enough free disk before each build, pruning or refusing the build when there isn't (see `DockerBroker::ensure_disk_space`)
+    pub fn with_disk_guard(mut self, guard: DiskGuard) -> DockerBroker {
+        self.disk_guard = Some(guard);
+        self
+    }
+
     /// Gets a list of existing docker images
     ///
     /// # Examples
@@ -56,22 +177,163 @@ impl DockerBroker {
     /// }
     /// ```
     pub async fn get_image_ids(&self) -> Vec<String> {
-        let images = self
-            .conn
-            .list_images(Some(ListImagesOptions::<String> {
-                all: true,
-                ..Default::default()
-            }))
-            .await
-            .unwrap();
+        match self.list_images(&ImageQuery::new().all()).await {
+            Ok(images) => images.into_iter().map(|i| i.id).collect(),
+            Err(e) => {
+                error!("{}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// Gets a list of docker images matching a query
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Filters restricting which images are returned
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let images = docker.list_images(&ImageQuery::new().app("scapegoat"));
+    /// for i in images {
+    ///     println!("{} {:?} {} bytes", i.id, i.repo_tags, i.size);
+    /// }
+    /// ```
+    pub async fn list_images(&self, query: &ImageQuery) -> Result<Vec<DockerImage>, String> {
+        let images = with_timeout("list images", self.timeouts.list, async {
+            self.conn
+                .list_images(Some(ListImagesOptions {
+                    all: query.all,
+                    filters: query.filters(),
+                    ..Default::default()
+                }))
+                .await
+                .map_err(|e| format!("Failed to list images: {:?}", e))
+        })
+        .await?;
 
-        let mut ids = vec![];
+        Ok(images.into_iter().map(DockerImage::from).collect())
+    }
 
-        for image in images {
-            println!("-> {:?}", image);
-            ids.push(image.id);
+    /// Gets the full details of a docker image
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let details = docker.inspect_image("12345");
+    /// println!("{:?} runs {:?}", details.exposed_ports, details.cmd);
+    /// ```
+    pub async fn inspect_image(&self, image: &str) -> Result<DockerImageDetails, String> {
+        with_timeout("inspect image", self.timeouts.inspect, async {
+            self.conn
+                .inspect_image(image)
+                .await
+                .map(DockerImageDetails::from)
+                .map_err(|e| format!("Failed to inspect image {}: {:?}", image, e))
+        })
+        .await
+    }
+
+    /// Checks whether an image exists
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    pub async fn image_exists(&self, image: &str) -> Result<bool, String> {
+        with_timeout("inspect image", self.timeouts.inspect, async {
+            match self.conn.inspect_image(image).await {
+                Ok(_) => Ok(true),
+                Err(e) => match e.kind() {
+                    ErrorKind::DockerResponseNotFoundError { .. } => Ok(false),
+                    _ => Err(format!("Failed to inspect image {}: {:?}", image, e)),
+                },
+            }
+        })
+        .await
+    }
+
+    /// Removes an image which no container uses any more
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    pub async fn remove_image(&self, image: &str) -> Result<(), String> {
+        with_timeout("remove image", self.timeouts.prune, async {
+            self.conn
+                .remove_image(image, None::<RemoveImageOptions>, None)
+                .await
+                .map_err(|e| format!("Failed to remove image {}: {:?}", image, e))
+        })
+        .await?;
+        info!("Removed image {}", image);
+        Ok(())
+    }
+
+    /// Gets the port the application in an image listens on, from its `PORT_LABEL` or else its `EXPOSE`
+    ///
+    /// Fails if the image has neither, or exposes several ports and has no label to pick one.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let port = docker.container_port("12345").await?; // 9000 for `EXPOSE 9000`
+    /// ```
+    pub async fn container_port(&self, image: &str) -> Result<i64, String> {
+        let details = self.inspect_image(image).await?;
+        if let Some(port) = details.labels.get(PORT_LABEL) {
+            return port
+                .parse()
+                .map_err(|_| format!("Image {} has an invalid {} {}", image, PORT_LABEL, port));
+        }
+        let exposed: Vec<i64> = details
+            .exposed_ports
+            .iter()
+            .filter_map(|p| p.strip_suffix("/tcp"))
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        match exposed.as_slice() {
+            [port] => Ok(*port),
+            [] => Err(format!(
+                "Image {} doesn't say which port it listens on, set `port` in the [config] of its {} or EXPOSE it in its Dockerfile",
+                image,
+                manifest::MANIFEST_FILE_NAME
+            )),
+            _ => Err(format!(
+                "Image {} exposes ports {:?}, set `port` in the [config] of its {} to pick one",
+                image,
+                exposed,
+                manifest::MANIFEST_FILE_NAME
+            )),
         }
-        ids
+    }
+
+    /// Gets the layers which make up a docker image, newest first
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    pub async fn image_history(&self, image: &str) -> Result<Vec<DockerImageLayer>, String> {
+        let history = with_timeout("image history", self.timeouts.inspect, async {
+            self.conn
+                .image_history(image)
+                .await
+                .map_err(|e| format!("Failed to get history of image {}: {:?}", image, e))
+        })
+        .await?;
+
+        Ok(history.into_iter().map(DockerImageLayer::from).collect())
     }
 
     /// Gets a list of running docker containers
@@ -86,46 +348,87 @@ impl DockerBroker {
     /// }
     /// ```
     pub async fn get_running_containers(&self) -> Vec<DockerContainer> {
-        let cs = self
-            .conn
-            .list_containers(Some(ListContainersOptions::<String> {
-                ..Default::default()
-            }))
-            .await
-            .unwrap();
-
-        let mut containers = vec![];
-
-        for c in cs {
-            let id = c.id.unwrap();
-            let name = match c.names {
-                // Get first name from vector and drop first character (they all start with /?)
-                Some(names) => String::from(&names[0][1..]),
-                None => String::from(""),
-            };
-            let ports = match c.ports {
-                Some(ps) => {
-                    let mut v = vec![];
-                    for p in ps {
-                        if let Some(port) = p.public_port {
-                            v.push(port);
-                        }
-                    }
-                    Some(v)
-                }
-                None => None,
-            };
-            containers.push(DockerContainer::new(
-                id, name, c.image, c.image_id, c.created, ports, c.state, c.status,
-            ));
+        match self.list_containers(&ContainerQuery::new()).await {
+            Ok(containers) => containers,
+            Err(e) => {
+                error!("{}", e);
+                vec![]
+            }
         }
+    }
 
-        containers
+    /// Gets a list of docker containers matching a query
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Filters restricting which containers are returned, and in what order
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let dead = docker.list_containers(&ContainerQuery::new().all().status("exited"));
+    /// for c in dead {
+    ///     println!("{} {:?}", c.name, c.status);
+    /// }
+    /// ```
+    pub async fn list_containers(
+        &self,
+        query: &ContainerQuery,
+    ) -> Result<Vec<DockerContainer>, String> {
+        let cs = with_timeout("list containers", self.timeouts.list, async {
+            self.conn
+                .list_containers(Some(ListContainersOptions {
+                    all: query.all,
+                    filters: query.filters(),
+                    ..Default::default()
+                }))
+                .await
+                .map_err(|e| format!("Failed to list containers: {:?}", e))
+        })
+        .await?;
+
+        let mut containers = cs.into_iter().map(DockerContainer::from).collect();
+        query.apply(&mut containers);
+        Ok(containers)
+    }
+
+    /// Gets the full runtime details of a docker container
+    ///
+    /// Secret-looking environment variables (passwords, tokens, keys...) have their values masked.
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let details = docker.inspect_container("12345");
+    /// if details.state.oom_killed {
+    ///     println!("{} ran out of memory", details.name);
+    /// }
+    /// ```
+    pub async fn inspect_container(
+        &self,
+        container_id: &str,
+    ) -> Result<DockerContainerDetails, String> {
+        with_timeout("inspect container", self.timeouts.inspect, async {
+            self.conn
+                .inspect_container(container_id, None::<InspectContainerOptions>)
+                .await
+                .map(DockerContainerDetails::from)
+                .map_err(|e| format!("Failed to inspect container {}: {:?}", container_id, e))
+        })
+        .await
     }
 
     /// Builds a docker image from a local project folder
     ///
     /// This will create a `/tmp/containers` directory if it doesn't exist to store a tar of the project before building the image.
+    /// The image is labelled with `MANAGED_LABEL`, and with `APP_LABEL` and `VERSION_LABEL` if the project has a `shipwreck.toml` (and `PORT_LABEL` if it sets `config.port`).
+    /// With a `DiskGuard` set, the build is refused if there isn't enough free disk even after pruning (see `DockerBroker::ensure_disk_space`).
     /// # Arguments
     ///
     /// * `source_path` - The path relative to the root of the crate which contains the desired image contents. A `Dockerfile` is expected to be in this folder.
@@ -137,82 +440,276 @@ impl DockerBroker {
     /// docker.build_image("./tmp/test-proj"); // builds image 12345 and maps 9000->9000
     /// ```
     pub async fn build_image(&self, source_path: &str) -> Result<DockerImageBuildResult, String> {
+        self.build_image_cancellable(source_path, &CancellationHandle::new())
+            .await
+    }
+
+    /// Builds a docker image from a local project folder, stopping early if `cancel` is triggered
+    ///
+    /// Cancelling (or hitting the build timeout) aborts the build stream, which makes docker stop the build and remove its intermediate containers.
+    /// The partially tagged image and the project tarball are removed as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` - The path relative to the root of the crate which contains the desired image contents. A `Dockerfile` is expected to be in this folder.
+    /// * `cancel` - A handle which can be used from another task to abandon the build
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let cancel = CancellationHandle::new();
+    /// let build = docker.build_image_cancellable("scapegoat", &cancel);
+    /// cancel.cancel(); // the build returns Err("Build cancelled")
+    /// ```
+    pub async fn build_image_cancellable(
+        &self,
+        source_path: &str,
+        cancel: &CancellationHandle,
+    ) -> Result<DockerImageBuildResult, String> {
+        self.build_image_with_labels(source_path, &HashMap::new(), cancel)
+            .await
+    }
+
+    /// Builds a docker image like `build_image_cancellable`, adding `extra_labels` to the usual app labels
+    async fn build_image_with_labels(
+        &self,
+        source_path: &str,
+        extra_labels: &HashMap<&str, &str>,
+        cancel: &CancellationHandle,
+    ) -> Result<DockerImageBuildResult, String> {
+        self.ensure_disk_space().await?;
         let container_guid = Uuid::new_v4().to_hyphenated().to_string();
+        let manifest = match ShipwreckManifest::from_dir(source_path) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                warn!("Building {} without app labels: {}", source_path, e);
+                None
+            }
+        };
+        let port = manifest
+            .as_ref()
+            .and_then(|m| m.config.port)
+            .map(|p| p.to_string());
+        let mut labels = HashMap::new();
+        labels.insert(MANAGED_LABEL, "true");
+        if let Some(m) = &manifest {
+            labels.insert(APP_LABEL, &m.app.name);
+            labels.insert(VERSION_LABEL, &m.app.version);
+        }
+        if let Some(port) = &port {
+            labels.insert(PORT_LABEL, port);
+        }
+        labels.extend(extra_labels);
+        let tar_path = format!("./tmp/containers/{}.tar.gz", &container_guid);
         // tar the directory
         let make_tar = || -> Result<(), std::io::Error> {
             // Create directory tree if it doesn't exist
             fs::create_dir_all("./tmp/containers")?;
-            let tar_gz = File::create(format!("./tmp/containers/{}.tar.gz", &container_guid))?;
+            let tar_gz = File::create(&tar_path)?;
             let enc = GzEncoder::new(tar_gz, Compression::default());
             let mut tar = tar::Builder::new(enc);
             tar.append_dir_all(".", source_path)?;
             tar.into_inner()?;
             Ok(())
         };
-        match make_tar() {
-            Ok(_) => {
-                info!("Tar for {} completed succesfully", source_path);
-                let mut log = vec![];
-                let build_result: Result<(), String> = async {
-                    let mut file =
-                        File::open(format!("./tmp/containers/{}.tar.gz", &container_guid))
-                            .expect("Could not find tarball");
-                    let mut contents = Vec::new();
-                    file.read_to_end(&mut contents)
-                        .expect("Failed to read tarball");
-
-                    info!("Building docker image [{}]", &container_guid);
-
-                    let mut build_results = self.conn.build_image(
-                        BuildImageOptions {
-                            dockerfile: "Dockerfile",
-                            t: &container_guid,
-                            rm: true,
-                            ..Default::default()
-                        },
-                        None,
-                        Some(contents.into()),
-                    );
-
-                    while let Some(result) = build_results.next().await {
-                        if let Ok(stage) = result {
-                            match stage {
-                                bollard::image::BuildImageResults::BuildImageStream { stream } => {
-                                    let data = str::replace(&stream, "\n", "");
-                                    if data.len() > 0 {
-                                        log.push(data);
-                                    }
-                                }
-                                _ => {
-                                    // TODO figure out what to do with other results
-                                    // BuildImageAux is called right before the final Stream message
-                                }
-                            }
-                        } else {
-                            // TODO figure out what to do with Err
+        if let Err(e) = make_tar() {
+            error!("Failed to tar source from path {}", source_path);
+            // Don't leave a half-written tarball behind
+            let _ = fs::remove_file(&tar_path);
+            return Err(format!("{:?}", e));
+        }
+        info!("Tar for {} completed succesfully", source_path);
+
+        let mut log = vec![];
+        let build = async {
+            let mut file = File::open(&tar_path)
+                .map_err(|e| format!("Could not find tarball {}: {}", tar_path, e))?;
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)
+                .map_err(|e| format!("Failed to read tarball {}: {}", tar_path, e))?;
+
+            info!("Building docker image [{}]", &container_guid);
+
+            let mut build_results = self.conn.build_image(
+                BuildImageOptions {
+                    dockerfile: "Dockerfile",
+                    t: &container_guid,
+                    rm: true,
+                    // Remove intermediate containers even if the build fails or is aborted
+                    forcerm: true,
+                    labels: labels.clone(),
+                    ..Default::default()
+                },
+                None,
+                Some(contents.into()),
+            );
+
+            while let Some(result) = build_results.next().await {
+                // BuildImageAux is called right before the final Stream message
+                match result {
+                    Ok(BuildImageResults::BuildImageStream { stream }) => {
+                        let data = str::replace(&stream, "\n", "");
+                        if !data.is_empty() {
+                            log.push(data);
                         }
                     }
-                    Ok(())
-                }
-                .await;
-                match build_result {
-                    Ok(_) => Ok(DockerImageBuildResult {
-                        log: log,
-                        image_id: container_guid.clone(),
-                    }),
-                    Err(e) => {
-                        error!("Error building container {}: {}", &container_guid, &e);
-                        Err(String::from("Failed to build image"))
-                    }
+                    Ok(BuildImageResults::BuildImageError { error, .. }) => return Err(error),
+                    Ok(_) => {}
+                    Err(e) => return Err(format!("{:?}", e)),
                 }
             }
+            Ok(())
+        };
+        let build_result = tokio::select! {
+            result = with_timeout("build image", self.timeouts.build, build) => result,
+            _ = cancel.cancelled() => Err(String::from("Build cancelled")),
+        };
+        // The daemon has the whole build context once the build ends, one way or another
+        if let Err(e) = fs::remove_file(&tar_path) {
+            warn!("Failed to remove tarball {}: {}", tar_path, e);
+        }
+
+        match build_result {
+            Ok(_) => Ok(DockerImageBuildResult {
+                log,
+                image_id: container_guid.clone(),
+                test: None,
+            }),
             Err(e) => {
-                error!("Failed to tar source from path {}", source_path);
-                Err(format!("{:?}", e))
+                error!("Error building container {}: {}", &container_guid, &e);
+                self.remove_partial_image(&container_guid).await;
+                Err(format!("Failed to build image: {}", e))
             }
         }
     }
 
+    /// Removes whatever a failed or cancelled build managed to tag
+    async fn remove_partial_image(&self, tag: &str) {
+        let removal = with_timeout("remove image", self.timeouts.prune, async {
+            self.conn
+                .remove_image(
+                    tag,
+                    Some(RemoveImageOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                    None,
+                )
+                .await
+                .map_err(|e| format!("{:?}", e))
+        })
+        .await;
+        if removal.is_ok() {
+            info!("Removed partial image {}", tag);
+        }
+    }
+
+    /// Runs a command to completion in a throwaway container, such as the `config.test` command of a `shipwreck.toml`
+    ///
+    /// The container is removed once the command exits or the test timeout is hit.
+    /// A command which runs but exits non-zero is an `Ok` result which has not passed; `Err` means the command could not be run.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to run the command in
+    /// * `command` - The command, run with `sh -c`
+    /// * `env` - Environment variables to set for the command
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let result = docker.run_tests("12345", "cargo test", &HashMap::new()).await?;
+    /// if !result.passed() {
+    ///     println!("{}", result.output.join("\n"));
+    /// }
+    /// ```
+    pub async fn run_tests(
+        &self,
+        image_id: &str,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<TestResult, String> {
+        let name = format!("{}-test-{}", image_id, Uuid::new_v4().to_simple());
+        let env: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let config = Config {
+            image: Some(image_id),
+            cmd: Some(vec!["sh", "-c", command]),
+            env: Some(env.iter().map(|e| e.as_str()).collect()),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+
+        info!("Running tests of {} in {}: {}", image_id, name, command);
+        let run = with_timeout("run tests", self.timeouts.test, async {
+            self.conn
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: name.as_str(),
+                    }),
+                    config,
+                )
+                .await
+                .map_err(|e| {
+                    format!("Failed to create test container from {}: {:?}", image_id, e)
+                })?;
+            self.conn
+                .start_container(&name, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(|e| format!("Failed to start test container {}: {:?}", name, e))?;
+            let mut wait = self
+                .conn
+                .wait_container(&name, None::<WaitContainerOptions<String>>);
+            match wait.next().await {
+                Some(Ok(response)) => Ok(response.status_code),
+                Some(Err(e)) => Err(format!(
+                    "Failed to wait for test container {}: {:?}",
+                    name, e
+                )),
+                None => Err(format!("Test container {} never exited", name)),
+            }
+        })
+        .await;
+        let output = match &run {
+            Ok(_) => self.get_container_logs(&name, None).await,
+            Err(_) => Ok(vec![]),
+        };
+
+        let removal = with_timeout("remove container", self.timeouts.prune, async {
+            self.conn
+                .remove_container(
+                    &name,
+                    Some(RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .map_err(|e| format!("{:?}", e))
+        })
+        .await;
+        if let Err(e) = removal {
+            warn!("Failed to remove test container {}: {}", name, e);
+        }
+
+        let result = TestResult {
+            command: String::from(command),
+            exit_code: run?,
+            output: output?,
+        };
+        if result.passed() {
+            info!("Tests of {} passed", image_id);
+        } else {
+            error!(
+                "Tests of {} failed with exit code {}",
+                image_id, result.exit_code
+            );
+        }
+        Ok(result)
+    }
+
     /// Both creates and starts a docker container
     ///
     /// # Arguments
@@ -226,11 +723,60 @@ impl DockerBroker {
     /// let docker = DockerBroker::new();
     /// docker.start_container("12345", 9000); // builds image 12345 and maps 9000->9000
     /// ```
-    pub async fn start_container(&self, image_id: &str, port: i64) -> Result<String, ()> {
+    pub async fn start_container(&self, image_id: &str, port: i64) -> Result<String, String> {
+        self.start_named_container(image_id, image_id, port).await
+    }
+
+    /// Both creates and starts a docker container with a name
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The id of the image to turn into a container
+    /// * `name` - The name to give the container, which must not be in use
+    /// * `port` - a port within the container which should be exposed. This will map the port to its corresponding port on the machine (i.e. 9000 -> 9000)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// docker.start_named_container("12345", "scapegoat-1", 9001); // maps 9001->9001
+    /// ```
+    pub async fn start_named_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        port: i64,
+    ) -> Result<String, String> {
+        self.start_mapped_container(image_id, name, port, port)
+            .await
+    }
+
+    /// Both creates and starts a docker container with a name, publishing a port within the container on a different port of the machine
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The id of the image to turn into a container
+    /// * `name` - The name to give the container, which must not be in use
+    /// * `container_port` - The port the application listens on within the container
+    /// * `host_port` - The port on the machine which `container_port` is published on
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// docker.start_mapped_container("12345", "scapegoat-2", 9000, 20001); // maps 20001->9000
+    /// ```
+    pub async fn start_mapped_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        container_port: i64,
+        host_port: i64,
+    ) -> Result<String, String> {
         // TODO support exposing multiple ports? Check out TCP vs UDP?
         let mut ports = HashMap::new();
 
-        let p = format!("{}/tcp", port);
+        let p = format!("{}/tcp", container_port);
 
         // TODO this is so dumb there must be a better way
         // but &port makes the 'exposed_ports' unhappy
@@ -241,7 +787,7 @@ impl DockerBroker {
             p.clone(),
             Some(vec![PortBinding {
                 host_ip: Some(String::from("0.0.0.0")),
-                host_port: Some(String::from(format!("{}", port))),
+                host_port: Some(format!("{}", host_port)),
             }]),
         );
 
@@ -257,29 +803,33 @@ impl DockerBroker {
             ..Default::default()
         };
 
-        println!("{:?}", config);
-
-        let res = self
-            .conn
-            .create_container(Some(CreateContainerOptions { name: image_id }), config)
-            .await;
-
-        println!("{:?}", res);
+        debug!("Creating container {} with {:?}", name, config);
 
-        if let Ok(response) = res {
-            info!("Docker built container {}", response.id);
-            // TODO why is this not Ok? Ok(String::from(response.id))
+        with_timeout("start container", self.timeouts.start, async {
             let res = self
                 .conn
-                .start_container(&response.id, None::<StartContainerOptions<String>>)
+                .create_container(Some(CreateContainerOptions { name }), config)
                 .await;
-            if let Ok(_) = res {
-                info!("Docker started container {}", response.id);
-                return Ok(String::from(response.id));
+
+            match res {
+                Ok(response) => {
+                    info!("Docker built container {}", response.id);
+                    self.conn
+                        .start_container(&response.id, None::<StartContainerOptions<String>>)
+                        .await
+                        .map_err(|e| {
+                            format!("Failed to start container {}: {:?}", response.id, e)
+                        })?;
+                    info!("Docker started container {}", response.id);
+                    Ok(response.id)
+                }
+                Err(e) => Err(format!(
+                    "Failed to create container from {}: {:?}",
+                    image_id, e
+                )),
             }
-            return Err(());
-        }
-        Err(())
+        })
+        .await
     }
 
     /// Stops a docker container
@@ -287,24 +837,115 @@ impl DockerBroker {
     /// # Arguments
     ///
     /// * `container_id` - The id of the container to kill
-    pub async fn stop_container(&self, container_id: &str) -> () {
-        self.conn
-            .stop_container(container_id, Some(StopContainerOptions { t: 10 }))
-            .await
-            .unwrap();
+    pub async fn stop_container(&self, container_id: &str) -> Result<(), String> {
+        with_timeout("stop container", self.timeouts.stop, async {
+            self.conn
+                .stop_container(container_id, Some(StopContainerOptions { t: 10 }))
+                .await
+                .map_err(|e| format!("Failed to stop container {}: {:?}", container_id, e))
+        })
+        .await?;
         info!("Killing docker container {}", container_id);
-        ()
+        Ok(())
+    }
+
+    /// Removes a stopped docker container, freeing its name
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    pub async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+        with_timeout("remove container", self.timeouts.prune, async {
+            self.conn
+                .remove_container(container_id, None::<RemoveContainerOptions>)
+                .await
+                .map_err(|e| format!("Failed to remove container {}: {:?}", container_id, e))
+        })
+        .await?;
+        info!("Removed docker container {}", container_id);
+        Ok(())
     }
 
-    // TODO figure out what stats are actually useful
-    pub async fn get_container_stats(&self, container_id: &str) -> () {}
+    /// Gets the current resource usage of a running docker container
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let stats = docker.get_container_stats("12345");
+    /// println!("{:.1}% cpu, {} bytes", stats.cpu_percent, stats.memory_usage);
+    /// ```
+    pub async fn get_container_stats(&self, container_id: &str) -> Result<ContainerStats, String> {
+        let mut stats = self
+            .conn
+            .stats(container_id, Some(StatsOptions { stream: false }));
+        with_timeout("container stats", self.timeouts.stats, async {
+            match stats.next().await {
+                Some(Ok(s)) => Ok(ContainerStats::from(s)),
+                Some(Err(e)) => Err(format!(
+                    "Failed to get stats for container {}: {:?}",
+                    container_id, e
+                )),
+                None => Err(format!("No stats reported for container {}", container_id)),
+            }
+        })
+        .await
+    }
+
+    /// Gets the output (stdout and stderr) of a docker container
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    /// * `tail` - The number of lines to return from the end of the output, `None` for all of it
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// for line in docker.get_container_logs("12345", Some(50)) {
+    ///     println!("{}", line);
+    /// }
+    /// ```
+    pub async fn get_container_logs(
+        &self,
+        container_id: &str,
+        tail: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        let mut output = self.conn.logs(
+            container_id,
+            Some(LogsOptions {
+                stdout: true,
+                stderr: true,
+                tail: tail
+                    .map(|t| t.to_string())
+                    .unwrap_or_else(|| String::from("all")),
+                ..Default::default()
+            }),
+        );
+        with_timeout("container logs", self.timeouts.logs, async {
+            let mut lines = vec![];
+            while let Some(chunk) = output.next().await {
+                let chunk = chunk
+                    .map_err(|e| format!("Failed to get logs of {}: {:?}", container_id, e))?;
+                // A chunk may hold several lines, or part of one
+                lines.extend(chunk.to_string().lines().map(String::from));
+            }
+            Ok(lines)
+        })
+        .await
+    }
 
     /// Remove unused images from docker
     ///
     /// # Arguments
     ///
     /// * `keep_if_created_before_time` - A time string indicating a duration since now.
-    /// Images created before them will be deleted. Defaults to 1 hour ago.
+    ///   Images created before them will be deleted. Defaults to 1 hour ago.
     ///
     /// # Examples
     ///
@@ -312,22 +953,28 @@ impl DockerBroker {
     /// let docker = DockerBroker::new();
     /// docker.prune_images("10m"); // prune images more than 10 min old
     /// ```
-    pub async fn prune_images(&self, keep_if_created_before_time: Option<&str>) -> () {
+    pub async fn prune_images(
+        &self,
+        keep_if_created_before_time: Option<&str>,
+    ) -> Result<(), String> {
         let mut filters = HashMap::new();
         filters.insert("until", vec![keep_if_created_before_time.unwrap_or("1h")]); // keep images created < until ago
         filters.insert("dangling", vec!["false"]); // remove all images that are not running
 
-        let out = self
-            .conn
-            .prune_images(Some(PruneImagesOptions { filters: filters }))
-            .await
-            .unwrap();
+        let out = with_timeout("prune images", self.timeouts.prune, async {
+            self.conn
+                .prune_images(Some(PruneImagesOptions { filters }))
+                .await
+                .map_err(|e| format!("Failed to prune images: {:?}", e))
+        })
+        .await?;
 
         info!(
             "Docker prune removed {} images, reclaimed {} bytes",
-            out.images_deleted.unwrap_or(vec![]).len(), // TODO verify if this is actually correct
+            out.images_deleted.unwrap_or_default().len(), // TODO verify if this is actually correct
             out.space_reclaimed
         );
+        Ok(())
     }
 
     /// Remove unused containers from docker
@@ -335,7 +982,7 @@ impl DockerBroker {
     /// # Arguments
     ///
     /// * `keep_if_created_before_time` - A time string indicating a duration since now.
-    /// Containers created before them will be deleted. Defaults to 1 hour ago.
+    ///   Containers created before them will be deleted. Defaults to 1 hour ago.
     ///
     /// # Examples
     ///
@@ -343,34 +990,192 @@ impl DockerBroker {
     /// let docker = DockerBroker::new();
     /// docker.prune_containers("10m"); // prune containers more than 10 min old
     /// ```
-    pub async fn prune_containers(&self, keep_if_created_before_time: Option<&str>) -> () {
+    pub async fn prune_containers(
+        &self,
+        keep_if_created_before_time: Option<&str>,
+    ) -> Result<(), String> {
+        // Container prune only removes stopped containers, and rejects a `dangling` filter
         let mut filters = HashMap::new();
-        filters.insert("until", vec![keep_if_created_before_time.unwrap_or("1h")]); // keep images created < until ago
-        filters.insert("dangling", vec!["false"]); // remove all images that are not running
+        filters.insert("until", vec![keep_if_created_before_time.unwrap_or("1h")]); // keep containers created < until ago
 
-        let out = self
-            .conn
-            .prune_containers(Some(PruneContainersOptions { filters: filters }))
-            .await
-            .unwrap();
+        let out = with_timeout("prune containers", self.timeouts.prune, async {
+            self.conn
+                .prune_containers(Some(PruneContainersOptions { filters }))
+                .await
+                .map_err(|e| format!("Failed to prune containers: {:?}", e))
+        })
+        .await?;
 
         info!(
-            "Docker prune removed {} images, reclaimed {} bytes",
-            out.containers_deleted.unwrap_or(vec![]).len(), // TODO verify if this is actually correct
+            "Docker prune removed {} containers, reclaimed {} bytes",
+            out.containers_deleted.unwrap_or_default().len(),
             out.space_reclaimed.unwrap_or(0)
         );
-        ()
+        Ok(())
+    }
+
+    /// Removes old images of each application according to a retention policy
+    ///
+    /// Images are grouped by their `APP_LABEL`, and the `policy.keep_last` most recent images of each application are kept.
+    /// Older images are removed unless a container (in any state) still uses them.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - How many images to keep per application, and whether this is a dry run
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let report = docker.apply_retention_policy(&RetentionPolicy::new(3).dry_run()).await?;
+    /// println!("Would remove {:?}", report.removed);
+    /// ```
+    pub async fn apply_retention_policy(
+        &self,
+        policy: &RetentionPolicy,
+    ) -> Result<RetentionReport, String> {
+        retention::apply(self, policy).await
     }
 
     /// Removes unused containers and images from docker
     /// Uses `docker::DockerBroker::prune_containers` and `docker::DockerBroker::prune_images` default `keep_if_created_before_time`.
-    pub async fn prune(&self) {
-        self.prune_containers(None).await;
-        self.prune_images(None).await;
+    pub async fn prune(&self) -> Result<(), String> {
+        self.prune_containers(None).await?;
+        self.prune_images(None).await
     }
 }
 
+#[async_trait]
+impl ContainerRuntime for DockerBroker {
+    async fn build_labelled_image(
+        &self,
+        source_path: &str,
+        labels: &HashMap<String, String>,
+    ) -> Result<DockerImageBuildResult, String> {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        self.build_image_with_labels(source_path, &labels, &CancellationHandle::new())
+            .await
+    }
+
+    async fn run_tests(
+        &self,
+        image_id: &str,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<TestResult, String> {
+        DockerBroker::run_tests(self, image_id, command, env).await
+    }
+
+    async fn start_mapped_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        container_port: i64,
+        host_port: i64,
+    ) -> Result<String, String> {
+        DockerBroker::start_mapped_container(self, image_id, name, container_port, host_port).await
+    }
+
+    async fn stop_container(&self, container_id: &str) -> Result<(), String> {
+        DockerBroker::stop_container(self, container_id).await
+    }
+
+    async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+        DockerBroker::remove_container(self, container_id).await
+    }
+
+    async fn image_exists(&self, image_id: &str) -> Result<bool, String> {
+        DockerBroker::image_exists(self, image_id).await
+    }
+
+    async fn remove_image(&self, image_id: &str) -> Result<(), String> {
+        DockerBroker::remove_image(self, image_id).await
+    }
+
+    async fn container_port(&self, image_id: &str) -> Result<Option<i64>, String> {
+        DockerBroker::container_port(self, image_id).await.map(Some)
+    }
+
+    async fn list_images(&self, query: &ImageQuery) -> Result<Vec<DockerImage>, String> {
+        DockerBroker::list_images(self, query).await
+    }
+
+    async fn list_containers(
+        &self,
+        query: &ContainerQuery,
+    ) -> Result<Vec<DockerContainer>, String> {
+        DockerBroker::list_containers(self, query).await
+    }
+
+    async fn container_stats(&self, container_id: &str) -> Result<ContainerStats, String> {
+        self.get_container_stats(container_id).await
+    }
+
+    async fn container_logs(
+        &self,
+        container_id: &str,
+        tail: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        self.get_container_logs(container_id, tail).await
+    }
+
+    async fn prune(&self) -> Result<(), String> {
+        DockerBroker::prune(self).await
+    }
+
+    async fn create_network(&self, app: &str, name: &str) -> Result<(), String> {
+        self.create_stack_network(app, name).await
+    }
+
+    async fn list_networks(&self, app: &str) -> Result<Vec<String>, String> {
+        self.list_stack_networks(app).await
+    }
+
+    async fn remove_network(&self, name: &str) -> Result<(), String> {
+        self.remove_stack_network(name).await
+    }
+
+    async fn start_service(&self, service: &ServiceContainer) -> Result<String, String> {
+        DockerBroker::start_service(self, service).await
+    }
+
+    async fn pull_image_if_missing(&self, image: &str) -> Result<(), String> {
+        DockerBroker::pull_image_if_missing(self, image).await
+    }
+
+    fn published_host(&self) -> String {
+        self.host.published_host()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DockerImageBuildResult {
     pub log: Vec<String>,
     pub image_id: String,
+
+    /// The outcome of the manifest's test command, if it has been run against the image
+    pub test: Option<TestResult>,
+}
+
+/// The outcome of running a test command against a built image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestResult {
+    /// The command which was run
+    pub command: String,
+
+    /// The code the command exited with
+    pub exit_code: i64,
+
+    /// The output (stdout and stderr) of the command
+    pub output: Vec<String>,
+}
+
+impl TestResult {
+    /// Whether the command exited successfully
+    pub fn passed(&self) -> bool {
+        self.exit_code == 0
+    }
 }
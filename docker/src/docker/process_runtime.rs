@@ -0,0 +1,703 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use super::container_stats::ContainerStats;
+use super::docker_container::{ContainerQuery, DockerContainer, DockerPort};
+use super::docker_image::{DockerImage, ImageQuery};
+use super::manifest::ShipwreckManifest;
+use super::runtime::ContainerRuntime;
+use super::{DockerImageBuildResult, TestResult, APP_LABEL, MANAGED_LABEL, VERSION_LABEL};
+
+/// The number of log lines kept for each process
+const LOG_CAPACITY: usize = 1000;
+
+/// The number of clock ticks per second used by `/proc/<pid>/stat`, which is 100 on every Linux we run on
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// How long a process has to exit after `SIGTERM` before it is killed, the same grace `docker stop` gives
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a stopping process is checked on
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A `ContainerRuntime` which runs applications as child processes of the agent, for machines without docker
+///
+/// "Building" a project only records its folder and `shipwreck.toml`; nothing is copied.
+/// Starting it runs the manifest's `config.run` command through `sh` in that folder, with the manifest's env-vars and `PORT` set.
+/// Stdout and stderr are captured, and stats are read from `/proc` for the process' PID.
+/// Stopping a process sends it `SIGTERM`, then kills it if it hasn't exited within the stop timeout.
+///
+/// # Examples
+///
+/// ```
+/// let runtime = ProcessRuntime::new();
+/// let build = runtime.build_image("scapegoat").await?;
+/// let id = runtime.start_container(&build.image_id, 9000).await?;
+/// println!("{:?}", runtime.container_logs(&id, Some(10)).await?);
+/// ```
+pub struct ProcessRuntime {
+    state: Mutex<ProcessState>,
+    stop_timeout: Duration,
+}
+
+impl Default for ProcessRuntime {
+    fn default() -> ProcessRuntime {
+        ProcessRuntime {
+            state: Mutex::new(ProcessState::default()),
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ProcessState {
+    images: HashMap<String, ProcessImage>,
+    processes: Vec<SupervisedProcess>,
+}
+
+/// A project folder registered by `build_image`
+struct ProcessImage {
+    id: String,
+    path: PathBuf,
+    manifest: ShipwreckManifest,
+    labels: HashMap<String, String>,
+}
+
+impl ProcessImage {
+    /// Describes this project folder like a docker image, counting the processes started from it
+    fn to_image(&self, processes: &[SupervisedProcess]) -> DockerImage {
+        DockerImage {
+            id: self.id.clone(),
+            repo_tags: vec![self.id.clone()],
+            size: 0,
+            created: 0,
+            labels: self.labels.clone(),
+            containers: processes.iter().filter(|p| p.image_id == self.id).count() as i64,
+        }
+    }
+}
+
+/// A running (or exited) child process started from a `ProcessImage`
+struct SupervisedProcess {
+    id: String,
+    name: String,
+    image_id: String,
+    command: String,
+    port: i64,
+    labels: HashMap<String, String>,
+    created: i64,
+    child: Child,
+    exit_code: Option<i32>,
+    logs: Arc<Mutex<VecDeque<String>>>,
+    /// The CPU time used and the instant it was sampled, for calculating CPU usage
+    last_cpu_sample: Option<(u64, Instant)>,
+}
+
+impl SupervisedProcess {
+    /// Checks whether the process is still running, recording its exit code if it has finished
+    fn refresh(&mut self) -> bool {
+        if self.exit_code.is_some() {
+            return false;
+        }
+        match self.child.try_wait() {
+            Ok(Some(status)) => {
+                // Processes killed by a signal have no exit code; report them like docker does
+                self.exit_code = Some(status.code().unwrap_or(137));
+                info!(
+                    "Process {} ({}) exited with {:?}",
+                    self.name,
+                    self.child.id(),
+                    self.exit_code
+                );
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                error!("Failed to check on process {}: {:?}", self.name, e);
+                true
+            }
+        }
+    }
+
+    fn to_container(&self) -> DockerContainer {
+        let (state, status) = match self.exit_code {
+            Some(code) => ("exited", format!("Exited ({})", code)),
+            None => ("running", format!("Up (pid {})", self.child.id())),
+        };
+        DockerContainer {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            names: vec![self.name.clone()],
+            image: Some(self.image_id.clone()),
+            image_id: Some(self.image_id.clone()),
+            command: Some(self.command.clone()),
+            created: Some(self.created),
+            ports: vec![DockerPort {
+                private_port: self.port,
+                public_port: Some(self.port),
+                protocol: String::from("tcp"),
+                ip: Some(String::from("0.0.0.0")),
+            }],
+            labels: self.labels.clone(),
+            networks: HashMap::new(),
+            mounts: vec![],
+            state: Some(String::from(state)),
+            status: Some(status),
+        }
+    }
+}
+
+impl ProcessState {
+    fn find_process(&mut self, container: &str) -> Option<&mut SupervisedProcess> {
+        self.processes
+            .iter_mut()
+            .find(|p| p.id == container || p.name == container)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Reads lines from a child's output into its log buffer until the stream closes
+fn capture_output<R: Read + Send + 'static>(stream: R, logs: Arc<Mutex<VecDeque<String>>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(l) => {
+                    let mut logs = logs.lock().unwrap();
+                    if logs.len() == LOG_CAPACITY {
+                        logs.pop_front();
+                    }
+                    logs.push_back(l);
+                }
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Reads the CPU time (user + system, in clock ticks) and resident memory (in bytes) of a process from `/proc`
+fn read_proc_usage(pid: u32) -> Result<(u64, u64), String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .map_err(|e| format!("Failed to read stats for pid {}: {}", pid, e))?;
+    // The command name may contain spaces, so count fields from the closing paren
+    let fields: Vec<&str> = stat
+        .rsplit(')')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+    // utime and stime are fields 14 and 15 of the stat file, which are 11 and 12 after the paren
+    let ticks = |i: usize| {
+        fields
+            .get(i)
+            .and_then(|f| f.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let cpu = ticks(11) + ticks(12);
+
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))
+        .map_err(|e| format!("Failed to read status for pid {}: {}", pid, e))?;
+    let memory = read_kb_field(&status, "VmRSS:") * 1024;
+    Ok((cpu, memory))
+}
+
+/// Reads a `Name:   1234 kB` field from a `/proc` file
+fn read_kb_field(contents: &str, field: &str) -> u64 {
+    contents
+        .lines()
+        .find(|l| l.starts_with(field))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+impl ProcessRuntime {
+    pub fn new() -> ProcessRuntime {
+        ProcessRuntime::default()
+    }
+
+    /// Sets how long a process has to exit after `SIGTERM` before it is killed
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let runtime = ProcessRuntime::new().with_stop_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn with_stop_timeout(mut self, timeout: Duration) -> ProcessRuntime {
+        self.stop_timeout = timeout;
+        self
+    }
+
+    /// Sends a signal to a process if it is still running, returning `false` if it had already exited
+    ///
+    /// The signal is sent under the state lock, so the process can't be reaped (and its PID reused) in between.
+    fn signal(&self, container_id: &str, signal: libc::c_int) -> Result<bool, String> {
+        let mut state = self.state.lock().unwrap();
+        let process = state
+            .find_process(container_id)
+            .ok_or_else(|| format!("No such process: {}", container_id))?;
+        if !process.refresh() {
+            return Ok(false);
+        }
+        // Safe as `kill` only sends a signal, to a child which hasn't been reaped
+        if unsafe { libc::kill(process.child.id() as libc::pid_t, signal) } != 0 {
+            warn!(
+                "Failed to signal process {}: {}",
+                process.name,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(true)
+    }
+
+    /// Waits for a process to exit, returning whether it did within `timeout`
+    ///
+    /// The process is polled rather than waited on, so neither the executor nor the state lock is held up while it stops.
+    async fn wait_for_exit(&self, container_id: &str, timeout: Duration) -> Result<bool, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let running = {
+                let mut state = self.state.lock().unwrap();
+                state
+                    .find_process(container_id)
+                    .ok_or_else(|| format!("No such process: {}", container_id))?
+                    .refresh()
+            };
+            if !running {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            tokio::time::delay_for(STOP_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for ProcessRuntime {
+    async fn build_labelled_image(
+        &self,
+        source_path: &str,
+        extra_labels: &HashMap<String, String>,
+    ) -> Result<DockerImageBuildResult, String> {
+        let manifest = ShipwreckManifest::from_dir(source_path)?;
+        if manifest.config.run.trim().is_empty() {
+            return Err(format!("{} has no config.run command", source_path));
+        }
+        let path = Path::new(source_path)
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve {}: {}", source_path, e))?;
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let log = vec![
+            format!("Registered {} from {}", manifest.app.name, path.display()),
+            format!("Run command: {}", manifest.config.run),
+        ];
+        info!("Process runtime registered {} as [{}]", source_path, id);
+        let mut labels = extra_labels.clone();
+        labels.insert(String::from(MANAGED_LABEL), String::from("true"));
+        labels.insert(String::from(APP_LABEL), manifest.app.name.clone());
+        labels.insert(String::from(VERSION_LABEL), manifest.app.version.clone());
+        self.state.lock().unwrap().images.insert(
+            id.clone(),
+            ProcessImage {
+                id: id.clone(),
+                path,
+                manifest,
+                labels,
+            },
+        );
+        Ok(DockerImageBuildResult {
+            log,
+            image_id: id,
+            test: None,
+        })
+    }
+
+    async fn run_tests(
+        &self,
+        image_id: &str,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<TestResult, String> {
+        let (path, manifest_env) = {
+            let state = self.state.lock().unwrap();
+            let image = state
+                .images
+                .get(image_id)
+                .ok_or_else(|| format!("No such image: {}", image_id))?;
+            (image.path.clone(), image.manifest.env_vars.clone())
+        };
+        info!("Process runtime running tests of {}: {}", image_id, command);
+        let mut run = Command::new("sh");
+        run.arg("-c")
+            // Interleave stderr with stdout, like the logs of a container
+            .arg(format!("exec 2>&1; {}", command))
+            .current_dir(&path)
+            .envs(&manifest_env)
+            .envs(env)
+            .stdin(Stdio::null());
+        let output = tokio::task::spawn_blocking(move || run.output())
+            .await
+            .map_err(|e| format!("Failed to run `{}`: {}", command, e))?
+            .map_err(|e| format!("Failed to run `{}`: {}", command, e))?;
+        Ok(TestResult {
+            command: String::from(command),
+            // Processes killed by a signal have no exit code; report them like docker does
+            exit_code: output.status.code().unwrap_or(137) as i64,
+            output: String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    /// Processes share the host's network, so they are told to listen on `host_port` through `PORT` and `container_port` is ignored
+    async fn start_mapped_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        _container_port: i64,
+        port: i64,
+    ) -> Result<String, String> {
+        let mut state = self.state.lock().unwrap();
+        for p in state.processes.iter_mut() {
+            p.refresh();
+        }
+        let image = state
+            .images
+            .get(image_id)
+            .ok_or_else(|| format!("No such image: {}", image_id))?;
+        if state.processes.iter().any(|p| p.name == name) {
+            return Err(format!("A process named {} already exists", name));
+        }
+        if state
+            .processes
+            .iter()
+            .any(|p| p.exit_code.is_none() && p.port == port)
+        {
+            return Err(format!(
+                "Port {} is already in use by another process",
+                port
+            ));
+        }
+
+        let run = image.manifest.config.run.clone();
+        let mut child = Command::new("sh")
+            .arg("-c")
+            // exec so the PID we supervise is the application rather than the shell
+            .arg(format!("exec {}", run))
+            .current_dir(&image.path)
+            .envs(&image.manifest.env_vars)
+            .env("PORT", port.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start `{}`: {}", run, e))?;
+
+        let logs = Arc::new(Mutex::new(VecDeque::new()));
+        if let Some(stdout) = child.stdout.take() {
+            capture_output(stdout, logs.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture_output(stderr, logs.clone());
+        }
+
+        // Containers carry their image's labels, as they do under docker
+        let labels = image.labels.clone();
+
+        let id = Uuid::new_v4().to_simple().to_string();
+        info!(
+            "Process runtime started {} as pid {} on port {}",
+            image_id,
+            child.id(),
+            port
+        );
+        let process = SupervisedProcess {
+            id: id.clone(),
+            name: String::from(name),
+            image_id: image.id.clone(),
+            command: run,
+            port,
+            labels,
+            created: now(),
+            child,
+            exit_code: None,
+            logs,
+            last_cpu_sample: None,
+        };
+        state.processes.push(process);
+        Ok(id)
+    }
+
+    async fn stop_container(&self, container_id: &str) -> Result<(), String> {
+        if !self.signal(container_id, libc::SIGTERM)? {
+            return Ok(());
+        }
+        info!("Stopping process {}", container_id);
+        if self.wait_for_exit(container_id, self.stop_timeout).await? {
+            return Ok(());
+        }
+
+        warn!(
+            "Process {} didn't exit within {:?} of SIGTERM, killing it",
+            container_id, self.stop_timeout
+        );
+        self.signal(container_id, libc::SIGKILL)?;
+        if self.wait_for_exit(container_id, self.stop_timeout).await? {
+            Ok(())
+        } else {
+            Err(format!(
+                "Process {} didn't exit after being killed",
+                container_id
+            ))
+        }
+    }
+
+    async fn remove_container(&self, container_id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let process = state
+            .find_process(container_id)
+            .ok_or_else(|| format!("No such process: {}", container_id))?;
+        if process.refresh() {
+            return Err(format!(
+                "Process {} is still running, stop it first",
+                process.name
+            ));
+        }
+        let id = process.id.clone();
+        state.processes.retain(|p| p.id != id);
+        Ok(())
+    }
+
+    async fn image_exists(&self, image_id: &str) -> Result<bool, String> {
+        Ok(self.state.lock().unwrap().images.contains_key(image_id))
+    }
+
+    async fn remove_image(&self, image_id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.processes.iter().any(|p| p.image_id == image_id) {
+            return Err(format!(
+                "Image {} is used by a process, remove it first",
+                image_id
+            ));
+        }
+        state
+            .images
+            .remove(image_id)
+            .map(|_| ())
+            .ok_or_else(|| format!("No such image: {}", image_id))
+    }
+
+    async fn container_port(&self, _image_id: &str) -> Result<Option<i64>, String> {
+        // Applications listen on whatever `PORT` they are given
+        Ok(None)
+    }
+
+    async fn list_images(&self, query: &ImageQuery) -> Result<Vec<DockerImage>, String> {
+        let mut state = self.state.lock().unwrap();
+        for p in state.processes.iter_mut() {
+            p.refresh();
+        }
+        let images = state
+            .images
+            .values()
+            .map(|i| i.to_image(&state.processes))
+            .filter(|i| query.matches(i))
+            .collect();
+        Ok(images)
+    }
+
+    async fn list_containers(
+        &self,
+        query: &ContainerQuery,
+    ) -> Result<Vec<DockerContainer>, String> {
+        let mut state = self.state.lock().unwrap();
+        let mut containers = vec![];
+        for p in state.processes.iter_mut() {
+            p.refresh();
+            let c = p.to_container();
+            if query.matches(&c) {
+                containers.push(c);
+            }
+        }
+        query.apply(&mut containers);
+        Ok(containers)
+    }
+
+    async fn container_stats(&self, container_id: &str) -> Result<ContainerStats, String> {
+        let mut state = self.state.lock().unwrap();
+        let process = state
+            .find_process(container_id)
+            .ok_or_else(|| format!("No such process: {}", container_id))?;
+        if !process.refresh() {
+            return Err(format!("Process {} is not running", process.name));
+        }
+        let pid = process.child.id();
+        let (cpu, memory_usage) = read_proc_usage(pid)?;
+        let sampled_at = Instant::now();
+        // The first sample has nothing to compare against, so it reports 0% like docker does
+        let cpu_percent = match process.last_cpu_sample {
+            Some((last_cpu, last_at)) => {
+                let elapsed = sampled_at.duration_since(last_at).as_secs_f64();
+                if elapsed > 0.0 {
+                    (cpu.saturating_sub(last_cpu)) as f64 / CLOCK_TICKS_PER_SECOND / elapsed * 100.0
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        process.last_cpu_sample = Some((cpu, sampled_at));
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        Ok(ContainerStats {
+            id: process.id.clone(),
+            name: process.name.clone(),
+            cpu_percent,
+            memory_usage,
+            memory_limit: read_kb_field(&meminfo, "MemTotal:") * 1024,
+            network_rx_bytes: 0,
+            network_tx_bytes: 0,
+            pids: 1,
+        })
+    }
+
+    async fn container_logs(
+        &self,
+        container_id: &str,
+        tail: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        let mut state = self.state.lock().unwrap();
+        let process = state
+            .find_process(container_id)
+            .ok_or_else(|| format!("No such process: {}", container_id))?;
+        let logs = process.logs.lock().unwrap();
+        let skip = tail.map(|t| logs.len().saturating_sub(t)).unwrap_or(0);
+        Ok(logs.iter().skip(skip).cloned().collect())
+    }
+
+    async fn prune(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.processes.retain_mut(|p| p.refresh());
+        let in_use: HashSet<String> = state.processes.iter().map(|p| p.image_id.clone()).collect();
+        let before = state.images.len();
+        state.images.retain(|id, _| in_use.contains(id));
+        info!(
+            "Process runtime pruned {} unused images",
+            before - state.images.len()
+        );
+        Ok(())
+    }
+}
+
+impl Drop for ProcessRuntime {
+    /// Child processes are not killed when their handles are dropped, so stop them with the runtime
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            for p in state.processes.iter_mut() {
+                if p.refresh() {
+                    let _ = p.child.kill();
+                    let _ = p.child.wait();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::TempProject;
+
+    /// A project whose `config.run` is `run`
+    fn project(run: &str) -> TempProject {
+        TempProject::new("scapegoat", "1.0.0", &format!("run = {:?}", run))
+    }
+
+    async fn state_of(runtime: &ProcessRuntime, id: &str) -> String {
+        let containers = runtime
+            .list_containers(&ContainerQuery::new().all())
+            .await
+            .unwrap();
+        let container = containers.iter().find(|c| c.id == id).unwrap();
+        container.state.clone().unwrap()
+    }
+
+    #[tokio::test]
+    async fn stop_lets_the_process_exit_on_sigterm() {
+        let runtime = ProcessRuntime::new().with_stop_timeout(Duration::from_secs(5));
+        let source = project("sleep 30");
+        let build = runtime.build_image(&source.path()).await.unwrap();
+        let id = runtime
+            .start_container(&build.image_id, 23000)
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        runtime.stop_container(&id).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(state_of(&runtime, &id).await, "exited");
+    }
+
+    #[tokio::test]
+    async fn stop_kills_a_process_which_ignores_sigterm() {
+        let runtime = Arc::new(ProcessRuntime::new().with_stop_timeout(Duration::from_millis(500)));
+        let source = project("sh -c \"trap '' TERM; while true; do sleep 0.1; done\"");
+        let build = runtime.build_image(&source.path()).await.unwrap();
+        let id = runtime
+            .start_container(&build.image_id, 23001)
+            .await
+            .unwrap();
+        // Let the shell install its trap, or SIGTERM would still end it
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        let stopping = {
+            let runtime = runtime.clone();
+            let id = id.clone();
+            tokio::spawn(async move { runtime.stop_container(&id).await })
+        };
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        // The runtime answers other calls while the process is given time to exit
+        assert_eq!(state_of(&runtime, &id).await, "running");
+        stopping.await.unwrap().unwrap();
+
+        assert_eq!(state_of(&runtime, &id).await, "exited");
+        let containers = runtime
+            .list_containers(&ContainerQuery::new().all())
+            .await
+            .unwrap();
+        let container = containers.iter().find(|c| c.id == id).unwrap();
+        assert!(container.status.as_deref().unwrap().contains("137"));
+    }
+
+    #[tokio::test]
+    async fn stopping_an_exited_process_succeeds() {
+        let runtime = ProcessRuntime::new();
+        let source = project("true");
+        let build = runtime.build_image(&source.path()).await.unwrap();
+        let id = runtime
+            .start_container(&build.image_id, 23002)
+            .await
+            .unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        runtime.stop_container(&id).await.unwrap();
+        assert!(runtime.stop_container("missing").await.is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::runtime::ContainerRuntime;
+
+/// How to tell whether a freshly started application is ready to receive traffic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadinessProbe {
+    /// Ready once the port accepts TCP connections
+    ///
+    /// Docker's userland proxy may accept connections before the application listens, so prefer `Http` where possible.
+    Tcp,
+
+    /// Ready once a `GET` of the path answers with a 2xx or 3xx status
+    Http { path: String },
+}
+
+impl ReadinessProbe {
+    /// Probes an application once
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host the application's port is published on
+    /// * `port` - The published port
+    /// * `timeout` - How long to wait for the application to answer
+    pub async fn check(&self, host: &str, port: i64, timeout: Duration) -> Result<(), String> {
+        let addr = format!("{}:{}", host, port);
+        let mut stream = match time::timeout(timeout, TcpStream::connect(&addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(format!("Failed to connect to {}: {}", addr, e)),
+            Err(_) => return Err(format!("Timed out connecting to {}", addr)),
+        };
+        let path = match self {
+            ReadinessProbe::Tcp => return Ok(()),
+            ReadinessProbe::Http { path } => path,
+        };
+
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        // Only the status line matters, e.g. `HTTP/1.1 200 OK`
+        let mut response = [0u8; 64];
+        let exchange = async {
+            stream.write_all(request.as_bytes()).await?;
+            stream.read(&mut response).await
+        };
+        let read = match time::timeout(timeout, exchange).await {
+            Ok(Ok(read)) => read,
+            Ok(Err(e)) => return Err(format!("No answer to probe from {}: {}", addr, e)),
+            Err(_) => return Err(format!("No answer to probe from {} in {:?}", addr, timeout)),
+        };
+        let status_line = String::from_utf8_lossy(&response[..read]);
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| format!("Invalid HTTP response from {}", addr))?;
+        if (200..400).contains(&status) {
+            Ok(())
+        } else {
+            Err(format!("GET {} on {} answered {}", path, addr, status))
+        }
+    }
+
+    /// Probes an application through its runtime until it is ready or `timeout` has passed
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - The runtime the application was started on, which knows the host its port is published on
+    /// * `port` - The published port
+    /// * `timeout` - How long to wait for the application to become ready
+    /// * `interval` - How long to wait between probes
+    pub async fn wait_until_ready(
+        &self,
+        runtime: &dyn ContainerRuntime,
+        port: i64,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<(), String> {
+        let host = runtime.published_host();
+        let deadline = Instant::now() + timeout;
+        loop {
+            match runtime.check_ready(self, port, interval).await {
+                Ok(()) => {
+                    info!("{}:{} is ready", host, port);
+                    return Ok(());
+                }
+                Err(e) if Instant::now() >= deadline => {
+                    warn!("{}:{} never became ready: {}", host, port, e);
+                    return Err(format!(
+                        "{}:{} was not ready after {:?}: {}",
+                        host, port, timeout, e
+                    ));
+                }
+                Err(_) => time::delay_for(interval).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Answers the first connection to a loopback port with `response`, returning the port
+    async fn answer_once(response: &'static str) -> i64 {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as i64;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 256];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+        port
+    }
+
+    fn http() -> ReadinessProbe {
+        ReadinessProbe::Http {
+            path: "/".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn http_probes_accept_success_and_redirects() {
+        let timeout = Duration::from_secs(1);
+        let ok = answer_once("HTTP/1.1 200 OK\r\n\r\n").await;
+        assert!(http().check("127.0.0.1", ok, timeout).await.is_ok());
+        let moved = answer_once("HTTP/1.1 302 Found\r\n\r\n").await;
+        assert!(http().check("127.0.0.1", moved, timeout).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn http_probes_reject_errors_and_garbage() {
+        let timeout = Duration::from_secs(1);
+        let failing = answer_once("HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+        let error = http().check("127.0.0.1", failing, timeout).await;
+        assert!(error.unwrap_err().contains("503"));
+        let garbage = answer_once("SSH-2.0-OpenSSH\r\n").await;
+        let error = http().check("127.0.0.1", garbage, timeout).await;
+        assert!(error.unwrap_err().contains("Invalid HTTP response"));
+    }
+
+    #[tokio::test]
+    async fn tcp_probes_only_need_a_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port() as i64;
+        let timeout = Duration::from_secs(1);
+        assert!(ReadinessProbe::Tcp
+            .check("127.0.0.1", port, timeout)
+            .await
+            .is_ok());
+        drop(listener);
+        assert!(ReadinessProbe::Tcp
+            .check("127.0.0.1", port, timeout)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,541 @@
+use bollard::auth::DockerCredentials;
+use bollard::image::{CreateImageOptions, CreateImageResults, PushImageOptions, TagImageOptions};
+use futures_util::stream::StreamExt;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use super::timeouts::with_timeout;
+use super::DockerBroker;
+
+/// The registry images without a registry host in their name belong to
+pub const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// A parsed image name, e.g. `localhost:5000/team/scapegoat:1.0.0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    /// The repository, including the registry host if there is one (e.g. `localhost:5000/team/scapegoat`)
+    pub repository: String,
+
+    /// The tag, if the name has one
+    pub tag: Option<String>,
+
+    /// The content digest (e.g. `sha256:...`), if the name has one
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Parses `repository[:tag][@digest]`
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The image name
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let reference = ImageReference::parse("localhost:5000/scapegoat:1.0.0")?;
+    /// assert_eq!(reference.registry(), "localhost:5000");
+    /// ```
+    pub fn parse(name: &str) -> Result<ImageReference, String> {
+        let (rest, digest) = match name.find('@') {
+            Some(i) => (&name[..i], Some(String::from(&name[i + 1..]))),
+            None => (name, None),
+        };
+        // A colon after the last slash separates the tag, one before it is a registry port
+        let last_slash = rest.rfind('/').map(|i| i + 1).unwrap_or(0);
+        let (repository, tag) = match rest[last_slash..].find(':') {
+            Some(i) => (
+                &rest[..last_slash + i],
+                Some(String::from(&rest[last_slash + i + 1..])),
+            ),
+            None => (rest, None),
+        };
+        if repository.is_empty()
+            || tag.as_deref() == Some("")
+            || digest.as_deref().is_some_and(|d| !d.contains(':'))
+        {
+            return Err(format!("Invalid image name {}", name));
+        }
+        Ok(ImageReference {
+            repository: String::from(repository),
+            tag,
+            digest,
+        })
+    }
+
+    /// The registry host the image belongs to, `docker.io` if its name doesn't start with one
+    ///
+    /// Like docker, the first part of the name is only a host if it has a `.` or `:`, or is `localhost`.
+    pub fn registry(&self) -> &str {
+        match self.repository.split_once('/') {
+            Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+                host
+            }
+            _ => DEFAULT_REGISTRY,
+        }
+    }
+
+    /// The digest if there is one, otherwise the tag, defaulting to `latest`
+    fn tag_or_digest(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+}
+
+/// A login for a registry
+///
+/// The password is never logged: `Debug` hides it, and it is only sent to docker in the `X-Registry-Auth` header.
+#[derive(Clone)]
+pub struct RegistryCredentials {
+    /// The registry host, e.g. `localhost:5000`
+    pub server: String,
+
+    /// The user to log in as
+    pub username: String,
+
+    /// The user's password or access token
+    pub password: String,
+}
+
+impl fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("server", &self.server)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl RegistryCredentials {
+    /// Finds the login for a registry, from the environment or else from docker's own config
+    ///
+    /// Returns `None` if there is no login for the registry, in which case it is used anonymously.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The registry host, e.g. `localhost:5000` (see `ImageReference::registry`)
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let reference = ImageReference::parse("localhost:5000/scapegoat:1.0.0")?;
+    /// let credentials = RegistryCredentials::lookup(reference.registry())?;
+    /// ```
+    pub fn lookup(server: &str) -> Result<Option<RegistryCredentials>, String> {
+        match RegistryCredentials::from_env(server) {
+            Some(credentials) => Ok(Some(credentials)),
+            None => RegistryCredentials::from_docker_config(server),
+        }
+    }
+
+    /// Reads a login from `KRAKEN_REGISTRY_USERNAME` and `KRAKEN_REGISTRY_PASSWORD`
+    ///
+    /// If `KRAKEN_REGISTRY` is set the login is only used for that registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The registry host the login is for
+    pub fn from_env(server: &str) -> Option<RegistryCredentials> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        if var("KRAKEN_REGISTRY").is_some_and(|r| r != server) {
+            return None;
+        }
+        Some(RegistryCredentials {
+            server: String::from(server),
+            username: var("KRAKEN_REGISTRY_USERNAME")?,
+            password: var("KRAKEN_REGISTRY_PASSWORD")?,
+        })
+    }
+
+    /// Reads a login saved by `docker login` in `$DOCKER_CONFIG/config.json` (`~/.docker/config.json` by default)
+    ///
+    /// Only logins stored in the file itself are found, not ones kept by a credential helper.
+    ///
+    /// # Arguments
+    ///
+    /// * `server` - The registry host the login is for
+    pub fn from_docker_config(server: &str) -> Result<Option<RegistryCredentials>, String> {
+        let dir = match env::var("DOCKER_CONFIG") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => match env::var("HOME") {
+                Ok(home) => PathBuf::from(home).join(".docker"),
+                Err(_) => return Ok(None),
+            },
+        };
+        let path = dir.join("config.json");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(None),
+        };
+        let config: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        let auths = match config.get("auths").and_then(|a| a.as_object()) {
+            Some(auths) => auths,
+            None => return Ok(None),
+        };
+        // Docker Hub logins are saved under its v1 URL
+        let auth = auths
+            .iter()
+            .find(|(key, _)| {
+                let host = key
+                    .trim_start_matches("https://")
+                    .trim_start_matches("http://");
+                let host = host.split('/').next().unwrap_or(host);
+                host == server || (server == DEFAULT_REGISTRY && host == "index.docker.io")
+            })
+            .and_then(|(_, entry)| entry.get("auth"))
+            .and_then(|auth| auth.as_str());
+        let auth = match auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        // `auth` is base64 of `username:password`
+        let decoded = base64::decode(auth)
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok())
+            .ok_or_else(|| format!("Invalid login for {} in {}", server, path.display()))?;
+        let (username, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid login for {} in {}", server, path.display()))?;
+        Ok(Some(RegistryCredentials {
+            server: String::from(server),
+            username: String::from(username),
+            password: String::from(password),
+        }))
+    }
+
+    fn to_docker(&self) -> DockerCredentials {
+        DockerCredentials {
+            username: Some(self.username.clone()),
+            password: Some(self.password.clone()),
+            serveraddress: Some(self.server.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// How far a push or pull has got
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RegistryProgress {
+    /// Docker reported progress on one layer of a pull
+    Layer {
+        /// The layer's short ID
+        id: String,
+
+        /// What is happening to it, e.g. `Downloading` or `Pull complete`
+        status: String,
+
+        /// Bytes done so far, while downloading or extracting
+        current: Option<u64>,
+
+        /// Bytes to do in all, while downloading or extracting
+        total: Option<u64>,
+    },
+
+    /// Docker reported the status of the whole image, e.g. its digest once pulled
+    Status {
+        /// Docker's message
+        message: String,
+    },
+
+    /// The push of an image has started
+    ///
+    /// bollard doesn't pass on docker's progress while pushing, so this is the only event until it ends.
+    Pushing {
+        /// The image being pushed
+        image: String,
+    },
+}
+
+/// An image pushed to a registry
+#[derive(Debug, Clone, Serialize)]
+pub struct PushedImage {
+    /// The name the image was pushed as
+    pub image: String,
+
+    /// Its `repository@sha256:...` in the registry, which pulls exactly this image
+    pub digest: String,
+}
+
+impl DockerBroker {
+    /// Gives an image another name, such as one in a registry to push it to
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The id or `name[:tag]` of the image
+    /// * `target` - The new `repository[:tag]`, the tag defaulting to `latest`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// docker.tag_image("scapegoat:1.0.0", "localhost:5000/scapegoat:1.0.0").await?;
+    /// ```
+    pub async fn tag_image(&self, image: &str, target: &str) -> Result<(), String> {
+        let reference = ImageReference::parse(target)?;
+        if reference.digest.is_some() {
+            return Err(format!("Can't tag an image with a digest ({})", target));
+        }
+        with_timeout("tag image", self.timeouts.inspect, async {
+            self.conn
+                .tag_image(
+                    image,
+                    Some(TagImageOptions {
+                        repo: reference.repository.as_str(),
+                        tag: reference.tag.as_deref().unwrap_or("latest"),
+                    }),
+                )
+                .await
+                .map_err(|e| format!("Failed to tag {} as {}: {:?}", image, target, e))
+        })
+        .await?;
+        info!("Tagged {} as {}", image, target);
+        Ok(())
+    }
+
+    /// Pushes an image to the registry named in it
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The `registry/repository[:tag]` to push, tagged with `tag_image` first
+    /// * `credentials` - The login for the registry, or `None` to push anonymously
+    /// * `progress` - Called as the push goes on
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let image = "localhost:5000/scapegoat:1.0.0";
+    /// let credentials = RegistryCredentials::lookup(ImageReference::parse(image)?.registry())?;
+    /// let pushed = docker.push_image(image, credentials.as_ref(), &|p| println!("{:?}", p)).await?;
+    /// println!("Pushed {}", pushed.digest);
+    /// ```
+    pub async fn push_image(
+        &self,
+        image: &str,
+        credentials: Option<&RegistryCredentials>,
+        progress: &(dyn Fn(RegistryProgress) + Sync),
+    ) -> Result<PushedImage, String> {
+        let reference = ImageReference::parse(image)?;
+        if reference.digest.is_some() {
+            return Err(format!("Can't push {}, push a tag instead", image));
+        }
+        info!("Pushing {}", image);
+        progress(RegistryProgress::Pushing {
+            image: String::from(image),
+        });
+        with_timeout("push image", self.timeouts.pull, async {
+            self.conn
+                .push_image(
+                    &reference.repository,
+                    Some(PushImageOptions {
+                        tag: reference.tag.as_deref().unwrap_or("latest"),
+                    }),
+                    credentials.map(|c| c.to_docker()),
+                )
+                .await
+                .map_err(|e| format!("Failed to push {}: {:?}", image, e))
+        })
+        .await?;
+
+        // Docker reports failed pushes (e.g. a bad login) in the progress bollard drops,
+        // so the push only counts once the image has a digest in the registry
+        let details = with_timeout("inspect image", self.timeouts.inspect, async {
+            self.conn
+                .inspect_image(image)
+                .await
+                .map_err(|e| format!("Failed to inspect image {}: {:?}", image, e))
+        })
+        .await?;
+        let prefix = format!("{}@", reference.repository);
+        let digest = details
+            .repo_digests
+            .unwrap_or_default()
+            .into_iter()
+            .find(|d| d.starts_with(&prefix))
+            .ok_or_else(|| {
+                format!(
+                    "Failed to push {}, check the registry is reachable and the login is valid",
+                    image
+                )
+            })?;
+        info!("Pushed {} as {}", image, digest);
+        Ok(PushedImage {
+            image: String::from(image),
+            digest,
+        })
+    }
+
+    /// Pulls an image by tag or digest
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The `repository[:tag]` or `repository@digest` to pull, the tag defaulting to `latest`
+    /// * `credentials` - The login for the registry, or `None` to pull anonymously
+    /// * `progress` - Called with each progress update docker sends
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// docker.pull_image("localhost:5000/scapegoat:1.0.0", None, &|p| println!("{:?}", p)).await?;
+    /// ```
+    pub async fn pull_image(
+        &self,
+        image: &str,
+        credentials: Option<&RegistryCredentials>,
+        progress: &(dyn Fn(RegistryProgress) + Sync),
+    ) -> Result<(), String> {
+        let reference = ImageReference::parse(image)?;
+        info!("Pulling image {}", image);
+        with_timeout("pull image", self.timeouts.pull, async {
+            // Without a tag docker would pull every tag of the repository
+            let mut pull = self.conn.create_image(
+                Some(CreateImageOptions {
+                    from_image: reference.repository.as_str(),
+                    tag: reference.tag_or_digest(),
+                    ..Default::default()
+                }),
+                None,
+                credentials.map(|c| c.to_docker()),
+            );
+            while let Some(result) = pull.next().await {
+                match result {
+                    Ok(CreateImageResults::CreateImageProgressResponse {
+                        status,
+                        progress_detail,
+                        id,
+                        ..
+                    }) => progress(match id {
+                        Some(id) => RegistryProgress::Layer {
+                            id,
+                            status,
+                            current: progress_detail.as_ref().and_then(|d| d.current),
+                            total: progress_detail.as_ref().and_then(|d| d.total),
+                        },
+                        None => RegistryProgress::Status { message: status },
+                    }),
+                    Ok(CreateImageResults::CreateImageError { error, .. }) => {
+                        return Err(format!("Failed to pull {}: {}", image, error))
+                    }
+                    Err(e) => return Err(format!("Failed to pull {}: {:?}", image, e)),
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        if !self.image_exists(image).await? {
+            warn!("Pulled {} but it is still missing", image);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str) -> ImageReference {
+        ImageReference::parse(name).unwrap()
+    }
+
+    #[test]
+    fn names_with_a_registry_port_keep_it_out_of_the_tag() {
+        let reference = parse("localhost:5000/x:1");
+        assert_eq!(reference.repository, "localhost:5000/x");
+        assert_eq!(reference.tag.as_deref(), Some("1"));
+        assert_eq!(reference.digest, None);
+        assert_eq!(reference.registry(), "localhost:5000");
+
+        let reference = parse("localhost:5000/team/x");
+        assert_eq!(reference.repository, "localhost:5000/team/x");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.tag_or_digest(), "latest");
+    }
+
+    #[test]
+    fn digests_are_split_from_the_name() {
+        let digest = "sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+        let reference = parse(&format!("x@{}", digest));
+        assert_eq!(reference.repository, "x");
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.digest.as_deref(), Some(digest));
+        assert_eq!(reference.tag_or_digest(), digest);
+        assert_eq!(reference.registry(), DEFAULT_REGISTRY);
+
+        let reference = parse(&format!("localhost:5000/x:1@{}", digest));
+        assert_eq!(reference.repository, "localhost:5000/x");
+        assert_eq!(reference.tag.as_deref(), Some("1"));
+        assert_eq!(reference.tag_or_digest(), digest);
+    }
+
+    #[test]
+    fn docker_hub_names_belong_to_the_default_registry() {
+        for name in &[
+            "scapegoat",
+            "scapegoat:1.0.0",
+            "library/busybox",
+            "team/scapegoat:1",
+        ] {
+            assert_eq!(parse(name).registry(), DEFAULT_REGISTRY, "{}", name);
+        }
+        assert_eq!(parse("team/scapegoat:1").repository, "team/scapegoat");
+        assert_eq!(parse("docker.io/library/busybox").registry(), "docker.io");
+        assert_eq!(parse("ghcr.io/team/scapegoat").registry(), "ghcr.io");
+        assert_eq!(parse("localhost/scapegoat").registry(), "localhost");
+    }
+
+    #[test]
+    fn malformed_names_are_rejected() {
+        for name in &["", ":1", "x:", "x@", "x@abc", "localhost:5000/x:"] {
+            assert!(ImageReference::parse(name).is_err(), "{}", name);
+        }
+    }
+
+    /// Pushes and pulls through a real registry
+    ///
+    /// Needs a docker daemon and a registry, e.g. `docker run -d -p 5000:5000 registry:2`, then `cargo test -- --ignored`.
+    /// `KRAKEN_TEST_REGISTRY` points it at a registry other than `localhost:5000`.
+    #[tokio::test(threaded_scheduler)]
+    #[ignore]
+    async fn images_round_trip_through_a_local_registry() {
+        let registry =
+            env::var("KRAKEN_TEST_REGISTRY").unwrap_or_else(|_| String::from("localhost:5000"));
+        let docker = DockerBroker::new().await.expect("No docker daemon");
+        let image = format!("{}/kraken-registry-test:1", registry);
+        let ignore = |_| {};
+
+        docker
+            .pull_image("busybox:latest", None, &ignore)
+            .await
+            .unwrap();
+        docker.tag_image("busybox:latest", &image).await.unwrap();
+        let pushed = docker.push_image(&image, None, &ignore).await.unwrap();
+        assert!(pushed
+            .digest
+            .starts_with(&format!("{}/kraken-registry-test@sha256:", registry)));
+
+        docker.remove_image(&image).await.unwrap();
+        assert!(!docker.image_exists(&image).await.unwrap());
+        docker.pull_image(&image, None, &ignore).await.unwrap();
+        assert!(docker.image_exists(&image).await.unwrap());
+
+        docker.remove_image(&image).await.unwrap();
+        docker.remove_image(&pushed.digest).await.unwrap();
+        docker
+            .pull_image(&pushed.digest, None, &ignore)
+            .await
+            .unwrap();
+        assert!(docker.image_exists(&pushed.digest).await.unwrap());
+        docker.remove_image(&pushed.digest).await.unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use super::docker_container::ContainerQuery;
+use super::docker_image::{DockerImage, ImageQuery};
+use super::runtime::ContainerRuntime;
+use super::APP_LABEL;
+
+/// Describes which images of each application should survive a cleanup
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// The number of most recent images to keep for each application
+    pub keep_last: usize,
+
+    /// When set, report what would be removed without removing anything
+    pub dry_run: bool,
+}
+
+impl RetentionPolicy {
+    pub fn new(keep_last: usize) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last,
+            dry_run: false,
+        }
+    }
+
+    /// Returns a copy of this policy which only reports what it would delete
+    pub fn dry_run(mut self) -> RetentionPolicy {
+        self.dry_run = true;
+        self
+    }
+}
+
+impl Default for RetentionPolicy {
+    /// Keeps the current and two previous versions of each application
+    fn default() -> RetentionPolicy {
+        RetentionPolicy::new(3)
+    }
+}
+
+/// The outcome of applying a `RetentionPolicy`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetentionReport {
+    /// Images kept because they are among the most recent for their application
+    pub kept: Vec<String>,
+
+    /// Images kept because a container (running or not) still references them
+    pub in_use: Vec<String>,
+
+    /// Images removed, or which would be removed during a dry run
+    pub removed: Vec<String>,
+
+    /// Images which could not be removed, along with the reason
+    pub failed: Vec<(String, String)>,
+
+    /// Whether this report comes from a dry run
+    pub dry_run: bool,
+}
+
+/// Decides which images to keep and which to remove under a policy
+///
+/// Images are grouped by the value of `app_label`; images without the label are ignored.
+/// Within each group the `keep_last` most recently created images are kept, and older images are only removed if no container uses them.
+///
+/// # Arguments
+///
+/// * `images` - The images to consider
+/// * `in_use` - The ids of images referenced by any container
+/// * `app_label` - The label used to group images by application
+/// * `policy` - The policy to apply
+pub fn plan(
+    images: &[DockerImage],
+    in_use: &HashSet<String>,
+    app_label: &str,
+    policy: &RetentionPolicy,
+) -> RetentionReport {
+    let mut apps: HashMap<&str, Vec<&DockerImage>> = HashMap::new();
+    for image in images {
+        if let Some(app) = image.labels.get(app_label) {
+            apps.entry(app).or_default().push(image);
+        }
+    }
+
+    let mut report = RetentionReport {
+        dry_run: policy.dry_run,
+        ..Default::default()
+    };
+
+    for (_, mut versions) in apps {
+        // Newest first, falling back to the id so the plan is stable
+        versions.sort_by(|a, b| b.created.cmp(&a.created).then(a.id.cmp(&b.id)));
+        for (index, image) in versions.into_iter().enumerate() {
+            if index < policy.keep_last {
+                report.kept.push(image.id.clone());
+            } else if in_use.contains(&image.id) {
+                report.in_use.push(image.id.clone());
+            } else {
+                report.removed.push(image.id.clone());
+            }
+        }
+    }
+
+    report
+}
+
+/// Removes old images of each application from a runtime according to a retention policy
+///
+/// Images are grouped by their `APP_LABEL`, and the `policy.keep_last` most recent images of each application are kept.
+/// Older images are removed unless a container (in any state) still uses them.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime holding the images
+/// * `policy` - How many images to keep per application, and whether this is a dry run
+///
+/// # Examples
+///
+/// ```
+/// let report = retention::apply(&docker, &RetentionPolicy::new(3).dry_run()).await?;
+/// println!("Would remove {:?}", report.removed);
+/// ```
+pub async fn apply(
+    runtime: &dyn ContainerRuntime,
+    policy: &RetentionPolicy,
+) -> Result<RetentionReport, String> {
+    let images = runtime
+        .list_images(&ImageQuery::new().label(APP_LABEL))
+        .await?;
+    let in_use: HashSet<String> = runtime
+        .list_containers(&ContainerQuery::new().all())
+        .await?
+        .into_iter()
+        .filter_map(|c| c.image_id)
+        .collect();
+
+    let mut report = plan(&images, &in_use, APP_LABEL, policy);
+    if policy.dry_run {
+        info!(
+            "Retention dry run would remove {} images: {:?}",
+            report.removed.len(),
+            report.removed
+        );
+        return Ok(report);
+    }
+
+    let mut removed = vec![];
+    for id in report.removed.drain(..) {
+        match runtime.remove_image(&id).await {
+            Ok(()) => {
+                info!("Retention removed image {}", id);
+                removed.push(id);
+            }
+            Err(e) => {
+                error!("Retention failed to remove image {}: {}", id, e);
+                report.failed.push((id, e));
+            }
+        }
+    }
+    report.removed = removed;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::FakeRuntime;
+
+    fn image(id: &str, app: Option<&str>, created: i64) -> DockerImage {
+        let mut labels = HashMap::new();
+        if let Some(app) = app {
+            labels.insert(String::from(APP_LABEL), String::from(app));
+        }
+        DockerImage {
+            id: String::from(id),
+            repo_tags: vec![],
+            size: 0,
+            created,
+            labels,
+            containers: 0,
+        }
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn plan_keeps_the_newest_images_of_each_app() {
+        // Listed out of order, as docker doesn't promise one
+        let images = vec![
+            image("b2", Some("scapegoat"), 200),
+            image("b4", Some("scapegoat"), 400),
+            image("b1", Some("scapegoat"), 100),
+            image("b3", Some("scapegoat"), 300),
+            image("k1", Some("kraken"), 50),
+            image("base", None, 10),
+        ];
+        let in_use = ["b1".to_string()].iter().cloned().collect();
+
+        let report = plan(&images, &in_use, APP_LABEL, &RetentionPolicy::new(2));
+
+        assert_eq!(sorted(report.kept), vec!["b3", "b4", "k1"]);
+        assert_eq!(report.in_use, vec!["b1"]);
+        assert_eq!(report.removed, vec!["b2"]);
+        assert!(!report.dry_run);
+    }
+
+    #[test]
+    fn plan_breaks_ties_in_creation_time_by_id() {
+        let images = vec![
+            image("c", Some("scapegoat"), 100),
+            image("a", Some("scapegoat"), 100),
+            image("b", Some("scapegoat"), 100),
+            image("old", Some("scapegoat"), 50),
+        ];
+        let policy = RetentionPolicy::new(2);
+
+        let report = plan(&images, &HashSet::new(), APP_LABEL, &policy);
+        assert_eq!(report.kept, vec!["a", "b"]);
+        assert_eq!(report.removed, vec!["c", "old"]);
+
+        // The same images in another order give the same plan
+        let reversed: Vec<DockerImage> = images.into_iter().rev().collect();
+        let again = plan(&reversed, &HashSet::new(), APP_LABEL, &policy);
+        assert_eq!(again.kept, report.kept);
+        assert_eq!(again.removed, report.removed);
+    }
+
+    #[test]
+    fn plan_with_nothing_to_keep_still_spares_images_in_use() {
+        let images = vec![
+            image("a", Some("scapegoat"), 100),
+            image("b", Some("scapegoat"), 200),
+        ];
+        let in_use = ["b".to_string()].iter().cloned().collect();
+
+        let report = plan(
+            &images,
+            &in_use,
+            APP_LABEL,
+            &RetentionPolicy::new(0).dry_run(),
+        );
+
+        assert!(report.kept.is_empty());
+        assert_eq!(report.in_use, vec!["b"]);
+        assert_eq!(report.removed, vec!["a"]);
+        assert!(report.dry_run);
+    }
+
+    #[tokio::test]
+    async fn apply_removes_old_unused_images() {
+        let runtime = FakeRuntime::new();
+        // Oldest first, the fake clock orders them by when they were added
+        let tags: Vec<String> = (1..=4)
+            .map(|v| runtime.add_image("scapegoat", &format!("{}.0.0", v)))
+            .collect();
+        let other = runtime.add_image("kraken", "1.0.0");
+        // The fake only records the host port, nothing is bound on this machine
+        runtime.start_container(&tags[0], 22000).await.unwrap();
+        let id = |tag: &str| {
+            runtime
+                .images()
+                .into_iter()
+                .find(|i| i.repo_tags[0] == format!("{}:latest", tag))
+                .unwrap()
+                .id
+        };
+        let ids: Vec<String> = tags.iter().map(|t| id(t)).collect();
+
+        let report = apply(&runtime, &RetentionPolicy::new(2)).await.unwrap();
+
+        assert_eq!(report.in_use, vec![ids[0].clone()]);
+        assert_eq!(report.removed, vec![ids[1].clone()]);
+        assert!(report.failed.is_empty());
+        let mut kept = report.kept.clone();
+        kept.sort();
+        let mut expected = vec![ids[2].clone(), ids[3].clone(), id(&other)];
+        expected.sort();
+        assert_eq!(kept, expected);
+        assert_eq!(runtime.images().len(), 4);
+        assert!(!runtime.image_exists(&tags[1]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn dry_run_removes_nothing() {
+        let runtime = FakeRuntime::new();
+        for v in 1..=3 {
+            runtime.add_image("scapegoat", &format!("{}.0.0", v));
+        }
+
+        let report = apply(&runtime, &RetentionPolicy::new(1).dry_run())
+            .await
+            .unwrap();
+
+        assert_eq!(report.removed.len(), 2);
+        assert!(report.dry_run);
+        assert_eq!(runtime.images().len(), 3);
+    }
+}
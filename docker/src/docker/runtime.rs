@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+use super::container_stats::ContainerStats;
+use super::docker_container::{ContainerQuery, DockerContainer};
+use super::docker_image::{DockerImage, ImageQuery};
+use super::readiness::ReadinessProbe;
+use super::stack::ServiceContainer;
+use super::{DockerImageBuildResult, TestResult};
+
+/// How long `host_port_free` waits for a remote host to answer before taking the port to be free
+const PORT_CHECK_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The operations Kraken needs from whatever builds and runs its applications
+///
+/// `DockerBroker` implements this against a live docker daemon, and in tests `FakeRuntime` implements it in memory so orchestration logic can be exercised without one.
+/// `ProcessRuntime` runs applications directly as child processes on machines without docker.
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    /// Builds an image from a local project folder
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` - The project folder, containing a `Dockerfile`
+    async fn build_image(&self, source_path: &str) -> Result<DockerImageBuildResult, String> {
+        self.build_labelled_image(source_path, &HashMap::new())
+            .await
+    }
+
+    /// Builds an image from a local project folder, adding `labels` to the usual app labels
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` - The project folder, containing a `Dockerfile`
+    /// * `labels` - Extra labels for the image, e.g. `COMMIT_LABEL` for a build of a git commit
+    async fn build_labelled_image(
+        &self,
+        source_path: &str,
+        labels: &HashMap<String, String>,
+    ) -> Result<DockerImageBuildResult, String>;
+
+    /// Runs a command to completion against a built image, such as the `config.test` command of a `shipwreck.toml`
+    ///
+    /// A command which exits non-zero is still `Ok`, `Err` means the command could not be run at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to run the command against
+    /// * `command` - The command, run with `sh -c`
+    /// * `env` - Environment variables to set for the command
+    async fn run_tests(
+        &self,
+        image_id: &str,
+        command: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<TestResult, String>;
+
+    /// Creates and starts a container named after its image, mapping `port` to the same port on the host
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to run
+    /// * `port` - The port to expose
+    async fn start_container(&self, image_id: &str, port: i64) -> Result<String, String> {
+        self.start_named_container(image_id, image_id, port).await
+    }
+
+    /// Creates and starts a container with a name, mapping `port` to the same port on the host
+    ///
+    /// Naming containers lets several replicas of the same image run side by side.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to run
+    /// * `name` - The name to give the container, which must not be in use
+    /// * `port` - The port to expose
+    async fn start_named_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        port: i64,
+    ) -> Result<String, String> {
+        self.start_mapped_container(image_id, name, port, port)
+            .await
+    }
+
+    /// Creates and starts a container with a name, publishing `container_port` on `host_port` of the host
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to run
+    /// * `name` - The name to give the container, which must not be in use
+    /// * `container_port` - The port the application listens on inside the container (see `container_port`)
+    /// * `host_port` - The host port to publish it on
+    async fn start_mapped_container(
+        &self,
+        image_id: &str,
+        name: &str,
+        container_port: i64,
+        host_port: i64,
+    ) -> Result<String, String>;
+
+    /// Stops a running container
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    async fn stop_container(&self, container_id: &str) -> Result<(), String>;
+
+    /// Removes a stopped container, freeing its name
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    async fn remove_container(&self, container_id: &str) -> Result<(), String>;
+
+    /// Checks whether an image still exists, e.g. before rolling back to it
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to look for
+    async fn image_exists(&self, image_id: &str) -> Result<bool, String>;
+
+    /// Removes an image which no container uses any more
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The id or tag of the image
+    async fn remove_image(&self, image_id: &str) -> Result<(), String>;
+
+    /// Gets the port the application in an image listens on inside its container
+    ///
+    /// `None` means the application listens on whichever port it is started with, so the host port can be used for both.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_id` - The image to look at
+    async fn container_port(&self, image_id: &str) -> Result<Option<i64>, String>;
+
+    /// Lists the images matching a query
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Filters restricting which images are returned
+    async fn list_images(&self, query: &ImageQuery) -> Result<Vec<DockerImage>, String>;
+
+    /// Lists the containers matching a query
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Filters restricting which containers are returned
+    async fn list_containers(&self, query: &ContainerQuery)
+        -> Result<Vec<DockerContainer>, String>;
+
+    /// Gets the current resource usage of a running container
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    async fn container_stats(&self, container_id: &str) -> Result<ContainerStats, String>;
+
+    /// Gets the most recent output (stdout and stderr) of a container
+    ///
+    /// # Arguments
+    ///
+    /// * `container_id` - The id or name of the container
+    /// * `tail` - The number of lines to return from the end of the output, `None` for all of it
+    async fn container_logs(
+        &self,
+        container_id: &str,
+        tail: Option<usize>,
+    ) -> Result<Vec<String>, String>;
+
+    /// Removes stopped containers and unused images
+    async fn prune(&self) -> Result<(), String>;
+
+    /// Creates a network for the services of a stack
+    ///
+    /// Runtimes without networks refuse, so stacks need a docker daemon.
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name from the `shipwreck.toml`, which the network is labelled with
+    /// * `name` - The name of the network
+    async fn create_network(&self, app: &str, name: &str) -> Result<(), String> {
+        let _ = app;
+        Err(format!(
+            "Failed to create network {}: stacks need a docker daemon",
+            name
+        ))
+    }
+
+    /// Lists the networks created for an app's stack, by name
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name from the `shipwreck.toml`
+    async fn list_networks(&self, app: &str) -> Result<Vec<String>, String> {
+        let _ = app;
+        Ok(vec![])
+    }
+
+    /// Removes a network which no container is attached to any more
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the network
+    async fn remove_network(&self, name: &str) -> Result<(), String> {
+        Err(format!("No such network: {}", name))
+    }
+
+    /// Creates and starts the container of a stack service, attached to its networks
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The container to create, whose networks must already exist
+    async fn start_service(&self, service: &ServiceContainer) -> Result<String, String> {
+        Err(format!(
+            "Failed to start {}: stacks need a docker daemon",
+            service.name
+        ))
+    }
+
+    /// Makes sure an image is present, pulling it from its registry if it isn't
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The image reference, e.g. `redis:6`
+    async fn pull_image_if_missing(&self, image: &str) -> Result<(), String> {
+        if self.image_exists(image).await? {
+            Ok(())
+        } else {
+            Err(format!("No such image: {}", image))
+        }
+    }
+
+    /// The host the ports containers publish are reachable on, e.g. the docker daemon's host rather than the agent's
+    fn published_host(&self) -> String {
+        String::from("127.0.0.1")
+    }
+
+    /// Checks whether nothing is listening on a host port of `published_host`, so a container can be published on it
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The host port
+    async fn host_port_free(&self, port: i64) -> bool {
+        let host = self.published_host();
+        if is_loopback(&host) {
+            // Docker publishes on every interface, so the port has to be free on all of them
+            return TcpListener::bind(("0.0.0.0", port as u16)).is_ok();
+        }
+        let addr = format!("{}:{}", host, port);
+        !matches!(
+            tokio::time::timeout(PORT_CHECK_TIMEOUT, TcpStream::connect(&addr)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Probes the application published on a host port once
+    ///
+    /// # Arguments
+    ///
+    /// * `probe` - How to tell whether the application is ready
+    /// * `port` - The host port the application is published on
+    /// * `timeout` - How long to wait for the application to answer
+    async fn check_ready(
+        &self,
+        probe: &ReadinessProbe,
+        port: i64,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        probe.check(&self.published_host(), port, timeout).await
+    }
+}
+
+/// Whether a host name is this machine
+fn is_loopback(host: &str) -> bool {
+    matches!(host, "localhost" | "[::1]" | "::1") || host.starts_with("127.")
+}
@@ -0,0 +1,545 @@
+use flate2::read::GzDecoder;
+use futures_util::stream::{Stream, StreamExt};
+use log::{info, warn};
+use std::fmt::Display;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+use uuid::Uuid;
+
+use super::manifest::{ShipwreckManifest, MANIFEST_FILE_NAME};
+use super::{DockerBroker, DockerImageBuildResult};
+
+/// Where uploaded archives are unpacked while they are built
+const SCRATCH_DIR: &str = "./tmp/uploads";
+
+/// Bounds on an uploaded build context, so one upload can't exhaust the agent's memory or disk
+#[derive(Debug, Clone)]
+pub struct ArchiveLimits {
+    /// The largest archive accepted, as uploaded (compressed if it is gzipped)
+    pub max_archive_bytes: u64,
+
+    /// The largest total size of the files in the archive once unpacked
+    pub max_unpacked_bytes: u64,
+
+    /// The most files, folders and links the archive may hold
+    pub max_entries: usize,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> ArchiveLimits {
+        ArchiveLimits {
+            max_archive_bytes: 100 * 1024 * 1024,
+            max_unpacked_bytes: 500 * 1024 * 1024,
+            max_entries: 10_000,
+        }
+    }
+}
+
+/// A project unpacked from an uploaded tar or tar.gz archive
+///
+/// The scratch folder it was unpacked into is removed when this is dropped.
+#[derive(Debug)]
+pub struct SourceArchive {
+    /// The scratch folder the archive was unpacked into
+    scratch: PathBuf,
+
+    /// The folder holding the `Dockerfile`, which is the archive root or the single folder in it
+    root: PathBuf,
+
+    /// The project's `shipwreck.toml`, if it has one next to the `Dockerfile`
+    pub manifest: Option<ShipwreckManifest>,
+}
+
+impl SourceArchive {
+    /// Validates and unpacks an archive held in memory
+    ///
+    /// Entries which would land outside the scratch folder (absolute paths, `..`, or links pointing out of the archive) are rejected,
+    /// as are device files and archives over `limits`.
+    /// This decompresses and writes the whole archive on the calling thread, so from async code use `unpack_blocking` instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `archive` - A tar archive, optionally gzipped
+    /// * `limits` - The sizes the archive must stay within
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let upload = fs::read("scapegoat.tar.gz")?;
+    /// let source = SourceArchive::unpack(&upload, &ArchiveLimits::default())?;
+    /// docker.build_image(&source.path()).await?;
+    /// ```
+    pub fn unpack(archive: &[u8], limits: &ArchiveLimits) -> Result<SourceArchive, String> {
+        SourceArchive::unpack_into(Path::new(SCRATCH_DIR), archive, limits)
+    }
+
+    /// Unpacks an archive like `unpack`, on tokio's blocking thread pool so the executor isn't held up
+    ///
+    /// # Arguments
+    ///
+    /// * `archive` - A tar archive, optionally gzipped
+    /// * `limits` - The sizes the archive must stay within
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let upload = fs::read("scapegoat.tar.gz")?;
+    /// let source = SourceArchive::unpack_blocking(upload, &ArchiveLimits::default()).await?;
+    /// ```
+    pub async fn unpack_blocking(
+        archive: Vec<u8>,
+        limits: &ArchiveLimits,
+    ) -> Result<SourceArchive, String> {
+        let limits = limits.clone();
+        tokio::task::spawn_blocking(move || SourceArchive::unpack(&archive, &limits))
+            .await
+            .map_err(|e| format!("Failed to unpack archive: {}", e))?
+    }
+
+    fn unpack_into(
+        scratch_dir: &Path,
+        archive: &[u8],
+        limits: &ArchiveLimits,
+    ) -> Result<SourceArchive, String> {
+        if archive.len() as u64 > limits.max_archive_bytes {
+            return Err(format!(
+                "Archive is {} bytes, over the limit of {}",
+                archive.len(),
+                limits.max_archive_bytes
+            ));
+        }
+        let scratch = scratch_dir.join(Uuid::new_v4().to_hyphenated().to_string());
+        fs::create_dir_all(&scratch)
+            .map_err(|e| format!("Failed to create {}: {}", scratch.display(), e))?;
+        // From here on the scratch folder is cleaned up on error by dropping `source`
+        let mut source = SourceArchive {
+            root: scratch.clone(),
+            scratch,
+            manifest: None,
+        };
+
+        // Gzip streams start with 1f 8b
+        let reader: Box<dyn Read> = if archive.starts_with(&[0x1f, 0x8b]) {
+            Box::new(GzDecoder::new(archive))
+        } else {
+            Box::new(archive)
+        };
+        unpack_entries(reader, &source.scratch, limits)?;
+
+        source.root = find_project_root(&source.scratch)?;
+        if source.root.join(MANIFEST_FILE_NAME).is_file() {
+            source.manifest = Some(ShipwreckManifest::from_dir(&source.path())?);
+        }
+        info!("Unpacked upload into {}", source.root.display());
+        Ok(source)
+    }
+
+    /// Reads an archive from a stream of chunks, such as an HTTP body, then unpacks it
+    ///
+    /// The stream is abandoned as soon as it passes `limits.max_archive_bytes`, rather than after buffering all of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The chunks of a tar archive, optionally gzipped
+    /// * `limits` - The sizes the archive must stay within
+    pub async fn unpack_stream<S, B, E>(
+        stream: S,
+        limits: &ArchiveLimits,
+    ) -> Result<SourceArchive, String>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: AsRef<[u8]>,
+        E: Display,
+    {
+        let archive = read_stream(stream, limits.max_archive_bytes).await?;
+        SourceArchive::unpack_blocking(archive, limits).await
+    }
+
+    /// The project folder, which can be passed to `DockerBroker::build_image`
+    pub fn path(&self) -> String {
+        self.root.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for SourceArchive {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.scratch) {
+            warn!("Failed to remove {}: {}", self.scratch.display(), e);
+        }
+    }
+}
+
+/// Collects a stream of chunks, failing once it grows past `max_bytes`
+///
+/// # Arguments
+///
+/// * `stream` - The chunks to collect
+/// * `max_bytes` - The most bytes to accept
+pub async fn read_stream<S, B, E>(mut stream: S, max_bytes: u64) -> Result<Vec<u8>, String>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    let mut data = vec![];
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| format!("Failed to read body: {}", e))?;
+        data.extend_from_slice(chunk.as_ref());
+        if data.len() as u64 > max_bytes {
+            return Err(format!("Body is over the limit of {} bytes", max_bytes));
+        }
+    }
+    Ok(data)
+}
+
+fn unpack_entries(reader: impl Read, dest: &Path, limits: &ArchiveLimits) -> Result<(), String> {
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("Failed to read archive: {}", e))?;
+    let mut count = 0;
+    let mut unpacked_bytes = 0;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Failed to read archive: {}", e))?;
+        let path = entry
+            .path()
+            .map_err(|e| format!("Invalid path in archive: {}", e))?
+            .into_owned();
+        if escapes_root(Path::new(""), &path) {
+            return Err(format!(
+                "{} would be unpacked outside the archive",
+                path.display()
+            ));
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .map_err(|e| format!("Invalid link {}: {}", path.display(), e))?
+                    .ok_or_else(|| format!("Link {} has no target", path.display()))?
+                    .into_owned();
+                // Symlinks are relative to their own folder, hard links to the archive root
+                let base = if entry.header().entry_type() == EntryType::Symlink {
+                    path.parent().unwrap_or_else(|| Path::new(""))
+                } else {
+                    Path::new("")
+                };
+                if escapes_root(base, &target) {
+                    return Err(format!(
+                        "{} links to {}, outside the archive",
+                        path.display(),
+                        target.display()
+                    ));
+                }
+            }
+            // Global pax headers only carry metadata
+            EntryType::XGlobalHeader => continue,
+            other => {
+                return Err(format!(
+                    "{} is a {:?}, which can't be part of a build context",
+                    path.display(),
+                    other
+                ))
+            }
+        }
+
+        count += 1;
+        if count > limits.max_entries {
+            return Err(format!(
+                "Archive has more than {} entries",
+                limits.max_entries
+            ));
+        }
+        unpacked_bytes += entry.size();
+        if unpacked_bytes > limits.max_unpacked_bytes {
+            return Err(format!(
+                "Archive unpacks to over the limit of {} bytes",
+                limits.max_unpacked_bytes
+            ));
+        }
+
+        let unpacked = entry
+            .unpack_in(dest)
+            .map_err(|e| format!("Failed to unpack {}: {}", path.display(), e))?;
+        if !unpacked {
+            return Err(format!(
+                "{} would be unpacked outside the archive",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path`, taken relative to `base` inside the archive, points outside of the archive
+fn escapes_root(base: &Path, path: &Path) -> bool {
+    let mut depth = base
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count();
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Finds the folder holding the `Dockerfile`
+///
+/// Archives made with `tar -C project .` have it at the root, ones made with `tar project/` have it inside a single folder.
+fn find_project_root(dir: &Path) -> Result<PathBuf, String> {
+    let mut root = dir.to_path_buf();
+    loop {
+        if root.join("Dockerfile").is_file() {
+            return Ok(root);
+        }
+        let entries: Vec<PathBuf> = fs::read_dir(&root)
+            .map_err(|e| format!("Failed to read {}: {}", root.display(), e))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        match entries.as_slice() {
+            [only] if only.is_dir() => root = only.clone(),
+            _ => return Err(String::from("No Dockerfile at the root of the archive")),
+        }
+    }
+}
+
+impl DockerBroker {
+    /// Builds a docker image from a project uploaded as a tar or tar.gz archive
+    ///
+    /// The archive is unpacked into a scratch folder (see `SourceArchive::unpack`), built like `build_image`, then removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `archive` - The project as a tar archive, optionally gzipped, with a `Dockerfile` at its root
+    /// * `limits` - The sizes the archive must stay within
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let upload = fs::read("scapegoat.tar.gz")?;
+    /// let build = docker.build_image_from_archive(upload, &ArchiveLimits::default()).await?;
+    /// ```
+    pub async fn build_image_from_archive(
+        &self,
+        archive: Vec<u8>,
+        limits: &ArchiveLimits,
+    ) -> Result<DockerImageBuildResult, String> {
+        let source = SourceArchive::unpack_blocking(archive, limits).await?;
+        self.build_image(&source.path()).await
+    }
+
+    /// Builds a docker image from a project archive read from a stream of chunks, such as an HTTP body
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The chunks of a tar archive, optionally gzipped, with a `Dockerfile` at its root
+    /// * `limits` - The sizes the archive must stay within
+    pub async fn build_image_from_stream<S, B, E>(
+        &self,
+        stream: S,
+        limits: &ArchiveLimits,
+    ) -> Result<DockerImageBuildResult, String>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: AsRef<[u8]>,
+        E: Display,
+    {
+        let source = SourceArchive::unpack_stream(stream, limits).await?;
+        self.build_image(&source.path()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tar::{Builder, Header};
+
+    /// A scratch folder of its own for each test, removed once it is done
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = std::env::temp_dir().join(format!("kraken-archive-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn unpack(&self, archive: &[u8], limits: &ArchiveLimits) -> Result<SourceArchive, String> {
+            SourceArchive::unpack_into(&self.0, archive, limits)
+        }
+
+        fn is_empty(&self) -> bool {
+            fs::read_dir(&self.0).unwrap().next().is_none()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A header whose path and link target are written as given, as `Header::set_path` refuses unsafe ones
+    fn raw_header(entry_type: EntryType, path: &str, link: &str, size: u64) -> Header {
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(size);
+        header.set_cksum();
+        header
+    }
+
+    /// An archive of `files`, as paths and contents
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        for (path, contents) in files {
+            let header = raw_header(EntryType::Regular, path, "", contents.len() as u64);
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn with_entry(entry_type: EntryType, path: &str, link: &str) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        let dockerfile = raw_header(EntryType::Regular, "Dockerfile", "", 0);
+        builder.append(&dockerfile, &b""[..]).unwrap();
+        builder
+            .append(&raw_header(entry_type, path, link, 0), &b""[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn entries_outside_the_archive_are_rejected() {
+        let scratch = Scratch::new();
+        let limits = ArchiveLimits::default();
+        for path in &["../x", "/etc/x", "src/../../x"] {
+            let upload = archive(&[("Dockerfile", "FROM scratch"), (path, "pwned")]);
+            let error = scratch.unpack(&upload, &limits).unwrap_err();
+            assert!(error.contains("outside the archive"), "{}", error);
+        }
+
+        let escaping = with_entry(EntryType::Symlink, "src/passwd", "../../etc/passwd");
+        let error = scratch.unpack(&escaping, &limits).unwrap_err();
+        assert!(error.contains("links to ../../etc/passwd"), "{}", error);
+        let inside = with_entry(EntryType::Symlink, "src/main.rs", "../Dockerfile");
+        assert!(scratch.unpack(&inside, &limits).is_ok());
+    }
+
+    #[test]
+    fn device_entries_are_rejected() {
+        let scratch = Scratch::new();
+        for entry_type in &[EntryType::Char, EntryType::Block, EntryType::Fifo] {
+            let upload = with_entry(*entry_type, "dev", "");
+            let error = scratch
+                .unpack(&upload, &ArchiveLimits::default())
+                .unwrap_err();
+            assert!(
+                error.contains("can't be part of a build context"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn archives_over_their_limits_are_rejected() {
+        let scratch = Scratch::new();
+        let upload = archive(&[("Dockerfile", "FROM scratch"), ("big", &"x".repeat(2048))]);
+        let limits = |max_archive_bytes, max_unpacked_bytes, max_entries| ArchiveLimits {
+            max_archive_bytes,
+            max_unpacked_bytes,
+            max_entries,
+        };
+
+        let error = scratch
+            .unpack(&upload, &limits(1024, 1 << 20, 10))
+            .unwrap_err();
+        assert!(error.contains("over the limit of 1024"), "{}", error);
+        let error = scratch
+            .unpack(&upload, &limits(1 << 20, 1024, 10))
+            .unwrap_err();
+        assert!(error.contains("unpacks to over the limit"), "{}", error);
+        let error = scratch
+            .unpack(&upload, &limits(1 << 20, 1 << 20, 1))
+            .unwrap_err();
+        assert!(error.contains("more than 1 entries"), "{}", error);
+        assert!(scratch
+            .unpack(&upload, &limits(1 << 20, 1 << 20, 2))
+            .is_ok());
+    }
+
+    #[test]
+    fn gzipped_archives_are_unpacked() {
+        let scratch = Scratch::new();
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(&archive(&[("Dockerfile", "FROM scratch")]))
+            .unwrap();
+        let upload = gzip.finish().unwrap();
+
+        let source = scratch.unpack(&upload, &ArchiveLimits::default()).unwrap();
+
+        let dockerfile = fs::read_to_string(Path::new(&source.path()).join("Dockerfile"));
+        assert_eq!(dockerfile.unwrap(), "FROM scratch");
+    }
+
+    #[test]
+    fn projects_are_found_at_the_root_or_in_a_single_folder() {
+        let scratch = Scratch::new();
+        let limits = ArchiveLimits::default();
+
+        let at_root = scratch
+            .unpack(&archive(&[("Dockerfile", "FROM scratch")]), &limits)
+            .unwrap();
+        assert_eq!(at_root.root, at_root.scratch);
+        let nested = scratch
+            .unpack(
+                &archive(&[("scapegoat/Dockerfile", "FROM scratch")]),
+                &limits,
+            )
+            .unwrap();
+        assert_eq!(nested.root, nested.scratch.join("scapegoat"));
+        let loose = archive(&[("a/Dockerfile", ""), ("b/Dockerfile", "")]);
+        let error = scratch.unpack(&loose, &limits).unwrap_err();
+        assert_eq!(error, "No Dockerfile at the root of the archive");
+    }
+
+    #[test]
+    fn scratch_folders_are_removed_on_error_and_drop() {
+        let scratch = Scratch::new();
+        let limits = ArchiveLimits::default();
+
+        assert!(scratch
+            .unpack(&archive(&[("README.md", "")]), &limits)
+            .is_err());
+        assert!(scratch.is_empty());
+        let source = scratch
+            .unpack(&archive(&[("Dockerfile", "FROM scratch")]), &limits)
+            .unwrap();
+        assert!(!scratch.is_empty());
+        drop(source);
+        assert!(scratch.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_are_cut_off_past_their_limit() {
+        let chunks = vec![Ok::<_, String>(vec![0u8; 600]), Ok(vec![0u8; 600])];
+        let error = read_stream(futures_util::stream::iter(chunks), 1000).await;
+        assert_eq!(error.unwrap_err(), "Body is over the limit of 1000 bytes");
+    }
+}
@@ -0,0 +1,532 @@
+use bollard::container::{Config, CreateContainerOptions, NetworkingConfig, StartContainerOptions};
+use bollard::models::EndpointSettings;
+use bollard::network::{
+    ConnectNetworkOptions, CreateNetworkOptions, EndpointSettings as NetworkEndpointSettings,
+    ListNetworksOptions,
+};
+use bollard::service::{HostConfig, PortBinding};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use super::docker_container::{ContainerQuery, CreatedOrder};
+use super::manifest::{ServiceSection, ShipwreckManifest};
+use super::registry::{ImageReference, RegistryCredentials};
+use super::runtime::ContainerRuntime;
+use super::timeouts::with_timeout;
+use super::{DockerBroker, APP_LABEL, MANAGED_LABEL, SERVICE_LABEL, VERSION_LABEL};
+
+/// The stack network services join when they don't list any
+pub const DEFAULT_NETWORK: &str = "default";
+
+/// A service of a stack which has been started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackService {
+    /// The service name from the `shipwreck.toml`
+    pub name: String,
+
+    /// The ID of the service's container
+    pub container_id: String,
+
+    /// The image the service is running
+    pub image: String,
+
+    /// The host ports the service publishes
+    pub ports: Vec<i64>,
+}
+
+/// A multi-service application which has been brought up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackDeployment {
+    /// The application name from the `shipwreck.toml`
+    pub app: String,
+
+    /// The docker networks created for the stack
+    pub networks: Vec<String>,
+
+    /// The services, in the order they were started
+    pub services: Vec<StackService>,
+}
+
+/// The container of one service of a stack, as `ContainerRuntime::start_service` creates it
+#[derive(Debug, Clone)]
+pub struct ServiceContainer {
+    /// The container name, `<app>-<service>`
+    pub name: String,
+
+    /// The service name, which other services reach it by on the networks they share
+    pub alias: String,
+
+    /// The image to run
+    pub image: String,
+
+    /// A command to run instead of the image's default, through `sh -c`
+    pub command: Option<String>,
+
+    pub env: HashMap<String, String>,
+    pub labels: HashMap<String, String>,
+
+    /// The ports to publish, each mapped to the same port on the host
+    pub ports: Vec<i64>,
+
+    /// The docker networks to attach to, created beforehand with `ContainerRuntime::create_network`
+    pub networks: Vec<String>,
+}
+
+/// The docker name of a stack network, which is scoped to its app so stacks don't share networks by accident
+fn network_name(app: &str, network: &str) -> String {
+    format!("{}_{}", app, network)
+}
+
+/// The networks a service joins, by their name in the `shipwreck.toml`
+fn service_networks(service: &ServiceSection) -> Vec<String> {
+    if service.networks.is_empty() {
+        vec![String::from(DEFAULT_NETWORK)]
+    } else {
+        service.networks.clone()
+    }
+}
+
+/// Brings up every service declared in a project's `shipwreck.toml`, as a unit
+///
+/// Networks are created first, then each service's image is built or pulled and its container started, dependencies first.
+/// Services reach each other by service name on the networks they share.
+/// Anything already running for the stack is brought down first, and if any service fails to start the whole stack is brought down again.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime to run the stack on
+/// * `source_path` - The project folder, containing a `shipwreck.toml` with `[services.*]` tables
+///
+/// # Examples
+///
+/// ```
+/// let docker = DockerBroker::new();
+/// let stack = stack::stack_up(&docker, "./tmp/scapegoat").await?;
+/// for s in stack.services {
+///     println!("{} is {} on {:?}", s.name, s.container_id, s.ports);
+/// }
+/// ```
+pub async fn stack_up(
+    runtime: &dyn ContainerRuntime,
+    source_path: &str,
+) -> Result<StackDeployment, String> {
+    let manifest = ShipwreckManifest::from_dir(source_path)?;
+    if manifest.services.is_empty() {
+        return Err(format!("{} declares no services", source_path));
+    }
+    let order = manifest.service_order()?;
+    let app = manifest.app.name.clone();
+    stack_down(runtime, &app).await?;
+
+    info!("Bringing up stack {} with services {:?}", app, order);
+    match start_stack(runtime, source_path, &manifest, &order).await {
+        Ok(stack) => Ok(stack),
+        Err(e) => {
+            error!("Stack {} failed to come up, bringing it down: {}", app, e);
+            if let Err(down) = stack_down(runtime, &app).await {
+                error!("Failed to bring down stack {}: {}", app, down);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Stops and removes every service container and network of a stack
+///
+/// Containers are stopped newest first, which is the reverse of the order `stack_up` started them in.
+/// A container or network which can't be removed doesn't stop the rest from being removed, the errors are returned together at the end.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime the stack runs on
+/// * `app` - The application name from the `shipwreck.toml`
+pub async fn stack_down(runtime: &dyn ContainerRuntime, app: &str) -> Result<(), String> {
+    let query = ContainerQuery::new()
+        .all()
+        .app(app)
+        .label(SERVICE_LABEL)
+        .sort_by_created(CreatedOrder::NewestFirst);
+    let mut errors = vec![];
+    for c in runtime.list_containers(&query).await? {
+        if c.state.as_deref() == Some("running") {
+            if let Err(e) = runtime.stop_container(&c.id).await {
+                warn!("Failed to stop {} of stack {}: {}", c.name, app, e);
+                errors.push(e);
+                continue;
+            }
+        }
+        if let Err(e) = runtime.remove_container(&c.id).await {
+            warn!("Failed to remove {} of stack {}: {}", c.name, app, e);
+            errors.push(e);
+        }
+    }
+
+    // A network can't be removed while a container is still attached to it
+    if errors.is_empty() {
+        for network in runtime.list_networks(app).await? {
+            match runtime.remove_network(&network).await {
+                Ok(()) => info!("Removed network {}", network),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Failed to bring down stack {}: {}",
+            app,
+            errors.join("; ")
+        ))
+    }
+}
+
+async fn start_stack(
+    runtime: &dyn ContainerRuntime,
+    source_path: &str,
+    manifest: &ShipwreckManifest,
+    order: &[String],
+) -> Result<StackDeployment, String> {
+    let app = &manifest.app.name;
+    let networks: BTreeSet<String> = manifest
+        .services
+        .values()
+        .flat_map(service_networks)
+        .collect();
+    let mut created_networks = vec![];
+    for network in &networks {
+        let name = network_name(app, network);
+        runtime.create_network(app, &name).await?;
+        created_networks.push(name);
+    }
+
+    // Services sharing a build folder share its image
+    let mut built: HashMap<String, String> = HashMap::new();
+    let mut services = vec![];
+    for name in order {
+        let service = &manifest.services[name];
+        let image = match (&service.build, &service.image) {
+            (Some(build), _) => match built.get(build) {
+                Some(image) => image.clone(),
+                None => {
+                    let path = Path::new(source_path).join(build);
+                    let result = runtime.build_image(&path.to_string_lossy()).await?;
+                    built.insert(build.clone(), result.image_id.clone());
+                    result.image_id
+                }
+            },
+            (None, Some(image)) => {
+                runtime.pull_image_if_missing(image).await?;
+                image.clone()
+            }
+            (None, None) => unreachable!("service_order checks every service has an image"),
+        };
+
+        let mut env = manifest.env_vars.clone();
+        env.extend(service.env.clone());
+        let mut labels = HashMap::new();
+        labels.insert(String::from(MANAGED_LABEL), String::from("true"));
+        labels.insert(String::from(APP_LABEL), app.clone());
+        labels.insert(String::from(VERSION_LABEL), manifest.app.version.clone());
+        labels.insert(String::from(SERVICE_LABEL), name.clone());
+        let container = ServiceContainer {
+            name: format!("{}-{}", app, name),
+            alias: name.clone(),
+            image: image.clone(),
+            command: service.command.clone(),
+            env,
+            labels,
+            ports: service.ports.clone(),
+            networks: service_networks(service)
+                .iter()
+                .map(|n| network_name(app, n))
+                .collect(),
+        };
+        let container_id = runtime.start_service(&container).await?;
+        info!("Started service {} of {} as {}", name, app, container_id);
+        services.push(StackService {
+            name: name.clone(),
+            container_id,
+            image,
+            ports: service.ports.clone(),
+        });
+    }
+    info!("Stack {} is up", app);
+    Ok(StackDeployment {
+        app: app.clone(),
+        networks: created_networks,
+        services,
+    })
+}
+
+impl DockerBroker {
+    /// Creates a bridge network for the services of a stack, labelled with its app
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name from the `shipwreck.toml`
+    /// * `name` - The docker name of the network
+    pub async fn create_stack_network(&self, app: &str, name: &str) -> Result<(), String> {
+        let mut labels = HashMap::new();
+        labels.insert(MANAGED_LABEL, "true");
+        labels.insert(APP_LABEL, app);
+        with_timeout("create network", self.timeouts.start, async {
+            self.conn
+                .create_network(CreateNetworkOptions {
+                    name,
+                    check_duplicate: true,
+                    driver: "bridge",
+                    labels,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| format!("Failed to create network {}: {:?}", name, e))
+        })
+        .await?;
+        info!("Created network {}", name);
+        Ok(())
+    }
+
+    /// Lists the networks of an app's stack, by docker name
+    ///
+    /// # Arguments
+    ///
+    /// * `app` - The application name from the `shipwreck.toml`
+    pub async fn list_stack_networks(&self, app: &str) -> Result<Vec<String>, String> {
+        let app_label = format!("{}={}", APP_LABEL, app);
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![MANAGED_LABEL, app_label.as_str()]);
+        let networks = with_timeout("list networks", self.timeouts.list, async {
+            self.conn
+                .list_networks(Some(ListNetworksOptions { filters }))
+                .await
+                .map_err(|e| format!("Failed to list networks of {}: {:?}", app, e))
+        })
+        .await?;
+        Ok(networks.into_iter().map(|n| n.name).collect())
+    }
+
+    /// Removes a network which no container is attached to any more
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The docker name of the network
+    pub async fn remove_stack_network(&self, name: &str) -> Result<(), String> {
+        with_timeout("remove network", self.timeouts.prune, async {
+            self.conn
+                .remove_network(name)
+                .await
+                .map_err(|e| format!("Failed to remove network {}: {:?}", name, e))
+        })
+        .await
+    }
+
+    /// Creates and starts the container of one service, attached to its networks under the service's name
+    ///
+    /// # Arguments
+    ///
+    /// * `service` - The container to create, whose networks must already exist
+    pub async fn start_service(&self, service: &ServiceContainer) -> Result<String, String> {
+        let mut exposed_ports = HashMap::new();
+        let mut port_bindings = HashMap::new();
+        for port in &service.ports {
+            exposed_ports.insert(format!("{}/tcp", port), HashMap::new());
+            port_bindings.insert(
+                format!("{}/tcp", port),
+                Some(vec![PortBinding {
+                    host_ip: Some(String::from("0.0.0.0")),
+                    host_port: Some(port.to_string()),
+                }]),
+            );
+        }
+
+        // A container can only be created on one network, the rest are connected before it starts
+        let first_network = service
+            .networks
+            .first()
+            .cloned()
+            .ok_or_else(|| format!("Service {} has no network", service.name))?;
+        let mut endpoints_config = HashMap::new();
+        endpoints_config.insert(
+            first_network.clone(),
+            EndpointSettings {
+                aliases: Some(vec![service.alias.clone()]),
+                ..Default::default()
+            },
+        );
+        let config = Config {
+            image: Some(service.image.clone()),
+            cmd: service
+                .command
+                .as_ref()
+                .map(|c| vec![String::from("sh"), String::from("-c"), c.clone()]),
+            env: Some(
+                service
+                    .env
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect(),
+            ),
+            labels: Some(service.labels.clone()),
+            exposed_ports: Some(exposed_ports),
+            host_config: Some(HostConfig {
+                port_bindings: Some(port_bindings),
+                network_mode: Some(first_network),
+                ..Default::default()
+            }),
+            networking_config: Some(NetworkingConfig { endpoints_config }),
+            ..Default::default()
+        };
+
+        with_timeout("start container", self.timeouts.start, async {
+            let created = self
+                .conn
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: service.name.as_str(),
+                    }),
+                    config,
+                )
+                .await
+                .map_err(|e| format!("Failed to create {}: {:?}", service.name, e))?;
+            for network in &service.networks[1..] {
+                self.conn
+                    .connect_network(
+                        network,
+                        ConnectNetworkOptions {
+                            container: created.id.as_str(),
+                            endpoint_config: NetworkEndpointSettings {
+                                aliases: vec![service.alias.as_str()],
+                                ..Default::default()
+                            },
+                        },
+                    )
+                    .await
+                    .map_err(|e| {
+                        format!(
+                            "Failed to attach {} to network {}: {:?}",
+                            service.name, network, e
+                        )
+                    })?;
+            }
+            self.conn
+                .start_container(&created.id, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(|e| format!("Failed to start {}: {:?}", service.name, e))?;
+            Ok(created.id)
+        })
+        .await
+    }
+
+    /// Pulls an image from its registry unless it is already present
+    pub async fn pull_image_if_missing(&self, image: &str) -> Result<(), String> {
+        if self.image_exists(image).await? {
+            return Ok(());
+        }
+        let registry = ImageReference::parse(image)?.registry().to_string();
+        let credentials = RegistryCredentials::lookup(&registry)?;
+        self.pull_image(image, credentials.as_ref(), &|_| {}).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::docker_container::DockerContainer;
+    use crate::docker::fake_runtime::{FakeRuntime, TempProject};
+
+    /// A stack whose web and worker are built from `app/`, and share redis
+    fn project() -> TempProject {
+        TempProject::with_files(&[
+            (
+                "shipwreck.toml",
+                "[app]\nname = \"scapegoat\"\nversion = \"1.0.0\"\n\n[config]\n\n\
+                 [services.web]\nbuild = \"app\"\nports = [9000]\ndepends-on = [\"redis\"]\n\
+                 networks = [\"front\", \"back\"]\n\n\
+                 [services.worker]\nbuild = \"app\"\ncommand = \"python worker.py\"\n\
+                 depends-on = [\"redis\"]\nnetworks = [\"back\"]\n\n\
+                 [services.redis]\nimage = \"redis:6\"\nnetworks = [\"back\"]\n",
+            ),
+            ("app/Dockerfile", "FROM scratch"),
+        ])
+    }
+
+    async fn services(runtime: &FakeRuntime) -> Vec<DockerContainer> {
+        let query = ContainerQuery::new()
+            .all()
+            .label(SERVICE_LABEL)
+            .sort_by_created(CreatedOrder::OldestFirst);
+        runtime.list_containers(&query).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn services_come_up_after_their_dependencies_on_their_networks() {
+        let runtime = FakeRuntime::new();
+        let project = project();
+
+        let stack = stack_up(&runtime, &project.path()).await.unwrap();
+
+        let names: Vec<&str> = stack.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["redis", "web", "worker"]);
+        assert_eq!(stack.networks, vec!["scapegoat_back", "scapegoat_front"]);
+        // web and worker share the image built from app/
+        assert_eq!(stack.services[1].image, stack.services[2].image);
+        assert_eq!(runtime.images().len(), 2);
+        let containers = services(&runtime).await;
+        assert_eq!(containers[0].name, "scapegoat-redis");
+        assert_eq!(containers[1].public_ports(), vec![9000]);
+        assert_eq!(containers[1].networks.len(), 2);
+        assert_eq!(containers[2].command.as_deref(), Some("python worker.py"));
+    }
+
+    #[tokio::test]
+    async fn bringing_a_stack_up_again_replaces_it() {
+        let runtime = FakeRuntime::new();
+        let project = project();
+        let first = stack_up(&runtime, &project.path()).await.unwrap();
+
+        let second = stack_up(&runtime, &project.path()).await.unwrap();
+
+        let containers = services(&runtime).await;
+        assert_eq!(containers.len(), 3);
+        assert!(containers
+            .iter()
+            .all(|c| c.id != first.services[0].container_id));
+        assert_eq!(containers[0].id, second.services[0].container_id);
+    }
+
+    #[tokio::test]
+    async fn a_service_failing_to_start_brings_the_stack_down() {
+        let runtime = FakeRuntime::new();
+        let project = project();
+        runtime.fail_starts_of(&Path::new(&project.path()).join("app").to_string_lossy());
+
+        let error = stack_up(&runtime, &project.path()).await.unwrap_err();
+
+        assert!(error.contains("scapegoat-web"), "{}", error);
+        assert!(services(&runtime).await.is_empty());
+        assert!(runtime.list_networks("scapegoat").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stack_down_stops_every_service_even_if_one_fails() {
+        let runtime = FakeRuntime::new();
+        let project = project();
+        stack_up(&runtime, &project.path()).await.unwrap();
+        runtime.fail_stops_of("scapegoat-web");
+
+        let error = stack_down(&runtime, "scapegoat").await.unwrap_err();
+
+        assert!(error.contains("scapegoat-web"), "{}", error);
+        let left: Vec<String> = services(&runtime)
+            .await
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(left, vec!["scapegoat-web"]);
+        // The networks stay until nothing is attached to them
+        assert_eq!(runtime.list_networks("scapegoat").await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,725 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::docker_container::DockerContainer;
+use super::endpoints::EndpointPublisher;
+use super::history::DeploymentRecord;
+use super::{APP_LABEL, MANAGED_LABEL, VERSION_LABEL};
+
+/// How large the journal may grow before a change compacts it, in bytes
+pub const COMPACT_THRESHOLD_BYTES: u64 = 1024 * 1024;
+
+/// What the agent knows about an application it owns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppState {
+    /// The application name from its `shipwreck.toml`
+    pub name: String,
+
+    /// The version currently deployed
+    pub version: String,
+
+    /// The image currently deployed
+    pub image_id: Option<String>,
+
+    /// The container currently serving the application, `None` if it is not running
+    pub container_id: Option<String>,
+
+    /// The host ports the serving container publishes
+    pub ports: Vec<i64>,
+}
+
+/// A single change to the stored state, as written to the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum StateEvent {
+    AppUpdated(AppState),
+    AppRemoved { name: String },
+    DeploymentRecorded(DeploymentRecord),
+    PortAllocated { port: i64, owner: String },
+    PortReleased { port: i64 },
+    EndpointPublished { app: String, port: i64 },
+}
+
+/// Everything held by a `StateStore`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredState {
+    /// The applications the agent owns, by name
+    pub apps: BTreeMap<String, AppState>,
+
+    /// Every deployment made by the agent, oldest first
+    pub deployments: Vec<DeploymentRecord>,
+
+    /// The host ports handed out, and the application holding each
+    pub ports: BTreeMap<i64, String>,
+
+    /// The host port each application's endpoint points at, by name
+    #[serde(default)]
+    pub endpoints: BTreeMap<String, i64>,
+}
+
+impl StoredState {
+    fn apply(&mut self, event: StateEvent) {
+        match event {
+            StateEvent::AppUpdated(app) => {
+                self.apps.insert(app.name.clone(), app);
+            }
+            StateEvent::AppRemoved { name } => {
+                self.apps.remove(&name);
+                self.endpoints.remove(&name);
+            }
+            StateEvent::DeploymentRecorded(record) => self.deployments.push(record),
+            StateEvent::PortAllocated { port, owner } => {
+                self.ports.insert(port, owner);
+            }
+            StateEvent::PortReleased { port } => {
+                self.ports.remove(&port);
+            }
+            StateEvent::EndpointPublished { app, port } => {
+                self.endpoints.insert(app, port);
+            }
+        }
+    }
+
+    /// The events releasing every port held by an application
+    fn release_events(&self, app: &str) -> Vec<StateEvent> {
+        self.ports
+            .iter()
+            .filter(|(_, owner)| *owner == app)
+            .map(|(port, _)| StateEvent::PortReleased { port: *port })
+            .collect()
+    }
+
+    /// The events which rebuild this state from nothing
+    fn to_events(&self) -> Vec<StateEvent> {
+        let apps = self.apps.values().cloned().map(StateEvent::AppUpdated);
+        let deployments = self
+            .deployments
+            .iter()
+            .cloned()
+            .map(StateEvent::DeploymentRecorded);
+        let ports = self
+            .ports
+            .iter()
+            .map(|(port, owner)| StateEvent::PortAllocated {
+                port: *port,
+                owner: owner.clone(),
+            });
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(|(app, port)| StateEvent::EndpointPublished {
+                app: app.clone(),
+                port: *port,
+            });
+        apps.chain(deployments)
+            .chain(ports)
+            .chain(endpoints)
+            .collect()
+    }
+}
+
+/// What `StateStore::reconcile` changed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    /// Apps whose recorded container is no longer running
+    pub lost: Vec<String>,
+
+    /// Running managed containers which were not in the store, and have been added to it
+    pub adopted: Vec<String>,
+
+    /// Apps whose recorded container is still running
+    pub confirmed: Vec<String>,
+}
+
+/// An on-disk record of the apps, deployments, port allocations and endpoints the agent owns, so they survive restarts
+///
+/// The store is also an `EndpointPublisher`, so where a redeploy or rollback moved an app is still known to the next command.
+///
+/// Every change is appended to a journal of JSON lines, which is replayed when the store is opened.
+/// Once the journal passes `COMPACT_THRESHOLD_BYTES` it is rewritten with only the events needed for the current state (see `compact`).
+///
+/// # Examples
+///
+/// ```
+/// let store = StateStore::open("./tmp/state.jsonl")?;
+/// let report = store.reconcile(&docker.get_running_containers().await)?;
+/// println!("Lost {:?}, adopted {:?}", report.lost, report.adopted);
+/// ```
+pub struct StateStore {
+    path: PathBuf,
+    state: Mutex<StoredState>,
+    compact_threshold: u64,
+}
+
+impl StateStore {
+    /// Opens a store, replaying its journal if it exists
+    ///
+    /// A crash mid-write can only tear the journal's last line, so an unreadable last line is dropped.
+    /// An unreadable line anywhere else means the journal is corrupt, and opening fails rather than losing what came after it.
+    /// The journal is compacted if its last line was torn or it has passed `COMPACT_THRESHOLD_BYTES`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The journal file, which is created (along with its folder) on the first change
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<StateStore, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = StoredState::default();
+        let mut torn = false;
+        let mut size = 0;
+        if path.exists() {
+            let file = File::open(&path)
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+            let lines = BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let last = lines.iter().rposition(|l| !l.trim().is_empty());
+            for (i, line) in lines.iter().enumerate() {
+                size += line.len() as u64 + 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(line) {
+                    Ok(event) => state.apply(event),
+                    Err(e) if Some(i) == last => {
+                        warn!(
+                            "Dropping torn last line {} of {}: {}",
+                            i + 1,
+                            path.display(),
+                            e
+                        );
+                        torn = true;
+                    }
+                    Err(e) => {
+                        return Err(format!(
+                            "{} is corrupt at line {}: {}",
+                            path.display(),
+                            i + 1,
+                            e
+                        ))
+                    }
+                }
+            }
+            info!(
+                "Loaded {} apps and {} deployments from {}",
+                state.apps.len(),
+                state.deployments.len(),
+                path.display()
+            );
+        }
+        let store = StateStore {
+            path,
+            state: Mutex::new(state),
+            compact_threshold: COMPACT_THRESHOLD_BYTES,
+        };
+        // Appending after a torn line would join the next event onto it
+        if torn || size > store.compact_threshold {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Sets how large the journal may grow before a change compacts it, in bytes
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let store = StateStore::open("./tmp/state.jsonl")?.with_compact_threshold(64 * 1024);
+    /// ```
+    pub fn with_compact_threshold(mut self, bytes: u64) -> StateStore {
+        self.compact_threshold = bytes;
+        self
+    }
+
+    /// Gets a copy of everything in the store
+    pub fn snapshot(&self) -> StoredState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Gets an application by name
+    pub fn app(&self, name: &str) -> Option<AppState> {
+        self.state.lock().unwrap().apps.get(name).cloned()
+    }
+
+    /// Gets every recorded deployment, oldest first
+    pub fn deployments(&self) -> Vec<DeploymentRecord> {
+        self.state.lock().unwrap().deployments.clone()
+    }
+
+    /// Gets the application holding a host port
+    pub fn port_owner(&self, port: i64) -> Option<String> {
+        self.state.lock().unwrap().ports.get(&port).cloned()
+    }
+
+    /// Records or replaces an application
+    pub fn save_app(&self, app: AppState) -> Result<(), String> {
+        self.commit(|_| Ok(vec![StateEvent::AppUpdated(app)]))
+    }
+
+    /// Forgets an application and releases its ports
+    pub fn remove_app(&self, name: &str) -> Result<(), String> {
+        self.commit(|state| {
+            let mut events = state.release_events(name);
+            events.push(StateEvent::AppRemoved {
+                name: String::from(name),
+            });
+            Ok(events)
+        })
+    }
+
+    /// Hands a host port to an application, failing if another application holds it
+    pub fn allocate_port(&self, port: i64, owner: &str) -> Result<(), String> {
+        self.commit(|state| {
+            if let Some(current) = state.ports.get(&port).filter(|o| *o != owner) {
+                return Err(format!("Port {} is already allocated to {}", port, current));
+            }
+            Ok(vec![StateEvent::PortAllocated {
+                port,
+                owner: String::from(owner),
+            }])
+        })
+    }
+
+    /// Returns a host port to the pool
+    pub fn release_port(&self, port: i64) -> Result<(), String> {
+        self.commit(|_| Ok(vec![StateEvent::PortReleased { port }]))
+    }
+
+    /// Records a deployment
+    ///
+    /// A successful deployment also becomes the app's current state, moving its port allocations and endpoint to the new container's ports.
+    pub fn record_deployment(&self, record: &DeploymentRecord) -> Result<(), String> {
+        self.commit(|state| Ok(deployment_events(state, record)))
+    }
+
+    /// Brings the store in line with the containers which are actually running, e.g. when the agent starts
+    ///
+    /// Apps whose container has gone are kept but marked as not running, and their ports are released.
+    /// Running containers carrying `MANAGED_LABEL` and `APP_LABEL` which the store doesn't know about are adopted.
+    ///
+    /// # Arguments
+    ///
+    /// * `running` - The running containers, from `DockerBroker::get_running_containers`
+    pub fn reconcile(&self, running: &[DockerContainer]) -> Result<ReconcileReport, String> {
+        let mut report = ReconcileReport::default();
+        self.commit(|state| Ok(reconcile_events(state, running, &mut report)))?;
+        Ok(report)
+    }
+
+    /// Rewrites the journal with only the events needed to rebuild the current state
+    ///
+    /// This happens by itself when the store is opened or changed with a journal past its compaction threshold.
+    pub fn compact(&self) -> Result<(), String> {
+        self.write_compacted(&self.state.lock().unwrap())
+    }
+
+    /// Rewrites the journal from `state`, which the caller holds the lock of
+    fn write_compacted(&self, state: &StoredState) -> Result<(), String> {
+        let tmp = self.path.with_extension("compacting");
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = File::create(&tmp)?;
+            for event in state.to_events() {
+                writeln!(file, "{}", serde_json::to_string(&event)?)?;
+            }
+            file.sync_all()?;
+            // Renaming is atomic, so a crash leaves either the old or the new journal
+            fs::rename(&tmp, &self.path)
+        };
+        write().map_err(|e| format!("Failed to compact {}: {}", self.path.display(), e))?;
+        info!("Compacted {}", self.path.display());
+        Ok(())
+    }
+
+    /// Gets the port each application's endpoint points at
+    pub fn endpoints(&self) -> BTreeMap<String, i64> {
+        self.state.lock().unwrap().endpoints.clone()
+    }
+
+    /// Works out events from the current state, appends them to the journal, then applies them, compacting the journal if it has grown past the threshold
+    ///
+    /// The lock is held throughout, so concurrent changes can't work from the same stale state.
+    fn commit<F>(&self, events: F) -> Result<(), String>
+    where
+        F: FnOnce(&StoredState) -> Result<Vec<StateEvent>, String>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let events = events(&state)?;
+        if events.is_empty() {
+            return Ok(());
+        }
+        let append = || -> std::io::Result<u64> {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let mut lines = String::new();
+            for event in &events {
+                lines.push_str(&serde_json::to_string(event)?);
+                lines.push('\n');
+            }
+            file.write_all(lines.as_bytes())?;
+            file.sync_data()?;
+            Ok(file.metadata()?.len())
+        };
+        let size =
+            append().map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        for event in events {
+            state.apply(event);
+        }
+        if size > self.compact_threshold {
+            // The events are already in the journal, so a failed compaction loses nothing
+            if let Err(e) = self.write_compacted(&state) {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EndpointPublisher for StateStore {
+    fn publish(&self, app: &str, port: i64) -> Result<(), String> {
+        let previous = self.published_port(app);
+        self.commit(|_| {
+            Ok(vec![StateEvent::EndpointPublished {
+                app: String::from(app),
+                port,
+            }])
+        })?;
+        match previous {
+            Some(p) => info!("Switched {} from port {} to {}", app, p, port),
+            None => info!("Published {} on port {}", app, port),
+        }
+        Ok(())
+    }
+
+    fn published_port(&self, app: &str) -> Option<i64> {
+        self.state.lock().unwrap().endpoints.get(app).copied()
+    }
+}
+
+/// The events recording a deployment, making a successful one the app's current state
+fn deployment_events(state: &StoredState, record: &DeploymentRecord) -> Vec<StateEvent> {
+    let mut events = vec![StateEvent::DeploymentRecorded(record.clone())];
+    if record.succeeded() {
+        events.extend(state.release_events(&record.app));
+        events.extend(record.ports.iter().map(|p| StateEvent::PortAllocated {
+            port: *p,
+            owner: record.app.clone(),
+        }));
+        if let Some(port) = record.ports.first() {
+            events.push(StateEvent::EndpointPublished {
+                app: record.app.clone(),
+                port: *port,
+            });
+        }
+        events.push(StateEvent::AppUpdated(AppState {
+            name: record.app.clone(),
+            version: record.version.clone(),
+            image_id: record.image_id.clone(),
+            container_id: record.container_id.clone(),
+            ports: record.ports.clone(),
+        }));
+    }
+    events
+}
+
+/// The events bringing `state` in line with the running containers, noting what changed in `report`
+fn reconcile_events(
+    state: &StoredState,
+    running: &[DockerContainer],
+    report: &mut ReconcileReport,
+) -> Vec<StateEvent> {
+    let mut events = vec![];
+    let is_running = |id: &str| running.iter().any(|c| c.id == id || c.name == id);
+
+    for app in state.apps.values() {
+        match &app.container_id {
+            Some(id) if is_running(id) => report.confirmed.push(app.name.clone()),
+            Some(id) => {
+                warn!("Container {} of {} is no longer running", id, app.name);
+                events.extend(state.release_events(&app.name));
+                events.push(StateEvent::AppUpdated(AppState {
+                    container_id: None,
+                    ports: vec![],
+                    ..app.clone()
+                }));
+                report.lost.push(app.name.clone());
+            }
+            None => {}
+        }
+    }
+
+    let known = |c: &DockerContainer| {
+        state
+            .apps
+            .values()
+            .any(|a| a.container_id.as_deref() == Some(&c.id))
+    };
+    for c in running.iter().filter(|c| !known(c)) {
+        let app = match (c.labels.get(MANAGED_LABEL), c.labels.get(APP_LABEL)) {
+            (Some(_), Some(app)) => app,
+            _ => continue,
+        };
+        // Several containers may claim the same app, e.g. mid redeploy; the newest wins
+        let newer_claim = running.iter().any(|other| {
+            other.id != c.id
+                && other.labels.get(APP_LABEL) == Some(app)
+                && other.created > c.created
+        });
+        if newer_claim || report.confirmed.contains(app) {
+            continue;
+        }
+        info!("Adopting container {} of {}", c.id, app);
+        let ports = c.public_ports();
+        events.extend(ports.iter().map(|p| StateEvent::PortAllocated {
+            port: *p,
+            owner: app.clone(),
+        }));
+        events.push(StateEvent::AppUpdated(AppState {
+            name: app.clone(),
+            version: c.labels.get(VERSION_LABEL).cloned().unwrap_or_default(),
+            image_id: c.image_id.clone(),
+            container_id: Some(c.id.clone()),
+            ports,
+        }));
+        report.adopted.push(app.clone());
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::docker_container::DockerPort;
+    use crate::docker::history::{DeploymentHistory, DeploymentOutcome};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn journal() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("kraken-state-{}", Uuid::new_v4()))
+            .join("state.jsonl")
+    }
+
+    fn app(name: &str, version: &str) -> AppState {
+        AppState {
+            name: String::from(name),
+            version: String::from(version),
+            image_id: Some(format!("sha256:{}", version)),
+            container_id: None,
+            ports: vec![],
+        }
+    }
+
+    /// A running container of an app, publishing a host port
+    fn container(id: &str, app: &str, port: i64, created: i64) -> DockerContainer {
+        let mut labels = HashMap::new();
+        labels.insert(String::from(MANAGED_LABEL), String::from("true"));
+        labels.insert(String::from(APP_LABEL), String::from(app));
+        labels.insert(String::from(VERSION_LABEL), String::from("1.0.0"));
+        DockerContainer {
+            id: String::from(id),
+            name: String::from(id),
+            names: vec![format!("/{}", id)],
+            image: Some(format!("{}:1.0.0", app)),
+            image_id: Some(format!("sha256:{}", id)),
+            command: None,
+            created: Some(created),
+            ports: vec![DockerPort {
+                private_port: 9000,
+                public_port: Some(port),
+                protocol: String::from("tcp"),
+                ip: Some(String::from("0.0.0.0")),
+            }],
+            labels,
+            networks: HashMap::new(),
+            mounts: vec![],
+            state: Some(String::from("running")),
+            status: None,
+        }
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn changes_survive_reopening() {
+        let path = journal();
+        let store = StateStore::open(&path).unwrap();
+        store.save_app(app("scapegoat", "1.0.0")).unwrap();
+        store.allocate_port(9000, "scapegoat").unwrap();
+        store.save_app(app("kraken", "0.1.0")).unwrap();
+        store.remove_app("kraken").unwrap();
+
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.app("scapegoat"), Some(app("scapegoat", "1.0.0")));
+        assert_eq!(reopened.app("kraken"), None);
+        assert_eq!(reopened.port_owner(9000).as_deref(), Some("scapegoat"));
+    }
+
+    #[test]
+    fn endpoints_survive_reopening_and_compaction() {
+        let path = journal();
+        let store = StateStore::open(&path).unwrap();
+        let mut record = DeploymentRecord::new(
+            "scapegoat",
+            "1.0.0",
+            &HashMap::new(),
+            DeploymentOutcome::Succeeded,
+        );
+        record.ports = vec![9000];
+        store.record_deployment(&record).unwrap();
+        assert_eq!(store.published_port("scapegoat"), Some(9000));
+        store.publish("scapegoat", 21000).unwrap();
+
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.published_port("scapegoat"), Some(21000));
+        reopened.compact().unwrap();
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.published_port("scapegoat"), Some(21000));
+        reopened.remove_app("scapegoat").unwrap();
+        assert_eq!(reopened.published_port("scapegoat"), None);
+    }
+
+    #[test]
+    fn a_torn_last_line_is_dropped_and_compacted_away() {
+        let path = journal();
+        let store = StateStore::open(&path).unwrap();
+        store.save_app(app("scapegoat", "1.0.0")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"AppUpdated\":{{\"name\":\"scape").unwrap();
+
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.app("scapegoat"), Some(app("scapegoat", "1.0.0")));
+        // The next change lands on its own line rather than on the torn one
+        reopened.save_app(app("scapegoat", "2.0.0")).unwrap();
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.app("scapegoat"), Some(app("scapegoat", "2.0.0")));
+    }
+
+    #[test]
+    fn corruption_before_the_last_line_fails_to_open() {
+        let path = journal();
+        let store = StateStore::open(&path).unwrap();
+        store.save_app(app("scapegoat", "1.0.0")).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "not json").unwrap();
+        drop(file);
+        store.save_app(app("scapegoat", "2.0.0")).unwrap();
+
+        let error = StateStore::open(&path).err().unwrap();
+        assert!(error.contains("corrupt at line 2"), "{}", error);
+    }
+
+    #[test]
+    fn the_journal_is_compacted_past_the_threshold() {
+        let path = journal();
+        let store = StateStore::open(&path)
+            .unwrap()
+            .with_compact_threshold(2048);
+        for i in 0..100 {
+            store
+                .save_app(app("scapegoat", &format!("1.0.{}", i)))
+                .unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() <= 2048);
+
+        let reopened = StateStore::open(&path).unwrap();
+        assert_eq!(reopened.app("scapegoat"), Some(app("scapegoat", "1.0.99")));
+        reopened.compact().unwrap();
+        assert_eq!(lines(&path), 1);
+    }
+
+    #[test]
+    fn reconcile_adopts_the_newest_unknown_container_of_an_app() {
+        let store = StateStore::open(journal()).unwrap();
+        let mut unmanaged = container("c", "kraken", 9102, 1);
+        unmanaged.labels.remove(MANAGED_LABEL);
+
+        let report = store
+            .reconcile(&[
+                container("a", "scapegoat", 9100, 1),
+                container("b", "scapegoat", 9101, 2),
+                unmanaged,
+            ])
+            .unwrap();
+
+        assert_eq!(report.adopted, vec![String::from("scapegoat")]);
+        assert!(report.lost.is_empty());
+        let adopted = store.app("scapegoat").unwrap();
+        assert_eq!(adopted.container_id.as_deref(), Some("b"));
+        assert_eq!(adopted.image_id.as_deref(), Some("sha256:b"));
+        assert_eq!(adopted.version, "1.0.0");
+        assert_eq!(adopted.ports, vec![9101]);
+        assert_eq!(store.port_owner(9101).as_deref(), Some("scapegoat"));
+        assert_eq!(store.app("kraken"), None);
+
+        let report = store
+            .reconcile(&[container("b", "scapegoat", 9101, 2)])
+            .unwrap();
+        assert_eq!(report.confirmed, vec![String::from("scapegoat")]);
+        assert!(report.adopted.is_empty());
+    }
+
+    #[test]
+    fn reconcile_marks_apps_whose_container_has_gone_as_lost() {
+        let path = journal();
+        let store = StateStore::open(&path).unwrap();
+        store
+            .save_app(AppState {
+                container_id: Some(String::from("gone")),
+                ports: vec![9200],
+                ..app("scapegoat", "1.0.0")
+            })
+            .unwrap();
+        store.allocate_port(9200, "scapegoat").unwrap();
+
+        let report = store.reconcile(&[]).unwrap();
+
+        assert_eq!(report.lost, vec![String::from("scapegoat")]);
+        let reopened = StateStore::open(&path).unwrap();
+        let lost = reopened.app("scapegoat").unwrap();
+        assert_eq!(lost.container_id, None);
+        assert!(lost.ports.is_empty());
+        assert_eq!(lost.image_id, app("scapegoat", "1.0.0").image_id);
+        assert_eq!(reopened.port_owner(9200), None);
+    }
+
+    #[test]
+    fn persisted_history_keeps_its_ids_across_restarts() {
+        let path = journal();
+        let history = DeploymentHistory::persisted(Arc::new(StateStore::open(&path).unwrap()));
+        let env = HashMap::new();
+        for version in &["1.0.0", "2.0.0"] {
+            history.record(DeploymentRecord::new(
+                "scapegoat",
+                version,
+                &env,
+                DeploymentOutcome::Succeeded,
+            ));
+        }
+
+        let history = DeploymentHistory::persisted(Arc::new(StateStore::open(&path).unwrap()));
+        assert_eq!(history.current("scapegoat").unwrap().id, 2);
+        let id = history.record(DeploymentRecord::new(
+            "scapegoat",
+            "3.0.0",
+            &env,
+            DeploymentOutcome::Failed(String::from("no port")),
+        ));
+        assert_eq!(id, 3);
+    }
+}
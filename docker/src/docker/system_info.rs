@@ -0,0 +1,424 @@
+use bollard::models::SystemDataUsageResponse;
+use bollard::ClientVersion;
+use hyper::{Body, Client, Response, StatusCode, Uri};
+use hyper_unix_connector::UnixClient;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::connection::DockerHost;
+use super::timeouts::with_timeout;
+use super::DockerBroker;
+
+/// What the docker daemon runs on, and how it stores images
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonInfo {
+    /// The docker engine version, e.g. `19.03.12`
+    pub version: String,
+
+    /// The highest API version the daemon speaks
+    pub api_version: String,
+
+    /// The daemon's operating system, e.g. `linux`
+    pub os: String,
+
+    /// The daemon's CPU architecture, e.g. `amd64`
+    pub arch: String,
+
+    /// The kernel the daemon runs on
+    pub kernel_version: String,
+
+    /// The storage driver holding image layers, e.g. `overlay2`
+    ///
+    /// This and the fields below are `None` when the daemon is reached over TLS (see `DockerBroker::daemon_info`).
+    pub storage_driver: Option<String>,
+
+    /// The CPUs available to the daemon
+    pub cpus: Option<u64>,
+
+    /// The memory of the daemon's host, in bytes
+    pub memory_bytes: Option<u64>,
+
+    /// The folder the daemon keeps images, containers and volumes in, e.g. `/var/lib/docker`
+    pub data_root: Option<String>,
+}
+
+/// The disk used by one kind of docker object
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageCategory {
+    /// How many there are
+    pub count: u64,
+
+    /// How many are in use, by a container or (for containers) by running
+    pub active: u64,
+
+    /// The bytes they take up, counting layers shared between images once
+    pub size_bytes: u64,
+
+    /// The bytes a prune of the unused ones would free
+    pub reclaimable_bytes: u64,
+}
+
+/// The disk used by docker, like `docker system df`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// Images, active when a container uses them
+    pub images: UsageCategory,
+
+    /// The writable layers of containers, active while running
+    pub containers: UsageCategory,
+
+    /// Volumes, active when a container mounts them
+    pub volumes: UsageCategory,
+
+    /// Layers cached by BuildKit builds, active while a build uses them
+    pub build_cache: UsageCategory,
+}
+
+impl DiskUsage {
+    /// The bytes taken up by everything
+    pub fn total_bytes(&self) -> u64 {
+        self.categories().iter().map(|(_, c)| c.size_bytes).sum()
+    }
+
+    /// The bytes a prune of everything unused would free
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.categories()
+            .iter()
+            .map(|(_, c)| c.reclaimable_bytes)
+            .sum()
+    }
+
+    /// Each kind of object with its usage, for reports
+    pub fn categories(&self) -> [(&'static str, &UsageCategory); 4] {
+        [
+            ("Images", &self.images),
+            ("Containers", &self.containers),
+            ("Volumes", &self.volumes),
+            ("Build cache", &self.build_cache),
+        ]
+    }
+}
+
+impl From<SystemDataUsageResponse> for DiskUsage {
+    fn from(df: SystemDataUsageResponse) -> DiskUsage {
+        // Docker reports sizes it couldn't work out as -1
+        let bytes = |size: i64| size.max(0) as u64;
+        let mut usage = DiskUsage::default();
+
+        let images = df.images.unwrap_or_default();
+        let mut used = 0;
+        for image in &images {
+            if image.containers > 0 {
+                usage.images.active += 1;
+                used += bytes(image.size - image.shared_size);
+            }
+        }
+        usage.images.count = images.len() as u64;
+        usage.images.size_bytes = bytes(df.layers_size.unwrap_or(0));
+        usage.images.reclaimable_bytes = usage.images.size_bytes.saturating_sub(used);
+
+        for container in df.containers.unwrap_or_default() {
+            let size = bytes(container.size_rw.unwrap_or(0));
+            usage.containers.count += 1;
+            usage.containers.size_bytes += size;
+            if container.state.as_deref() == Some("running") {
+                usage.containers.active += 1;
+            } else {
+                usage.containers.reclaimable_bytes += size;
+            }
+        }
+
+        for volume in df.volumes.unwrap_or_default() {
+            let (size, refs) = volume
+                .usage_data
+                .map(|u| (bytes(u.size), u.ref_count))
+                .unwrap_or((0, 0));
+            usage.volumes.count += 1;
+            usage.volumes.size_bytes += size;
+            if refs > 0 {
+                usage.volumes.active += 1;
+            } else {
+                usage.volumes.reclaimable_bytes += size;
+            }
+        }
+
+        for record in df.build_cache.unwrap_or_default() {
+            // Shared records are counted by the records they are shared with
+            if record.shared.unwrap_or(false) {
+                continue;
+            }
+            let size = bytes(record.size.unwrap_or(0));
+            usage.build_cache.count += 1;
+            usage.build_cache.size_bytes += size;
+            if record.in_use.unwrap_or(false) {
+                usage.build_cache.active += 1;
+            } else {
+                usage.build_cache.reclaimable_bytes += size;
+            }
+        }
+        usage
+    }
+}
+
+/// The parts of docker's `/info` response which `DaemonInfo` holds
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InfoResponse {
+    driver: Option<String>,
+    #[serde(rename = "NCPU")]
+    ncpu: Option<u64>,
+    mem_total: Option<u64>,
+    docker_root_dir: Option<String>,
+}
+
+impl DockerBroker {
+    /// Gets the daemon's version, the resources of its host and how it stores images
+    ///
+    /// bollard has no call for docker's `/info` endpoint, so it is requested with hyper over the same unix socket or TCP address, at the API version bollard speaks.
+    /// Over TLS only the fields from `/version` are filled in.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let info = docker.daemon_info().await?;
+    /// println!("Docker {} on {} CPUs", info.version, info.cpus.unwrap_or(0));
+    /// ```
+    pub async fn daemon_info(&self) -> Result<DaemonInfo, String> {
+        let version = with_timeout("get docker version", self.timeouts.inspect, async {
+            self.conn
+                .version()
+                .await
+                .map_err(|e| format!("Failed to get docker version: {:?}", e))
+        })
+        .await?;
+        let info = match &self.host {
+            DockerHost::Tls { .. } => InfoResponse::default(),
+            host => {
+                let version = self.conn.client_version();
+                let info = with_timeout("get docker info", self.timeouts.inspect, async {
+                    request_info(host, &version).await
+                });
+                match info.await {
+                    Ok(info) => info,
+                    Err(e) => {
+                        warn!("{}", e);
+                        InfoResponse::default()
+                    }
+                }
+            }
+        };
+        Ok(DaemonInfo {
+            version: version.version,
+            api_version: version.api_version,
+            os: version.os,
+            arch: version.arch,
+            kernel_version: version.kernel_version,
+            storage_driver: info.driver,
+            cpus: info.ncpu,
+            memory_bytes: info.mem_total,
+            data_root: info.docker_root_dir,
+        })
+    }
+
+    /// Gets the disk used by images, containers, volumes and the build cache, and how much of it a prune would free
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let docker = DockerBroker::new();
+    /// let usage = docker.disk_usage().await?;
+    /// println!("{} of {} bytes reclaimable", usage.reclaimable_bytes(), usage.total_bytes());
+    /// ```
+    pub async fn disk_usage(&self) -> Result<DiskUsage, String> {
+        let df = with_timeout("get disk usage", self.timeouts.list, async {
+            self.conn
+                .df()
+                .await
+                .map_err(|e| format!("Failed to get docker disk usage: {:?}", e))
+        })
+        .await?;
+        Ok(DiskUsage::from(df))
+    }
+}
+
+/// Requests docker's `/info` over a unix socket or plain TCP, as bollard has no call for it
+async fn request_info(host: &DockerHost, version: &ClientVersion) -> Result<InfoResponse, String> {
+    let path = format!("/v{}.{}/info", version.major_version, version.minor_version);
+    let response: Result<Response<Body>, _> = match host {
+        DockerHost::Unix(socket) => {
+            let uri: Uri = hyper_unix_connector::Uri::new(socket, &path).into();
+            Client::builder()
+                .build::<_, Body>(UnixClient)
+                .get(uri)
+                .await
+        }
+        DockerHost::Tcp(addr) => {
+            let uri: Uri = format!("http://{}{}", addr, path)
+                .parse()
+                .map_err(|e| format!("Invalid docker address {}: {}", addr, e))?;
+            Client::new().get(uri).await
+        }
+        DockerHost::Tls { .. } => return Err(String::from("Can't request /info over TLS")),
+    };
+    let response = response.map_err(|e| format!("Failed to request docker info: {}", e))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| format!("Failed to read docker info: {}", e))?;
+    if status != StatusCode::OK {
+        return Err(format!(
+            "Docker info request failed: {} {}",
+            status,
+            String::from_utf8_lossy(&body).trim()
+        ));
+    }
+    serde_json::from_slice(&body).map_err(|e| format!("Failed to parse docker info: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::TempProject;
+    use bollard::models::{
+        BuildCache, ContainerSummaryInner, ImageSummary, Volume, VolumeUsageData,
+    };
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    fn image(size: i64, shared_size: i64, containers: i64) -> ImageSummary {
+        ImageSummary {
+            size,
+            shared_size,
+            containers,
+            ..Default::default()
+        }
+    }
+
+    fn container(size_rw: i64, state: &str) -> ContainerSummaryInner {
+        ContainerSummaryInner {
+            size_rw: Some(size_rw),
+            state: Some(String::from(state)),
+            ..Default::default()
+        }
+    }
+
+    fn volume(size: i64, ref_count: i64) -> Volume {
+        Volume {
+            usage_data: Some(VolumeUsageData { size, ref_count }),
+            ..Default::default()
+        }
+    }
+
+    fn cache(size: i64, in_use: bool, shared: bool) -> BuildCache {
+        BuildCache {
+            size: Some(size),
+            in_use: Some(in_use),
+            shared: Some(shared),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn disk_usage_is_folded_like_docker_system_df() {
+        let usage = DiskUsage::from(SystemDataUsageResponse {
+            layers_size: Some(1000),
+            images: Some(vec![image(600, 200, 1), image(300, 200, 0)]),
+            containers: Some(vec![
+                container(50, "running"),
+                container(20, "exited"),
+                container(-1, "created"),
+            ]),
+            volumes: Some(vec![volume(100, 1), volume(40, 0), volume(-1, 0)]),
+            build_cache: Some(vec![
+                cache(10, true, false),
+                cache(30, false, false),
+                cache(70, false, true),
+            ]),
+        });
+
+        assert_eq!(
+            (
+                usage.images.count,
+                usage.images.active,
+                usage.images.size_bytes
+            ),
+            (2, 1, 1000)
+        );
+        // Only the layers of the used image which it doesn't share are kept
+        assert_eq!(usage.images.reclaimable_bytes, 600);
+        assert_eq!((usage.containers.count, usage.containers.active), (3, 1));
+        assert_eq!(
+            (
+                usage.containers.size_bytes,
+                usage.containers.reclaimable_bytes
+            ),
+            (70, 20)
+        );
+        assert_eq!((usage.volumes.count, usage.volumes.active), (3, 1));
+        assert_eq!(
+            (usage.volumes.size_bytes, usage.volumes.reclaimable_bytes),
+            (140, 40)
+        );
+        assert_eq!((usage.build_cache.count, usage.build_cache.active), (2, 1));
+        assert_eq!(
+            (
+                usage.build_cache.size_bytes,
+                usage.build_cache.reclaimable_bytes
+            ),
+            (40, 30)
+        );
+        assert_eq!(usage.total_bytes(), 1000 + 70 + 140 + 40);
+        assert_eq!(usage.reclaimable_bytes(), 600 + 20 + 40 + 30);
+    }
+
+    #[test]
+    fn empty_disk_usage_is_all_zero() {
+        let usage = DiskUsage::from(SystemDataUsageResponse::default());
+        assert_eq!(usage.total_bytes(), 0);
+        assert_eq!(usage.images.count, 0);
+    }
+
+    #[tokio::test]
+    async fn info_is_requested_at_the_clients_api_version() {
+        let folder = TempProject::with_files(&[]);
+        let socket = Path::new(&folder.path()).join("docker.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let daemon = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream)
+                .read_line(&mut request_line)
+                .unwrap();
+            let body =
+                r#"{"Driver":"overlay2","NCPU":4,"MemTotal":8192,"DockerRootDir":"/srv/docker"}"#;
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request_line
+        });
+
+        let version = ClientVersion {
+            major_version: 1,
+            minor_version: 38,
+        };
+        let info = request_info(&DockerHost::Unix(socket), &version)
+            .await
+            .unwrap();
+
+        assert!(daemon
+            .join()
+            .unwrap()
+            .starts_with("GET /v1.38/info HTTP/1.1"));
+        assert_eq!(info.driver.as_deref(), Some("overlay2"));
+        assert_eq!(info.ncpu, Some(4));
+        assert_eq!(info.mem_total, Some(8192));
+        assert_eq!(info.docker_root_dir.as_deref(), Some("/srv/docker"));
+    }
+}
@@ -0,0 +1,172 @@
+use log::error;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time;
+
+/// How long `DockerBroker` waits for each kind of docker operation before giving up on it
+///
+/// # Examples
+///
+/// ```
+/// let docker = DockerBroker::new().with_timeouts(OperationTimeouts {
+///     build: Duration::from_secs(5 * 60),
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct OperationTimeouts {
+    /// Listing images and containers
+    pub list: Duration,
+
+    /// Inspecting a single image or container
+    pub inspect: Duration,
+
+    /// Building an image, from tarring the source to the last build step
+    pub build: Duration,
+
+    /// Pulling an image from a registry
+    pub pull: Duration,
+
+    /// Saving images to an archive, or loading them from one
+    pub transfer: Duration,
+
+    /// Creating and starting a container
+    pub start: Duration,
+
+    /// Running a test command in a throwaway container, from creating it to the command exiting
+    pub test: Duration,
+
+    /// Stopping a container, including docker's own 10 second grace period
+    pub stop: Duration,
+
+    /// Reading the stats of a container
+    pub stats: Duration,
+
+    /// Reading the logs of a container
+    pub logs: Duration,
+
+    /// Pruning and removing images and containers
+    pub prune: Duration,
+}
+
+impl Default for OperationTimeouts {
+    fn default() -> OperationTimeouts {
+        OperationTimeouts {
+            list: Duration::from_secs(30),
+            inspect: Duration::from_secs(30),
+            build: Duration::from_secs(30 * 60),
+            pull: Duration::from_secs(10 * 60),
+            transfer: Duration::from_secs(30 * 60),
+            start: Duration::from_secs(60),
+            test: Duration::from_secs(10 * 60),
+            stop: Duration::from_secs(30),
+            stats: Duration::from_secs(30),
+            logs: Duration::from_secs(60),
+            prune: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Runs an operation, failing it if it takes longer than `limit`
+///
+/// # Arguments
+///
+/// * `operation` - A description of the operation for the error message (e.g. `stop container 12345`)
+/// * `limit` - How long to wait for the operation
+/// * `f` - The operation
+pub async fn with_timeout<T, F>(operation: &str, limit: Duration, f: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, String>>,
+{
+    match time::timeout(limit, f).await {
+        Ok(result) => result,
+        Err(_) => {
+            error!("Docker {} timed out after {:?}", operation, limit);
+            Err(format!("Docker {} timed out after {:?}", operation, limit))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::fake_runtime::{StalledDaemon, TempProject};
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn operations_which_finish_in_time_return_their_result() {
+        let result = with_timeout("list images", Duration::from_secs(1), async {
+            Ok::<_, String>(3)
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+        let result = with_timeout("list images", Duration::from_secs(1), async {
+            Err::<i32, _>(String::from("Failed to list images"))
+        })
+        .await;
+        assert_eq!(result, Err(String::from("Failed to list images")));
+    }
+
+    #[tokio::test]
+    async fn operations_which_overrun_fail() {
+        let result = with_timeout("stop container 12345", Duration::from_millis(20), async {
+            time::delay_for(Duration::from_secs(5)).await;
+            Ok::<_, String>(())
+        })
+        .await;
+        assert_eq!(
+            result,
+            Err(String::from(
+                "Docker stop container 12345 timed out after 20ms"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_hung_daemon_times_out_a_stop() {
+        let daemon = StalledDaemon::start();
+        let docker = daemon.broker(OperationTimeouts {
+            stop: Duration::from_millis(200),
+            ..Default::default()
+        });
+
+        let error = docker.stop_container("scapegoat").await.unwrap_err();
+
+        assert_eq!(error, "Docker stop container timed out after 200ms");
+        assert_eq!(
+            daemon.requests(),
+            vec![String::from("POST /containers/scapegoat/stop?t=10")]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_build_which_times_out_removes_its_partial_image_and_tarball() {
+        let daemon = StalledDaemon::start();
+        let docker = daemon.broker(OperationTimeouts {
+            build: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let project = TempProject::new("scapegoat", "1.0.0", "");
+
+        let error = docker.build_image(&project.path()).await.unwrap_err();
+
+        assert_eq!(
+            error,
+            "Failed to build image: Docker build image timed out after 300ms"
+        );
+        let removal = daemon
+            .requests()
+            .into_iter()
+            .find(|r| r.starts_with("DELETE ") && r.contains("/images/"))
+            .expect("the partial image was not removed");
+        let tag = removal
+            .rsplit("/images/")
+            .next()
+            .unwrap()
+            .split('?')
+            .next()
+            .unwrap()
+            .to_string();
+        assert!(!Path::new(&format!("./tmp/containers/{}.tar.gz", tag)).exists());
+    }
+}